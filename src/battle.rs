@@ -1,178 +1,1507 @@
-use rand::Rng;
-use rusqlite::{Connection, Result};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::error::Error as StdError;
 
 use crate::{
+    battle_results,
+    config::GrowthConfig,
+    growth::{self, StatGains},
+    items::{self, EquipmentBonus},
+    loot::{self, LootDrop, LootEntry},
     monsters::{self, Monster},
+    repository::Repository,
+    skills::{Skill, SkillKind},
     users::{self, User},
     util,
 };
 
+const MAX_TURNS: i32 = 20; // この回数で決着がつかなければモンスターはにげだす
+
+// 戦闘エンジンのバージョン。同じシードとスナップショットから違う戦闘になる変更をしたら上げる
+// battle_results に一緒に保存し、違うバージョンの記録は再現を確かめない
+pub const ENGINE_VERSION: i32 = 1;
+
+// ダメージ計算式
+//   通常攻撃: (attack + 0..=1の乱数) - 相手のdefense
+//   会心の一撃: 確率 CRITICAL_BASE_RATE + luck * CRITICAL_RATE_PER_LUCK（上限 CRITICAL_MAX_RATE）で発生し、
+//              相手のdefenseを無視して (attack + 0..=1の乱数) * CRITICAL_MULTIPLIER
+//   スキル攻撃: power + 0..=luckの乱数 - 相手のdefense / 2（会心なし、回避されない）
+//   ガード中の相手へのダメージは半分
+//   どの場合も最低 MIN_DAMAGE は与えるので、防御が高くても戦闘が止まらない
+const MIN_DAMAGE: i32 = 1;
+const CRITICAL_BASE_RATE: f64 = 0.03;
+const CRITICAL_RATE_PER_LUCK: f64 = 0.01;
+const CRITICAL_MAX_RATE: f64 = 0.5;
+const CRITICAL_MULTIPLIER: f64 = 1.5;
+
+// 戦闘の決着
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BattleOutcome {
+    Victory,
+    Defeat,
+    Escape, // モンスターがにげだした（引き分け）
+}
+
+impl BattleOutcome {
+    pub fn to_i32(self) -> i32 {
+        match self {
+            BattleOutcome::Victory => 1,
+            BattleOutcome::Defeat => 2,
+            BattleOutcome::Escape => 3,
+        }
+    }
+
+    pub fn from_i32(value: i32) -> Option<BattleOutcome> {
+        match value {
+            1 => Some(BattleOutcome::Victory),
+            2 => Some(BattleOutcome::Defeat),
+            3 => Some(BattleOutcome::Escape),
+            _ => None,
+        }
+    }
+}
+
+// どちら側の行動か
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    Player,
+    Ally(usize), // パーティの仲間（BattleSnapshot::allies の添字）
+    Monster,
+    Enemy(usize), // 2体目以降のモンスター（BattleSnapshot::enemies の添字）
+}
+
+impl Side {
+    pub fn is_monster(self) -> bool {
+        matches!(self, Side::Monster | Side::Enemy(_))
+    }
+}
+
+// 戦闘開始時点の参加者のステータス
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Combatant {
+    pub name: String,
+    pub npub: Option<String>, // プレイヤーのみ（hex）
+    pub picture: String,
+    pub hp: i32,
+    pub max_hp: i32,
+    pub mp: i32,
+    pub max_mp: i32,
+    pub attack: i32,
+    pub defense: i32,
+    pub agility: i32,
+    pub luck: i32,
+    #[serde(default)]
+    pub skills: Vec<Skill>,
+    #[serde(default)]
+    pub selected_skill: Option<String>, // 優先して使うスキル（なければおまかせ）
+}
+
+impl Combatant {
+    // 装備の補正込みの能力値で参加する
+    pub fn from_user(user: &User, bonus: &EquipmentBonus) -> Combatant {
+        Combatant {
+            name: user.npub.clone(),
+            npub: Some(user.npub.clone()),
+            picture: String::new(),
+            hp: user.current_hp,
+            max_hp: user.max_hp,
+            mp: user.current_mp,
+            max_mp: user.max_mp,
+            attack: user.attack + bonus.attack,
+            defense: user.defense + bonus.defense,
+            agility: user.agility + bonus.agility,
+            luck: user.luck,
+            skills: Vec::new(),
+            selected_skill: None,
+        }
+    }
+
+    pub fn from_monster(monster: &Monster) -> Combatant {
+        Combatant {
+            name: monster.name.clone(),
+            npub: None,
+            picture: monster.picture.clone(),
+            hp: monster.hp,
+            max_hp: monster.hp,
+            mp: monster.mp,
+            max_mp: monster.mp,
+            attack: monster.attack,
+            defense: monster.defense,
+            agility: monster.agility,
+            luck: monster.luck,
+            skills: Vec::new(),
+            selected_skill: None,
+        }
+    }
+}
+
+// 戦闘を再現するのに必要な情報（シードと合わせて保存する）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BattleSnapshot {
+    pub player: Combatant,
+    pub monster: Combatant,
+    #[serde(default)]
+    pub allies: Vec<Combatant>, // パーティで戦うときの仲間
+    #[serde(default)]
+    pub enemies: Vec<Combatant>, // 群れで現れたときの2体目以降
+}
+
+impl BattleSnapshot {
+    pub fn combatant(&self, side: Side) -> &Combatant {
+        match side {
+            Side::Player => &self.player,
+            Side::Ally(index) => &self.allies[index],
+            Side::Monster => &self.monster,
+            Side::Enemy(index) => &self.enemies[index],
+        }
+    }
+
+    // モンスター側の参加者（プレイヤーはこの順に狙う）
+    pub fn monster_sides(&self) -> Vec<Side> {
+        let mut sides = vec![Side::Monster];
+        sides.extend((0..self.enemies.len()).map(Side::Enemy));
+        sides
+    }
+
+    // 決闘では相手のユーザーが monster 側に入る
+    pub fn is_duel(&self) -> bool {
+        self.monster.npub.is_some()
+    }
+
+    // プレイヤー側の参加者（先頭は .leveling したユーザー）
+    pub fn party_sides(&self) -> Vec<Side> {
+        let mut sides = vec![Side::Player];
+        sides.extend((0..self.allies.len()).map(Side::Ally));
+        sides
+    }
+}
+
+// 戦闘中に起きた出来事
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BattleEvent {
+    Appear,
+    Attack {
+        attacker: Side,
+    },
+    Dodge {
+        defender: Side,
+    },
+    Critical {
+        attacker: Side,
+    },
+    Damage {
+        target: Side,
+        amount: i32,
+        hp: i32,
+    },
+    CastSkill {
+        caster: Side,
+        skill: String,
+        mp: i32,
+    },
+    Heal {
+        target: Side,
+        amount: i32,
+        hp: i32,
+    },
+    Guard {
+        side: Side,
+    },
+    Flee {
+        side: Side,
+    },
+    Death {
+        side: Side,
+    },
+    Rewards {
+        experience: i32,
+        gold: i32,
+    },
+    LevelUp {
+        side: Side,
+        level: i32,
+        gains: StatGains,
+        stat_points: i32,
+    },
+    GoldLost {
+        side: Side,
+        amount: i32,
+    },
+    ItemDrop {
+        side: Side,
+        item: String,
+        quantity: i32,
+    },
+}
+
+impl BattleEvent {
+    // 決着のあとに simulate_battle が足す出来事（fight は出さない）
+    fn is_aftermath(&self) -> bool {
+        matches!(
+            self,
+            BattleEvent::Rewards { .. }
+                | BattleEvent::LevelUp { .. }
+                | BattleEvent::GoldLost { .. }
+                | BattleEvent::ItemDrop { .. }
+        )
+    }
+}
+
+// 戦闘エンジンの出力
+pub struct Fight {
+    pub events: Vec<BattleEvent>,
+    pub outcome: BattleOutcome,
+    pub player_hp: i32,
+    pub player_mp: i32,
+    pub ally_hp: Vec<i32>,
+    pub ally_mp: Vec<i32>,
+    pub defeated: Vec<Side>,  // 倒したモンスター
+    pub monster_hp: Vec<i32>, // モンスターの戦闘後のHP（monster_sides と同じ順）
+}
+
 pub struct BattleResult {
     pub user_id: i32,
-    pub monster_id: i32,
+    pub monster_id: i32,                // 群れのときは先頭のモンスター
+    pub defeated_monster_ids: Vec<i32>, // 倒したモンスター（負けたときは空）
+    pub outcome: BattleOutcome,
     pub victory: bool,
     pub experience_gain: i32,
     pub gold_gain: i32,
-    pub battle_log: String,
+    pub seed: u64,
+    pub snapshot: BattleSnapshot,
+    pub events: Vec<BattleEvent>,
+    pub updated_user: User, // 戦闘後のユーザーの状態（保存は apply_battle_result で行う）
+    pub updated_allies: Vec<User>, // 仲間の戦闘後の状態（snapshot.allies と同じ順）
+    pub drops: Vec<LootDrop>, // 手に入れたアイテム（.leveling したユーザーが受け取る）
+}
+
+pub fn new_seed() -> u64 {
+    rand::thread_rng().gen()
+}
+
+pub fn rng_from_seed(seed: u64) -> ChaCha8Rng {
+    ChaCha8Rng::seed_from_u64(seed)
 }
 
-fn should_dodge(defense_agility: i32, attack_agility: i32) -> bool {
+fn should_dodge<R: Rng>(rng: &mut R, defense_agility: i32, attack_agility: i32) -> bool {
     let agility_difference = defense_agility - attack_agility; // 素早さの差
-    let dodge_probability = 0.1 + (agility_difference as f64 / 100.0).max(0.0).min(1.0); // 確率を正規化
-    println!(
-        "agility_difference:{} 確率:{}",
-        agility_difference, dodge_probability
-    );
-    let mut rng = rand::thread_rng();
+    let dodge_probability = 0.1 + (agility_difference as f64 / 100.0).clamp(0.0, 1.0); // 確率を正規化
 
     rng.gen::<f64>() < dodge_probability // 確率に基づいて判定
 }
 
-pub fn simulate_battle(conn: &Connection, user: &User, monster: &Monster) -> BattleResult {
-    let mut battle_log = String::new();
-    let mut rng = rand::thread_rng();
-    let mut user_hp = user.current_hp;
-    let mut monster_hp = monster.hp;
-    let npub1 = util::get_npub1(user.npub.clone()).unwrap();
-
-    println!("{}が現れた！", monster.name);
-
-    battle_log.push_str(&format!(
-        "{}\n{}が現れた！\n",
-        monster.picture, monster.name
-    ));
-
-    let mut turn = 20;
-
-    while user_hp > 0 && monster_hp > 0 && turn > 0 {
-        println!("user_hp:{} monster_hp:{}", user_hp, monster_hp);
-        // ユーザーの攻撃
-        battle_log.push_str(&format!("nostr:{} のこうげき！\n", npub1));
-        if !should_dodge(
-            monster.agility,
-            user.agility + rng.gen_range(0..user.luck + 1),
-        ) {
-            let attack =
-                rng.gen_range(user.attack..user.attack + 2) + rng.gen_range(0..user.luck + 1);
-            println!("attack:{}", attack);
-            let damage = (attack - monster.defense).max(0);
-            monster_hp -= damage;
-            battle_log.push_str(&format!(
-                "{} に {} のダメージをあたえた！\n",
-                monster.name, damage
-            ));
-        } else {
-            battle_log.push_str(&format!("{}はひらりとかわした！\n", monster.name));
-        }
-
-        // モンスターの攻撃
-        if monster_hp > 0 {
-            battle_log.push_str(&format!("{} のこうげき！\n", monster.name));
-            if !should_dodge(user.agility, monster.agility) {
-                let attack = rng.gen_range(monster.attack..monster.attack + 2);
-                let damage = (attack - user.defense).max(0);
-                user_hp -= damage;
-                battle_log.push_str(&format!(
-                    "nostr:{} は {} のダメージをうけた！ HP:{}/{}\n",
-                    npub1, damage, user_hp, user.max_hp
-                ));
+fn should_critical<R: Rng>(rng: &mut R, luck: i32) -> bool {
+    let critical_probability =
+        (CRITICAL_BASE_RATE + luck.max(0) as f64 * CRITICAL_RATE_PER_LUCK).min(CRITICAL_MAX_RATE);
+
+    rng.gen::<f64>() < critical_probability
+}
+
+// 通常攻撃のダメージ。会心ならtrueも返す
+fn attack_damage<R: Rng>(rng: &mut R, attacker: &Combatant, defender: &Combatant) -> (i32, bool) {
+    let attack = rng.gen_range(attacker.attack..attacker.attack + 2);
+    if should_critical(rng, attacker.luck) {
+        let damage = (attack as f64 * CRITICAL_MULTIPLIER) as i32;
+        (damage.max(MIN_DAMAGE), true)
+    } else {
+        ((attack - defender.defense).max(MIN_DAMAGE), false)
+    }
+}
+
+// 戦闘中に変化する値
+struct FighterState {
+    hp: i32,
+    mp: i32,
+    guarding: bool,
+}
+
+impl FighterState {
+    fn new(combatant: &Combatant) -> FighterState {
+        FighterState {
+            hp: combatant.hp,
+            mp: combatant.mp,
+            guarding: false,
+        }
+    }
+
+    // ガード中ならダメージは半分（それでも MIN_DAMAGE は受ける）
+    fn take_damage(&mut self, damage: i32) -> i32 {
+        let damage = if self.guarding { damage / 2 } else { damage }.max(MIN_DAMAGE);
+        self.hp -= damage;
+        damage
+    }
+}
+
+// 使うスキルを決める。指定されたスキルを優先し、なければHPが減ったときに回復する
+fn choose_skill<'a>(combatant: &'a Combatant, state: &FighterState) -> Option<&'a Skill> {
+    let affordable = combatant
+        .skills
+        .iter()
+        .filter(|skill| skill.mp_cost <= state.mp);
+
+    if let Some(name) = &combatant.selected_skill {
+        if let Some(skill) = affordable.clone().find(|skill| &skill.name == name) {
+            // 回復は半分を切ってから
+            if skill.kind != SkillKind::Heal || state.hp * 2 <= combatant.max_hp {
+                return Some(skill);
+            }
+        }
+    }
+
+    if state.hp * 3 <= combatant.max_hp {
+        return affordable
+            .filter(|skill| skill.kind == SkillKind::Heal)
+            .max_by_key(|skill| skill.power);
+    }
+
+    None
+}
+
+// 1人分の行動
+fn take_turn<R: Rng>(
+    rng: &mut R,
+    snapshot: &BattleSnapshot,
+    side: Side,
+    actor_state: &mut FighterState,
+    target_side: Side,
+    target_state: &mut FighterState,
+    events: &mut Vec<BattleEvent>,
+) {
+    let actor = snapshot.combatant(side);
+    let target = snapshot.combatant(target_side);
+    actor_state.guarding = false;
+
+    if let Some(skill) = choose_skill(actor, actor_state) {
+        actor_state.mp -= skill.mp_cost;
+        events.push(BattleEvent::CastSkill {
+            caster: side,
+            skill: skill.name.clone(),
+            mp: actor_state.mp,
+        });
+        match skill.kind {
+            SkillKind::Heal => {
+                let amount = (skill.power + rng.gen_range(0..skill.power.max(0) / 2 + 1))
+                    .min(actor.max_hp - actor_state.hp)
+                    .max(0);
+                actor_state.hp += amount;
+                events.push(BattleEvent::Heal {
+                    target: side,
+                    amount,
+                    hp: actor_state.hp,
+                });
+            }
+            SkillKind::Attack => {
+                let damage =
+                    skill.power + rng.gen_range(0..actor.luck.max(0) + 1) - target.defense / 2;
+                let damage = target_state.take_damage(damage);
+                events.push(BattleEvent::Damage {
+                    target: target_side,
+                    amount: damage,
+                    hp: target_state.hp,
+                });
+            }
+            SkillKind::Guard => {
+                actor_state.guarding = true;
+                events.push(BattleEvent::Guard { side });
+            }
+        }
+        return;
+    }
+
+    events.push(BattleEvent::Attack { attacker: side });
+    let agility = actor.agility + rng.gen_range(0..actor.luck.max(0) + 1);
+    if !should_dodge(rng, target.agility, agility) {
+        let (damage, critical) = attack_damage(rng, actor, target);
+        if critical {
+            events.push(BattleEvent::Critical { attacker: side });
+        }
+        let damage = target_state.take_damage(damage);
+        events.push(BattleEvent::Damage {
+            target: target_side,
+            amount: damage,
+            hp: target_state.hp,
+        });
+    } else {
+        events.push(BattleEvent::Dodge {
+            defender: target_side,
+        });
+    }
+}
+
+// DBに触れずに戦闘だけを進める。同じrngの状態とsnapshotからは常に同じ結果になる
+// パーティのときは全員が順番に行動し、モンスターは生きている仲間からランダムに1人を狙う
+// モンスターが群れのときは、プレイヤー側は先頭から順に倒していく
+pub fn fight<R: Rng>(rng: &mut R, snapshot: &BattleSnapshot) -> Fight {
+    let party = snapshot.party_sides();
+    let monsters = snapshot.monster_sides();
+    let mut events = vec![BattleEvent::Appear];
+    let mut party_states: Vec<FighterState> = party
+        .iter()
+        .map(|side| FighterState::new(snapshot.combatant(*side)))
+        .collect();
+    let mut monster_states: Vec<FighterState> = monsters
+        .iter()
+        .map(|side| FighterState::new(snapshot.combatant(*side)))
+        .collect();
+    let mut last_target = Side::Player;
+    let mut last_defeated = Side::Monster;
+
+    let mut turn = MAX_TURNS;
+
+    while party_states.iter().any(|state| state.hp > 0)
+        && monster_states.iter().any(|state| state.hp > 0)
+        && turn > 0
+    {
+        // ユーザーの行動
+        for (side, state) in party.iter().zip(party_states.iter_mut()) {
+            let target = monster_states.iter().position(|state| state.hp > 0);
+            if let (true, Some(target)) = (state.hp > 0, target) {
+                take_turn(
+                    rng,
+                    snapshot,
+                    *side,
+                    state,
+                    monsters[target],
+                    &mut monster_states[target],
+                    &mut events,
+                );
+                if monster_states[target].hp <= 0 {
+                    last_defeated = monsters[target];
+                    // まだ残っているなら、倒したことをその場で伝える
+                    if monster_states.iter().any(|state| state.hp > 0) {
+                        events.push(BattleEvent::Death {
+                            side: last_defeated,
+                        });
+                    }
+                }
+            }
+        }
+
+        // モンスターの行動
+        for (side, state) in monsters.iter().zip(monster_states.iter_mut()) {
+            let alive: Vec<usize> = (0..party.len())
+                .filter(|index| party_states[*index].hp > 0)
+                .collect();
+            if state.hp <= 0 || alive.is_empty() {
+                continue;
+            }
+            // 1人のときは乱数を使わない（これまでの戦闘の再現に影響させないため）
+            let index = if alive.len() == 1 {
+                alive[0]
             } else {
-                battle_log.push_str(&format!("nostr:{}はひらりとかわした！\n", npub1));
+                alive[rng.gen_range(0..alive.len())]
+            };
+            last_target = party[index];
+            take_turn(
+                rng,
+                snapshot,
+                *side,
+                state,
+                last_target,
+                &mut party_states[index],
+                &mut events,
+            );
+            // 仲間が残っているなら、倒れたことをその場で伝える
+            if party_states[index].hp <= 0 && party_states.iter().any(|state| state.hp > 0) {
+                events.push(BattleEvent::Death { side: last_target });
             }
         }
         turn -= 1;
     }
 
     // 戦闘結果の決定
-    if user_hp > 0 && monster_hp > 0 {
-        battle_log.push_str(&format!("{}はにげだした！", monster.name));
-        BattleResult {
-            user_id: user.user_id,
-            monster_id: monster.id,
-            victory: false,
-            experience_gain: 0,
-            gold_gain: 0,
-            battle_log,
+    let party_alive = party_states.iter().any(|state| state.hp > 0);
+    let monsters_alive = monster_states.iter().any(|state| state.hp > 0);
+    let outcome = if party_alive && monsters_alive {
+        for (side, state) in monsters.iter().zip(monster_states.iter()) {
+            if state.hp > 0 {
+                events.push(BattleEvent::Flee { side: *side });
+            }
         }
+        BattleOutcome::Escape
+    } else if party_alive {
+        events.push(BattleEvent::Death {
+            side: last_defeated,
+        });
+        BattleOutcome::Victory
     } else {
-        let victory = user_hp > 0;
-        let experience_gain = if victory {
-            monster.experience_reward
-        } else {
-            0
-        };
-        let gold_gain = if victory { monster.gold_reward } else { 0 };
+        events.push(BattleEvent::Death { side: last_target });
+        BattleOutcome::Defeat
+    };
 
-        if victory {
-            battle_log.push_str(&format!("{} を倒した！\n", monster.name));
-            battle_log.push_str(&format!(
-                "経験値 {} と {} GOLD を手に入れた！\n",
-                experience_gain, gold_gain
-            ));
-            let result = monsters::defeat_monster(&conn, monster, user.user_id);
-            if result.is_err() {
-                println!("Error defeat monster: {:?}", result.err().unwrap());
-            } else {
-                let next_exp = user.experience + experience_gain;
-                let level = util::level_from_experience(next_exp.try_into().unwrap());
-                println!("next_exp:{} level:{}", next_exp, level);
-                if level > user.level.try_into().unwrap() {
-                    battle_log.push_str(&format!("nostr:{} はレベルがあがった！\n", npub1));
-                }
+    Fight {
+        events,
+        outcome,
+        player_hp: party_states[0].hp,
+        player_mp: party_states[0].mp,
+        ally_hp: party_states[1..].iter().map(|state| state.hp).collect(),
+        ally_mp: party_states[1..].iter().map(|state| state.mp).collect(),
+        defeated: monsters
+            .iter()
+            .zip(monster_states.iter())
+            .filter(|(_, state)| state.hp <= 0)
+            .map(|(side, _)| *side)
+            .collect(),
+        monster_hp: monster_states.iter().map(|state| state.hp.max(0)).collect(),
+    }
+}
+
+// 保存されたシードとスナップショットから戦闘を再現する
+pub fn replay(seed: u64, snapshot: &BattleSnapshot) -> Fight {
+    fight(&mut rng_from_seed(seed), snapshot)
+}
+
+// 再現した戦闘が保存された決着と出来事の列に一致するか
+// 保存された列は報酬などの出来事が後ろに続くので、再現した分を先頭と比べて残りは決着のあとの出来事だけか確かめる
+pub fn replay_matches(
+    seed: u64,
+    snapshot: &BattleSnapshot,
+    outcome: BattleOutcome,
+    events: &[BattleEvent],
+) -> bool {
+    let fight = replay(seed, snapshot);
+    fight.outcome == outcome
+        && events.starts_with(&fight.events)
+        && events[fight.events.len()..]
+            .iter()
+            .all(BattleEvent::is_aftermath)
+}
+
+fn load_player(repo: &dyn Repository, user: &User) -> Result<Combatant, Box<dyn StdError>> {
+    let bonus = repo.get_equipment_bonus(user.user_id)?;
+    let mut player = Combatant::from_user(user, &bonus);
+    player.skills = repo.get_skills_for_level(user.level)?;
+    player.selected_skill = repo.get_selected_skill(user.user_id)?;
+
+    Ok(player)
+}
+
+// 装備・スキルなど戦闘に必要な情報をDBから集める。ひとりで戦うなら allies は空
+// monsters は1体以上。同じ名前のモンスターが複数いるときは「スライムA」「スライムB」と呼び分ける
+pub fn load_snapshot(
+    repo: &dyn Repository,
+    user: &User,
+    allies: &[User],
+    monsters: &[Monster],
+) -> Result<BattleSnapshot, Box<dyn StdError>> {
+    let player = load_player(repo, user)?;
+    let allies = allies
+        .iter()
+        .map(|ally| load_player(repo, ally))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut enemies = Vec::new();
+    for monster in monsters {
+        let mut enemy = Combatant::from_monster(monster);
+        enemy.skills = repo.get_skills_for_level(monster.level)?;
+        let same_name: Vec<&Monster> = monsters
+            .iter()
+            .filter(|other| other.name == monster.name)
+            .collect();
+        if same_name.len() > 1 {
+            let index = same_name
+                .iter()
+                .position(|other| other.id == monster.id)
+                .unwrap_or(0);
+            enemy.name = format!("{}{}", monster.name, (b'A' + (index % 26) as u8) as char);
+        }
+        enemies.push(enemy);
+    }
+    let monster = enemies.remove(0);
+
+    Ok(BattleSnapshot {
+        player,
+        monster,
+        allies,
+        enemies,
+    })
+}
+
+// 決闘はおたがい全快の状態で戦う。戦闘後のHP/MPは保存しない
+pub fn load_duel_snapshot(
+    repo: &dyn Repository,
+    challenger: &User,
+    opponent: &User,
+) -> Result<BattleSnapshot, Box<dyn StdError>> {
+    let mut player = load_player(repo, challenger)?;
+    let mut rival = load_player(repo, opponent)?;
+    for combatant in [&mut player, &mut rival] {
+        combatant.hp = combatant.max_hp;
+        combatant.mp = combatant.max_mp;
+    }
+
+    Ok(BattleSnapshot {
+        player,
+        monster: rival,
+        allies: Vec::new(),
+        enemies: Vec::new(),
+    })
+}
+
+// 戦闘に参加したユーザーと戦闘後のHP/MP
+struct Participant<'a> {
+    side: Side,
+    user: &'a User,
+    hp: i32,
+    mp: i32,
+}
+
+// 報酬を受け取り、上がったレベルの分だけ能力値を伸ばす
+fn gain_rewards<R: Rng>(
+    rng: &mut R,
+    participant: &Participant,
+    experience_gain: i32,
+    gold_gain: i32,
+    growth_config: &GrowthConfig,
+    events: &mut Vec<BattleEvent>,
+) -> User {
+    let user = participant.user;
+    let mut updated_user = user.clone();
+    let next_exp = user.experience + experience_gain;
+    // レベルが下がることはない
+    let level = (util::level_from_experience(next_exp.try_into().unwrap()) as i32).max(user.level);
+
+    updated_user.level = level;
+    updated_user.experience = next_exp;
+    updated_user.gold = user.gold + gold_gain;
+    updated_user.current_hp = participant.hp.max(1); // 倒れた仲間はHP1で起き上がる
+    updated_user.current_mp = participant.mp;
+
+    // 一度に複数レベル上がったときは1レベルずつ伸ばす
+    for new_level in user.level + 1..=level {
+        let gains = growth::roll_gains(rng, growth_config);
+        gains.apply_to(&mut updated_user);
+        updated_user.stat_points += growth_config.stat_points_per_level;
+        events.push(BattleEvent::LevelUp {
+            side: participant.side,
+            level: new_level,
+            gains,
+            stat_points: growth_config.stat_points_per_level,
+        });
+    }
+
+    updated_user
+}
+
+// 戦闘の外で報酬を受け取る（レイドの分配など）
+pub fn grant_rewards<R: Rng>(
+    rng: &mut R,
+    user: &User,
+    experience_gain: i32,
+    gold_gain: i32,
+    growth_config: &GrowthConfig,
+) -> User {
+    let participant = Participant {
+        side: Side::Player,
+        user,
+        hp: user.current_hp,
+        mp: user.current_mp,
+    };
 
-                let user_update = User {
-                    user_id: user.user_id,
-                    npub: user.npub.clone(),
-                    level: level.try_into().unwrap(),
-                    experience: user.experience + experience_gain,
-                    gold: user.gold + gold_gain,
-                    current_hp: user_hp,
-                    max_hp: user.max_hp,
-                    current_mp: user.current_mp,
-                    max_mp: user.max_mp,
-                    attack: user.attack,
-                    defense: user.defense,
-                    agility: user.agility,
-                    luck: user.luck,
-                };
-                let result = users::update_user(&conn, &user_update);
-                if result.is_err() {
-                    println!("Error update user: {:?}", result.err().unwrap());
+    gain_rewards(
+        rng,
+        &participant,
+        experience_gain,
+        gold_gain,
+        growth_config,
+        &mut Vec::new(),
+    )
+}
+
+// シードから戦闘を行い、報酬や戦闘後のユーザーの状態まで計算する（DBには書き込まない）
+// 倒したモンスターの経験値とGOLDを合計し、パーティのときは人数で割る（端数は切り上げ）
+// モンスターの一部がにげだしたときも、倒した分の報酬はもらえる
+// 負けたときは報酬がないので、途中で倒したモンスターも倒したことにしない（出現中に戻る）
+// 勝ったときだけ、倒したモンスターの loot（get_loot_for_monsters で読み込んだもの）からアイテムを判定する
+pub fn simulate_battle(
+    seed: u64,
+    user: &User,
+    allies: &[User],
+    monsters: &[Monster],
+    loot: &[LootEntry],
+    snapshot: BattleSnapshot,
+    growth_config: &GrowthConfig,
+) -> BattleResult {
+    let mut rng = rng_from_seed(seed);
+    let fight = fight(&mut rng, &snapshot);
+    let mut events = fight.events;
+    let mut participants = vec![Participant {
+        side: Side::Player,
+        user,
+        hp: fight.player_hp,
+        mp: fight.player_mp,
+    }];
+    for (index, ally) in allies.iter().enumerate() {
+        participants.push(Participant {
+            side: Side::Ally(index),
+            user: ally,
+            hp: fight.ally_hp[index],
+            mp: fight.ally_mp[index],
+        });
+    }
+    let defeated: Vec<&Monster> = fight
+        .defeated
+        .iter()
+        .map(|side| match side {
+            Side::Enemy(index) => &monsters[index + 1],
+            _ => &monsters[0],
+        })
+        .collect();
+    let mut updated_users = Vec::new();
+    let mut experience_gain = 0;
+    let mut gold_gain = 0;
+    let mut drops = Vec::new();
+
+    match fight.outcome {
+        BattleOutcome::Victory | BattleOutcome::Escape => {
+            let count = participants.len() as i32;
+            let experience: i32 = defeated
+                .iter()
+                .map(|monster| monster.experience_reward)
+                .sum();
+            let gold: i32 = defeated.iter().map(|monster| monster.gold_reward).sum();
+            experience_gain = (experience + count - 1) / count;
+            gold_gain = (gold + count - 1) / count;
+            if !defeated.is_empty() {
+                events.push(BattleEvent::Rewards {
+                    experience: experience_gain,
+                    gold: gold_gain,
+                });
+            }
+
+            for participant in &participants {
+                updated_users.push(gain_rewards(
+                    &mut rng,
+                    participant,
+                    experience_gain,
+                    gold_gain,
+                    growth_config,
+                    &mut events,
+                ));
+            }
+
+            if fight.outcome == BattleOutcome::Victory {
+                drops = loot::roll_loot(&mut rng, loot, &defeated);
+                for drop in drops.iter() {
+                    events.push(BattleEvent::ItemDrop {
+                        side: Side::Player,
+                        item: drop.item_name.clone(),
+                        quantity: drop.quantity,
+                    });
                 }
             }
+        }
+        BattleOutcome::Defeat => {
+            for Participant {
+                side, user: member, ..
+            } in participants
+            {
+                events.push(BattleEvent::GoldLost {
+                    side,
+                    amount: member.gold - member.gold / 2,
+                });
+                let mut updated_user = member.clone();
+                updated_user.gold = member.gold / 2;
+                updated_user.current_hp = member.max_hp;
+                updated_user.current_mp = member.max_mp;
+                updated_users.push(updated_user);
+            }
+        }
+    }
+
+    let defeated_monster_ids = match fight.outcome {
+        BattleOutcome::Defeat => Vec::new(),
+        _ => defeated.iter().map(|monster| monster.id).collect(),
+    };
+    let updated_allies = updated_users.split_off(1);
+    BattleResult {
+        user_id: user.user_id,
+        monster_id: monsters[0].id,
+        defeated_monster_ids,
+        outcome: fight.outcome,
+        victory: fight.outcome == BattleOutcome::Victory,
+        experience_gain,
+        gold_gain,
+        seed,
+        snapshot,
+        events,
+        updated_user: updated_users.remove(0),
+        updated_allies,
+        drops,
+    }
+}
+
+// simulate_battle の結果（モンスター・ユーザー・戦歴・ドロップ）をDBに反映して battle_id を返す
+// 途中で失敗したら何も書き込まないように、1つのトランザクションでまとめて保存する
+pub fn apply_battle_result(
+    conn: &Connection,
+    monsters: &[Monster],
+    result: &BattleResult,
+    battle_log: &str,
+    event_id: &str,
+) -> Result<i64, Box<dyn StdError>> {
+    let tx = conn.unchecked_transaction()?;
+    let battle_id = write_battle_result(&tx, monsters, result, battle_log, event_id)?;
+    tx.commit()?;
+
+    Ok(battle_id)
+}
+
+// apply_battle_result の中身。呼び出し側のトランザクションの中で使う
+pub(crate) fn write_battle_result(
+    tx: &Connection,
+    monsters: &[Monster],
+    result: &BattleResult,
+    battle_log: &str,
+    event_id: &str,
+) -> Result<i64, Box<dyn StdError>> {
+    // 倒したモンスターはにげられても倒れたまま。倒せなかったモンスターは予約を外す
+    for monster in monsters {
+        if result.defeated_monster_ids.contains(&monster.id) {
+            monsters::defeat_monster(tx, monster, result.user_id)?;
         } else {
-            battle_log.push_str(&format!(
-                "nostr:{}はしんでしまった！\nGOLDが半分になってしまった！\n",
-                npub1
+            monsters::release_monster(tx, monster, result.user_id)?;
+        }
+    }
+    users::update_user(tx, &result.updated_user)?;
+    for ally in &result.updated_allies {
+        users::update_user(tx, ally)?;
+    }
+    for drop in &result.drops {
+        items::give_item(tx, result.user_id, drop.item_id, drop.quantity)?;
+    }
+    let battle_id = battle_results::add_battle_result(tx, result, battle_log, event_id)?;
+
+    Ok(battle_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn combatant(name: &str, npub: Option<&str>, hp: i32, attack: i32, defense: i32) -> Combatant {
+        Combatant {
+            name: name.to_string(),
+            npub: npub.map(str::to_string),
+            picture: String::new(),
+            hp,
+            max_hp: hp,
+            mp: 0,
+            max_mp: 0,
+            attack,
+            defense,
+            agility: 4,
+            luck: 0,
+            skills: Vec::new(),
+            selected_skill: None,
+        }
+    }
+
+    fn snapshot() -> BattleSnapshot {
+        BattleSnapshot {
+            player: combatant("player", Some("npub_player"), 20, 6, 2),
+            monster: combatant("スライム", None, 12, 4, 1),
+            allies: Vec::new(),
+            enemies: Vec::new(),
+        }
+    }
+
+    fn user() -> User {
+        User {
+            user_id: 1,
+            npub: "npub_player".to_string(),
+            level: 1,
+            experience: 0,
+            gold: 10,
+            current_hp: 20,
+            max_hp: 20,
+            current_mp: 0,
+            max_mp: 0,
+            attack: 6,
+            defense: 2,
+            agility: 4,
+            luck: 0,
+            stat_points: 0,
+        }
+    }
+
+    fn monster() -> Monster {
+        Monster {
+            id: 1,
+            level: 1,
+            status: 3,
+            name: "スライム".to_string(),
+            picture: String::new(),
+            attack: 4,
+            defense: 1,
+            agility: 4,
+            experience_reward: 5,
+            gold_reward: 3,
+            hp: 12,
+            mp: 0,
+            defeat_user_id: -1,
+            luck: 0,
+        }
+    }
+
+    // 同じシードとスナップショットなら同じ戦闘になる。この結果が変わるときは ENGINE_VERSION を上げる
+    #[test]
+    fn fight_is_pinned_by_seed() {
+        let fight = replay(42, &snapshot());
+
+        assert_eq!(fight.outcome, BattleOutcome::Victory);
+        assert_eq!(fight.player_hp, 18);
+        assert_eq!(fight.monster_hp, vec![0]);
+        assert_eq!(fight.defeated, vec![Side::Monster]);
+        assert_eq!(
+            fight.events,
+            vec![
+                BattleEvent::Appear,
+                BattleEvent::Attack {
+                    attacker: Side::Player
+                },
+                BattleEvent::Damage {
+                    target: Side::Monster,
+                    amount: 6,
+                    hp: 6
+                },
+                BattleEvent::Attack {
+                    attacker: Side::Monster
+                },
+                BattleEvent::Damage {
+                    target: Side::Player,
+                    amount: 2,
+                    hp: 18
+                },
+                BattleEvent::Attack {
+                    attacker: Side::Player
+                },
+                BattleEvent::Damage {
+                    target: Side::Monster,
+                    amount: 6,
+                    hp: 0
+                },
+                BattleEvent::Death {
+                    side: Side::Monster
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn replay_matches_simulated_battle() {
+        for seed in [1, 7, 42, u64::MAX] {
+            let result = simulate_battle(
+                seed,
+                &user(),
+                &[],
+                &[monster()],
+                &[],
+                snapshot(),
+                &GrowthConfig::default(),
+            );
+            let fight = replay(seed, &result.snapshot);
+
+            assert_eq!(fight.outcome, result.outcome);
+            assert!(result.events.starts_with(&fight.events));
+            assert!(replay_matches(
+                seed,
+                &result.snapshot,
+                result.outcome,
+                &result.events
             ));
-            let user_update = User {
-                user_id: user.user_id,
-                npub: user.npub.clone(),
-                level: user.level,
-                experience: user.experience,
-                gold: user.gold / 2,
-                current_hp: user.max_hp,
-                max_hp: user.max_hp,
-                current_mp: user.max_mp,
-                max_mp: user.max_mp,
-                attack: user.attack,
-                defense: user.defense,
-                agility: user.agility,
-                luck: user.luck,
-            };
-            let result = users::update_user(&conn, &user_update);
-            if result.is_err() {
-                println!("Error update user: {:?}", result.err().unwrap());
+        }
+    }
+
+    #[test]
+    fn replay_rejects_different_events() {
+        let result = simulate_battle(
+            42,
+            &user(),
+            &[],
+            &[monster()],
+            &[],
+            snapshot(),
+            &GrowthConfig::default(),
+        );
+        let mut missing = result.events.clone();
+        missing.remove(1);
+        let mut extra = result.events.clone();
+        extra.push(BattleEvent::Appear);
+
+        assert!(!replay_matches(
+            42,
+            &result.snapshot,
+            result.outcome,
+            &missing
+        ));
+        assert!(!replay_matches(
+            42,
+            &result.snapshot,
+            result.outcome,
+            &extra
+        ));
+        assert!(!replay_matches(
+            42,
+            &result.snapshot,
+            BattleOutcome::Defeat,
+            &result.events
+        ));
+    }
+
+    // user() と同じ強さの仲間を2人連れたパーティ
+    fn party() -> (BattleSnapshot, Vec<User>) {
+        let allies: Vec<User> = (2..=3)
+            .map(|user_id| User {
+                user_id,
+                npub: format!("npub_ally{}", user_id),
+                ..user()
+            })
+            .collect();
+        let mut snapshot = snapshot();
+        snapshot.allies = allies
+            .iter()
+            .map(|ally| combatant(&ally.npub, Some(&ally.npub), 20, 6, 2))
+            .collect();
+
+        (snapshot, allies)
+    }
+
+    // スライム（けいけんち5、GOLD3）を3人で倒すと、1人あたり 2 と 1（端数は切り上げ）
+    #[test]
+    fn party_shares_the_rewards() {
+        let (snapshot, allies) = party();
+
+        let result = simulate_battle(
+            42,
+            &user(),
+            &allies,
+            &[monster()],
+            &[],
+            snapshot,
+            &GrowthConfig::default(),
+        );
+
+        assert_eq!(result.outcome, BattleOutcome::Victory);
+        assert_eq!((result.experience_gain, result.gold_gain), (2, 1));
+        assert!(result.events.contains(&BattleEvent::Rewards {
+            experience: 2,
+            gold: 1
+        }));
+        let members = std::iter::once(&result.updated_user).chain(&result.updated_allies);
+        let gains: Vec<(i32, i32, i32)> = members
+            .map(|member| (member.user_id, member.experience, member.gold))
+            .collect();
+        assert_eq!(gains, vec![(1, 2, 11), (2, 2, 11), (3, 2, 11)]);
+    }
+
+    // 負けたときは全員がGOLDを半分失い、HP/MPは全快で戻る
+    #[test]
+    fn party_defeat_costs_everyone_half_their_gold() {
+        let (mut snapshot, allies) = party();
+        snapshot.monster = combatant("ドラゴン", None, 1000, 100, 100);
+        let dragon = Monster {
+            hp: 1000,
+            attack: 100,
+            defense: 100,
+            ..monster()
+        };
+
+        let result = simulate_battle(
+            42,
+            &user(),
+            &allies,
+            &[dragon],
+            &[],
+            snapshot,
+            &GrowthConfig::default(),
+        );
+
+        assert_eq!(result.outcome, BattleOutcome::Defeat);
+        assert!(result.defeated_monster_ids.is_empty());
+        for member in std::iter::once(&result.updated_user).chain(&result.updated_allies) {
+            assert_eq!(member.gold, 5);
+            assert_eq!(member.current_hp, member.max_hp);
+            assert_eq!(member.experience, 0);
+        }
+    }
+
+    // 出来事の列から、ダメージを受けた側を順に取り出す
+    fn damage_targets(events: &[BattleEvent]) -> Vec<Side> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                BattleEvent::Damage { target, .. } => Some(*target),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // プレイヤー側は先頭のモンスターから順に倒していくので、狙う相手は後ろにしか移らない
+    #[test]
+    fn party_attacks_monsters_in_order() {
+        let mut snapshot = snapshot();
+        snapshot.player = combatant("player", Some("npub_player"), 100, 6, 2);
+        snapshot.enemies = vec![
+            combatant("スライムB", None, 12, 4, 1),
+            combatant("スライムC", None, 12, 4, 1),
+        ];
+
+        for seed in 0..20 {
+            let fight = replay(seed, &snapshot);
+
+            let order: Vec<usize> = damage_targets(&fight.events)
+                .into_iter()
+                .filter(|side| side.is_monster())
+                .map(|side| match side {
+                    Side::Enemy(index) => index + 1,
+                    _ => 0,
+                })
+                .collect();
+            assert!(order.windows(2).all(|pair| pair[0] <= pair[1]));
+            assert_eq!(fight.outcome, BattleOutcome::Victory);
+            assert_eq!(
+                fight.defeated,
+                vec![Side::Monster, Side::Enemy(0), Side::Enemy(1)]
+            );
+        }
+    }
+
+    // モンスターは生きている仲間だけを狙い、倒れた仲間はもう狙わない
+    #[test]
+    fn monsters_only_target_members_still_standing() {
+        let (mut snapshot, _) = party();
+        snapshot.allies[0].hp = 1;
+        snapshot.monster = combatant("ゴーレム", None, 200, 4, 100);
+        let mut targets = Vec::new();
+
+        for seed in 0..20 {
+            let fight = replay(seed, &snapshot);
+
+            let damaged = damage_targets(&fight.events);
+            if let Some(death) = damaged.iter().position(|side| *side == Side::Ally(0)) {
+                assert!(!damaged[death + 1..].contains(&Side::Ally(0)));
+            }
+            targets.extend(damaged.into_iter().filter(|side| !side.is_monster()));
+        }
+
+        // 狙う相手はランダムなので、全員が一度は狙われる
+        for side in [Side::Player, Side::Ally(0), Side::Ally(1)] {
+            assert!(targets.contains(&side));
+        }
+    }
+
+    // 群れを全部倒したら、全部の報酬を合わせて受け取る
+    #[test]
+    fn defeating_a_group_pays_for_every_monster() {
+        let mut snapshot = snapshot();
+        snapshot.player = combatant("player", Some("npub_player"), 100, 6, 2);
+        snapshot.enemies = vec![combatant("スライムB", None, 12, 4, 1)];
+        let monsters = [monster(), Monster { id: 2, ..monster() }];
+
+        let result = simulate_battle(
+            42,
+            &user(),
+            &[],
+            &monsters,
+            &[],
+            snapshot,
+            &GrowthConfig::default(),
+        );
+
+        assert_eq!(result.outcome, BattleOutcome::Victory);
+        assert_eq!(result.defeated_monster_ids, vec![1, 2]);
+        assert_eq!((result.experience_gain, result.gold_gain), (10, 6));
+    }
+
+    fn lucky(mut combatant: Combatant, luck: i32) -> Combatant {
+        combatant.luck = luck;
+        combatant
+    }
+
+    // 会心の一撃は相手の防御を無視して attack の1.5倍（小数は切り捨て）。そうでなければ防御が高くても1
+    #[test]
+    fn critical_hits_ignore_defense() {
+        let attacker = lucky(combatant("player", None, 20, 10, 0), 100);
+        let defender = combatant("ゴーレム", None, 20, 0, 100);
+        let mut rng = rng_from_seed(1);
+
+        let mut criticals = 0;
+        for _ in 0..200 {
+            match attack_damage(&mut rng, &attacker, &defender) {
+                (damage, true) => {
+                    assert!(damage == 15 || damage == 16, "{}", damage);
+                    criticals += 1;
+                }
+                (damage, false) => assert_eq!(damage, MIN_DAMAGE),
             }
         }
 
-        BattleResult {
-            user_id: user.user_id,
-            monster_id: monster.id,
-            victory,
-            experience_gain,
-            gold_gain,
-            battle_log,
+        // luck 100 でも確率は上限の50%まで
+        assert!((70..=130).contains(&criticals), "{}", criticals);
+    }
+
+    #[test]
+    fn luck_raises_the_critical_rate() {
+        let defender = combatant("スライム", None, 20, 0, 0);
+        let criticals = |luck: i32| {
+            let attacker = lucky(combatant("player", None, 20, 10, 0), luck);
+            let mut rng = rng_from_seed(7);
+            (0..2000)
+                .filter(|_| attack_damage(&mut rng, &attacker, &defender).1)
+                .count()
+        };
+
+        // 3%、13%、50%（上限）
+        assert!((30..=90).contains(&criticals(0)));
+        assert!((200..=320).contains(&criticals(10)));
+        assert!((900..=1100).contains(&criticals(1000)));
+        // マイナスの luck は0として扱う
+        assert_eq!(criticals(-50), criticals(0));
+    }
+
+    #[test]
+    fn every_hit_does_at_least_minimum_damage() {
+        let attacker = lucky(combatant("player", None, 20, -5, 0), 1000);
+        let defender = combatant("ゴーレム", None, 20, 0, 100);
+        let mut rng = rng_from_seed(3);
+
+        for _ in 0..100 {
+            assert_eq!(attack_damage(&mut rng, &attacker, &defender).0, MIN_DAMAGE);
+        }
+
+        // ガード中は半分になっても1は受ける
+        let mut state = FighterState::new(&defender);
+        state.guarding = true;
+        assert_eq!(state.take_damage(1), MIN_DAMAGE);
+        assert_eq!(state.take_damage(9), 4);
+        assert_eq!(state.hp, 15);
+    }
+
+    fn skill(name: &str, kind: SkillKind, mp_cost: i32, power: i32) -> Skill {
+        Skill {
+            skill_id: 1,
+            name: name.to_string(),
+            kind,
+            mp_cost,
+            power,
+            learn_level: 1,
+        }
+    }
+
+    fn state(hp: i32, mp: i32) -> FighterState {
+        FighterState {
+            hp,
+            mp,
+            guarding: false,
+        }
+    }
+
+    #[test]
+    fn selected_skill_needs_enough_mp() {
+        let mut caster = combatant("player", Some("npub_player"), 30, 6, 2);
+        caster.skills = vec![skill("メラ", SkillKind::Attack, 3, 8)];
+        caster.selected_skill = Some("メラ".to_string());
+
+        assert_eq!(choose_skill(&caster, &state(30, 3)).unwrap().name, "メラ");
+        assert!(choose_skill(&caster, &state(30, 2)).is_none());
+    }
+
+    #[test]
+    fn heals_wait_until_hp_runs_low() {
+        let mut caster = combatant("player", Some("npub_player"), 30, 6, 2);
+        caster.skills = vec![
+            skill("ホイミ", SkillKind::Heal, 2, 10),
+            skill("ベホイミ", SkillKind::Heal, 5, 30),
+        ];
+        caster.selected_skill = Some("ホイミ".to_string());
+
+        // 選んだ回復は半分を切ってから
+        assert!(choose_skill(&caster, &state(16, 10)).is_none());
+        assert_eq!(
+            choose_skill(&caster, &state(15, 10)).unwrap().name,
+            "ホイミ"
+        );
+
+        // おまかせでは3分の1を切ったら、MPの足りる一番強い回復
+        caster.selected_skill = None;
+        assert!(choose_skill(&caster, &state(11, 10)).is_none());
+        assert_eq!(
+            choose_skill(&caster, &state(10, 10)).unwrap().name,
+            "ベホイミ"
+        );
+        assert_eq!(choose_skill(&caster, &state(10, 4)).unwrap().name, "ホイミ");
+    }
+
+    // 唱えるたびに MP が減り、足りなくなったら通常攻撃に戻る
+    #[test]
+    fn casting_spends_mp_until_it_runs_out() {
+        let mut snapshot = snapshot();
+        snapshot.player.mp = 7;
+        snapshot.player.max_mp = 7;
+        snapshot.player.skills = vec![skill("スクルト", SkillKind::Guard, 3, 0)];
+        snapshot.player.selected_skill = Some("スクルト".to_string());
+        snapshot.monster = combatant("ゴーレム", None, 60, 4, 0);
+
+        for seed in 0..20 {
+            let fight = replay(seed, &snapshot);
+
+            let player_events: Vec<&BattleEvent> = fight
+                .events
+                .iter()
+                .filter(|event| {
+                    matches!(
+                        event,
+                        BattleEvent::CastSkill {
+                            caster: Side::Player,
+                            ..
+                        } | BattleEvent::Attack {
+                            attacker: Side::Player
+                        }
+                    )
+                })
+                .collect();
+            assert!(matches!(
+                player_events[..3],
+                [
+                    BattleEvent::CastSkill { mp: 4, .. },
+                    BattleEvent::CastSkill { mp: 1, .. },
+                    BattleEvent::Attack { .. },
+                ]
+            ));
+            assert!(!player_events[2..]
+                .iter()
+                .any(|event| matches!(event, BattleEvent::CastSkill { .. })));
+        }
+    }
+
+    // 設定で power をマイナスにした回復スキルでも、乱数の範囲が空にならず回復量は0
+    #[test]
+    fn negative_heal_power_heals_nothing() {
+        let mut snapshot = snapshot();
+        snapshot.player.hp = 5;
+        snapshot.player.mp = 10;
+        snapshot.player.max_mp = 10;
+        snapshot.player.skills = vec![Skill {
+            skill_id: 1,
+            name: "のろい".to_string(),
+            kind: SkillKind::Heal,
+            mp_cost: 1,
+            power: -10,
+            learn_level: 1,
+        }];
+
+        for seed in 0..20 {
+            let fight = replay(seed, &snapshot);
+
+            let heals: Vec<i32> = fight
+                .events
+                .iter()
+                .filter_map(|event| match event {
+                    BattleEvent::Heal { amount, .. } => Some(*amount),
+                    _ => None,
+                })
+                .collect();
+            assert!(!heals.is_empty());
+            assert!(heals.iter().all(|amount| *amount == 0));
         }
     }
+
+    // (status, reserved_by)
+    fn reservation(conn: &Connection, id: i32) -> (i32, Option<i32>) {
+        conn.query_row(
+            "SELECT status, reserved_by FROM monsters WHERE id = ?1",
+            rusqlite::params![id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap()
+    }
+
+    fn count(conn: &Connection, table: &str) -> i32 {
+        conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+            row.get(0)
+        })
+        .unwrap()
+    }
+
+    // 2体目の予約がほかのユーザーに取られていたら、1体目を倒したことも含めて何も保存しない
+    #[test]
+    fn lost_reservation_rolls_back_the_whole_battle() {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::db::migrate(&conn).unwrap();
+        let player = users::add_user(&conn, "npub_player").unwrap();
+        let master_id = monsters::add_monster_master(
+            &conn,
+            &monsters::NewMonsterMaster {
+                level: 1,
+                name: "スライム".to_string(),
+                picture: String::new(),
+                attack: 4,
+                defense: 1,
+                agility: 4,
+                experience_reward: 5,
+                gold_reward: 3,
+                luck: 0,
+                rarity_weight: 100,
+                zone_id: None,
+            },
+        )
+        .unwrap();
+        monsters::spawn_monster(&conn, master_id, 2, &[]).unwrap();
+        monsters::reserve_monster(&conn, 1, player.user_id, 100).unwrap();
+        monsters::reserve_monster(&conn, 2, player.user_id + 1, 100).unwrap();
+        let item = items::add_item(&conn, "やくそう", "tool", 0, 0, 0).unwrap();
+        let defeated = [
+            Monster {
+                id: 1,
+                hp: 0,
+                ..monster()
+            },
+            Monster {
+                id: 2,
+                hp: 0,
+                ..monster()
+            },
+        ];
+        let mut result = simulate_battle(
+            42,
+            &user(),
+            &[],
+            &[monster()],
+            &[],
+            snapshot(),
+            &GrowthConfig::default(),
+        );
+        result.user_id = player.user_id;
+        result.defeated_monster_ids = vec![1, 2];
+        result.updated_user = User {
+            experience: 100,
+            gold: 500,
+            ..player.clone()
+        };
+        result.drops = vec![LootDrop {
+            item_id: item.item_id,
+            item_name: item.name.clone(),
+            quantity: 1,
+        }];
+
+        let e = apply_battle_result(&conn, &defeated, &result, "log", "event").unwrap_err();
+
+        assert!(matches!(
+            e.downcast_ref::<monsters::MonsterError>(),
+            Some(monsters::MonsterError::NotReserved(2))
+        ));
+        let saved = users::get_user_by_id(&conn, player.user_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (saved.experience, saved.gold),
+            (player.experience, player.gold)
+        );
+        assert_eq!(reservation(&conn, 1), (3, Some(player.user_id)));
+        assert_eq!(reservation(&conn, 2), (3, Some(player.user_id + 1)));
+        assert_eq!(count(&conn, "battle_results"), 0);
+        assert_eq!(count(&conn, "inventory"), 0);
+    }
 }
@@ -0,0 +1,421 @@
+use serde_json::json;
+
+use crate::battle::{BattleEvent, BattleSnapshot, Combatant, Side};
+use crate::growth::StatGains;
+use crate::util;
+
+// 戦闘ログの表示形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogStyle {
+    Japanese,
+    English,
+    Summary,
+    Json,
+}
+
+impl LogStyle {
+    pub fn parse(value: &str) -> Option<LogStyle> {
+        match value.trim().to_lowercase().as_str() {
+            "ja" | "japanese" => Some(LogStyle::Japanese),
+            "en" | "english" => Some(LogStyle::English),
+            "summary" => Some(LogStyle::Summary),
+            "json" => Some(LogStyle::Json),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogStyle::Japanese => "japanese",
+            LogStyle::English => "english",
+            LogStyle::Summary => "summary",
+            LogStyle::Json => "json",
+        }
+    }
+
+    pub fn renderer(self) -> Box<dyn BattleLogRenderer> {
+        match self {
+            LogStyle::Japanese => Box::new(JapaneseRenderer),
+            LogStyle::English => Box::new(EnglishRenderer),
+            LogStyle::Summary => Box::new(SummaryRenderer),
+            LogStyle::Json => Box::new(JsonRenderer),
+        }
+    }
+}
+
+pub trait BattleLogRenderer {
+    fn render(&self, snapshot: &BattleSnapshot, events: &[BattleEvent]) -> String;
+}
+
+pub fn render(style: LogStyle, snapshot: &BattleSnapshot, events: &[BattleEvent]) -> String {
+    style.renderer().render(snapshot, events)
+}
+
+// プレイヤーは nostr:npub1... でメンションし、モンスターは名前のまま
+fn mention(combatant: &Combatant) -> String {
+    match &combatant.npub {
+        Some(npub) => format!("nostr:{}", util::get_npub1(npub.clone()).unwrap()),
+        None => combatant.name.clone(),
+    }
+}
+
+fn name_of(snapshot: &BattleSnapshot, side: Side) -> String {
+    mention(snapshot.combatant(side))
+}
+
+// 現れたモンスターを1体ずつ絵と一緒に並べる
+fn appear_text(snapshot: &BattleSnapshot, template: &str) -> String {
+    snapshot
+        .monster_sides()
+        .into_iter()
+        .map(|side| {
+            let monster = snapshot.combatant(side);
+            format!(
+                "{}\n{}",
+                monster.picture,
+                template.replace("{}", &monster.name)
+            )
+        })
+        .collect()
+}
+
+fn monster_names(snapshot: &BattleSnapshot, separator: &str) -> String {
+    snapshot
+        .monster_sides()
+        .into_iter()
+        .map(|side| name_of(snapshot, side))
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+// パーティ全員のメンション（ひとりならそのユーザーだけ）
+fn party_names(snapshot: &BattleSnapshot, separator: &str) -> String {
+    snapshot
+        .party_sides()
+        .into_iter()
+        .map(|side| name_of(snapshot, side))
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+// 上がった能力値だけを並べる（例: "さいだいHP+3 ちから+1"）
+fn gains_text(gains: &StatGains, labels: [&str; 5]) -> String {
+    let values = [
+        gains.hp,
+        gains.mp,
+        gains.attack,
+        gains.defense,
+        gains.agility,
+    ];
+    let parts: Vec<String> = labels
+        .iter()
+        .zip(values.iter())
+        .filter(|(_, value)| **value != 0)
+        .map(|(label, value)| format!("{}{:+}", label, value))
+        .collect();
+    if parts.is_empty() {
+        String::new()
+    } else {
+        format!("{}\n", parts.join(" "))
+    }
+}
+
+fn japanese_gains(gains: &StatGains) -> String {
+    gains_text(
+        gains,
+        [
+            "さいだいHP",
+            "さいだいMP",
+            "ちから",
+            "しゅびりょく",
+            "すばやさ",
+        ],
+    )
+}
+
+fn english_gains(gains: &StatGains) -> String {
+    gains_text(gains, ["MaxHP", "MaxMP", "ATK", "DEF", "AGI"])
+}
+
+// これまでの日本語のナレーション
+pub struct JapaneseRenderer;
+
+impl BattleLogRenderer for JapaneseRenderer {
+    fn render(&self, snapshot: &BattleSnapshot, events: &[BattleEvent]) -> String {
+        let mut battle_log = String::new();
+
+        for event in events {
+            let line = match event {
+                BattleEvent::Appear if snapshot.is_duel() => format!(
+                    "{} と {} の決闘がはじまった！\n",
+                    name_of(snapshot, Side::Player),
+                    name_of(snapshot, Side::Monster)
+                ),
+                BattleEvent::Appear if !snapshot.allies.is_empty() => format!(
+                    "{}{} のパーティはたちむかった！\n",
+                    appear_text(snapshot, "{}が現れた！\n"),
+                    party_names(snapshot, " と ")
+                ),
+                BattleEvent::Appear => appear_text(snapshot, "{}が現れた！\n"),
+                BattleEvent::Attack { attacker } => {
+                    format!("{} のこうげき！\n", name_of(snapshot, *attacker))
+                }
+                BattleEvent::Dodge { defender } => {
+                    format!("{}はひらりとかわした！\n", name_of(snapshot, *defender))
+                }
+                BattleEvent::Critical { attacker } if attacker.is_monster() => {
+                    "つうこんのいちげき！\n".to_string()
+                }
+                BattleEvent::Critical { .. } => "かいしんのいちげき！\n".to_string(),
+                BattleEvent::Damage { target, amount, .. } if target.is_monster() => format!(
+                    "{} に {} のダメージをあたえた！\n",
+                    name_of(snapshot, *target),
+                    amount
+                ),
+                BattleEvent::Damage { target, amount, hp } => format!(
+                    "{} は {} のダメージをうけた！ HP:{}/{}\n",
+                    name_of(snapshot, *target),
+                    amount,
+                    hp,
+                    snapshot.combatant(*target).max_hp
+                ),
+                BattleEvent::CastSkill { caster, skill, .. } => {
+                    format!("{} は {} をつかった！\n", name_of(snapshot, *caster), skill)
+                }
+                BattleEvent::Heal { target, amount, .. } => format!(
+                    "{} のHPが {} かいふくした！\n",
+                    name_of(snapshot, *target),
+                    amount
+                ),
+                BattleEvent::Guard { side } => {
+                    format!("{} はみをまもっている！\n", name_of(snapshot, *side))
+                }
+                BattleEvent::Flee { .. } if snapshot.is_duel() => {
+                    "決着がつかなかった！\n".to_string()
+                }
+                BattleEvent::Flee { side } => {
+                    format!("{}はにげだした！\n", name_of(snapshot, *side))
+                }
+                BattleEvent::Death { side } if side.is_monster() => {
+                    format!("{} を倒した！\n", name_of(snapshot, *side))
+                }
+                BattleEvent::Death { side } => {
+                    format!("{}はしんでしまった！\n", name_of(snapshot, *side))
+                }
+                BattleEvent::Rewards { experience, gold } => {
+                    format!("経験値 {} と {} GOLD を手に入れた！\n", experience, gold)
+                }
+                BattleEvent::LevelUp {
+                    side,
+                    level,
+                    gains,
+                    stat_points,
+                } => format!(
+                    "{} はレベルがあがった！ level:{}\n{}ステータスポイント+{}\n",
+                    name_of(snapshot, *side),
+                    level,
+                    japanese_gains(gains),
+                    stat_points
+                ),
+                BattleEvent::GoldLost { side, .. } if !snapshot.allies.is_empty() => {
+                    format!(
+                        "{} のGOLDが半分になってしまった！\n",
+                        name_of(snapshot, *side)
+                    )
+                }
+                BattleEvent::GoldLost { .. } => "GOLDが半分になってしまった！\n".to_string(),
+                BattleEvent::ItemDrop {
+                    side,
+                    item,
+                    quantity,
+                } if !snapshot.allies.is_empty() => format!(
+                    "{} は {} を {}こ 手に入れた！\n",
+                    name_of(snapshot, *side),
+                    item,
+                    quantity
+                ),
+                BattleEvent::ItemDrop { item, quantity, .. } => {
+                    format!("{} を {}こ 手に入れた！\n", item, quantity)
+                }
+            };
+            battle_log.push_str(&line);
+        }
+
+        battle_log
+    }
+}
+
+pub struct EnglishRenderer;
+
+impl BattleLogRenderer for EnglishRenderer {
+    fn render(&self, snapshot: &BattleSnapshot, events: &[BattleEvent]) -> String {
+        let mut battle_log = String::new();
+
+        for event in events {
+            let line = match event {
+                BattleEvent::Appear if snapshot.is_duel() => format!(
+                    "A duel between {} and {} begins!\n",
+                    name_of(snapshot, Side::Player),
+                    name_of(snapshot, Side::Monster)
+                ),
+                BattleEvent::Appear if !snapshot.allies.is_empty() => format!(
+                    "{}The party of {} stands against it!\n",
+                    appear_text(snapshot, "A wild {} appeared!\n"),
+                    party_names(snapshot, ", ")
+                ),
+                BattleEvent::Appear => appear_text(snapshot, "A wild {} appeared!\n"),
+                BattleEvent::Attack { attacker } => {
+                    format!("{} attacks!\n", name_of(snapshot, *attacker))
+                }
+                BattleEvent::Dodge { defender } => {
+                    format!("{} nimbly dodged!\n", name_of(snapshot, *defender))
+                }
+                BattleEvent::Critical { .. } => "A critical hit!\n".to_string(),
+                BattleEvent::Damage { target, amount, .. } if target.is_monster() => format!(
+                    "Dealt {} damage to {}!\n",
+                    amount,
+                    name_of(snapshot, *target)
+                ),
+                BattleEvent::Damage { target, amount, hp } => format!(
+                    "{} took {} damage! HP:{}/{}\n",
+                    name_of(snapshot, *target),
+                    amount,
+                    hp,
+                    snapshot.combatant(*target).max_hp
+                ),
+                BattleEvent::CastSkill { caster, skill, .. } => {
+                    format!("{} used {}!\n", name_of(snapshot, *caster), skill)
+                }
+                BattleEvent::Heal { target, amount, .. } => {
+                    format!("{} recovered {} HP!\n", name_of(snapshot, *target), amount)
+                }
+                BattleEvent::Guard { side } => {
+                    format!("{} is guarding!\n", name_of(snapshot, *side))
+                }
+                BattleEvent::Flee { .. } if snapshot.is_duel() => {
+                    "The duel ended in a draw!\n".to_string()
+                }
+                BattleEvent::Flee { side } => format!("{} ran away!\n", name_of(snapshot, *side)),
+                BattleEvent::Death { side } if side.is_monster() => {
+                    format!("Defeated {}!\n", name_of(snapshot, *side))
+                }
+                BattleEvent::Death { side } => {
+                    format!("{} has fallen!\n", name_of(snapshot, *side))
+                }
+                BattleEvent::Rewards { experience, gold } => {
+                    format!("Gained {} EXP and {} GOLD!\n", experience, gold)
+                }
+                BattleEvent::LevelUp {
+                    side,
+                    level,
+                    gains,
+                    stat_points,
+                } => format!(
+                    "{} reached level {}!\n{}Stat points +{}\n",
+                    name_of(snapshot, *side),
+                    level,
+                    english_gains(gains),
+                    stat_points
+                ),
+                BattleEvent::GoldLost { side, amount } => {
+                    format!("{} lost {} GOLD...\n", name_of(snapshot, *side), amount)
+                }
+                BattleEvent::ItemDrop {
+                    side,
+                    item,
+                    quantity,
+                } => format!(
+                    "{} obtained {} x{}!\n",
+                    name_of(snapshot, *side),
+                    item,
+                    quantity
+                ),
+            };
+            battle_log.push_str(&line);
+        }
+
+        battle_log
+    }
+}
+
+// 1行だけの要約（例: nostr:npub1... vs スライム 勝利 3ターン 与ダメージ12 被ダメージ4 EXP+5 GOLD+3）
+pub struct SummaryRenderer;
+
+impl BattleLogRenderer for SummaryRenderer {
+    fn render(&self, snapshot: &BattleSnapshot, events: &[BattleEvent]) -> String {
+        let mut turns = 0;
+        let mut dealt = 0;
+        let mut taken = 0;
+        let mut result = "";
+        let mut extras = Vec::new();
+
+        for event in events {
+            match event {
+                BattleEvent::Attack {
+                    attacker: Side::Player,
+                }
+                | BattleEvent::CastSkill {
+                    caster: Side::Player,
+                    ..
+                } => turns += 1,
+                BattleEvent::Damage { target, amount, .. } if target.is_monster() => {
+                    dealt += amount
+                }
+                BattleEvent::Damage { amount, .. } => taken += amount,
+                BattleEvent::Flee { .. } => result = "引き分け",
+                BattleEvent::Death { side } if side.is_monster() => result = "勝利",
+                BattleEvent::Death { .. } => result = "敗北",
+                BattleEvent::Rewards { experience, gold } => {
+                    extras.push(format!("EXP+{} GOLD+{}", experience, gold))
+                }
+                BattleEvent::LevelUp { side, level, .. } if !snapshot.allies.is_empty() => {
+                    extras.push(format!("{} Lv{}", name_of(snapshot, *side), level))
+                }
+                BattleEvent::LevelUp { level, .. } => extras.push(format!("Lv{}", level)),
+                BattleEvent::GoldLost { amount, .. } => extras.push(format!("GOLD-{}", amount)),
+                BattleEvent::ItemDrop { item, quantity, .. } => {
+                    extras.push(format!("{}x{}", item, quantity))
+                }
+                _ => {}
+            }
+        }
+
+        let mut summary = format!(
+            "{} vs {} {} {}ターン 与ダメージ{} 被ダメージ{}",
+            party_names(snapshot, " & "),
+            monster_names(snapshot, " & "),
+            result,
+            turns,
+            dealt,
+            taken
+        );
+        for extra in extras {
+            summary.push(' ');
+            summary.push_str(&extra);
+        }
+        summary.push('\n');
+
+        summary
+    }
+}
+
+pub struct JsonRenderer;
+
+impl BattleLogRenderer for JsonRenderer {
+    fn render(&self, snapshot: &BattleSnapshot, events: &[BattleEvent]) -> String {
+        let mut value = json!({
+            "player": snapshot.player.npub,
+            "monster": snapshot.monster.name,
+            "events": events,
+        });
+        if !snapshot.allies.is_empty() {
+            let allies: Vec<_> = snapshot.allies.iter().map(|ally| &ally.npub).collect();
+            value["allies"] = json!(allies);
+        }
+        if !snapshot.enemies.is_empty() {
+            let enemies: Vec<_> = snapshot.enemies.iter().map(|enemy| &enemy.name).collect();
+            value["enemies"] = json!(enemies);
+        }
+        format!("{}\n", value)
+    }
+}
@@ -0,0 +1,179 @@
+use rusqlite::{Connection, Result};
+use std::error::Error as StdError;
+
+use crate::battle::{self, BattleEvent, BattleOutcome, BattleResult, BattleSnapshot};
+
+// battle_resultsテーブルの1行を保持する構造体
+#[derive(Clone)]
+pub struct BattleRecord {
+    pub battle_id: i64,
+    pub user_id: i32,
+    pub monster_id: i32,
+    pub outcome: BattleOutcome,
+    pub experience_gain: i32,
+    pub gold_gain: i32,
+    pub battle_log: String,
+    pub event_id: String,
+    pub created_at: String,
+    pub seed: u64,
+    pub snapshot: Option<BattleSnapshot>, // 保存前の古い記録にはない
+    pub engine_version: i32,              // 保存前の古い記録は0
+    pub events: Option<Vec<BattleEvent>>, // 保存前の古い記録にはない
+}
+
+// ユーザーごとの戦績
+pub struct BattleStats {
+    pub victories: i32,
+    pub defeats: i32,
+    pub escapes: i32,
+}
+
+const SELECT_BATTLE_RECORD: &str = "SELECT
+        battle_id,
+        user_id,
+        monster_id,
+        COALESCE(outcome, CASE WHEN victory THEN 1 ELSE 2 END),
+        experience_gain,
+        gold_gain,
+        battle_log,
+        COALESCE(event_id, ''),
+        COALESCE(created_at, ''),
+        COALESCE(seed, 0),
+        snapshot,
+        COALESCE(engine_version, 0),
+        events
+    FROM battle_results";
+
+fn battle_record_from_row(row: &rusqlite::Row) -> Result<BattleRecord> {
+    let outcome: i32 = row.get(3)?;
+    let seed: i64 = row.get(9)?;
+    let snapshot: Option<String> = row.get(10)?;
+    let events: Option<String> = row.get(12)?;
+    Ok(BattleRecord {
+        battle_id: row.get(0)?,
+        user_id: row.get(1)?,
+        monster_id: row.get(2)?,
+        outcome: BattleOutcome::from_i32(outcome).unwrap_or(BattleOutcome::Defeat),
+        experience_gain: row.get(4)?,
+        gold_gain: row.get(5)?,
+        battle_log: row.get(6)?,
+        event_id: row.get(7)?,
+        created_at: row.get(8)?,
+        seed: seed as u64,
+        snapshot: snapshot.and_then(|json| serde_json::from_str(&json).ok()),
+        engine_version: row.get(11)?,
+        events: events.and_then(|json| serde_json::from_str(&json).ok()),
+    })
+}
+
+// 戦闘結果を保存して battle_id を返す
+// .leveling・ダンジョン・レイドの戦闘はここに記録する。レイドの monster_id は monster_master の id
+// 決闘はモンスターと戦わないので battle_results には入れず、duels の行にシード・スナップショット・戦闘ログごと記録する
+// パーティで戦ったときは参加者全員の戦歴に同じ戦闘を記録し、.leveling したユーザーの battle_id を返す
+pub fn add_battle_result(
+    conn: &Connection,
+    result: &BattleResult,
+    battle_log: &str,
+    event_id: &str,
+) -> Result<i64, Box<dyn StdError>> {
+    let snapshot = serde_json::to_string(&result.snapshot)?;
+    let events = serde_json::to_string(&result.events)?;
+    let user_ids = std::iter::once(result.user_id)
+        .chain(result.updated_allies.iter().map(|ally| ally.user_id));
+    let mut battle_id = None;
+
+    for user_id in user_ids {
+        conn.execute(
+            "INSERT INTO battle_results (
+                    user_id,
+                    monster_id,
+                    victory,
+                    experience_gain,
+                    gold_gain,
+                    battle_log,
+                    outcome,
+                    event_id,
+                    created_at,
+                    seed,
+                    snapshot,
+                    engine_version,
+                    events)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, datetime('now'), ?9, ?10, ?11, ?12)",
+            rusqlite::params![
+                user_id,
+                result.monster_id,
+                result.victory,
+                result.experience_gain,
+                result.gold_gain,
+                battle_log,
+                result.outcome.to_i32(),
+                event_id,
+                result.seed as i64, // SQLiteのINTEGERは符号付きなのでビット列のまま保存する
+                snapshot,
+                battle::ENGINE_VERSION,
+                events,
+            ],
+        )?;
+        battle_id.get_or_insert(conn.last_insert_rowid());
+    }
+
+    Ok(battle_id.unwrap_or_default())
+}
+
+pub fn get_battle_result_by_id(
+    conn: &Connection,
+    battle_id: i64,
+) -> Result<Option<BattleRecord>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(&format!("{} WHERE battle_id = ?1", SELECT_BATTLE_RECORD))?;
+    let mut record_iter =
+        statement.query_map(rusqlite::params![battle_id], battle_record_from_row)?;
+
+    Ok(record_iter.next().transpose()?)
+}
+
+// 新しい順にユーザーの戦闘履歴を取得する（limit件ずつoffsetからページング）
+pub fn get_battle_results_by_user(
+    conn: &Connection,
+    user_id: i32,
+    limit: i32,
+    offset: i32,
+) -> Result<Vec<BattleRecord>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(&format!(
+        "{} WHERE user_id = ?1 ORDER BY battle_id DESC LIMIT ?2 OFFSET ?3",
+        SELECT_BATTLE_RECORD
+    ))?;
+    let records = statement
+        .query_map(
+            rusqlite::params![user_id, limit, offset],
+            battle_record_from_row,
+        )?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(records)
+}
+
+pub fn get_battle_stats_by_user(
+    conn: &Connection,
+    user_id: i32,
+) -> Result<BattleStats, Box<dyn StdError>> {
+    let stats = conn.query_row(
+        "SELECT
+            COALESCE(SUM(CASE WHEN o = 1 THEN 1 ELSE 0 END), 0),
+            COALESCE(SUM(CASE WHEN o = 2 THEN 1 ELSE 0 END), 0),
+            COALESCE(SUM(CASE WHEN o = 3 THEN 1 ELSE 0 END), 0)
+        FROM (
+            SELECT COALESCE(outcome, CASE WHEN victory THEN 1 ELSE 2 END) AS o
+            FROM battle_results WHERE user_id = ?1
+        )",
+        rusqlite::params![user_id],
+        |row| {
+            Ok(BattleStats {
+                victories: row.get(0)?,
+                defeats: row.get(1)?,
+                escapes: row.get(2)?,
+            })
+        },
+    )?;
+
+    Ok(stats)
+}
@@ -1,22 +1,29 @@
 use crate::battle;
+use crate::battle_log::{self, LogStyle};
 use crate::config;
+use crate::duel;
+use crate::dungeon;
 use crate::gpt;
+use crate::items;
 use crate::monsters;
+use crate::party;
+use crate::raid;
+use crate::repository::Repository;
+use crate::shop;
+use crate::stats;
 use crate::users;
 use crate::util;
 use nostr_sdk::prelude::*;
-use rusqlite::Connection;
 
 pub async fn command_handler(
     config: &config::AppConfig,
-    conn: &Connection,
+    repo: &dyn Repository,
     my_keys: Keys,
     event: &Event,
 ) -> Result<bool> {
     println!("command_handler");
     let admin_pubkeys = &config.bot.admin_pubkeys;
     let bot_names = &config.bot.bot_names;
-    let mut handled: bool = false;
     let secret_key = my_keys.secret_key().unwrap().to_string();
     let is_admin = admin_pubkeys.iter().any(|s| *s == event.pubkey.to_string());
     let has_mention = util::extract_mention(bot_names, event).unwrap();
@@ -33,40 +40,147 @@ pub async fn command_handler(
         }
     }
 
+    match dispatch(config, repo, event, &message, &secret_key, is_admin).await {
+        Ok(handled) => Ok(handled),
+        Err(e) => {
+            // 1つのコマンドが失敗してもボットは止めずに、記録して本人に知らせる
+            eprintln!("Error command_handler: {:?}", e);
+            if let Err(e) =
+                util::reply_to(config, event.clone(), &secret_key, SYSTEM_ERROR_MESSAGE).await
+            {
+                eprintln!("Error reply_to: {:?}", e);
+            }
+            Ok(false)
+        }
+    }
+}
+
+const SYSTEM_ERROR_MESSAGE: &str =
+    "あら、何かシステムが異常なようですわ！急ぎマスターに報告して参ります！しばらくお待ちくださいまし。";
+
+// メッセージに含まれるコマンドを実行する。leveling したときは true
+async fn dispatch(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+    is_admin: bool,
+) -> Result<bool> {
+    let mut handled: bool = false;
     if message.starts_with(".guild join") {
         println!(".guild join");
-        join_guild(config, &conn, event, &secret_key).await?;
+        join_guild(config, repo, event, secret_key).await?;
     } else if message.contains(".status") {
         println!(".status");
-        status(config, &conn, event, &secret_key).await?;
+        status(config, repo, event, secret_key).await?;
     } else if message.contains(".leveling") {
         println!(".leveling");
-        leveling(config, &conn, event, &secret_key).await?;
+        leveling(config, repo, event, secret_key).await?;
         handled = true;
+    } else if message.contains(".travel") {
+        travel(config, repo, event, message, secret_key).await?;
+    } else if message.contains(".party") {
+        party_command(config, repo, event, message, secret_key).await?;
+    } else if message.contains(".duel") {
+        duel_command(config, repo, event, message, secret_key).await?;
+    } else if message.contains(".raid") {
+        raid_command(config, repo, event, message, secret_key).await?;
+    } else if message.contains(".dungeon") {
+        dungeon_command(config, repo, event, message, secret_key).await?;
+    } else if message.contains(".logstyle") {
+        log_style(config, repo, event, message, secret_key).await?;
+    } else if message.contains(".history") {
+        history(config, repo, event, message, secret_key).await?;
+    } else if message.contains(".skills") {
+        skill_list(config, repo, event, secret_key).await?;
+    } else if message.contains(".skill") {
+        select_skill(config, repo, event, message, secret_key).await?;
+    } else if message.contains(".allocate") {
+        allocate(config, repo, event, message, secret_key).await?;
+    } else if message.contains(".respec") {
+        respec(config, repo, event, secret_key).await?;
+    } else if message.contains(".inn") {
+        inn(config, repo, event, secret_key).await?;
+    } else if message.contains(".inventory") {
+        inventory(config, repo, event, secret_key).await?;
+    } else if message.contains(".unequip") {
+        unequip(config, repo, event, message, secret_key).await?;
+    } else if message.contains(".equip") {
+        equip(config, repo, event, message, secret_key).await?;
+    } else if message.contains(".shop") {
+        shop_list(config, repo, event, secret_key).await?;
+    } else if message.contains(".buy") {
+        buy(config, repo, event, message, secret_key).await?;
+    } else if message.contains(".sell") {
+        sell(config, repo, event, message, secret_key).await?;
     }
     if is_admin {
         println!("admin");
         if message.contains(".add monster") {
             println!(".add monster");
-            add_monster(config, &conn, event, &message, &secret_key).await?;
+            add_monster(config, repo, event, message, secret_key).await?;
+        } else if message.contains(".add item") {
+            add_item(config, repo, event, message, secret_key).await?;
+        } else if message.contains(".give item") {
+            give_item(config, repo, event, message, secret_key).await?;
+        } else if message.contains(".add shop") {
+            add_shop_item(config, repo, event, message, secret_key).await?;
+        } else if message.contains(".remove shop") {
+            remove_shop_item(config, repo, event, message, secret_key).await?;
+        } else if message.contains(".add zone") {
+            add_zone(config, repo, event, message, secret_key).await?;
+        } else if message.contains(".assign zone") {
+            assign_zone(config, repo, event, message, secret_key).await?;
+        } else if message.contains(".add dungeon") {
+            add_dungeon(config, repo, event, message, secret_key).await?;
+        } else if message.contains(".add loot") {
+            add_loot(config, repo, event, message, secret_key).await?;
+        } else if message.contains(".spawn raid") {
+            spawn_raid(config, repo, event, message, secret_key).await?;
         } else if message.contains(".spawn") {
             println!(".spawn");
-            spawn_monster(config, &conn, event, &message, &secret_key).await?;
+            spawn_monster(config, repo, event, message, secret_key).await?;
+        } else if message.contains(".battlelog") {
+            battle_log(config, repo, event, message, secret_key).await?;
         }
     }
 
     Ok(handled)
 }
 
+// 発言者のユーザーを読み込む。自然回復はここでまとめて反映する
+fn load_user(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+) -> std::result::Result<users::User, Box<dyn std::error::Error>> {
+    load_user_by_npub(config, repo, &event.author().to_string())
+}
+
+fn load_user_by_npub(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    npub: &str,
+) -> std::result::Result<users::User, Box<dyn std::error::Error>> {
+    let user = repo.get_user_by_npub(npub)?;
+    repo.apply_regeneration(
+        user,
+        config.regeneration.hp_per_hour,
+        config.regeneration.mp_per_hour,
+        chrono::Utc::now().timestamp(),
+    )
+}
+
 async fn join_guild(
     config: &config::AppConfig,
-    conn: &Connection,
+    repo: &dyn Repository,
     event: &Event,
     secret_key: &str,
 ) -> Result<()> {
     let prompt = &config.bot.prompt;
 
-    match users::add_user(&conn, &event.author().to_string()) {
+    match repo.add_user(&event.author().to_string()) {
         Ok(user) => {
             let reply = gpt::get_reply(
                 &prompt,
@@ -106,13 +220,7 @@ async fn join_guild(
                 .await?;
             } else {
                 eprintln!("Error adding user: {:?}", e); // その他のエラー
-                util::reply_to(
-                  config,
-                  event.clone(),
-                  secret_key,
-                  "あら、何かシステムが異常なようですわ！急ぎマスターに報告して参ります！しばらくお待ちくださいまし。",
-              )
-              .await?;
+                util::reply_to(config, event.clone(), secret_key, SYSTEM_ERROR_MESSAGE).await?;
             }
         }
     }
@@ -122,27 +230,34 @@ async fn join_guild(
 
 async fn status(
     config: &config::AppConfig,
-    conn: &Connection,
+    repo: &dyn Repository,
     event: &Event,
     secret_key: &str,
 ) -> Result<()> {
-    match users::get_user_by_npub(&conn, &event.author().to_string()) {
+    match load_user(config, repo, event) {
         Ok(user) => {
             let next_exp = util::experience_all_for_level(user.level as u32 + 2) + 1;
+            let bonus = repo.get_equipment_bonus(user.user_id)?;
+            let zone = repo.get_current_zone(user.user_id)?;
             let answer = &format!(
-              "あなたのステータスは以下の通りですわ。\nlevel:{}\nたいりょく:{}/{}\nまりょく:{}/{}\nちから:{}\nしゅびりょく:{}\nすばやさ{}\nうん:{}\nけいけんち:{}\nGOLD:{}\nつぎのlevelまで:{}",
+              "あなたのステータスは以下の通りですわ。\nげんざいち:{}\nlevel:{}\nたいりょく:{}/{}\nまりょく:{}/{}\nちから:{}{}\nしゅびりょく:{}{}\nすばやさ{}{}\nうん:{}\nけいけんち:{}\nGOLD:{}\nつぎのlevelまで:{}\nステータスポイント:{}",
+              zone.map(|zone| zone.name).unwrap_or_else(|| "ギルド周辺".to_string()),
               user.level,
               user.current_hp,
               user.max_hp,
               user.current_mp,
               user.max_mp,
               user.attack,
+              bonus_text(bonus.attack),
               user.defense,
+              bonus_text(bonus.defense),
               user.agility,
+              bonus_text(bonus.agility),
               user.luck,
               user.experience,
               user.gold,
               (next_exp - user.experience as u32) as i32,
+              user.stat_points,
             );
             util::reply_to(config, event.clone(), secret_key, &answer).await?;
         }
@@ -159,16 +274,53 @@ async fn status(
     Ok(())
 }
 
+// 装備の補正があるときだけ "(+3)" のように表示する
+fn bonus_text(bonus: i32) -> String {
+    if bonus == 0 {
+        String::new()
+    } else {
+        format!("({:+})", bonus)
+    }
+}
+
 async fn leveling(
     config: &config::AppConfig,
-    conn: &Connection,
+    repo: &dyn Repository,
     event: &Event,
     secret_key: &str,
 ) -> Result<()> {
-    match users::get_user_by_npub(&conn, &event.author().to_string()) {
+    match load_user(config, repo, event) {
         Ok(user) => {
-            if let Some(monster) = monsters::get_random_monster(&conn)? {
-                let result = battle::simulate_battle(conn, &user, &monster);
+            let allies = load_party_allies(config, repo, &user)?;
+            // パーティのときは平均レベルに合わせる
+            let level = (user.level + allies.iter().map(|ally| ally.level).sum::<i32>())
+                / (allies.len() as i32 + 1);
+            // パーティのときも発言者のいる地域で戦う
+            let zone_id = repo
+                .get_current_zone(user.user_id)?
+                .map(|zone| zone.zone_id);
+            let monsters = repo.get_random_monsters(
+                level,
+                zone_id,
+                user.user_id,
+                chrono::Utc::now().timestamp(),
+                &config.encounter,
+            )?;
+            if !monsters.is_empty() {
+                let snapshot = battle::load_snapshot(repo, &user, &allies, &monsters)?;
+                let loot = repo.get_loot_for_monsters(&monsters)?;
+                let result = battle::simulate_battle(
+                    battle::new_seed(),
+                    &user,
+                    &allies,
+                    &monsters,
+                    &loot,
+                    snapshot,
+                    &config.growth,
+                );
+                let style = log_style_for(config, repo, &user, event)?;
+                let battle_log = battle_log::render(style, &result.snapshot, &result.events);
+                repo.apply_battle_result(&monsters, &result, &battle_log, &event.id.to_hex())?;
                 let message = if result.victory {
                     "ご無事で何よりでした。"
                 } else {
@@ -176,7 +328,7 @@ async fn leveling(
                 };
                 let answer = format!(
                     "おかえりなさいまし。冒険日誌を見せてくださいね\n```\n{}```\n\n{}",
-                    result.battle_log, message
+                    battle_log, message
                 );
                 util::reply_to(config, event.clone(), secret_key, &answer).await?;
             } else {
@@ -203,75 +355,854 @@ async fn leveling(
     Ok(())
 }
 
-async fn add_monster(
+// .travel だけなら地域の一覧、.travel <地域名> でその地域に移動する
+async fn travel(
     config: &config::AppConfig,
-    conn: &Connection,
+    repo: &dyn Repository,
     event: &Event,
     message: &str,
     secret_key: &str,
 ) -> Result<()> {
-    let lines: Vec<String> = message.lines().map(|line| line.to_string()).collect();
+    let user = match load_user(config, repo, event) {
+        Ok(user) => user,
+        Err(_) => {
+            util::reply_to(
+                config,
+                event.clone(),
+                secret_key,
+                "安全のため、ギルド登録なしの冒険は禁じられておりますわ。",
+            )
+            .await?;
+            return Ok(());
+        }
+    };
 
-    println!("len:{}", lines.len());
-    if lines.len() == 9 {
-        conn.execute(
-          "INSERT INTO monster_master (level, name, picture, attack, defense, agility, experience_reward, gold_reward) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
-          rusqlite::params!(
-            lines[1],
-            lines[2],
-            lines[3],
-            lines[4],
-            lines[5],
-            lines[6],
-            lines[7],
-            lines[8],
-          ),
-      )?;
-        util::reply_to(
-            config,
-            event.clone(),
-            secret_key,
-            &format!("{}をマスターに追加致しましたわ。", lines[2]),
-        )
-        .await?;
+    let name = argument_after(message, ".travel");
+    let answer = if name.is_empty() {
+        let current = repo
+            .get_current_zone(user.user_id)?
+            .map(|zone| zone.zone_id);
+        let zones = repo.get_zones()?;
+        let mut answer = String::from("出かけられる地域はこちらですわ。\n");
+        if zones.is_empty() {
+            answer.push_str("あいにく今はギルド周辺しかございませんの。");
+        }
+        for zone in zones.iter() {
+            let mark = if Some(zone.zone_id) == current {
+                "*"
+            } else {
+                " "
+            };
+            answer.push_str(&format!(
+                "{}{} (推奨level:{}) {}\n",
+                mark, zone.name, zone.recommended_level, zone.description
+            ));
+        }
+        answer
+    } else {
+        match repo.get_zone_by_name(name)? {
+            Some(zone) => {
+                repo.travel(user.user_id, &zone)?;
+                let warning = if user.level < zone.recommended_level {
+                    "\nあなたにはまだ危険な場所ですわ。お気をつけて。"
+                } else {
+                    ""
+                };
+                format!(
+                    "{}へ向かわれるのですね。\n{}{}",
+                    zone.name, zone.description, warning
+                )
+            }
+            None => format!("{}という地域は存じ上げませんわ。", name),
+        }
+    };
+    util::reply_to(config, event.clone(), secret_key, &answer).await?;
+
+    Ok(())
+}
+
+// パーティに参加していれば、自分以外の参加済みメンバーを読み込む
+fn load_party_allies(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    user: &users::User,
+) -> std::result::Result<Vec<users::User>, Box<dyn std::error::Error>> {
+    let mut allies = Vec::new();
+    if let Some(party) = repo.get_party_by_user(user.user_id)? {
+        for member in party.joined_members() {
+            if member.user_id != user.user_id {
+                allies.push(load_user_by_npub(config, repo, &member.npub)?);
+            }
+        }
+    }
+
+    Ok(allies)
+}
+
+fn party_error_message(e: &party::PartyError) -> String {
+    match e {
+        party::PartyError::AlreadyInParty => {
+            "すでにパーティに入っていらっしゃいますわ。".to_string()
+        }
+        party::PartyError::NotInParty => {
+            "パーティに入っていらっしゃらないようですわね。".to_string()
+        }
+        party::PartyError::NotLeader => "仲間を誘えるのはリーダーだけですわ。".to_string(),
+        party::PartyError::NoInvitation => "どなたからも誘われていないようですわね。".to_string(),
+        party::PartyError::AlreadyInvited => "その方はもうお誘いしていますわ。".to_string(),
+        party::PartyError::PartyFull => {
+            format!("パーティは{}人までですわ。", party::MAX_PARTY_SIZE)
+        }
+    }
+}
+
+fn party_text(party: &party::Party) -> String {
+    let mut text = String::new();
+    for member in &party.members {
+        let role = if member.user_id == party.leader_id {
+            "リーダー"
+        } else if member.joined {
+            "なかま"
+        } else {
+            "招待中"
+        };
+        text.push_str(&format!(
+            "nostr:{} ({})\n",
+            util::get_npub1(member.npub.clone()).unwrap(),
+            role
+        ));
+    }
+
+    text
+}
+
+// .party create / .party invite nostr:npub1... / .party join / .party leave / .party
+async fn party_command(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    match load_user(config, repo, event) {
+        Ok(user) => {
+            let argument = argument_after(message, ".party");
+            let result = match argument.split_whitespace().next().unwrap_or("") {
+                "create" => repo.create_party(user.user_id)
+                    .map(|_| "パーティを作りましたわ。.party invite で仲間を誘ってくださいまし。".to_string()),
+                "invite" => match util::find_pubkey(&argument["invite".len()..])
+                    .and_then(|npub| repo.get_user_by_npub(&npub).ok())
+                {
+                    Some(invitee) if invitee.user_id != user.user_id => {
+                        repo.invite(user.user_id, invitee.user_id).map(|_| {
+                            format!(
+                                "nostr:{} をパーティにお誘いしましたわ。.party join で参加できますわよ。",
+                                util::get_npub1(invitee.npub.clone()).unwrap()
+                            )
+                        })
+                    }
+                    _ => Ok("ギルドに登録されている方を指定してくださいまし。".to_string()),
+                },
+                "join" => repo.join_party(user.user_id).map(|party| {
+                    format!("パーティに参加しましたわ。\n{}", party_text(&party))
+                }),
+                "leave" => repo.leave_party(user.user_id).map(|disbanded| {
+                    if disbanded {
+                        "パーティを解散しましたわ。".to_string()
+                    } else {
+                        "パーティを抜けましたわ。".to_string()
+                    }
+                }),
+                _ => match repo.get_party_by_user(user.user_id) {
+                    Ok(Some(party)) => Ok(format!("パーティ\n{}", party_text(&party))),
+                    Ok(None) => Ok("パーティに入っていらっしゃらないようですわね。.party create で作れますわ。".to_string()),
+                    Err(e) => Err(e),
+                },
+            };
+            let answer = match result {
+                Ok(answer) => answer,
+                Err(e) => match e.downcast_ref::<party::PartyError>() {
+                    Some(e) => party_error_message(e),
+                    None => return Err(e),
+                },
+            };
+            util::reply_to(config, event.clone(), secret_key, &answer).await?;
+        }
+        Err(_) => {
+            util::reply_to(
+                config,
+                event.clone(),
+                secret_key,
+                "あなたはまだギルドに登録されておられないようですわね。",
+            )
+            .await?;
+        }
     }
 
     Ok(())
 }
 
-async fn spawn_monster(
+fn duel_error_message(e: &duel::DuelError) -> String {
+    match e {
+        duel::DuelError::SelfChallenge => "ご自分とは決闘できませんわ。".to_string(),
+        duel::DuelError::AlreadyPending => "まだ返事を待っている決闘がありますわ。".to_string(),
+        duel::DuelError::Cooldown { remaining_minutes } => format!(
+            "その方とはさきほど決闘したばかりですわ。あと{}分お待ちくださいまし。",
+            remaining_minutes
+        ),
+        duel::DuelError::InvalidWager(_) => "賭け金は0以上にしてくださいまし。".to_string(),
+        duel::DuelError::NotEnoughGold { wager, gold } => format!(
+            "賭け金{}GOLDに対して、{}GOLDしかお持ちでないようですわね。",
+            wager, gold
+        ),
+        duel::DuelError::NoChallenge => "決闘の申し込みはないようですわね。".to_string(),
+    }
+}
+
+// .duel nostr:npub1... [賭け金] / .duel accept / .duel decline / .duel cancel
+async fn duel_command(
     config: &config::AppConfig,
-    conn: &Connection,
+    repo: &dyn Repository,
     event: &Event,
     message: &str,
     secret_key: &str,
 ) -> Result<()> {
-    let lines: Vec<String> = message.lines().map(|line| line.to_string()).collect();
-    println!("len:{}", lines.len());
-    if lines.len() == 3 {
-        let mut suceess = false;
-        let monster_id = lines[1].parse::<i32>();
-        if !monster_id.is_err() {
-            let amount_op = lines[2].parse::<i32>();
-            if !amount_op.is_err() {
-                let amount = amount_op.unwrap();
-                let monster = monsters::spawn_monster(conn, monster_id.unwrap(), amount).unwrap();
+    let user = match load_user(config, repo, event) {
+        Ok(user) => user,
+        Err(_) => {
+            util::reply_to(
+                config,
+                event.clone(),
+                secret_key,
+                "あなたはまだギルドに登録されておられないようですわね。",
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+    let now = chrono::Utc::now().timestamp();
+    let argument = argument_after(message, ".duel");
+    let result = match argument.split_whitespace().next().unwrap_or("") {
+        "accept" => match repo.get_pending_duel_for(user.user_id)? {
+            Some(challenge) if now - challenge.created_at > config.duel.expire_minutes * 60 => repo
+                .close_duel(&challenge, duel::DuelStatus::Expired, now)
+                .map(|_| "その申し込みは期限が切れてしまいましたわ。".to_string()),
+            Some(challenge) => accept_duel(config, repo, event, &challenge, &user, now),
+            None => Err(Box::new(duel::DuelError::NoChallenge).into()),
+        },
+        "decline" => match repo.get_pending_duel_for(user.user_id)? {
+            Some(challenge) => repo
+                .close_duel(&challenge, duel::DuelStatus::Declined, now)
+                .map(|_| "決闘をおことわりしましたわ。".to_string()),
+            None => Err(Box::new(duel::DuelError::NoChallenge).into()),
+        },
+        "cancel" => match repo.get_pending_duel_by(user.user_id)? {
+            Some(challenge) => repo
+                .close_duel(&challenge, duel::DuelStatus::Cancelled, now)
+                .map(|_| "決闘の申し込みを取り下げましたわ。".to_string()),
+            None => Err(Box::new(duel::DuelError::NoChallenge).into()),
+        },
+        _ => {
+            let opponent =
+                util::find_pubkey(argument).and_then(|npub| repo.get_user_by_npub(&npub).ok());
+            // 公開鍵のあとに数字があれば賭け金
+            let wager = argument
+                .split_whitespace()
+                .filter_map(|word| word.parse::<i32>().ok())
+                .next_back()
+                .unwrap_or(0);
+            match opponent {
+                Some(opponent) => repo.challenge(user.user_id,
+                    opponent.user_id,
+                    wager,
+                    config.duel.cooldown_minutes,
+                    now,
+                )
+                .map(|_| {
+                    format!(
+                        "nostr:{} に決闘を申し込みましたわ。賭け金は{}GOLDですわ。\n受けるなら .duel accept、ことわるなら .duel decline と返してくださいまし。",
+                        util::get_npub1(opponent.npub.clone()).unwrap(),
+                        wager
+                    )
+                }),
+                None => Ok("ギルドに登録されている方を指定してくださいまし。".to_string()),
+            }
+        }
+    };
+    let answer = match result {
+        Ok(answer) => answer,
+        Err(e) => match e.downcast_ref::<duel::DuelError>() {
+            Some(e) => duel_error_message(e),
+            None => return Err(e),
+        },
+    };
+    util::reply_to(config, event.clone(), secret_key, &answer).await?;
+
+    Ok(())
+}
+
+// 申し込んだ側をプレイヤー、受けた側をモンスターの位置に置いて戦う
+// 戦闘は battle_results ではなく duels の行に記録する（.history には出ない）
+fn accept_duel(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    challenge: &duel::Duel,
+    opponent: &users::User,
+    now: i64,
+) -> std::result::Result<String, Box<dyn std::error::Error>> {
+    // 申し込んだ側がいなくなっていれば、申し込みもなかったことにする
+    let challenger = repo
+        .get_user_by_id(challenge.challenger_id)?
+        .ok_or(duel::DuelError::NoChallenge)?;
+    let challenger = load_user_by_npub(config, repo, &challenger.npub)?;
+    let snapshot = battle::load_duel_snapshot(repo, &challenger, opponent)?;
+    let seed = battle::new_seed();
+    let fight = battle::replay(seed, &snapshot);
+    let winner = match fight.outcome {
+        battle::BattleOutcome::Victory => Some(&challenger),
+        battle::BattleOutcome::Defeat => Some(opponent),
+        battle::BattleOutcome::Escape => None,
+    };
+    let style = log_style_for(config, repo, opponent, event)?;
+    let battle_log = battle_log::render(style, &snapshot, &fight.events);
+    repo.resolve_duel(
+        challenge,
+        winner.map(|winner| winner.user_id),
+        seed,
+        &snapshot,
+        &battle_log,
+        now,
+    )?;
+
+    let message = match winner {
+        Some(winner) if challenge.wager > 0 => format!(
+            "nostr:{} の勝ちですわ！賭け金{}GOLDをお渡ししましたわ。",
+            util::get_npub1(winner.npub.clone()).unwrap(),
+            challenge.wager * 2
+        ),
+        Some(winner) => format!(
+            "nostr:{} の勝ちですわ！",
+            util::get_npub1(winner.npub.clone()).unwrap()
+        ),
+        None => "引き分けですわね。賭け金はお返ししましたわ。".to_string(),
+    };
+
+    Ok(format!(
+        "決闘の記録ですわ\n```\n{}```\n\n{}",
+        battle_log, message
+    ))
+}
+
+fn dungeon_error_message(e: &dungeon::DungeonError) -> String {
+    match e {
+        dungeon::DungeonError::AlreadyInRun(name) => format!(
+            "あなたはまだ{}を探索中ですわ。進むなら .dungeon、引き返すなら .dungeon retreat ですわ。",
+            name
+        ),
+        dungeon::DungeonError::NoRun => "今はダンジョンを探索しておられませんわ。".to_string(),
+    }
+}
+
+// 探索を終えたときは、すべてのフロアの戦闘ログをまとめて返す
+fn dungeon_summary(run: &dungeon::DungeonRun, battle_log: &str, message: &str) -> String {
+    format!(
+        "{}の冒険日誌ですわ\n```\n{}{}```\n\n{}\n突破したフロア:{}/{}\nけいけんち:{}\nGOLD:{}",
+        run.dungeon.name,
+        run.battle_log,
+        battle_log,
+        message,
+        run.floor,
+        run.dungeon.floors,
+        run.experience,
+        run.gold
+    )
+}
+
+// .dungeon <名前> で探索を始め、.dungeon で次のフロアへ、.dungeon retreat で引き返す
+async fn dungeon_command(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    let user = match load_user(config, repo, event) {
+        Ok(user) => user,
+        Err(_) => {
+            util::reply_to(
+                config,
+                event.clone(),
+                secret_key,
+                "安全のため、ギルド登録なしの冒険は禁じられておりますわ。",
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+    let now = chrono::Utc::now().timestamp();
+    let name = argument_after(message, ".dungeon");
+    let run = repo.get_active_run(user.user_id)?;
+    let result = match (name, run) {
+        ("retreat", Some(run)) => repo
+            .retreat(&run, now)
+            .map(|_| dungeon_summary(&run, "", "無理をせず引き返すのも立派な判断ですわ。")),
+        ("retreat", None) => Err(Box::new(dungeon::DungeonError::NoRun).into()),
+        ("", Some(run)) => explore_floor(config, repo, event, &user, run, now),
+        ("", None) => {
+            let mut answer = String::from("挑めるダンジョンはこちらですわ。\n");
+            let dungeons = repo.get_dungeons()?;
+            if dungeons.is_empty() {
+                answer.push_str("あいにく今はございませんの。");
+            }
+            for dungeon in dungeons.iter() {
+                answer.push_str(&format!("{} ({}階)\n", dungeon.name, dungeon.floors));
+            }
+            Ok(answer)
+        }
+        (name, Some(run)) if name == run.dungeon.name => {
+            explore_floor(config, repo, event, &user, run, now)
+        }
+        (_, Some(run)) => {
+            Err(Box::new(dungeon::DungeonError::AlreadyInRun(run.dungeon.name)).into())
+        }
+        (name, None) => match repo.get_dungeon_by_name(name)? {
+            Some(dungeon) => repo
+                .start_run(
+                    user.user_id,
+                    &dungeon,
+                    user.current_hp,
+                    user.current_mp,
+                    now,
+                )
+                .and_then(|run| explore_floor(config, repo, event, &user, run, now)),
+            None => Ok(format!("{}というダンジョンは存じ上げませんわ。", name)),
+        },
+    };
+    let answer = match result {
+        Ok(answer) => answer,
+        Err(e) => match e.downcast_ref::<dungeon::DungeonError>() {
+            Some(e) => dungeon_error_message(e),
+            None => return Err(e),
+        },
+    };
+    util::reply_to(config, event.clone(), secret_key, &answer).await?;
+
+    Ok(())
+}
+
+// 次のフロアで戦う。HP/MPはダンジョンに入ってから回復しない
+// 深いフロアほど強いモンスターが現れ、最後のフロアにはボスが待っている
+fn explore_floor(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    user: &users::User,
+    mut run: dungeon::DungeonRun,
+    now: i64,
+) -> std::result::Result<String, Box<dyn std::error::Error>> {
+    let floor = run.next_floor();
+    // ダンジョンのモンスターはワールドのモンスターを使わず、この探索専用に召喚する
+    let monster_ids = if run.is_boss_floor() {
+        vec![run.dungeon.boss_monster_id]
+    } else {
+        repo.choose_floor_monsters(
+            user.level + floor - 1,
+            run.dungeon.zone_id,
+            &config.encounter,
+        )?
+    };
+    let monsters = repo.spawn_for_run(
+        run.run_id,
+        &monster_ids,
+        user.user_id,
+        now + config.encounter.reservation_minutes.max(1) * 60,
+        &config.variants,
+    )?;
+    if monsters.is_empty() {
+        let battle_log = format!("【{}階】\nモンスターはいなかった\n", floor);
+        repo.record_floor(
+            &run,
+            &dungeon::FloorResult {
+                cleared: true,
+                status: dungeon::RunStatus::Active,
+                current_hp: run.current_hp,
+                current_mp: run.current_mp,
+                experience: 0,
+                gold: 0,
+                battle_log: &battle_log,
+                recorded_at: now,
+            },
+        )?;
+        return Ok(format!(
+            "{}階にはモンスターがいないようですわね。先へ進むなら .dungeon、引き返すなら .dungeon retreat ですわ。",
+            floor
+        ));
+    }
+
+    let mut player = user.clone();
+    player.current_hp = run.current_hp.min(player.max_hp);
+    player.current_mp = run.current_mp.min(player.max_mp);
+    let snapshot = battle::load_snapshot(repo, &player, &[], &monsters)?;
+    let loot = repo.get_loot_for_monsters(&monsters)?;
+    let result = battle::simulate_battle(
+        battle::new_seed(),
+        &player,
+        &[],
+        &monsters,
+        &loot,
+        snapshot,
+        &config.growth,
+    );
+    let style = log_style_for(config, repo, user, event)?;
+    let battle_log = format!(
+        "【{}階】\n{}",
+        floor,
+        battle_log::render(style, &result.snapshot, &result.events)
+    );
+
+    // ボスはにげられたら倒したことにならない
+    let cleared = match result.outcome {
+        battle::BattleOutcome::Victory => true,
+        battle::BattleOutcome::Escape => !run.is_boss_floor(),
+        battle::BattleOutcome::Defeat => false,
+    };
+    let status = match result.outcome {
+        battle::BattleOutcome::Defeat => dungeon::RunStatus::Defeated,
+        _ if cleared && run.is_boss_floor() => dungeon::RunStatus::Cleared,
+        _ => dungeon::RunStatus::Active,
+    };
+    repo.apply_floor_battle(
+        &run,
+        &dungeon::FloorResult {
+            cleared,
+            status,
+            current_hp: result.updated_user.current_hp,
+            current_mp: result.updated_user.current_mp,
+            experience: result.experience_gain,
+            gold: result.gold_gain,
+            battle_log: &battle_log,
+            recorded_at: now,
+        },
+        &monsters,
+        &result,
+        &battle_log,
+        &event.id.to_hex(),
+    )?;
+    run.floor += cleared as i32;
+    run.experience += result.experience_gain;
+    run.gold += result.gold_gain;
+
+    let answer = match status {
+        dungeon::RunStatus::Cleared => dungeon_summary(
+            &run,
+            &battle_log,
+            "ダンジョンを踏破なさったのですね！お見事ですわ。",
+        ),
+        dungeon::RunStatus::Defeated => dungeon_summary(
+            &run,
+            &battle_log,
+            "無茶をなさったようですね。こうして戻ってこられるのも不滅の鍵の冒険者の福音ですわね。",
+        ),
+        _ => {
+            let message = if cleared {
+                format!("{}階を突破なさいましたわ。", floor)
+            } else {
+                format!("{}階のボスににげられてしまいましたわ。", floor)
+            };
+            format!(
+                "{}\nたいりょく:{}/{}\nまりょく:{}/{}\n先へ進むなら .dungeon、引き返すなら .dungeon retreat ですわ。",
+                message,
+                result.updated_user.current_hp,
+                result.updated_user.max_hp,
+                result.updated_user.current_mp,
+                result.updated_user.max_mp
+            )
+        }
+    };
+
+    Ok(answer)
+}
+
+fn raid_error_message(e: &raid::RaidError) -> String {
+    match e {
+        raid::RaidError::NoRaid => "今はレイドボスは現れておりませんわ。".to_string(),
+        raid::RaidError::AlreadyActive(name) => format!("まだ{}が暴れておりますわ。", name),
+        raid::RaidError::Cooldown { remaining_minutes } => format!(
+            "少し休んでくださいまし。あと{}分で攻撃できますわ。",
+            remaining_minutes
+        ),
+    }
+}
+
+fn raid_ranking_text(repo: &dyn Repository, raid: &raid::Raid) -> Result<String> {
+    let mut answer = format!(
+        "{}の討伐ランキングですわ。(のこりHP:{}/{})\n",
+        raid.monster.name, raid.monster.hp, raid.max_hp
+    );
+    for (rank, contribution) in repo
+        .get_contributions(raid.raid_id)?
+        .iter()
+        .take(RAID_RANKING_SIZE)
+        .enumerate()
+    {
+        answer.push_str(&format!(
+            "{}. nostr:{} {}ダメージ ({}回)\n",
+            rank + 1,
+            util::get_npub1(contribution.npub.clone()).unwrap(),
+            contribution.damage,
+            contribution.attacks
+        ));
+    }
+
+    Ok(answer)
+}
+
+const RAID_RANKING_SIZE: usize = 10;
+
+// .raid でレイドボスを攻撃し、.raid ranking で与えたダメージのランキングを見る
+async fn raid_command(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    let result = if argument_after(message, ".raid") == "ranking" {
+        match repo.get_latest_raid()? {
+            Some(raid) => Ok(raid_ranking_text(repo, &raid)?),
+            None => Err(Box::new(raid::RaidError::NoRaid).into()),
+        }
+    } else {
+        let user = match load_user(config, repo, event) {
+            Ok(user) => user,
+            Err(_) => {
                 util::reply_to(
                     config,
                     event.clone(),
                     secret_key,
-                    &format!("{} を {}体召喚しましたわ。マスター。", monster.name, amount),
+                    "安全のため、ギルド登録なしの冒険は禁じられておりますわ。",
                 )
                 .await?;
-                suceess = true;
+                return Ok(());
             }
+        };
+        match repo.get_active_raid()? {
+            Some(raid) => attack_raid(config, repo, event, &user, &raid),
+            None => Err(Box::new(raid::RaidError::NoRaid).into()),
         }
-        if !suceess {
+    };
+    let answer = match result {
+        Ok(answer) => answer,
+        Err(e) => match e.downcast_ref::<raid::RaidError>() {
+            Some(e) => raid_error_message(e),
+            None => return Err(e),
+        },
+    };
+    util::reply_to(config, event.clone(), secret_key, &answer).await?;
+
+    Ok(())
+}
+
+// 共有のHPを持ったレイドボスと戦い、削ったHPと戦闘の記録を保存する。倒れてもHP1で戻り、GOLDは失わない
+fn attack_raid(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    user: &users::User,
+    raid: &raid::Raid,
+) -> std::result::Result<String, Box<dyn std::error::Error>> {
+    let now = chrono::Utc::now().timestamp();
+    let mut snapshot = battle::load_snapshot(repo, user, &[], std::slice::from_ref(&raid.monster))?;
+    snapshot.monster.max_hp = raid.max_hp;
+    let seed = battle::new_seed();
+    let fight = battle::replay(seed, &snapshot);
+    let damage = raid.monster.hp - fight.monster_hp[0];
+    let result = raid::battle_result(raid, user, seed, snapshot, fight);
+
+    let style = log_style_for(config, repo, user, event)?;
+    let battle_log = battle_log::render(style, &result.snapshot, &result.events);
+    let hp = repo.record_attack(
+        raid,
+        &raid::RaidAttack {
+            result: &result,
+            damage,
+            battle_log: &battle_log,
+            event_id: &event.id.to_hex(),
+        },
+        config.raid.cooldown_minutes,
+        now,
+    )?;
+    let mut answer = format!(
+        "レイドの記録ですわ\n```\n{}```\n\n{}に{}のダメージを与えましたわ。のこりHP:{}/{}",
+        battle_log, raid.monster.name, damage, hp, raid.max_hp
+    );
+
+    if hp <= 0 {
+        let rewards = repo.finish_raid(raid, &config.growth, now)?;
+        if !rewards.is_empty() {
+            answer.push_str(&format!(
+                "\n\n{}を討伐しましたわ！みなさまに報酬をお渡ししますわね。\n",
+                raid.monster.name
+            ));
+        }
+        for reward in rewards.iter() {
+            let level_up = match reward.level {
+                Some(level) => format!(" level{}にアップ", level),
+                None => String::new(),
+            };
+            answer.push_str(&format!(
+                "nostr:{} {}ダメージ けいけんち+{} GOLD+{}{}\n",
+                util::get_npub1(reward.npub.clone()).unwrap(),
+                reward.damage,
+                reward.experience,
+                reward.gold,
+                level_up
+            ));
+        }
+    }
+
+    Ok(answer)
+}
+
+// ユーザーの設定 > チャンネルごとの設定 > 日本語 の順で戦闘ログの形式を決める
+fn log_style_for(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    user: &users::User,
+    event: &Event,
+) -> Result<LogStyle> {
+    let channel_style = if event.kind() == Kind::EncryptedDirectMessage {
+        &config.battle_log.direct_message
+    } else {
+        &config.battle_log.text_note
+    };
+    let style = repo
+        .get_log_style(user.user_id)?
+        .and_then(|style| LogStyle::parse(&style))
+        .or_else(|| channel_style.as_deref().and_then(LogStyle::parse))
+        .unwrap_or(LogStyle::Japanese);
+
+    Ok(style)
+}
+
+async fn log_style(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    match load_user(config, repo, event) {
+        Ok(user) => {
+            let answer = match LogStyle::parse(argument_after(message, ".logstyle")) {
+                Some(style) => {
+                    repo.set_log_style(user.user_id, style.as_str())?;
+                    format!("冒険日誌の書き方を {} にいたしましたわ。", style.as_str())
+                }
+                None => {
+                    "japanese / english / summary / json の中から選んでくださいまし。".to_string()
+                }
+            };
+            util::reply_to(config, event.clone(), secret_key, &answer).await?;
+        }
+        Err(_) => {
+            util::reply_to(
+                config,
+                event.clone(),
+                secret_key,
+                "あなたはまだギルドに登録されておられないようですわね。",
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn skill_list(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    secret_key: &str,
+) -> Result<()> {
+    match load_user(config, repo, event) {
+        Ok(user) => {
+            let learned = repo.get_skills_for_level(user.level)?;
+            let selected = repo.get_selected_skill(user.user_id)?;
+            let mut answer = format!(
+                "あなたの覚えているスキルですわ。(まりょく:{}/{})\n",
+                user.current_mp, user.max_mp
+            );
+            if learned.is_empty() {
+                answer.push_str("まだ何も覚えていらっしゃらないようですわね。\n");
+            }
+            for skill in learned.iter() {
+                answer.push_str(&format!(
+                    "{}{} しょうひMP:{}\n",
+                    if selected.as_ref() == Some(&skill.name) {
+                        "*"
+                    } else {
+                        ""
+                    },
+                    skill.name,
+                    skill.mp_cost
+                ));
+            }
+            if let Some(skill) = repo.get_next_skill(user.level)? {
+                answer.push_str(&format!(
+                    "level{}で{}を覚えますわ。",
+                    skill.learn_level, skill.name
+                ));
+            }
+            util::reply_to(config, event.clone(), secret_key, &answer).await?;
+        }
+        Err(_) => {
+            util::reply_to(
+                config,
+                event.clone(),
+                secret_key,
+                "あなたはまだギルドに登録されておられないようですわね。",
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+// .skill <スキル名> で戦闘中に優先して使うスキルを決める。.skill auto でおまかせに戻す
+async fn select_skill(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    match load_user(config, repo, event) {
+        Ok(user) => {
+            let name = argument_after(message, ".skill");
+            let answer = if name == "auto" || name.is_empty() {
+                repo.set_selected_skill(user.user_id, None)?;
+                "スキルはおまかせにいたしますわ。".to_string()
+            } else if repo
+                .get_skills_for_level(user.level)?
+                .iter()
+                .any(|skill| skill.name == name)
+            {
+                repo.set_selected_skill(user.user_id, Some(name))?;
+                format!("戦闘では{}を優先して使いますわ。", name)
+            } else {
+                format!("{}はまだ覚えていらっしゃらないようですわね。", name)
+            };
+            util::reply_to(config, event.clone(), secret_key, &answer).await?;
+        }
+        Err(_) => {
             util::reply_to(
                 config,
                 event.clone(),
                 secret_key,
-                &format!("指示がおかしいようですわね。しっかりしてくださいね。"),
+                "あなたはまだギルドに登録されておられないようですわね。",
             )
             .await?;
         }
@@ -279,3 +1210,1048 @@ async fn spawn_monster(
 
     Ok(())
 }
+
+// .allocate attack 2 のように、ステータスポイントを振り分ける
+async fn allocate(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    match load_user(config, repo, event) {
+        Ok(user) => {
+            let args: Vec<&str> = argument_after(message, ".allocate")
+                .split_whitespace()
+                .collect();
+            let stat = args.first().and_then(|name| stats::Stat::parse(name));
+            let points = match args.get(1) {
+                Some(points) => points.parse::<i32>().ok(),
+                None => Some(1),
+            };
+            let answer = match (stat, points) {
+                (Some(stat), Some(points)) => match repo.allocate(&user, stat, points) {
+                    Ok(updated_user) => format!(
+                        "{}に{}ポイント振り分けましたわ。のこりのステータスポイントは{}ですわ。",
+                        stat.label(),
+                        points,
+                        updated_user.stat_points
+                    ),
+                    Err(e) => match e.downcast_ref::<stats::AllocateError>() {
+                        Some(stats::AllocateError::NotEnoughPoints { available, .. }) => {
+                            format!("ステータスポイントは{}しかございませんわ。", available)
+                        }
+                        Some(_) => "1以上の数を指定してくださいまし。".to_string(),
+                        None => return Err(e),
+                    },
+                },
+                _ => "たいりょく(hp) まりょく(mp) ちから(attack) しゅびりょく(defense) すばやさ(agility) うん(luck) のどれに何ポイント振り分けるか教えてくださいまし。".to_string(),
+            };
+            util::reply_to(config, event.clone(), secret_key, &answer).await?;
+        }
+        Err(_) => {
+            util::reply_to(
+                config,
+                event.clone(),
+                secret_key,
+                "あなたはまだギルドに登録されておられないようですわね。",
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn respec(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    secret_key: &str,
+) -> Result<()> {
+    match load_user(config, repo, event) {
+        Ok(user) => {
+            let cost = user.level.max(1) * config.growth.respec_cost_per_level;
+            let answer = match repo.respec(&user, cost) {
+                Ok(updated_user) => format!(
+                    "{}GOLDいただいて、振り分けをやりなおしましたわ。ステータスポイントは{}ですわ。",
+                    cost, updated_user.stat_points
+                ),
+                Err(e) => match e.downcast_ref::<stats::AllocateError>() {
+                    Some(stats::AllocateError::NotEnoughGold { cost, gold }) => format!(
+                        "やりなおしには{}GOLD必要ですわ。あなたは{}GOLDしかお持ちでないようですわね。",
+                        cost, gold
+                    ),
+                    Some(_) => "まだ何も振り分けていらっしゃらないようですわね。".to_string(),
+                    None => return Err(e),
+                },
+            };
+            util::reply_to(config, event.clone(), secret_key, &answer).await?;
+        }
+        Err(_) => {
+            util::reply_to(
+                config,
+                event.clone(),
+                secret_key,
+                "あなたはまだギルドに登録されておられないようですわね。",
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn inn(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    secret_key: &str,
+) -> Result<()> {
+    match load_user(config, repo, event) {
+        Ok(user) => {
+            let cost = user.level.max(1) * config.inn.cost_per_level;
+            let answer = if user.current_hp >= user.max_hp && user.current_mp >= user.max_mp {
+                "お元気そうですわね。お休みになる必要はございませんわ。".to_string()
+            } else if repo.rest_at_inn(&user, cost, chrono::Utc::now().timestamp())? {
+                format!(
+                    "{}GOLDいただきますわ。ごゆっくりお休みくださいまし。\nたいりょく:{}/{}\nまりょく:{}/{}",
+                    cost, user.max_hp, user.max_hp, user.max_mp, user.max_mp
+                )
+            } else {
+                format!(
+                    "お泊まりには{}GOLD必要ですわ。あなたは{}GOLDしかお持ちでないようですわね。",
+                    cost, user.gold
+                )
+            };
+            util::reply_to(config, event.clone(), secret_key, &answer).await?;
+        }
+        Err(_) => {
+            util::reply_to(
+                config,
+                event.clone(),
+                secret_key,
+                "あなたはまだギルドに登録されておられないようですわね。",
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+const HISTORY_PAGE_SIZE: i32 = 5;
+
+// コマンドと同じ行の後ろに続く文字列を取り出す（例: ".equip どうのつるぎ" -> "どうのつるぎ"）
+fn argument_after<'a>(message: &'a str, command: &str) -> &'a str {
+    match message.find(command) {
+        Some(index) => message[index + command.len()..]
+            .lines()
+            .next()
+            .unwrap_or("")
+            .trim(),
+        None => "",
+    }
+}
+
+// コマンドの後ろに続く数値を取り出す（例: ".history 2" -> Some(2)）
+fn parse_number_after(message: &str, command: &str) -> Option<i64> {
+    argument_after(message, command)
+        .split_whitespace()
+        .next()?
+        .parse::<i64>()
+        .ok()
+}
+
+async fn history(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    match load_user(config, repo, event) {
+        Ok(user) => {
+            let page = parse_number_after(message, ".history").unwrap_or(1).max(1) as i32;
+            let stats = repo.get_battle_stats_by_user(user.user_id)?;
+            let records = repo.get_battle_results_by_user(
+                user.user_id,
+                HISTORY_PAGE_SIZE,
+                (page - 1) * HISTORY_PAGE_SIZE,
+            )?;
+            let mut answer = format!(
+                "あなたの戦績ですわ。\n{}勝 {}敗 {}引き分け\n({}ページ目)\n",
+                stats.victories, stats.defeats, stats.escapes, page
+            );
+            if records.is_empty() {
+                answer.push_str("記録はございませんわ。");
+            }
+            for record in records.iter() {
+                let outcome = match record.outcome {
+                    battle::BattleOutcome::Victory => "勝利",
+                    battle::BattleOutcome::Defeat => "敗北",
+                    battle::BattleOutcome::Escape => "引き分け",
+                };
+                answer.push_str(&format!(
+                    "#{} {} {} けいけんち+{} GOLD+{}\n",
+                    record.battle_id,
+                    record.created_at,
+                    outcome,
+                    record.experience_gain,
+                    record.gold_gain
+                ));
+            }
+            util::reply_to(config, event.clone(), secret_key, &answer).await?;
+        }
+        Err(_) => {
+            util::reply_to(
+                config,
+                event.clone(),
+                secret_key,
+                "あなたはまだギルドに登録されておられないようですわね。",
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+// 管理者向け: 戦闘ログを丸ごと確認する
+async fn battle_log(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    let record = match parse_number_after(message, ".battlelog") {
+        Some(battle_id) => repo.get_battle_result_by_id(battle_id)?,
+        None => None,
+    };
+    let answer = match record {
+        Some(record) => {
+            // シードから戦闘を再現して、決着と出来事の列が記録と食い違わないか確かめる
+            // 戦闘エンジンのバージョンが違う記録は同じ戦闘にならないので確かめない
+            let replayed = match (&record.snapshot, &record.events) {
+                (Some(_), _) if record.engine_version != battle::ENGINE_VERSION => "旧バージョン",
+                (Some(snapshot), Some(events)) => {
+                    if battle::replay_matches(record.seed, snapshot, record.outcome, events) {
+                        "一致"
+                    } else {
+                        "不一致"
+                    }
+                }
+                _ => "記録なし",
+            };
+            format!(
+                "battle_id:{}\nuser_id:{}\nmonster_id:{}\nevent_id:{}\ndate:{}\nseed:{}\nreplay:{}\n```\n{}```",
+                record.battle_id,
+                record.user_id,
+                record.monster_id,
+                record.event_id,
+                record.created_at,
+                record.seed,
+                replayed,
+                record.battle_log
+            )
+        }
+        None => "その記録は見つかりませんわね。".to_string(),
+    };
+    util::reply_to(config, event.clone(), secret_key, &answer).await?;
+
+    Ok(())
+}
+
+async fn inventory(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    secret_key: &str,
+) -> Result<()> {
+    match load_user(config, repo, event) {
+        Ok(user) => {
+            let entries = repo.get_inventory(user.user_id)?;
+            let mut answer = String::from("あなたの持ち物ですわ。\n");
+            if entries.is_empty() {
+                answer.push_str("なにも持っていらっしゃらないようですわね。");
+            }
+            for entry in entries.iter() {
+                answer.push_str(&format!(
+                    "{}{} x{} (ちから{:+} しゅびりょく{:+} すばやさ{:+})\n",
+                    if entry.equipped { "E:" } else { "" },
+                    entry.item.name,
+                    entry.quantity,
+                    entry.item.attack_bonus,
+                    entry.item.defense_bonus,
+                    entry.item.agility_bonus,
+                ));
+            }
+            util::reply_to(config, event.clone(), secret_key, &answer).await?;
+        }
+        Err(_) => {
+            util::reply_to(
+                config,
+                event.clone(),
+                secret_key,
+                "あなたはまだギルドに登録されておられないようですわね。",
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn equip(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    match load_user(config, repo, event) {
+        Ok(user) => {
+            let name = argument_after(message, ".equip");
+            let answer = match repo.equip_item(user.user_id, name) {
+                Ok(item) => format!(
+                    "{}を{}にそうびいたしましたわ。",
+                    item.name,
+                    items::slot_name(&item.item_type)
+                ),
+                Err(e) => match e.downcast_ref::<items::EquipError>() {
+                    Some(items::EquipError::NotEquippable(_)) => {
+                        format!("{}はそうびできませんわよ。", name)
+                    }
+                    Some(_) => format!("{}はお持ちでないようですわね。", name),
+                    None => return Err(e),
+                },
+            };
+            util::reply_to(config, event.clone(), secret_key, &answer).await?;
+        }
+        Err(_) => {
+            util::reply_to(
+                config,
+                event.clone(),
+                secret_key,
+                "あなたはまだギルドに登録されておられないようですわね。",
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn unequip(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    match load_user(config, repo, event) {
+        Ok(user) => {
+            // スロット名でもアイテム名でも外せるようにする
+            let target = argument_after(message, ".unequip");
+            let slot = match items::parse_slot(target) {
+                Some(slot) => Some(slot.to_string()),
+                None => repo
+                    .get_equipment(user.user_id)?
+                    .into_iter()
+                    .find(|item| item.name == target)
+                    .map(|item| item.item_type),
+            };
+            let result = match slot {
+                Some(slot) => repo.unequip_slot(user.user_id, &slot).map(Some),
+                None => Ok(None),
+            };
+            let answer = match result {
+                Ok(Some(item)) => format!("{}をはずしましたわ。", item.name),
+                Ok(None) => "なにをはずすのか教えてくださいまし。".to_string(),
+                Err(e) => match e.downcast_ref::<items::EquipError>() {
+                    Some(_) => "そこにはなにもそうびしていませんわよ。".to_string(),
+                    None => return Err(e),
+                },
+            };
+            util::reply_to(config, event.clone(), secret_key, &answer).await?;
+        }
+        Err(_) => {
+            util::reply_to(
+                config,
+                event.clone(),
+                secret_key,
+                "あなたはまだギルドに登録されておられないようですわね。",
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn shop_list(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    secret_key: &str,
+) -> Result<()> {
+    let entries = repo.get_shop_entries()?;
+    let mut answer = String::from("いらっしゃいませ。本日の品ぞろえですわ。\n");
+    if entries.is_empty() {
+        answer.push_str("あいにく今は何も置いておりませんの。");
+    }
+    for entry in entries.iter() {
+        let stock = if entry.stock == shop::UNLIMITED_STOCK {
+            String::new()
+        } else {
+            format!(" のこり{}", entry.stock)
+        };
+        answer.push_str(&format!(
+            "{} {}GOLD (ちから{:+} しゅびりょく{:+} すばやさ{:+}){}\n",
+            entry.item.name,
+            entry.price,
+            entry.item.attack_bonus,
+            entry.item.defense_bonus,
+            entry.item.agility_bonus,
+            stock,
+        ));
+    }
+    util::reply_to(config, event.clone(), secret_key, &answer).await?;
+
+    Ok(())
+}
+
+fn shop_error_message(e: &shop::ShopError) -> String {
+    match e {
+        shop::ShopError::NotForSale(name) => format!("{}はこのお店では扱っておりませんわ。", name),
+        shop::ShopError::OutOfStock(name) => format!("{}は売り切れですわ。", name),
+        shop::ShopError::NotEnoughGold { price, gold } => format!(
+            "{}GOLD必要ですけれど、{}GOLDしかお持ちでないようですわね。",
+            price, gold
+        ),
+        shop::ShopError::NotOwned(name) => format!("{}はお持ちでないようですわね。", name),
+        shop::ShopError::Equipped(name) => {
+            format!("{}はそうび中ですわ。はずしてからお持ちくださいね。", name)
+        }
+    }
+}
+
+async fn buy(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    match load_user(config, repo, event) {
+        Ok(user) => {
+            let name = argument_after(message, ".buy");
+            let answer = match repo.buy_item(user.user_id, name) {
+                Ok(entry) => format!(
+                    "{}を{}GOLDでお買い上げですわ。まいどありがとうございます。",
+                    entry.item.name, entry.price
+                ),
+                Err(e) => match e.downcast_ref::<shop::ShopError>() {
+                    Some(shop_error) => shop_error_message(shop_error),
+                    None => return Err(e),
+                },
+            };
+            util::reply_to(config, event.clone(), secret_key, &answer).await?;
+        }
+        Err(_) => {
+            util::reply_to(
+                config,
+                event.clone(),
+                secret_key,
+                "あなたはまだギルドに登録されておられないようですわね。",
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn sell(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    match load_user(config, repo, event) {
+        Ok(user) => {
+            let name = argument_after(message, ".sell");
+            let answer = match repo.sell_item(user.user_id, name) {
+                Ok(entry) => format!(
+                    "{}を{}GOLDで買い取らせていただきましたわ。",
+                    entry.item.name,
+                    entry.sell_price()
+                ),
+                Err(e) => match e.downcast_ref::<shop::ShopError>() {
+                    Some(shop_error) => shop_error_message(shop_error),
+                    None => return Err(e),
+                },
+            };
+            util::reply_to(config, event.clone(), secret_key, &answer).await?;
+        }
+        Err(_) => {
+            util::reply_to(
+                config,
+                event.clone(),
+                secret_key,
+                "あなたはまだギルドに登録されておられないようですわね。",
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn add_monster(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    let lines: Vec<String> = message.lines().map(|line| line.to_string()).collect();
+
+    // 10行目のluck、11行目のrarity_weight（出やすさ）、12行目の地域名は省略できる
+    if (9..=12).contains(&lines.len()) {
+        let numbers: Vec<i32> = [1, 4, 5, 6, 7, 8]
+            .iter()
+            .filter_map(|index| lines[*index].trim().parse::<i32>().ok())
+            .collect();
+        let luck = lines
+            .get(9)
+            .map_or(Ok(0), |line| line.trim().parse::<i32>());
+        let rarity_weight = lines
+            .get(10)
+            .map_or(Ok(100), |line| line.trim().parse::<i32>());
+        let zone_id = match lines.get(11) {
+            Some(line) => match repo.get_zone_by_name(line.trim())? {
+                Some(zone) => Some(zone.zone_id),
+                None => {
+                    util::reply_to(
+                        config,
+                        event.clone(),
+                        secret_key,
+                        &format!("{}という地域は存じ上げませんわ。", line.trim()),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+            },
+            None => None,
+        };
+        let answer = match (numbers.as_slice(), luck, rarity_weight) {
+            (
+                [level, attack, defense, agility, experience_reward, gold_reward],
+                Ok(luck),
+                Ok(rarity_weight),
+            ) => {
+                repo.add_monster_master(&monsters::NewMonsterMaster {
+                    level: *level,
+                    name: lines[2].clone(),
+                    picture: lines[3].clone(),
+                    attack: *attack,
+                    defense: *defense,
+                    agility: *agility,
+                    experience_reward: *experience_reward,
+                    gold_reward: *gold_reward,
+                    luck,
+                    rarity_weight,
+                    zone_id,
+                })?;
+                format!("{}をマスターに追加致しましたわ。", lines[2])
+            }
+            _ => "指示がおかしいようですわね。しっかりしてくださいね。".to_string(),
+        };
+        util::reply_to(config, event.clone(), secret_key, &answer).await?;
+    }
+
+    Ok(())
+}
+
+async fn add_item(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    let lines: Vec<String> = message.lines().map(|line| line.to_string()).collect();
+
+    if lines.len() == 6 {
+        let bonuses: Vec<i32> = lines[3..6]
+            .iter()
+            .filter_map(|line| line.trim().parse::<i32>().ok())
+            .collect();
+        let slot = items::parse_slot(lines[2].trim());
+        if let (Some(slot), 3) = (slot, bonuses.len()) {
+            let item = repo.add_item(lines[1].trim(), slot, bonuses[0], bonuses[1], bonuses[2])?;
+            util::reply_to(
+                config,
+                event.clone(),
+                secret_key,
+                &format!("{}をアイテムに追加致しましたわ。", item.name),
+            )
+            .await?;
+        } else {
+            util::reply_to(
+                config,
+                event.clone(),
+                secret_key,
+                "指示がおかしいようですわね。しっかりしてくださいね。",
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn give_item(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    let lines: Vec<String> = message.lines().map(|line| line.to_string()).collect();
+
+    if lines.len() == 4 {
+        let user = repo.get_user_by_npub(lines[1].trim());
+        let item = repo.get_item_by_name(lines[2].trim())?;
+        let quantity = lines[3].trim().parse::<i32>();
+        let answer = match (user, item, quantity) {
+            (Ok(user), Some(item), Ok(quantity)) if quantity > 0 => {
+                repo.give_item(user.user_id, item.item_id, quantity)?;
+                format!("{}を{}個お渡ししましたわ。", item.name, quantity)
+            }
+            _ => "指示がおかしいようですわね。しっかりしてくださいね。".to_string(),
+        };
+        util::reply_to(config, event.clone(), secret_key, &answer).await?;
+    }
+
+    Ok(())
+}
+
+// .add shop
+// アイテム名
+// 価格
+// 在庫（-1なら売り切れなし）
+async fn add_shop_item(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    let lines: Vec<String> = message.lines().map(|line| line.to_string()).collect();
+
+    if lines.len() == 4 {
+        let item = repo.get_item_by_name(lines[1].trim())?;
+        let price = lines[2].trim().parse::<i32>();
+        let stock = lines[3].trim().parse::<i32>();
+        let answer = match (item, price, stock) {
+            (Some(item), Ok(price), Ok(stock)) if price >= 0 && stock >= shop::UNLIMITED_STOCK => {
+                repo.set_shop_entry(item.item_id, price, stock)?;
+                format!("{}を{}GOLDでお店に並べましたわ。", item.name, price)
+            }
+            _ => "指示がおかしいようですわね。しっかりしてくださいね。".to_string(),
+        };
+        util::reply_to(config, event.clone(), secret_key, &answer).await?;
+    }
+
+    Ok(())
+}
+
+async fn remove_shop_item(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    let lines: Vec<String> = message.lines().map(|line| line.to_string()).collect();
+
+    if lines.len() == 2 {
+        let answer = match repo.get_item_by_name(lines[1].trim())? {
+            Some(item) if repo.remove_shop_entry(item.item_id)? => {
+                format!("{}をお店から下げましたわ。", item.name)
+            }
+            _ => "そのアイテムはお店に並んでおりませんわ。".to_string(),
+        };
+        util::reply_to(config, event.clone(), secret_key, &answer).await?;
+    }
+
+    Ok(())
+}
+
+// 地域名
+// 推奨レベル
+// 説明
+async fn add_zone(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    let lines: Vec<String> = message.lines().map(|line| line.to_string()).collect();
+
+    if lines.len() == 4 {
+        let answer = match lines[2].trim().parse::<i32>() {
+            Ok(recommended_level) => {
+                let zone = repo.add_zone(lines[1].trim(), recommended_level, lines[3].trim())?;
+                format!("{}を地域に追加致しましたわ。", zone.name)
+            }
+            Err(_) => "指示がおかしいようですわね。しっかりしてくださいね。".to_string(),
+        };
+        util::reply_to(config, event.clone(), secret_key, &answer).await?;
+    }
+
+    Ok(())
+}
+
+// monster_master のID
+// 地域名
+async fn assign_zone(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    let lines: Vec<String> = message.lines().map(|line| line.to_string()).collect();
+
+    if lines.len() == 3 {
+        let monster_master_id = lines[1].trim().parse::<i32>();
+        let zone = repo.get_zone_by_name(lines[2].trim())?;
+        let answer = match (monster_master_id, zone) {
+            (Ok(monster_master_id), Some(zone))
+                if repo.assign_monster(monster_master_id, zone.zone_id)? =>
+            {
+                format!("{}にモンスターを割り当てましたわ。", zone.name)
+            }
+            _ => "指示がおかしいようですわね。しっかりしてくださいね。".to_string(),
+        };
+        util::reply_to(config, event.clone(), secret_key, &answer).await?;
+    }
+
+    Ok(())
+}
+
+// ダンジョン名
+// 地域名（モンスターが現れる地域）
+// フロアの数
+// ボスの monster_master のID
+async fn add_dungeon(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    let lines: Vec<String> = message.lines().map(|line| line.to_string()).collect();
+
+    if lines.len() == 5 {
+        let zone = repo.get_zone_by_name(lines[2].trim())?;
+        let floors = lines[3].trim().parse::<i32>();
+        let boss = match lines[4].trim().parse::<i32>() {
+            Ok(id) => repo.get_monster_master(id)?,
+            Err(_) => None,
+        };
+        let answer = match (zone, floors, boss) {
+            (Some(zone), Ok(floors), Some(boss)) if floors > 0 => {
+                let dungeon = repo.add_dungeon(lines[1].trim(), zone.zone_id, floors, boss.id)?;
+                format!(
+                    "{}を追加致しましたわ。{}階で{}が待ち構えておりますわ。",
+                    dungeon.name, dungeon.floors, boss.name
+                )
+            }
+            _ => "指示がおかしいようですわね。しっかりしてくださいね。".to_string(),
+        };
+        util::reply_to(config, event.clone(), secret_key, &answer).await?;
+    }
+
+    Ok(())
+}
+
+// .add loot
+// monster_master のID
+// アイテム名
+// 落とす確率（0.0〜1.0）
+// 最小の個数
+// 最大の個数
+async fn add_loot(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    let lines: Vec<String> = message.lines().map(|line| line.to_string()).collect();
+
+    if lines.len() == 6 {
+        let monster = match lines[1].trim().parse::<i32>() {
+            Ok(id) => repo.get_monster_master(id)?,
+            Err(_) => None,
+        };
+        let item = repo.get_item_by_name(lines[2].trim())?;
+        let probability = lines[3].trim().parse::<f64>();
+        let min_quantity = lines[4].trim().parse::<i32>();
+        let max_quantity = lines[5].trim().parse::<i32>();
+        let answer = match (monster, item, probability, min_quantity, max_quantity) {
+            (Some(monster), Some(item), Ok(probability), Ok(min_quantity), Ok(max_quantity))
+                if (0.0..=1.0).contains(&probability)
+                    && min_quantity > 0
+                    && max_quantity >= min_quantity =>
+            {
+                repo.set_loot_entry(
+                    monster.id,
+                    item.item_id,
+                    probability,
+                    min_quantity,
+                    max_quantity,
+                )?;
+                format!("{}が{}を落とすようにしましたわ。", monster.name, item.name)
+            }
+            _ => "指示がおかしいようですわね。しっかりしてくださいね。".to_string(),
+        };
+        util::reply_to(config, event.clone(), secret_key, &answer).await?;
+    }
+
+    Ok(())
+}
+
+// .spawn raid
+// monster_master のID
+// HP（みんなで削る共有のHP）
+// けいけんち（討伐したときに全員で分ける総額）
+// GOLD（討伐したときに全員で分ける総額）
+async fn spawn_raid(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    let lines: Vec<String> = message.lines().map(|line| line.to_string()).collect();
+
+    if lines.len() == 5 {
+        let monster = match lines[1].trim().parse::<i32>() {
+            Ok(id) => repo.get_monster_master(id)?,
+            Err(_) => None,
+        };
+        let numbers: Vec<i32> = lines[2..5]
+            .iter()
+            .filter_map(|line| line.trim().parse::<i32>().ok())
+            .collect();
+        let answer = match (monster, numbers.as_slice()) {
+            (Some(monster), [hp, experience, gold]) if *hp > 0 => {
+                let now = chrono::Utc::now().timestamp();
+                match repo.spawn_raid(&monster, *hp, *experience, *gold, now) {
+                    Ok(raid) => format!(
+                        "{}がレイドボスとして現れましたわ！HP:{} みなさま .raid で挑んでくださいまし。",
+                        raid.monster.name, raid.max_hp
+                    ),
+                    Err(e) => match e.downcast_ref::<raid::RaidError>() {
+                        Some(e) => raid_error_message(e),
+                        None => return Err(e),
+                    },
+                }
+            }
+            _ => "指示がおかしいようですわね。しっかりしてくださいね。".to_string(),
+        };
+        util::reply_to(config, event.clone(), secret_key, &answer).await?;
+    }
+
+    Ok(())
+}
+
+async fn spawn_monster(
+    config: &config::AppConfig,
+    repo: &dyn Repository,
+    event: &Event,
+    message: &str,
+    secret_key: &str,
+) -> Result<()> {
+    let lines: Vec<String> = message.lines().map(|line| line.to_string()).collect();
+    if lines.len() == 3 {
+        let mut suceess = false;
+        if let (Ok(monster_id), Ok(amount)) = (lines[1].parse::<i32>(), lines[2].parse::<i32>()) {
+            // マスターにないIDなら「指示がおかしい」と返す
+            match repo.spawn_monster(monster_id, amount, &config.variants) {
+                Ok(monster) => {
+                    util::reply_to(
+                        config,
+                        event.clone(),
+                        secret_key,
+                        &format!("{} を {}体召喚しましたわ。マスター。", monster.name, amount),
+                    )
+                    .await?;
+                    suceess = true;
+                }
+                Err(e) => eprintln!("Error spawn_monster: {:?}", e),
+            }
+        }
+        if !suceess {
+            util::reply_to(
+                config,
+                event.clone(),
+                secret_key,
+                "指示がおかしいようですわね。しっかりしてくださいね。",
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_repository::MemoryRepository;
+    use crate::monsters::NewMonsterMaster;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn test_config() -> config::AppConfig {
+        serde_yaml::from_str(
+            "relay_servers: {write: [], read: []}\nbot: {admin_pubkeys: [], bot_names: [], prompt: ''}",
+        )
+        .unwrap()
+    }
+
+    fn test_event() -> Event {
+        EventBuilder::text_note("test", [])
+            .to_event(&Keys::generate())
+            .unwrap()
+    }
+
+    // util::get_npub1 で表示できるように、npub には公開鍵を使う
+    fn add_user(repo: &MemoryRepository, gold: i32, strength: i32) -> users::User {
+        let mut user = repo
+            .add_user(&Keys::generate().public_key().to_hex())
+            .unwrap();
+        user.gold = gold;
+        user.attack += strength;
+        user.agility += strength;
+        repo.update_user(&user);
+
+        user
+    }
+
+    fn weak_master(zone_id: Option<i64>) -> NewMonsterMaster {
+        NewMonsterMaster {
+            level: 1,
+            name: "スライム".to_string(),
+            picture: String::new(),
+            attack: 1,
+            defense: 1,
+            agility: 1,
+            experience_reward: 10,
+            gold_reward: 5,
+            luck: 0,
+            rarity_weight: 100,
+            zone_id,
+        }
+    }
+
+    #[test]
+    fn accepted_duel_pays_the_winner() {
+        let config = test_config();
+        let memory = MemoryRepository::new();
+        let challenger = add_user(&memory, 100, 1000);
+        let opponent = add_user(&memory, 100, 0);
+        let repo: &dyn Repository = &memory;
+        let challenge = repo
+            .challenge(challenger.user_id, opponent.user_id, 50, 60, NOW)
+            .unwrap();
+
+        let answer = accept_duel(&config, repo, &test_event(), &challenge, &opponent, NOW).unwrap();
+
+        assert!(answer.contains("100GOLD"));
+        assert_eq!(
+            repo.get_user_by_id(challenger.user_id)
+                .unwrap()
+                .unwrap()
+                .gold,
+            150
+        );
+        assert_eq!(
+            repo.get_user_by_id(opponent.user_id).unwrap().unwrap().gold,
+            50
+        );
+        assert!(repo
+            .get_pending_duel_for(opponent.user_id)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn clearing_the_boss_floor_finishes_the_run() {
+        let config = test_config();
+        let memory = MemoryRepository::new();
+        let user = add_user(&memory, 0, 1000);
+        let repo: &dyn Repository = &memory;
+        let zone = repo.add_zone("はじまりの森", 1, "").unwrap();
+        let boss_id = repo
+            .add_monster_master(&weak_master(Some(zone.zone_id)))
+            .unwrap();
+        let dungeon = repo
+            .add_dungeon("森の洞窟", zone.zone_id, 1, boss_id)
+            .unwrap();
+        let run = repo
+            .start_run(
+                user.user_id,
+                &dungeon,
+                user.current_hp,
+                user.current_mp,
+                NOW,
+            )
+            .unwrap();
+        let event = test_event();
+
+        explore_floor(&config, repo, &event, &user, run, NOW).unwrap();
+
+        assert!(repo.get_active_run(user.user_id).unwrap().is_none());
+        let records = repo
+            .get_battle_results_by_user(user.user_id, 10, 0)
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].event_id, event.id.to_hex());
+        assert!(records[0].battle_log.starts_with("【1階】"));
+        assert!(repo.get_user_by_id(user.user_id).unwrap().unwrap().gold > 0);
+    }
+
+    #[test]
+    fn finishing_blow_on_a_raid_hands_out_rewards() {
+        let config = test_config();
+        let memory = MemoryRepository::new();
+        let user = add_user(&memory, 0, 1000);
+        let repo: &dyn Repository = &memory;
+        let master_id = repo.add_monster_master(&weak_master(None)).unwrap();
+        let master = repo.get_monster_master(master_id).unwrap().unwrap();
+        let raid = repo.spawn_raid(&master, 30, 90, 30, NOW).unwrap();
+
+        let event = test_event();
+        let answer = attack_raid(&config, repo, &event, &user, &raid).unwrap();
+
+        assert!(answer.contains("討伐しましたわ"));
+        assert!(repo.get_active_raid().unwrap().is_none());
+        assert_eq!(repo.get_user_by_id(user.user_id).unwrap().unwrap().gold, 30);
+        let history = repo
+            .get_battle_results_by_user(user.user_id, 10, 0)
+            .unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].event_id, event.id.to_hex());
+        assert!(answer.contains(&history[0].battle_log));
+    }
+}
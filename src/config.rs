@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::growth::StatGains;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BotConfig {
     pub admin_pubkeys: Vec<String>,
@@ -13,8 +15,249 @@ pub struct RelayConfig {
     pub read: Vec<String>,
 }
 
+// 戦闘ログの表示形式（japanese / english / summary / json）をチャンネルごとに指定する
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BattleLogConfig {
+    pub text_note: Option<String>,
+    pub direct_message: Option<String>,
+}
+
+// 宿屋の料金は level * cost_per_level GOLD
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InnConfig {
+    pub cost_per_level: i32,
+}
+
+impl Default for InnConfig {
+    fn default() -> Self {
+        InnConfig { cost_per_level: 5 }
+    }
+}
+
+// モンスターとの遭遇
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EncounterConfig {
+    pub max_monsters: i32,   // 1回の遭遇で現れるモンスターの最大数
+    pub mixed_species: bool, // 違う種類のモンスターが一緒に現れるか
+    #[serde(default = "default_level_window")]
+    pub level_window: i32, // ユーザーとのレベル差がこの範囲のモンスターだけが現れる
+    #[serde(default = "default_reservation_minutes")]
+    pub reservation_minutes: i64, // 戦闘中のモンスターを予約しておく時間（過ぎたらほかのユーザーも戦える）
+}
+
+fn default_level_window() -> i32 {
+    3
+}
+
+fn default_reservation_minutes() -> i64 {
+    5
+}
+
+impl Default for EncounterConfig {
+    fn default() -> Self {
+        EncounterConfig {
+            max_monsters: 3,
+            mixed_species: true,
+            level_window: default_level_window(),
+            reservation_minutes: default_reservation_minutes(),
+        }
+    }
+}
+
+// 決闘の設定
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DuelConfig {
+    pub cooldown_minutes: i64, // 同じ相手と次に決闘できるまでの時間
+    pub expire_minutes: i64,   // この時間を過ぎた申し込みは受けられない
+}
+
+impl Default for DuelConfig {
+    fn default() -> Self {
+        DuelConfig {
+            cooldown_minutes: 60,
+            expire_minutes: 30,
+        }
+    }
+}
+
+// レイドボスの設定
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RaidConfig {
+    pub cooldown_minutes: i64, // 同じユーザーが次に .raid で攻撃できるまでの時間
+}
+
+impl Default for RaidConfig {
+    fn default() -> Self {
+        RaidConfig {
+            cooldown_minutes: 10,
+        }
+    }
+}
+
+// エリートや色違いなど、まれに現れる強いモンスター
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VariantConfig {
+    pub name: String,           // monsters.variant に記録する名前
+    pub prefix: String,         // モンスター名の前につける
+    pub chance: f64,            // 召喚したモンスターがこの変わり種になる確率（0.0〜1.0）
+    pub stat_multiplier: f64,   // HP・ちから・しゅびりょく・すばやさの倍率
+    pub reward_multiplier: f64, // けいけんち・GOLDの倍率
+}
+
+fn default_variants() -> Vec<VariantConfig> {
+    vec![
+        VariantConfig {
+            name: "elite".to_string(),
+            prefix: "エリート".to_string(),
+            chance: 0.05,
+            stat_multiplier: 1.5,
+            reward_multiplier: 3.0,
+        },
+        VariantConfig {
+            name: "shiny".to_string(),
+            prefix: "色違いの".to_string(),
+            chance: 0.01,
+            stat_multiplier: 1.2,
+            reward_multiplier: 10.0,
+        },
+    ]
+}
+
+// monster_master のモンスターごとの目標の数
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RespawnTarget {
+    pub monster_id: i32, // monster_master の id
+    pub population: i32, // 生きているモンスターをこの数まで増やす
+    #[serde(default = "default_respawn_per_tick")]
+    pub per_tick: i32, // 1回に召喚する最大数
+}
+
+fn default_respawn_per_tick() -> i32 {
+    1
+}
+
+// モンスターの自動召喚（targets が空なら召喚しない）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RespawnConfig {
+    pub interval_minutes: u64,
+    #[serde(default)]
+    pub targets: Vec<RespawnTarget>,
+}
+
+impl Default for RespawnConfig {
+    fn default() -> Self {
+        RespawnConfig {
+            interval_minutes: 10,
+            targets: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Sqlite,   // quest.db
+    Postgres, // postgres feature つきでビルドしたときだけ使える
+}
+
+// ゲームの状態の保存先
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    #[serde(default)]
+    pub url: String, // backend が postgres のときの接続先
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: StorageBackend::Sqlite,
+            url: String::new(),
+        }
+    }
+}
+
+// 時間経過による自然回復（0なら回復しない）
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RegenerationConfig {
+    #[serde(default)]
+    pub hp_per_hour: i32,
+    #[serde(default)]
+    pub mp_per_hour: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GrowthMode {
+    Random, // min_points〜max_points をランダムに振り分ける
+    Fixed,  // 毎回 fixed の分だけ上がる
+}
+
+// レベルアップ時の能力値の伸び方
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GrowthConfig {
+    pub mode: GrowthMode,
+    #[serde(default)]
+    pub min_points: i32,
+    #[serde(default)]
+    pub max_points: i32,
+    #[serde(default)]
+    pub fixed: StatGains,
+    #[serde(default = "default_stat_points_per_level")]
+    pub stat_points_per_level: i32, // レベルアップごとに自分で振り分けられるポイント
+    #[serde(default = "default_respec_cost_per_level")]
+    pub respec_cost_per_level: i32, // .respec の料金は level * respec_cost_per_level GOLD
+}
+
+fn default_stat_points_per_level() -> i32 {
+    3
+}
+
+fn default_respec_cost_per_level() -> i32 {
+    10
+}
+
+impl Default for GrowthConfig {
+    fn default() -> Self {
+        GrowthConfig {
+            mode: GrowthMode::Random,
+            min_points: 3,
+            max_points: 6,
+            fixed: StatGains {
+                hp: 3,
+                mp: 1,
+                attack: 1,
+                defense: 1,
+                agility: 1,
+            },
+            stat_points_per_level: default_stat_points_per_level(),
+            respec_cost_per_level: default_respec_cost_per_level(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AppConfig {
     pub relay_servers: RelayConfig,
     pub bot: BotConfig,
+    #[serde(default)]
+    pub battle_log: BattleLogConfig,
+    #[serde(default)]
+    pub inn: InnConfig,
+    #[serde(default)]
+    pub regeneration: RegenerationConfig,
+    #[serde(default)]
+    pub growth: GrowthConfig,
+    #[serde(default)]
+    pub duel: DuelConfig,
+    #[serde(default)]
+    pub encounter: EncounterConfig,
+    #[serde(default)]
+    pub raid: RaidConfig,
+    #[serde(default)]
+    pub respawn: RespawnConfig,
+    #[serde(default = "default_variants")]
+    pub variants: Vec<VariantConfig>,
+    #[serde(default)]
+    pub storage: StorageConfig,
 }
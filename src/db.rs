@@ -1,4 +1,21 @@
 use rusqlite::{Connection, Result};
+use std::error::Error as StdError;
+use std::fmt;
+
+// カラムがまだなければ足す（column は "名前 型" の形）
+fn add_column(conn: &Connection, table: &str, column: &str) -> Result<()> {
+    let name = column.split_whitespace().next().unwrap_or(column);
+    let exists: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        rusqlite::params![table, name],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {}", table, column), [])?;
+    }
+
+    Ok(())
+}
 
 fn create_user_table(conn: &Connection) -> Result<()> {
     if let Err(e) = conn.execute(
@@ -15,7 +32,12 @@ fn create_user_table(conn: &Connection) -> Result<()> {
             attack INTEGER DEFAULT 3,
             defense INTEGER DEFAULT 2,
             agility INTEGER DEFAULT 2,
-            luck INTEGER DEFAULT 0
+            luck INTEGER DEFAULT 0,
+            log_style TEXT,
+            regenerated_at INTEGER,
+            selected_skill TEXT,
+            stat_points INTEGER DEFAULT 0,
+            zone_id INTEGER
         )",
         [],
     ) {
@@ -26,6 +48,21 @@ fn create_user_table(conn: &Connection) -> Result<()> {
     Ok(())
 }
 
+// 既存のusersテーブルに後から追加したカラムを足す
+fn upgrade_user_table(conn: &Connection) -> Result<()> {
+    for column in [
+        "log_style TEXT",
+        "regenerated_at INTEGER",
+        "selected_skill TEXT",
+        "stat_points INTEGER DEFAULT 0",
+        "zone_id INTEGER",
+    ] {
+        add_column(conn, "users", column)?;
+    }
+
+    Ok(())
+}
+
 fn create_monster_master_table(conn: &Connection) -> Result<()> {
     if let Err(e) = conn.execute(
         "CREATE TABLE IF NOT EXISTS monster_master (
@@ -40,7 +77,10 @@ fn create_monster_master_table(conn: &Connection) -> Result<()> {
             experience_reward INTEGER,
             gold_reward INTEGER,
             hp INTEGER DEFAULT 10,
-            mp INTEGER DEFAULT 5
+            mp INTEGER DEFAULT 5,
+            luck INTEGER DEFAULT 0,
+            rarity_weight INTEGER DEFAULT 100,
+            zone_id INTEGER
         )",
         [],
     ) {
@@ -51,6 +91,17 @@ fn create_monster_master_table(conn: &Connection) -> Result<()> {
     Ok(())
 }
 
+// monsters は SELECT * で読むので、カラムの順番を変えないこと
+fn upgrade_monster_tables(conn: &Connection) -> Result<()> {
+    add_column(conn, "monster_master", "luck INTEGER DEFAULT 0")?;
+    add_column(conn, "monsters", "luck INTEGER DEFAULT 0")?;
+    add_column(conn, "monster_master", "rarity_weight INTEGER DEFAULT 100")?;
+    add_column(conn, "monster_master", "zone_id INTEGER")?;
+    add_column(conn, "monsters", "variant TEXT")?;
+
+    Ok(())
+}
+
 fn create_monster_table(conn: &Connection) -> Result<()> {
     if let Err(e) = conn.execute(
         "CREATE TABLE IF NOT EXISTS monsters (
@@ -67,7 +118,9 @@ fn create_monster_table(conn: &Connection) -> Result<()> {
             gold_reward INTEGER,
             hp INTEGER DEFAULT 10,
             mp INTEGER DEFAULT 5,
-            defeat_user_id INTEGER DEFAULT -1
+            defeat_user_id INTEGER DEFAULT -1,
+            luck INTEGER DEFAULT 0,
+            variant TEXT
         )",
         [],
     ) {
@@ -88,6 +141,11 @@ fn create_battle_results_table(conn: &Connection) -> Result<()> {
             experience_gain INTEGER,
             gold_gain INTEGER,
             battle_log TEXT,
+            outcome INTEGER,
+            event_id TEXT,
+            created_at TEXT,
+            seed INTEGER,
+            snapshot TEXT,
             FOREIGN KEY (user_id) REFERENCES users (user_id),
             FOREIGN KEY (monster_id) REFERENCES monsters (monster_id)
         )",
@@ -100,6 +158,28 @@ fn create_battle_results_table(conn: &Connection) -> Result<()> {
     Ok(())
 }
 
+// 既存のbattle_resultsテーブルに後から追加したカラムを足す
+fn upgrade_battle_results_table(conn: &Connection) -> Result<()> {
+    for column in [
+        "outcome INTEGER",
+        "event_id TEXT",
+        "created_at TEXT",
+        "seed INTEGER",
+        "snapshot TEXT",
+    ] {
+        add_column(conn, "battle_results", column)?;
+    }
+    if let Err(e) = conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_battle_results_user_id ON battle_results (user_id, battle_id)",
+        [],
+    ) {
+        eprintln!("Error upgrade_battle_results_table: {:?}", e);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
 fn create_items_table(conn: &Connection) -> Result<()> {
     if let Err(e) = conn.execute(
         "CREATE TABLE IF NOT EXISTS items (
@@ -119,14 +199,893 @@ fn create_items_table(conn: &Connection) -> Result<()> {
     Ok(())
 }
 
-pub fn connect() -> Result<Connection> {
-    let conn = Connection::open("quest.db")?;
+fn create_inventory_table(conn: &Connection) -> Result<()> {
+    if let Err(e) = conn.execute(
+        "CREATE TABLE IF NOT EXISTS inventory (
+            user_id INTEGER,
+            item_id INTEGER,
+            quantity INTEGER DEFAULT 0,
+            PRIMARY KEY (user_id, item_id),
+            FOREIGN KEY (user_id) REFERENCES users (user_id),
+            FOREIGN KEY (item_id) REFERENCES items (item_id)
+        )",
+        [],
+    ) {
+        eprintln!("Error create_inventory_table: {:?}", e);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+fn create_equipment_table(conn: &Connection) -> Result<()> {
+    if let Err(e) = conn.execute(
+        "CREATE TABLE IF NOT EXISTS equipment (
+            user_id INTEGER,
+            slot TEXT,
+            item_id INTEGER,
+            PRIMARY KEY (user_id, slot),
+            FOREIGN KEY (user_id) REFERENCES users (user_id),
+            FOREIGN KEY (item_id) REFERENCES items (item_id)
+        )",
+        [],
+    ) {
+        eprintln!("Error create_equipment_table: {:?}", e);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+fn create_shop_table(conn: &Connection) -> Result<()> {
+    if let Err(e) = conn.execute(
+        "CREATE TABLE IF NOT EXISTS shop (
+            item_id INTEGER PRIMARY KEY,
+            price INTEGER DEFAULT 0,
+            stock INTEGER DEFAULT -1,
+            FOREIGN KEY (item_id) REFERENCES items (item_id)
+        )",
+        [],
+    ) {
+        eprintln!("Error create_shop_table: {:?}", e);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+fn create_skills_table(conn: &Connection) -> Result<()> {
+    if let Err(e) = conn.execute(
+        "CREATE TABLE IF NOT EXISTS skills (
+            skill_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT UNIQUE,
+            kind TEXT,
+            mp_cost INTEGER DEFAULT 0,
+            power INTEGER DEFAULT 0,
+            learn_level INTEGER DEFAULT 1
+        )",
+        [],
+    ) {
+        eprintln!("Error create_skills_table: {:?}", e);
+        return Err(e);
+    }
+
+    // 初期スキル
+    if let Err(e) = conn.execute(
+        "INSERT OR IGNORE INTO skills (name, kind, mp_cost, power, learn_level) VALUES
+            ('ガード', 'guard', 2, 0, 1),
+            ('ヒール', 'heal', 3, 10, 2),
+            ('ファイア', 'attack', 4, 8, 3),
+            ('ハイヒール', 'heal', 8, 30, 8),
+            ('フレイム', 'attack', 9, 20, 10)",
+        [],
+    ) {
+        eprintln!("Error create_skills_table: {:?}", e);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+fn create_stat_allocations_table(conn: &Connection) -> Result<()> {
+    if let Err(e) = conn.execute(
+        "CREATE TABLE IF NOT EXISTS stat_allocations (
+            user_id INTEGER,
+            stat TEXT,
+            points INTEGER DEFAULT 0,
+            PRIMARY KEY (user_id, stat),
+            FOREIGN KEY (user_id) REFERENCES users (user_id)
+        )",
+        [],
+    ) {
+        eprintln!("Error create_stat_allocations_table: {:?}", e);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+fn create_party_tables(conn: &Connection) -> Result<()> {
+    if let Err(e) = conn.execute(
+        "CREATE TABLE IF NOT EXISTS parties (
+            party_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            leader_id INTEGER,
+            created_at TEXT,
+            FOREIGN KEY (leader_id) REFERENCES users (user_id)
+        )",
+        [],
+    ) {
+        eprintln!("Error create_party_tables: {:?}", e);
+        return Err(e);
+    }
+
+    // joined が 0 なら招待中、1 なら参加済み
+    if let Err(e) = conn.execute(
+        "CREATE TABLE IF NOT EXISTS party_members (
+            party_id INTEGER,
+            user_id INTEGER,
+            joined INTEGER DEFAULT 0,
+            PRIMARY KEY (party_id, user_id),
+            FOREIGN KEY (party_id) REFERENCES parties (party_id),
+            FOREIGN KEY (user_id) REFERENCES users (user_id)
+        )",
+        [],
+    ) {
+        eprintln!("Error create_party_tables: {:?}", e);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+fn create_duels_table(conn: &Connection) -> Result<()> {
+    // status 0:申し込み中 1:決着 2:ことわられた 3:取り下げ 4:期限切れ
+    if let Err(e) = conn.execute(
+        "CREATE TABLE IF NOT EXISTS duels (
+            duel_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            challenger_id INTEGER,
+            opponent_id INTEGER,
+            wager INTEGER DEFAULT 0,
+            status INTEGER DEFAULT 0,
+            created_at INTEGER,
+            resolved_at INTEGER,
+            winner_id INTEGER,
+            seed INTEGER,
+            snapshot TEXT,
+            battle_log TEXT,
+            FOREIGN KEY (challenger_id) REFERENCES users (user_id),
+            FOREIGN KEY (opponent_id) REFERENCES users (user_id)
+        )",
+        [],
+    ) {
+        eprintln!("Error create_duels_table: {:?}", e);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+fn create_zones_table(conn: &Connection) -> Result<()> {
+    if let Err(e) = conn.execute(
+        "CREATE TABLE IF NOT EXISTS zones (
+            zone_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT UNIQUE,
+            recommended_level INTEGER DEFAULT 1,
+            description TEXT
+        )",
+        [],
+    ) {
+        eprintln!("Error create_zones_table: {:?}", e);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+fn create_dungeon_tables(conn: &Connection) -> Result<()> {
+    // boss_monster_id は monster_master の id。最後のフロアに現れる
+    if let Err(e) = conn.execute(
+        "CREATE TABLE IF NOT EXISTS dungeons (
+            dungeon_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT UNIQUE,
+            zone_id INTEGER,
+            floors INTEGER DEFAULT 3,
+            boss_monster_id INTEGER,
+            FOREIGN KEY (zone_id) REFERENCES zones (zone_id),
+            FOREIGN KEY (boss_monster_id) REFERENCES monster_master (id)
+        )",
+        [],
+    ) {
+        eprintln!("Error create_dungeon_tables: {:?}", e);
+        return Err(e);
+    }
+
+    // status 0:探索中 1:踏破 2:引き返した 3:力尽きた
+    // floor は突破したフロアの数。current_hp/current_mp はフロアの間で回復しない
+    if let Err(e) = conn.execute(
+        "CREATE TABLE IF NOT EXISTS dungeon_runs (
+            run_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER,
+            dungeon_id INTEGER,
+            floor INTEGER DEFAULT 0,
+            status INTEGER DEFAULT 0,
+            current_hp INTEGER,
+            current_mp INTEGER,
+            experience INTEGER DEFAULT 0,
+            gold INTEGER DEFAULT 0,
+            battle_log TEXT DEFAULT '',
+            started_at INTEGER,
+            finished_at INTEGER,
+            FOREIGN KEY (user_id) REFERENCES users (user_id),
+            FOREIGN KEY (dungeon_id) REFERENCES dungeons (dungeon_id)
+        )",
+        [],
+    ) {
+        eprintln!("Error create_dungeon_tables: {:?}", e);
+        return Err(e);
+    }
+
+    Ok(())
+}
 
-    let _ = create_user_table(&conn);
-    let _ = create_monster_master_table(&conn);
-    let _ = create_monster_table(&conn);
-    let _ = create_battle_results_table(&conn);
-    let _ = create_items_table(&conn);
+fn create_raid_tables(conn: &Connection) -> Result<()> {
+    // monster_id は monster_master の id。hp はみんなで削る共有のHP
+    // status 0:出現中 1:討伐済み
+    if let Err(e) = conn.execute(
+        "CREATE TABLE IF NOT EXISTS raids (
+            raid_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            monster_id INTEGER,
+            level INTEGER,
+            name TEXT,
+            picture TEXT,
+            attack INTEGER,
+            defense INTEGER,
+            agility INTEGER,
+            experience_reward INTEGER,
+            gold_reward INTEGER,
+            hp INTEGER,
+            max_hp INTEGER,
+            mp INTEGER,
+            luck INTEGER DEFAULT 0,
+            status INTEGER DEFAULT 0,
+            created_at INTEGER,
+            defeated_at INTEGER,
+            FOREIGN KEY (monster_id) REFERENCES monster_master (id)
+        )",
+        [],
+    ) {
+        eprintln!("Error create_raid_tables: {:?}", e);
+        return Err(e);
+    }
+
+    if let Err(e) = conn.execute(
+        "CREATE TABLE IF NOT EXISTS raid_contributions (
+            raid_id INTEGER,
+            user_id INTEGER,
+            damage INTEGER DEFAULT 0,
+            attacks INTEGER DEFAULT 0,
+            last_attack_at INTEGER,
+            PRIMARY KEY (raid_id, user_id),
+            FOREIGN KEY (raid_id) REFERENCES raids (raid_id),
+            FOREIGN KEY (user_id) REFERENCES users (user_id)
+        )",
+        [],
+    ) {
+        eprintln!("Error create_raid_tables: {:?}", e);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+fn create_loot_tables_table(conn: &Connection) -> Result<()> {
+    // monster_id は monster_master の id。probability は 0.0〜1.0
+    if let Err(e) = conn.execute(
+        "CREATE TABLE IF NOT EXISTS loot_tables (
+            monster_id INTEGER,
+            item_id INTEGER,
+            probability REAL DEFAULT 0,
+            min_quantity INTEGER DEFAULT 1,
+            max_quantity INTEGER DEFAULT 1,
+            PRIMARY KEY (monster_id, item_id),
+            FOREIGN KEY (monster_id) REFERENCES monster_master (id),
+            FOREIGN KEY (item_id) REFERENCES items (item_id)
+        )",
+        [],
+    ) {
+        eprintln!("Error create_loot_tables_table: {:?}", e);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+// schema_version ができる前のテーブルをまとめて作る
+// どのテーブル・カラムもなければ作るだけなので、途中まで作られた古いquest.dbにも適用できる
+fn create_base_tables(conn: &Connection) -> Result<()> {
+    create_user_table(conn)?;
+    upgrade_user_table(conn)?;
+    create_monster_master_table(conn)?;
+    create_monster_table(conn)?;
+    upgrade_monster_tables(conn)?;
+    create_battle_results_table(conn)?;
+    upgrade_battle_results_table(conn)?;
+    create_items_table(conn)?;
+    create_inventory_table(conn)?;
+    create_equipment_table(conn)?;
+    create_shop_table(conn)?;
+    create_skills_table(conn)?;
+    create_stat_allocations_table(conn)?;
+    create_party_tables(conn)?;
+    create_duels_table(conn)?;
+    create_zones_table(conn)?;
+    create_dungeon_tables(conn)?;
+    create_raid_tables(conn)?;
+    create_loot_tables_table(conn)?;
+
+    Ok(())
+}
+
+// 戦闘中のモンスターを予約したユーザーと、予約が切れる時刻
+fn add_monster_reservations(conn: &Connection) -> Result<()> {
+    add_column(conn, "monsters", "reserved_by INTEGER")?;
+    add_column(conn, "monsters", "reserved_until INTEGER")?;
+
+    Ok(())
+}
+
+// ダンジョンの探索専用に召喚したモンスターには探索の run_id をつける
+fn add_dungeon_monsters(conn: &Connection) -> Result<()> {
+    add_column(conn, "monsters", "dungeon_run_id INTEGER")?;
+
+    Ok(())
+}
+
+// 戦闘エンジンのバージョンと出来事の列を保存して、.battlelog で再現を確かめられるようにする
+fn add_battle_engine_version(conn: &Connection) -> Result<()> {
+    add_column(conn, "battle_results", "engine_version INTEGER")?;
+    add_column(conn, "battle_results", "events TEXT")?;
+
+    Ok(())
+}
+
+// PostgreSQL で create_base_tables と同じテーブルを作る
+// id は SERIAL/BIGSERIAL、時刻は BIGINT、真偽値は BOOLEAN にする
+// party_members の invited_order は SQLite の rowid の代わり（招待した順）
+#[cfg(feature = "postgres")]
+const POSTGRES_BASE_TABLES: &str = "
+    CREATE TABLE IF NOT EXISTS users (
+        user_id SERIAL PRIMARY KEY,
+        npub TEXT UNIQUE,
+        level INTEGER DEFAULT 1,
+        experience INTEGER DEFAULT 0,
+        gold INTEGER DEFAULT 0,
+        current_hp INTEGER DEFAULT 10,
+        max_hp INTEGER DEFAULT 10,
+        current_mp INTEGER DEFAULT 0,
+        max_mp INTEGER DEFAULT 0,
+        attack INTEGER DEFAULT 3,
+        defense INTEGER DEFAULT 2,
+        agility INTEGER DEFAULT 2,
+        luck INTEGER DEFAULT 0,
+        log_style TEXT,
+        regenerated_at BIGINT,
+        selected_skill TEXT,
+        stat_points INTEGER DEFAULT 0,
+        zone_id BIGINT
+    );
+    CREATE TABLE IF NOT EXISTS monster_master (
+        id SERIAL PRIMARY KEY,
+        level INTEGER DEFAULT 1,
+        status INTEGER DEFAULT 1,
+        name TEXT,
+        picture TEXT,
+        attack INTEGER,
+        defense INTEGER,
+        agility INTEGER,
+        experience_reward INTEGER,
+        gold_reward INTEGER,
+        hp INTEGER DEFAULT 10,
+        mp INTEGER DEFAULT 5,
+        luck INTEGER DEFAULT 0,
+        rarity_weight INTEGER DEFAULT 100,
+        zone_id BIGINT
+    );
+    CREATE TABLE IF NOT EXISTS monsters (
+        id SERIAL PRIMARY KEY,
+        monster_id INTEGER,
+        level INTEGER DEFAULT 1,
+        status INTEGER DEFAULT 1,
+        name TEXT,
+        picture TEXT,
+        attack INTEGER,
+        defense INTEGER,
+        agility INTEGER,
+        experience_reward INTEGER,
+        gold_reward INTEGER,
+        hp INTEGER DEFAULT 10,
+        mp INTEGER DEFAULT 5,
+        defeat_user_id INTEGER DEFAULT -1,
+        luck INTEGER DEFAULT 0,
+        variant TEXT
+    );
+    CREATE TABLE IF NOT EXISTS battle_results (
+        battle_id BIGSERIAL PRIMARY KEY,
+        user_id INTEGER REFERENCES users (user_id),
+        monster_id INTEGER,
+        victory BOOLEAN,
+        experience_gain INTEGER,
+        gold_gain INTEGER,
+        battle_log TEXT,
+        outcome INTEGER,
+        event_id TEXT,
+        created_at TEXT,
+        seed BIGINT,
+        snapshot TEXT
+    );
+    CREATE INDEX IF NOT EXISTS idx_battle_results_user_id ON battle_results (user_id, battle_id);
+    CREATE TABLE IF NOT EXISTS items (
+        item_id SERIAL PRIMARY KEY,
+        name TEXT,
+        type TEXT,
+        attack_bonus INTEGER DEFAULT 0,
+        defense_bonus INTEGER DEFAULT 0,
+        agility_bonus INTEGER DEFAULT 0
+    );
+    CREATE TABLE IF NOT EXISTS inventory (
+        user_id INTEGER REFERENCES users (user_id),
+        item_id INTEGER REFERENCES items (item_id),
+        quantity INTEGER DEFAULT 0,
+        PRIMARY KEY (user_id, item_id)
+    );
+    CREATE TABLE IF NOT EXISTS equipment (
+        user_id INTEGER REFERENCES users (user_id),
+        slot TEXT,
+        item_id INTEGER REFERENCES items (item_id),
+        PRIMARY KEY (user_id, slot)
+    );
+    CREATE TABLE IF NOT EXISTS shop (
+        item_id INTEGER PRIMARY KEY REFERENCES items (item_id),
+        price INTEGER DEFAULT 0,
+        stock INTEGER DEFAULT -1
+    );
+    CREATE TABLE IF NOT EXISTS skills (
+        skill_id SERIAL PRIMARY KEY,
+        name TEXT UNIQUE,
+        kind TEXT,
+        mp_cost INTEGER DEFAULT 0,
+        power INTEGER DEFAULT 0,
+        learn_level INTEGER DEFAULT 1
+    );
+    INSERT INTO skills (name, kind, mp_cost, power, learn_level) VALUES
+        ('ガード', 'guard', 2, 0, 1),
+        ('ヒール', 'heal', 3, 10, 2),
+        ('ファイア', 'attack', 4, 8, 3),
+        ('ハイヒール', 'heal', 8, 30, 8),
+        ('フレイム', 'attack', 9, 20, 10)
+    ON CONFLICT (name) DO NOTHING;
+    CREATE TABLE IF NOT EXISTS stat_allocations (
+        user_id INTEGER REFERENCES users (user_id),
+        stat TEXT,
+        points INTEGER DEFAULT 0,
+        PRIMARY KEY (user_id, stat)
+    );
+    CREATE TABLE IF NOT EXISTS parties (
+        party_id BIGSERIAL PRIMARY KEY,
+        leader_id INTEGER REFERENCES users (user_id),
+        created_at TEXT
+    );
+    CREATE TABLE IF NOT EXISTS party_members (
+        party_id BIGINT REFERENCES parties (party_id),
+        user_id INTEGER REFERENCES users (user_id),
+        joined BOOLEAN DEFAULT FALSE,
+        invited_order BIGSERIAL,
+        PRIMARY KEY (party_id, user_id)
+    );
+    CREATE TABLE IF NOT EXISTS duels (
+        duel_id BIGSERIAL PRIMARY KEY,
+        challenger_id INTEGER REFERENCES users (user_id),
+        opponent_id INTEGER REFERENCES users (user_id),
+        wager INTEGER DEFAULT 0,
+        status INTEGER DEFAULT 0,
+        created_at BIGINT,
+        resolved_at BIGINT,
+        winner_id INTEGER,
+        seed BIGINT,
+        snapshot TEXT,
+        battle_log TEXT
+    );
+    CREATE TABLE IF NOT EXISTS zones (
+        zone_id BIGSERIAL PRIMARY KEY,
+        name TEXT UNIQUE,
+        recommended_level INTEGER DEFAULT 1,
+        description TEXT
+    );
+    CREATE TABLE IF NOT EXISTS dungeons (
+        dungeon_id BIGSERIAL PRIMARY KEY,
+        name TEXT UNIQUE,
+        zone_id BIGINT REFERENCES zones (zone_id),
+        floors INTEGER DEFAULT 3,
+        boss_monster_id INTEGER REFERENCES monster_master (id)
+    );
+    CREATE TABLE IF NOT EXISTS dungeon_runs (
+        run_id BIGSERIAL PRIMARY KEY,
+        user_id INTEGER REFERENCES users (user_id),
+        dungeon_id BIGINT REFERENCES dungeons (dungeon_id),
+        floor INTEGER DEFAULT 0,
+        status INTEGER DEFAULT 0,
+        current_hp INTEGER,
+        current_mp INTEGER,
+        experience INTEGER DEFAULT 0,
+        gold INTEGER DEFAULT 0,
+        battle_log TEXT DEFAULT '',
+        started_at BIGINT,
+        finished_at BIGINT
+    );
+    CREATE TABLE IF NOT EXISTS raids (
+        raid_id BIGSERIAL PRIMARY KEY,
+        monster_id INTEGER REFERENCES monster_master (id),
+        level INTEGER,
+        name TEXT,
+        picture TEXT,
+        attack INTEGER,
+        defense INTEGER,
+        agility INTEGER,
+        experience_reward INTEGER,
+        gold_reward INTEGER,
+        hp INTEGER,
+        max_hp INTEGER,
+        mp INTEGER,
+        luck INTEGER DEFAULT 0,
+        status INTEGER DEFAULT 0,
+        created_at BIGINT,
+        defeated_at BIGINT
+    );
+    CREATE TABLE IF NOT EXISTS raid_contributions (
+        raid_id BIGINT REFERENCES raids (raid_id),
+        user_id INTEGER REFERENCES users (user_id),
+        damage INTEGER DEFAULT 0,
+        attacks INTEGER DEFAULT 0,
+        last_attack_at BIGINT,
+        PRIMARY KEY (raid_id, user_id)
+    );
+    CREATE TABLE IF NOT EXISTS loot_tables (
+        monster_id INTEGER REFERENCES monster_master (id),
+        item_id INTEGER REFERENCES items (item_id),
+        probability DOUBLE PRECISION DEFAULT 0,
+        min_quantity INTEGER DEFAULT 1,
+        max_quantity INTEGER DEFAULT 1,
+        PRIMARY KEY (monster_id, item_id)
+    );";
+
+// 番号つきのスキーマ変更。適用済みのものは書き換えず、変更は新しい番号で末尾に足す
+// SQLite と PostgreSQL は同じ番号で同じテーブル・カラムになるようにする
+pub(crate) struct Migration {
+    pub(crate) version: i32,
+    pub(crate) description: &'static str,
+    apply: fn(&Connection) -> Result<()>,
+    #[cfg(feature = "postgres")]
+    pub(crate) postgres: &'static str, // PostgreSQL で同じ変更をするSQL
+}
+
+pub(crate) const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create base tables",
+        apply: create_base_tables,
+        #[cfg(feature = "postgres")]
+        postgres: POSTGRES_BASE_TABLES,
+    },
+    Migration {
+        version: 2,
+        description: "monster reservations",
+        apply: add_monster_reservations,
+        #[cfg(feature = "postgres")]
+        postgres: "
+            ALTER TABLE monsters ADD COLUMN IF NOT EXISTS reserved_by INTEGER;
+            ALTER TABLE monsters ADD COLUMN IF NOT EXISTS reserved_until BIGINT;",
+    },
+    Migration {
+        version: 3,
+        description: "dungeon monsters",
+        apply: add_dungeon_monsters,
+        #[cfg(feature = "postgres")]
+        postgres: "ALTER TABLE monsters ADD COLUMN IF NOT EXISTS dungeon_run_id BIGINT;",
+    },
+    Migration {
+        version: 4,
+        description: "battle engine version",
+        apply: add_battle_engine_version,
+        #[cfg(feature = "postgres")]
+        postgres: "
+            ALTER TABLE battle_results ADD COLUMN IF NOT EXISTS engine_version INTEGER;
+            ALTER TABLE battle_results ADD COLUMN IF NOT EXISTS events TEXT;",
+    },
+];
+
+#[derive(Debug)]
+pub(crate) enum SchemaError {
+    TooNew { found: i32, known: i32 },
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchemaError::TooNew { found, known } => write!(
+                f,
+                "quest.db schema version {} is newer than this build knows ({})",
+                found, known
+            ),
+        }
+    }
+}
+
+impl StdError for SchemaError {}
+
+fn create_schema_version_table(conn: &Connection) -> Result<()> {
+    if let Err(e) = conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT,
+            applied_at INTEGER
+        )",
+        [],
+    ) {
+        eprintln!("Error create_schema_version_table: {:?}", e);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+pub fn get_schema_version(conn: &Connection) -> Result<i32, Box<dyn StdError>> {
+    let version = conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        [],
+        |row| row.get(0),
+    )?;
+
+    Ok(version)
+}
+
+// まだ適用していないマイグレーションを1つのトランザクションで適用し、適用後のバージョンを返す
+// このビルドが知らない新しいバージョンのDBなら、壊さないように SchemaError::TooNew で止める
+pub fn migrate(conn: &Connection) -> Result<i32, Box<dyn StdError>> {
+    create_schema_version_table(conn)?;
+    let current = get_schema_version(conn)?;
+    let latest = MIGRATIONS.last().map_or(0, |migration| migration.version);
+    if current > latest {
+        return Err(Box::new(SchemaError::TooNew {
+            found: current,
+            known: latest,
+        }));
+    }
+
+    let tx = conn.unchecked_transaction()?;
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > current)
+    {
+        (migration.apply)(&tx)?;
+        tx.execute(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, ?2, ?3)",
+            rusqlite::params![
+                migration.version,
+                migration.description,
+                chrono::Utc::now().timestamp()
+            ],
+        )?;
+    }
+    tx.commit()?;
+
+    Ok(latest)
+}
+
+pub fn connect() -> Result<Connection, Box<dyn StdError>> {
+    let conn = Connection::open("quest.db")?;
+    migrate(&conn)?;
 
     Ok(conn)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{battle, battle_results, users};
+
+    fn columns(conn: &Connection, table: &str) -> Vec<String> {
+        let mut statement = conn
+            .prepare("SELECT name FROM pragma_table_info(?1)")
+            .unwrap();
+        let names = statement
+            .query_map(rusqlite::params![table], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<Vec<String>>>()
+            .unwrap();
+        names
+    }
+
+    fn applied_versions(conn: &Connection) -> Vec<i32> {
+        let mut statement = conn
+            .prepare("SELECT version FROM schema_version ORDER BY version")
+            .unwrap();
+        let versions = statement
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<Vec<i32>>>()
+            .unwrap();
+        versions
+    }
+
+    #[test]
+    fn versions_are_numbered_in_order() {
+        let versions: Vec<i32> = MIGRATIONS
+            .iter()
+            .map(|migration| migration.version)
+            .collect();
+        let expected: Vec<i32> = (1..=MIGRATIONS.len() as i32).collect();
+
+        assert_eq!(versions, expected);
+    }
+
+    #[test]
+    fn fresh_database_gets_every_migration_once() {
+        let conn = Connection::open_in_memory().unwrap();
+        let latest = MIGRATIONS.last().unwrap().version;
+
+        assert_eq!(migrate(&conn).unwrap(), latest);
+        assert_eq!(migrate(&conn).unwrap(), latest);
+
+        assert_eq!(applied_versions(&conn), (1..=latest).collect::<Vec<_>>());
+        assert!(columns(&conn, "monsters").contains(&"dungeon_run_id".to_string()));
+        assert!(columns(&conn, "battle_results").contains(&"engine_version".to_string()));
+    }
+
+    // schema_version ができる前の、最初のころの quest.db
+    #[test]
+    fn legacy_database_is_upgraded_in_place() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE users (
+                user_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                npub TEXT UNIQUE,
+                level INTEGER DEFAULT 1,
+                experience INTEGER DEFAULT 0,
+                gold INTEGER DEFAULT 0,
+                current_hp INTEGER DEFAULT 10,
+                max_hp INTEGER DEFAULT 10,
+                current_mp INTEGER DEFAULT 0,
+                max_mp INTEGER DEFAULT 0,
+                attack INTEGER DEFAULT 3,
+                defense INTEGER DEFAULT 2,
+                agility INTEGER DEFAULT 2,
+                luck INTEGER DEFAULT 0
+            );
+            CREATE TABLE monsters (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                monster_id INTEGER,
+                level INTEGER DEFAULT 1,
+                status INTEGER DEFAULT 1,
+                name TEXT,
+                picture TEXT,
+                attack INTEGER,
+                defense INTEGER,
+                agility INTEGER,
+                experience_reward INTEGER,
+                gold_reward INTEGER,
+                hp INTEGER DEFAULT 10,
+                mp INTEGER DEFAULT 5,
+                defeat_user_id INTEGER DEFAULT -1
+            );
+            CREATE TABLE battle_results (
+                battle_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER,
+                monster_id INTEGER,
+                victory BOOLEAN,
+                experience_gain INTEGER,
+                gold_gain INTEGER,
+                battle_log TEXT
+            );
+            INSERT INTO users (npub, gold) VALUES ('npub_old', 42);
+            INSERT INTO battle_results (user_id, monster_id, victory, experience_gain, gold_gain, battle_log)
+                VALUES (1, 1, 1, 5, 3, 'old log');",
+        )
+        .unwrap();
+
+        migrate(&conn).unwrap();
+
+        let user = users::get_user_by_npub(&conn, "npub_old").unwrap();
+        assert_eq!(user.gold, 42);
+        let record = battle_results::get_battle_result_by_id(&conn, 1)
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.outcome, battle::BattleOutcome::Victory);
+        assert_eq!(record.engine_version, 0);
+        assert!(record.snapshot.is_none());
+        assert!(record.events.is_none());
+        assert!(columns(&conn, "monsters").contains(&"reserved_by".to_string()));
+    }
+
+    // PostgreSQL 用のSQLから テーブル名 -> カラム名 を読み取る
+    #[cfg(feature = "postgres")]
+    fn postgres_schema() -> std::collections::BTreeMap<String, Vec<String>> {
+        let mut tables = std::collections::BTreeMap::new();
+        for statement in MIGRATIONS
+            .iter()
+            .flat_map(|migration| migration.postgres.split(';'))
+        {
+            let statement = statement.trim();
+            if let Some(rest) = statement.strip_prefix("CREATE TABLE IF NOT EXISTS ") {
+                let (table, body) = rest.split_once('(').unwrap();
+                let columns: Vec<String> = body
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with("PRIMARY KEY"))
+                    .filter_map(|line| line.split_whitespace().next())
+                    .filter(|name| *name != ")")
+                    .map(str::to_string)
+                    .collect();
+                tables.insert(table.trim().to_string(), columns);
+            } else if let Some(rest) = statement.strip_prefix("ALTER TABLE ") {
+                let (table, column) = rest.split_once(" ADD COLUMN IF NOT EXISTS ").unwrap();
+                let column = column.split_whitespace().next().unwrap();
+                tables.get_mut(table).unwrap().push(column.to_string());
+            }
+        }
+        tables
+    }
+
+    #[cfg(feature = "postgres")]
+    #[test]
+    fn postgres_migrations_match_sqlite_tables() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        let mut statement = conn
+            .prepare(
+                "SELECT name FROM sqlite_master
+                WHERE type = 'table' AND name NOT IN ('sqlite_sequence', 'schema_version')",
+            )
+            .unwrap();
+        let sqlite_tables = statement
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<Vec<String>>>()
+            .unwrap();
+
+        let mut sqlite = std::collections::BTreeMap::new();
+        for table in sqlite_tables {
+            let mut names = columns(&conn, &table);
+            names.sort();
+            sqlite.insert(table, names);
+        }
+        // invited_order は SQLite の rowid の代わりなので比べない
+        let mut postgres = postgres_schema();
+        postgres
+            .get_mut("party_members")
+            .unwrap()
+            .retain(|name| name != "invited_order");
+        for names in postgres.values_mut() {
+            names.sort();
+        }
+
+        assert_eq!(postgres, sqlite);
+    }
+
+    #[test]
+    fn newer_database_is_left_alone() {
+        let conn = Connection::open_in_memory().unwrap();
+        let latest = migrate(&conn).unwrap();
+        conn.execute(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, 'future', 0)",
+            rusqlite::params![latest + 1],
+        )
+        .unwrap();
+
+        let e = migrate(&conn).unwrap_err();
+
+        assert!(matches!(
+            e.downcast_ref::<SchemaError>(),
+            Some(SchemaError::TooNew { found, known }) if *found == latest + 1 && *known == latest
+        ));
+    }
+}
@@ -0,0 +1,451 @@
+use rusqlite::{Connection, Result};
+use std::error::Error as StdError;
+use std::fmt;
+
+use crate::battle::BattleSnapshot;
+
+// 決闘の状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuelStatus {
+    Pending,
+    Finished,
+    Declined,
+    Cancelled,
+    Expired,
+}
+
+impl DuelStatus {
+    pub fn to_i32(self) -> i32 {
+        match self {
+            DuelStatus::Pending => 0,
+            DuelStatus::Finished => 1,
+            DuelStatus::Declined => 2,
+            DuelStatus::Cancelled => 3,
+            DuelStatus::Expired => 4,
+        }
+    }
+}
+
+// 申し込み中の決闘
+pub struct Duel {
+    pub duel_id: i64,
+    pub challenger_id: i32,
+    pub opponent_id: i32,
+    pub wager: i32, // 申し込んだ側はすでに預けている
+    pub created_at: i64,
+}
+
+#[derive(Debug)]
+pub(crate) enum DuelError {
+    SelfChallenge,
+    AlreadyPending,
+    Cooldown { remaining_minutes: i64 },
+    InvalidWager(i32),
+    NotEnoughGold { wager: i32, gold: i32 },
+    NoChallenge,
+}
+
+impl fmt::Display for DuelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DuelError::SelfChallenge => write!(f, "Cannot duel yourself"),
+            DuelError::AlreadyPending => write!(f, "A duel is already pending"),
+            DuelError::Cooldown { remaining_minutes } => {
+                write!(f, "Duel cooldown: {} minutes left", remaining_minutes)
+            }
+            DuelError::InvalidWager(wager) => write!(f, "Invalid wager: {}", wager),
+            DuelError::NotEnoughGold { wager, gold } => {
+                write!(f, "Not enough gold: wager {} but has {}", wager, gold)
+            }
+            DuelError::NoChallenge => write!(f, "No pending duel"),
+        }
+    }
+}
+
+impl StdError for DuelError {}
+
+const SELECT_DUEL: &str =
+    "SELECT duel_id, challenger_id, opponent_id, wager, created_at FROM duels";
+
+fn duel_from_row(row: &rusqlite::Row) -> Result<Duel> {
+    Ok(Duel {
+        duel_id: row.get(0)?,
+        challenger_id: row.get(1)?,
+        opponent_id: row.get(2)?,
+        wager: row.get(3)?,
+        created_at: row.get(4)?,
+    })
+}
+
+// 自分あての申し込み（一番新しいもの）
+pub fn get_pending_duel_for(
+    conn: &Connection,
+    opponent_id: i32,
+) -> Result<Option<Duel>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(&format!(
+        "{} WHERE opponent_id = ?1 AND status = 0 ORDER BY duel_id DESC LIMIT 1",
+        SELECT_DUEL
+    ))?;
+    let mut duel_iter = statement.query_map(rusqlite::params![opponent_id], duel_from_row)?;
+
+    Ok(duel_iter.next().transpose()?)
+}
+
+// 自分が出している申し込み
+pub fn get_pending_duel_by(
+    conn: &Connection,
+    challenger_id: i32,
+) -> Result<Option<Duel>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(&format!(
+        "{} WHERE challenger_id = ?1 AND status = 0 ORDER BY duel_id DESC LIMIT 1",
+        SELECT_DUEL
+    ))?;
+    let mut duel_iter = statement.query_map(rusqlite::params![challenger_id], duel_from_row)?;
+
+    Ok(duel_iter.next().transpose()?)
+}
+
+// GOLDが足りていれば預かる
+fn hold_wager(conn: &Connection, user_id: i32, wager: i32) -> Result<(), Box<dyn StdError>> {
+    let paid = conn.execute(
+        "UPDATE users SET gold = gold - ?1 WHERE user_id = ?2 AND gold >= ?1",
+        rusqlite::params![wager, user_id],
+    )?;
+    if paid == 0 {
+        let gold: i32 = conn.query_row(
+            "SELECT gold FROM users WHERE user_id = ?1",
+            rusqlite::params![user_id],
+            |row| row.get(0),
+        )?;
+        return Err(Box::new(DuelError::NotEnoughGold { wager, gold }));
+    }
+
+    Ok(())
+}
+
+fn pay(conn: &Connection, user_id: i32, amount: i32) -> Result<(), Box<dyn StdError>> {
+    conn.execute(
+        "UPDATE users SET gold = gold + ?1 WHERE user_id = ?2",
+        rusqlite::params![amount, user_id],
+    )?;
+
+    Ok(())
+}
+
+pub(crate) fn check_challenge(
+    challenger_id: i32,
+    opponent_id: i32,
+    wager: i32,
+) -> Result<(), DuelError> {
+    if challenger_id == opponent_id {
+        return Err(DuelError::SelfChallenge);
+    }
+    if wager < 0 {
+        return Err(DuelError::InvalidWager(wager));
+    }
+
+    Ok(())
+}
+
+// 同じ二人が最後に決着してから cooldown_minutes たっていなければ DuelError::Cooldown
+pub(crate) fn check_cooldown(
+    last_resolved: Option<i64>,
+    cooldown_minutes: i64,
+    now: i64,
+) -> Result<(), DuelError> {
+    if let Some(resolved_at) = last_resolved {
+        let remaining = resolved_at + cooldown_minutes * 60 - now;
+        if remaining > 0 {
+            return Err(DuelError::Cooldown {
+                remaining_minutes: (remaining + 59) / 60,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// 決闘を申し込む。賭け金は申し込んだ時点で預かる
+pub fn challenge(
+    conn: &Connection,
+    challenger_id: i32,
+    opponent_id: i32,
+    wager: i32,
+    cooldown_minutes: i64,
+    now: i64,
+) -> Result<Duel, Box<dyn StdError>> {
+    check_challenge(challenger_id, opponent_id, wager)?;
+
+    let tx = conn.unchecked_transaction()?;
+    let pending: i32 = tx.query_row(
+        "SELECT COUNT(*) FROM duels
+        WHERE status = 0 AND (challenger_id IN (?1, ?2) OR opponent_id IN (?1, ?2))",
+        rusqlite::params![challenger_id, opponent_id],
+        |row| row.get(0),
+    )?;
+    if pending > 0 {
+        return Err(Box::new(DuelError::AlreadyPending));
+    }
+
+    // 同じ二人が続けて決闘してGOLDや戦歴を稼げないようにする
+    let last_resolved: Option<i64> = tx.query_row(
+        "SELECT MAX(resolved_at) FROM duels
+        WHERE status = 1
+          AND ((challenger_id = ?1 AND opponent_id = ?2) OR (challenger_id = ?2 AND opponent_id = ?1))",
+        rusqlite::params![challenger_id, opponent_id],
+        |row| row.get(0),
+    )?;
+    check_cooldown(last_resolved, cooldown_minutes, now)?;
+
+    if wager > 0 {
+        hold_wager(&tx, challenger_id, wager)?;
+    }
+    tx.execute(
+        "INSERT INTO duels (challenger_id, opponent_id, wager, status, created_at)
+        VALUES (?1, ?2, ?3, ?4, ?5)",
+        rusqlite::params![
+            challenger_id,
+            opponent_id,
+            wager,
+            DuelStatus::Pending.to_i32(),
+            now
+        ],
+    )?;
+    let duel_id = tx.last_insert_rowid();
+    tx.commit()?;
+
+    Ok(Duel {
+        duel_id,
+        challenger_id,
+        opponent_id,
+        wager,
+        created_at: now,
+    })
+}
+
+// ことわる・取り下げる・期限切れのときは、預かった賭け金を申し込んだ側に返す
+pub fn close_duel(
+    conn: &Connection,
+    duel: &Duel,
+    status: DuelStatus,
+    now: i64,
+) -> Result<(), Box<dyn StdError>> {
+    let tx = conn.unchecked_transaction()?;
+    let closed = tx.execute(
+        "UPDATE duels SET status = ?1, resolved_at = ?2 WHERE duel_id = ?3 AND status = 0",
+        rusqlite::params![status.to_i32(), now, duel.duel_id],
+    )?;
+    if closed == 0 {
+        return Err(Box::new(DuelError::NoChallenge));
+    }
+    pay(&tx, duel.challenger_id, duel.wager)?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+// 受けた側の賭け金を預かり、勝った方に両方の賭け金を渡す。引き分けなら返す
+// 決闘の記録は battle_results ではなくこの行に残す（勝者・シード・スナップショット・戦闘ログ）
+pub fn resolve_duel(
+    conn: &Connection,
+    duel: &Duel,
+    winner_id: Option<i32>,
+    seed: u64,
+    snapshot: &BattleSnapshot,
+    battle_log: &str,
+    now: i64,
+) -> Result<(), Box<dyn StdError>> {
+    let tx = conn.unchecked_transaction()?;
+    if duel.wager > 0 {
+        hold_wager(&tx, duel.opponent_id, duel.wager)?;
+    }
+    let resolved = tx.execute(
+        "UPDATE duels
+        SET status = ?1, resolved_at = ?2, winner_id = ?3, seed = ?4, snapshot = ?5, battle_log = ?6
+        WHERE duel_id = ?7 AND status = 0",
+        rusqlite::params![
+            DuelStatus::Finished.to_i32(),
+            now,
+            winner_id,
+            seed as i64,
+            serde_json::to_string(snapshot)?,
+            battle_log,
+            duel.duel_id
+        ],
+    )?;
+    if resolved == 0 {
+        return Err(Box::new(DuelError::NoChallenge));
+    }
+    match winner_id {
+        Some(winner_id) => pay(&tx, winner_id, duel.wager * 2)?,
+        None => {
+            pay(&tx, duel.challenger_id, duel.wager)?;
+            pay(&tx, duel.opponent_id, duel.wager)?;
+        }
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::battle::Combatant;
+    use crate::items::EquipmentBonus;
+    use crate::{db, users};
+
+    // 100 GOLD ずつ持ったふたり
+    fn setup() -> (Connection, i32, i32) {
+        let conn = Connection::open_in_memory().unwrap();
+        db::migrate(&conn).unwrap();
+        let mut ids = Vec::new();
+        for npub in ["npub_challenger", "npub_opponent"] {
+            let mut user = users::add_user(&conn, npub).unwrap();
+            user.gold = 100;
+            users::update_user(&conn, &user).unwrap();
+            ids.push(user.user_id);
+        }
+
+        (conn, ids[0], ids[1])
+    }
+
+    fn gold(conn: &Connection, user_id: i32) -> i32 {
+        users::get_user_by_id(conn, user_id).unwrap().unwrap().gold
+    }
+
+    fn snapshot(conn: &Connection, challenger_id: i32, opponent_id: i32) -> BattleSnapshot {
+        let combatant = |user_id| {
+            let user = users::get_user_by_id(conn, user_id).unwrap().unwrap();
+            Combatant::from_user(&user, &EquipmentBonus::default())
+        };
+        BattleSnapshot {
+            player: combatant(challenger_id),
+            monster: combatant(opponent_id),
+            allies: Vec::new(),
+            enemies: Vec::new(),
+        }
+    }
+
+    fn duel_error(result: Result<(), Box<dyn StdError>>) -> DuelError {
+        match result.unwrap_err().downcast::<DuelError>() {
+            Ok(e) => *e,
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn challenge_holds_the_wager() {
+        let (conn, challenger, opponent) = setup();
+
+        challenge(&conn, challenger, opponent, 30, 60, 0).unwrap();
+
+        assert_eq!(gold(&conn, challenger), 70);
+        assert_eq!(gold(&conn, opponent), 100);
+        assert!(get_pending_duel_for(&conn, opponent).unwrap().is_some());
+    }
+
+    #[test]
+    fn challenge_without_enough_gold_is_not_recorded() {
+        let (conn, challenger, opponent) = setup();
+
+        let e = duel_error(challenge(&conn, challenger, opponent, 101, 60, 0).map(|_| ()));
+
+        assert!(matches!(
+            e,
+            DuelError::NotEnoughGold {
+                wager: 101,
+                gold: 100
+            }
+        ));
+        assert_eq!(gold(&conn, challenger), 100);
+        assert!(get_pending_duel_for(&conn, opponent).unwrap().is_none());
+    }
+
+    #[test]
+    fn declined_duel_refunds_once() {
+        let (conn, challenger, opponent) = setup();
+        let duel = challenge(&conn, challenger, opponent, 30, 60, 0).unwrap();
+
+        close_duel(&conn, &duel, DuelStatus::Declined, 10).unwrap();
+        let e = duel_error(close_duel(&conn, &duel, DuelStatus::Expired, 20));
+
+        assert!(matches!(e, DuelError::NoChallenge));
+        assert_eq!(gold(&conn, challenger), 100);
+    }
+
+    #[test]
+    fn winner_takes_both_wagers() {
+        let (conn, challenger, opponent) = setup();
+        let duel = challenge(&conn, challenger, opponent, 30, 60, 0).unwrap();
+        let snapshot = snapshot(&conn, challenger, opponent);
+
+        resolve_duel(&conn, &duel, Some(opponent), 1, &snapshot, "", 10).unwrap();
+
+        assert_eq!(gold(&conn, challenger), 70);
+        assert_eq!(gold(&conn, opponent), 130);
+        assert!(get_pending_duel_for(&conn, opponent).unwrap().is_none());
+    }
+
+    #[test]
+    fn draw_returns_both_wagers() {
+        let (conn, challenger, opponent) = setup();
+        let duel = challenge(&conn, challenger, opponent, 30, 60, 0).unwrap();
+        let snapshot = snapshot(&conn, challenger, opponent);
+
+        resolve_duel(&conn, &duel, None, 1, &snapshot, "", 10).unwrap();
+
+        assert_eq!(gold(&conn, challenger), 100);
+        assert_eq!(gold(&conn, opponent), 100);
+    }
+
+    #[test]
+    fn opponent_without_enough_gold_leaves_duel_pending() {
+        let (conn, challenger, opponent) = setup();
+        let duel = challenge(&conn, challenger, opponent, 30, 60, 0).unwrap();
+        let mut poor = users::get_user_by_id(&conn, opponent).unwrap().unwrap();
+        poor.gold = 10;
+        users::update_user(&conn, &poor).unwrap();
+        let snapshot = snapshot(&conn, challenger, opponent);
+
+        let e = duel_error(resolve_duel(
+            &conn,
+            &duel,
+            Some(challenger),
+            1,
+            &snapshot,
+            "",
+            10,
+        ));
+
+        assert!(matches!(
+            e,
+            DuelError::NotEnoughGold {
+                wager: 30,
+                gold: 10
+            }
+        ));
+        assert_eq!(gold(&conn, challenger), 70);
+        assert_eq!(gold(&conn, opponent), 10);
+        assert!(get_pending_duel_for(&conn, opponent).unwrap().is_some());
+    }
+
+    #[test]
+    fn same_pair_waits_for_cooldown() {
+        let (conn, challenger, opponent) = setup();
+        let duel = challenge(&conn, challenger, opponent, 0, 60, 0).unwrap();
+        let snapshot = snapshot(&conn, challenger, opponent);
+        resolve_duel(&conn, &duel, Some(challenger), 1, &snapshot, "", 100).unwrap();
+
+        let e =
+            duel_error(challenge(&conn, opponent, challenger, 0, 60, 100 + 59 * 60).map(|_| ()));
+
+        assert!(matches!(
+            e,
+            DuelError::Cooldown {
+                remaining_minutes: 1
+            }
+        ));
+        challenge(&conn, opponent, challenger, 0, 60, 100 + 60 * 60).unwrap();
+    }
+}
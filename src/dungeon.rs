@@ -0,0 +1,487 @@
+use rusqlite::{Connection, Result};
+use std::error::Error as StdError;
+use std::fmt;
+
+use crate::battle::{self, BattleResult};
+use crate::monsters::Monster;
+
+// 探索の状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunStatus {
+    Active,
+    Cleared,
+    Retreated,
+    Defeated,
+}
+
+impl RunStatus {
+    pub fn to_i32(self) -> i32 {
+        match self {
+            RunStatus::Active => 0,
+            RunStatus::Cleared => 1,
+            RunStatus::Retreated => 2,
+            RunStatus::Defeated => 3,
+        }
+    }
+}
+
+// 何フロアも続けて戦うダンジョン。モンスターは zone_id の地域から現れる
+#[derive(Clone)]
+pub struct Dungeon {
+    pub dungeon_id: i64,
+    pub name: String,
+    pub zone_id: i64,
+    pub floors: i32,
+    pub boss_monster_id: i32, // monster_master の id
+}
+
+// 探索中のダンジョン
+pub struct DungeonRun {
+    pub run_id: i64,
+    pub dungeon: Dungeon,
+    pub floor: i32, // 突破したフロアの数
+    pub current_hp: i32,
+    pub current_mp: i32,
+    pub experience: i32,
+    pub gold: i32,
+    pub battle_log: String, // これまでのフロアの戦闘ログをつなげたもの
+}
+
+impl DungeonRun {
+    // 次に挑むフロア
+    pub fn next_floor(&self) -> i32 {
+        self.floor + 1
+    }
+
+    pub fn is_boss_floor(&self) -> bool {
+        self.next_floor() >= self.dungeon.floors
+    }
+}
+
+// 1フロア分の結果
+pub struct FloorResult<'a> {
+    pub cleared: bool,
+    pub status: RunStatus,
+    pub current_hp: i32,
+    pub current_mp: i32,
+    pub experience: i32,
+    pub gold: i32,
+    pub battle_log: &'a str,
+    pub recorded_at: i64, // 探索が終わったときは finished_at になる
+}
+
+#[derive(Debug)]
+pub(crate) enum DungeonError {
+    AlreadyInRun(String),
+    NoRun,
+}
+
+impl fmt::Display for DungeonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DungeonError::AlreadyInRun(name) => write!(f, "Already exploring '{}'", name),
+            DungeonError::NoRun => write!(f, "Not exploring a dungeon"),
+        }
+    }
+}
+
+impl StdError for DungeonError {}
+
+const SELECT_DUNGEON: &str =
+    "SELECT dungeon_id, name, zone_id, floors, boss_monster_id FROM dungeons";
+
+fn dungeon_from_row(row: &rusqlite::Row) -> Result<Dungeon> {
+    Ok(Dungeon {
+        dungeon_id: row.get(0)?,
+        name: row.get(1)?,
+        zone_id: row.get(2)?,
+        floors: row.get(3)?,
+        boss_monster_id: row.get(4)?,
+    })
+}
+
+pub fn get_dungeons(conn: &Connection) -> Result<Vec<Dungeon>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(&format!("{} ORDER BY dungeon_id", SELECT_DUNGEON))?;
+    let dungeons = statement
+        .query_map([], dungeon_from_row)?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(dungeons)
+}
+
+pub fn get_dungeon_by_name(
+    conn: &Connection,
+    name: &str,
+) -> Result<Option<Dungeon>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(&format!("{} WHERE name = ?1", SELECT_DUNGEON))?;
+    let mut dungeon_iter = statement.query_map(rusqlite::params![name], dungeon_from_row)?;
+
+    Ok(dungeon_iter.next().transpose()?)
+}
+
+// 同じ名前のダンジョンがあれば上書きする
+pub fn add_dungeon(
+    conn: &Connection,
+    name: &str,
+    zone_id: i64,
+    floors: i32,
+    boss_monster_id: i32,
+) -> Result<Dungeon, Box<dyn StdError>> {
+    conn.execute(
+        "INSERT INTO dungeons (name, zone_id, floors, boss_monster_id) VALUES (?1, ?2, ?3, ?4)
+        ON CONFLICT (name) DO UPDATE SET
+            zone_id = excluded.zone_id,
+            floors = excluded.floors,
+            boss_monster_id = excluded.boss_monster_id",
+        rusqlite::params![name, zone_id, floors, boss_monster_id],
+    )?;
+
+    Ok(get_dungeon_by_name(conn, name)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?)
+}
+
+// 探索中のダンジョン（なければNone）
+pub fn get_active_run(
+    conn: &Connection,
+    user_id: i32,
+) -> Result<Option<DungeonRun>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(
+        "SELECT
+            dungeon_runs.run_id,
+            dungeon_runs.floor,
+            dungeon_runs.current_hp,
+            dungeon_runs.current_mp,
+            dungeon_runs.experience,
+            dungeon_runs.gold,
+            dungeon_runs.battle_log,
+            dungeons.dungeon_id,
+            dungeons.name,
+            dungeons.zone_id,
+            dungeons.floors,
+            dungeons.boss_monster_id
+        FROM dungeon_runs
+        JOIN dungeons ON dungeons.dungeon_id = dungeon_runs.dungeon_id
+        WHERE dungeon_runs.user_id = ?1 AND dungeon_runs.status = 0
+        ORDER BY dungeon_runs.run_id DESC LIMIT 1",
+    )?;
+    let mut run_iter = statement.query_map(rusqlite::params![user_id], |row| {
+        Ok(DungeonRun {
+            run_id: row.get(0)?,
+            floor: row.get(1)?,
+            current_hp: row.get(2)?,
+            current_mp: row.get(3)?,
+            experience: row.get(4)?,
+            gold: row.get(5)?,
+            battle_log: row.get(6)?,
+            dungeon: Dungeon {
+                dungeon_id: row.get(7)?,
+                name: row.get(8)?,
+                zone_id: row.get(9)?,
+                floors: row.get(10)?,
+                boss_monster_id: row.get(11)?,
+            },
+        })
+    })?;
+
+    Ok(run_iter.next().transpose()?)
+}
+
+// ダンジョンに入る。入ったときのHP/MPで探索を始める
+pub fn start_run(
+    conn: &Connection,
+    user_id: i32,
+    dungeon: &Dungeon,
+    current_hp: i32,
+    current_mp: i32,
+    now: i64,
+) -> Result<DungeonRun, Box<dyn StdError>> {
+    if let Some(run) = get_active_run(conn, user_id)? {
+        return Err(Box::new(DungeonError::AlreadyInRun(run.dungeon.name)));
+    }
+
+    conn.execute(
+        "INSERT INTO dungeon_runs (user_id, dungeon_id, status, current_hp, current_mp, started_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        rusqlite::params![
+            user_id,
+            dungeon.dungeon_id,
+            RunStatus::Active.to_i32(),
+            current_hp,
+            current_mp,
+            now
+        ],
+    )?;
+
+    Ok(get_active_run(conn, user_id)?.ok_or(DungeonError::NoRun)?)
+}
+
+// 1フロア分の結果を記録する。探索が終わったら finished_at も記録する
+pub fn record_floor(
+    conn: &Connection,
+    run: &DungeonRun,
+    result: &FloorResult,
+) -> Result<(), Box<dyn StdError>> {
+    let tx = conn.unchecked_transaction()?;
+    write_floor(&tx, run, result)?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+// record_floor の中身。呼び出し側のトランザクションの中で使う
+// 探索が終わったら、その探索のために出したモンスターも片付ける
+fn write_floor(
+    tx: &Connection,
+    run: &DungeonRun,
+    result: &FloorResult,
+) -> Result<(), Box<dyn StdError>> {
+    let finished_at = if result.status == RunStatus::Active {
+        None
+    } else {
+        Some(result.recorded_at)
+    };
+    let updated = tx.execute(
+        "UPDATE dungeon_runs
+        SET floor = floor + ?1,
+            status = ?2,
+            current_hp = ?3,
+            current_mp = ?4,
+            experience = experience + ?5,
+            gold = gold + ?6,
+            battle_log = battle_log || ?7,
+            finished_at = ?8
+        WHERE run_id = ?9 AND status = 0",
+        rusqlite::params![
+            result.cleared as i32,
+            result.status.to_i32(),
+            result.current_hp,
+            result.current_mp,
+            result.experience,
+            result.gold,
+            result.battle_log,
+            finished_at,
+            run.run_id
+        ],
+    )?;
+    if updated == 0 {
+        return Err(Box::new(DungeonError::NoRun));
+    }
+    if finished_at.is_some() {
+        remove_run_monsters(tx, run.run_id)?;
+    }
+
+    Ok(())
+}
+
+// 探索のために出したモンスターを消す。倒したかどうかは battle_results に残っている
+fn remove_run_monsters(tx: &Connection, run_id: i64) -> Result<(), Box<dyn StdError>> {
+    tx.execute(
+        "DELETE FROM monsters WHERE dungeon_run_id = ?1",
+        rusqlite::params![run_id],
+    )?;
+
+    Ok(())
+}
+
+// フロアでの戦闘結果と探索の進み具合をひとつのトランザクションで保存する
+// 探索がすでに終わっていたら（別の発言で引き返したなど）戦闘結果も保存しない
+pub fn apply_floor_battle(
+    conn: &Connection,
+    run: &DungeonRun,
+    floor: &FloorResult,
+    monsters: &[Monster],
+    result: &BattleResult,
+    battle_log: &str,
+    event_id: &str,
+) -> Result<i64, Box<dyn StdError>> {
+    let tx = conn.unchecked_transaction()?;
+    let battle_id = battle::write_battle_result(&tx, monsters, result, battle_log, event_id)?;
+    write_floor(&tx, run, floor)?;
+    tx.commit()?;
+
+    Ok(battle_id)
+}
+
+// 探索をやめて引き返す。それまでの報酬はそのまま
+pub fn retreat(conn: &Connection, run: &DungeonRun, now: i64) -> Result<(), Box<dyn StdError>> {
+    let tx = conn.unchecked_transaction()?;
+    let updated = tx.execute(
+        "UPDATE dungeon_runs SET status = ?1, finished_at = ?2 WHERE run_id = ?3 AND status = 0",
+        rusqlite::params![RunStatus::Retreated.to_i32(), now, run.run_id],
+    )?;
+    if updated == 0 {
+        return Err(Box::new(DungeonError::NoRun));
+    }
+    remove_run_monsters(&tx, run.run_id)?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monsters::{self, NewMonsterMaster};
+    use crate::{db, users, zones};
+
+    // 3フロアのダンジョンに入り、1フロア目のモンスターを2体出したところ。地上にも1体いる
+    fn setup() -> (Connection, DungeonRun) {
+        let conn = Connection::open_in_memory().unwrap();
+        db::migrate(&conn).unwrap();
+        let user = users::add_user(&conn, "npub_hero").unwrap();
+        let zone = zones::add_zone(&conn, "はじまりの森", 1, "").unwrap();
+        let master_id = monsters::add_monster_master(
+            &conn,
+            &NewMonsterMaster {
+                level: 1,
+                name: "スライム".to_string(),
+                picture: String::new(),
+                attack: 1,
+                defense: 1,
+                agility: 1,
+                experience_reward: 1,
+                gold_reward: 1,
+                luck: 0,
+                rarity_weight: 100,
+                zone_id: Some(zone.zone_id),
+            },
+        )
+        .unwrap();
+        monsters::spawn_monster(&conn, master_id, 1, &[]).unwrap();
+        let dungeon = add_dungeon(&conn, "森の洞窟", zone.zone_id, 3, master_id).unwrap();
+        let run = start_run(&conn, user.user_id, &dungeon, 10, 5, 0).unwrap();
+        monsters::spawn_for_run(
+            &conn,
+            run.run_id,
+            &[master_id, master_id],
+            user.user_id,
+            60,
+            &[],
+        )
+        .unwrap();
+
+        (conn, run)
+    }
+
+    fn floor(status: RunStatus) -> FloorResult<'static> {
+        FloorResult {
+            cleared: status != RunStatus::Defeated,
+            status,
+            current_hp: 10,
+            current_mp: 5,
+            experience: 1,
+            gold: 1,
+            battle_log: "log\n",
+            recorded_at: 10,
+        }
+    }
+
+    // (探索用のモンスターの数, 地上のモンスターの数)
+    fn monster_counts(conn: &Connection) -> (i32, i32) {
+        conn.query_row(
+            "SELECT
+                COUNT(dungeon_run_id),
+                COUNT(*) - COUNT(dungeon_run_id)
+            FROM monsters",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap()
+    }
+
+    // (status, floor, finished_at)
+    fn run_row(conn: &Connection, run: &DungeonRun) -> (i32, i32, Option<i64>) {
+        conn.query_row(
+            "SELECT status, floor, finished_at FROM dungeon_runs WHERE run_id = ?1",
+            rusqlite::params![run.run_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn cleared_floors_lead_to_the_boss() {
+        let (conn, run) = setup();
+        assert_eq!(run.next_floor(), 1);
+        assert!(!run.is_boss_floor());
+
+        record_floor(&conn, &run, &floor(RunStatus::Active)).unwrap();
+        record_floor(&conn, &run, &floor(RunStatus::Active)).unwrap();
+
+        let run = get_active_run(&conn, 1).unwrap().unwrap();
+        assert_eq!(run.floor, 2);
+        assert_eq!(run.next_floor(), 3);
+        assert!(run.is_boss_floor());
+        assert_eq!((run.experience, run.gold), (2, 2));
+        assert_eq!(run.battle_log, "log\nlog\n");
+    }
+
+    #[test]
+    fn clearing_the_boss_finishes_the_run() {
+        let (conn, run) = setup();
+
+        record_floor(&conn, &run, &floor(RunStatus::Cleared)).unwrap();
+
+        assert_eq!(run_row(&conn, &run), (1, 1, Some(10)));
+        assert!(get_active_run(&conn, 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn defeat_ends_the_run_without_advancing() {
+        let (conn, run) = setup();
+
+        record_floor(&conn, &run, &floor(RunStatus::Defeated)).unwrap();
+
+        assert_eq!(run_row(&conn, &run), (3, 0, Some(10)));
+        let e = record_floor(&conn, &run, &floor(RunStatus::Active)).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<DungeonError>(),
+            Some(DungeonError::NoRun)
+        ));
+    }
+
+    #[test]
+    fn only_one_run_at_a_time() {
+        let (conn, run) = setup();
+
+        let e = start_run(&conn, 1, &run.dungeon, 10, 5, 0).err().unwrap();
+
+        assert!(matches!(
+            e.downcast_ref::<DungeonError>(),
+            Some(DungeonError::AlreadyInRun(name)) if name == "森の洞窟"
+        ));
+    }
+
+    #[test]
+    fn run_monsters_stay_while_the_run_goes_on() {
+        let (conn, run) = setup();
+
+        record_floor(&conn, &run, &floor(RunStatus::Active)).unwrap();
+
+        assert_eq!(monster_counts(&conn), (2, 1));
+    }
+
+    #[test]
+    fn finished_run_removes_its_monsters() {
+        for status in [RunStatus::Cleared, RunStatus::Defeated] {
+            let (conn, run) = setup();
+
+            record_floor(&conn, &run, &floor(status)).unwrap();
+
+            assert_eq!(monster_counts(&conn), (0, 1));
+        }
+    }
+
+    #[test]
+    fn retreat_removes_the_run_monsters() {
+        let (conn, run) = setup();
+
+        retreat(&conn, &run, 10).unwrap();
+
+        assert_eq!(monster_counts(&conn), (0, 1));
+        let e = retreat(&conn, &run, 20).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<DungeonError>(),
+            Some(DungeonError::NoRun)
+        ));
+    }
+}
@@ -0,0 +1,187 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{GrowthConfig, GrowthMode};
+use crate::users::User;
+
+// レベルアップ1回分の能力値の上昇量
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatGains {
+    #[serde(default)]
+    pub hp: i32,
+    #[serde(default)]
+    pub mp: i32,
+    #[serde(default)]
+    pub attack: i32,
+    #[serde(default)]
+    pub defense: i32,
+    #[serde(default)]
+    pub agility: i32,
+}
+
+impl StatGains {
+    // 上昇量をユーザーに反映する。増えた最大HP/MPの分は現在値も回復する
+    pub fn apply_to(&self, user: &mut User) {
+        user.max_hp += self.hp;
+        user.current_hp += self.hp;
+        user.max_mp += self.mp;
+        user.current_mp += self.mp;
+        user.attack += self.attack;
+        user.defense += self.defense;
+        user.agility += self.agility;
+    }
+}
+
+// users::distribute_bonus_points と同じやり方でポイントをランダムに振り分ける
+fn roll_random_gains<R: Rng>(rng: &mut R, min_points: i32, max_points: i32) -> StatGains {
+    let bonus_points = rng.gen_range(min_points..=max_points.max(min_points)); // ボーナスポイントの総数
+    let mut remaining_points = bonus_points; // 残っているポイント
+    let mut gains = StatGains::default();
+
+    // 残りのポイントがゼロになるまでループ
+    while remaining_points > 0 {
+        let attribute = rng.gen_range(0..5); // 0: HP, 1: MP, 2: Agility, 3:attack 4: defense
+        let points = rng.gen_range(1..=remaining_points); // 割り当てるポイント
+
+        match attribute {
+            0 => gains.hp += points,
+            1 => gains.mp += points,
+            2 => gains.agility += points,
+            3 => gains.attack += points,
+            4 => gains.defense += points,
+            _ => unreachable!(),
+        }
+
+        remaining_points -= points; // 残りのポイントを更新
+    }
+
+    gains
+}
+
+// レベルが1上がるごとの上昇量を決める
+pub fn roll_gains<R: Rng>(rng: &mut R, config: &GrowthConfig) -> StatGains {
+    match config.mode {
+        GrowthMode::Random => roll_random_gains(rng, config.min_points, config.max_points),
+        GrowthMode::Fixed => config.fixed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::battle;
+
+    fn user(level: i32, experience: i32) -> User {
+        User {
+            user_id: 1,
+            npub: "npub_hero".to_string(),
+            level,
+            experience,
+            gold: 0,
+            current_hp: 5,
+            max_hp: 20,
+            current_mp: 0,
+            max_mp: 4,
+            attack: 5,
+            defense: 3,
+            agility: 3,
+            luck: 0,
+            stat_points: 0,
+        }
+    }
+
+    fn fixed() -> GrowthConfig {
+        GrowthConfig {
+            mode: GrowthMode::Fixed,
+            fixed: StatGains {
+                hp: 3,
+                mp: 1,
+                attack: 2,
+                defense: 1,
+                agility: 1,
+            },
+            ..GrowthConfig::default()
+        }
+    }
+
+    fn total(gains: &StatGains) -> i32 {
+        gains.hp + gains.mp + gains.attack + gains.defense + gains.agility
+    }
+
+    #[test]
+    fn random_gains_stay_within_the_configured_points() {
+        let config = GrowthConfig::default();
+        let mut rng = battle::rng_from_seed(1);
+
+        for _ in 0..200 {
+            let gains = roll_gains(&mut rng, &config);
+
+            assert!((config.min_points..=config.max_points).contains(&total(&gains)));
+            assert!(gains.hp >= 0 && gains.mp >= 0 && gains.attack >= 0);
+            assert!(gains.defense >= 0 && gains.agility >= 0);
+        }
+    }
+
+    // max_points が min_points より小さい設定なら min_points ちょうど
+    #[test]
+    fn max_points_below_min_points_uses_min_points() {
+        let config = GrowthConfig {
+            min_points: 4,
+            max_points: 1,
+            ..GrowthConfig::default()
+        };
+        let mut rng = battle::rng_from_seed(2);
+
+        for _ in 0..50 {
+            assert_eq!(total(&roll_gains(&mut rng, &config)), 4);
+        }
+    }
+
+    #[test]
+    fn fixed_mode_gives_the_same_gains_every_level() {
+        let config = fixed();
+        let mut rng = battle::rng_from_seed(3);
+
+        for _ in 0..10 {
+            assert_eq!(roll_gains(&mut rng, &config), config.fixed);
+        }
+    }
+
+    // 増えた最大HP/MPの分は現在値も増える
+    #[test]
+    fn gains_raise_current_hp_and_mp_with_the_maximum() {
+        let mut user = user(1, 0);
+
+        fixed().fixed.apply_to(&mut user);
+
+        assert_eq!((user.current_hp, user.max_hp), (8, 23));
+        assert_eq!((user.current_mp, user.max_mp), (1, 5));
+        assert_eq!((user.attack, user.defense, user.agility), (7, 4, 4));
+    }
+
+    // けいけんち100でレベル1から4まで上がり、3回分伸びる
+    #[test]
+    fn multiple_level_ups_grow_once_per_level() {
+        let config = fixed();
+        let mut rng = battle::rng_from_seed(4);
+
+        let updated = battle::grant_rewards(&mut rng, &user(1, 0), 100, 0, &config);
+
+        assert_eq!(updated.level, 4);
+        assert_eq!(updated.max_hp, 20 + 3 * 3);
+        assert_eq!(updated.max_mp, 4 + 3);
+        assert_eq!(updated.attack, 5 + 3 * 2);
+        assert_eq!(updated.stat_points, 3 * config.stat_points_per_level);
+    }
+
+    #[test]
+    fn level_never_goes_down() {
+        let mut rng = battle::rng_from_seed(5);
+
+        let updated = battle::grant_rewards(&mut rng, &user(10, 0), 1, 0, &fixed());
+
+        assert_eq!(updated.level, 10);
+        assert_eq!(updated.max_hp, 20);
+        assert_eq!(updated.stat_points, 0);
+    }
+}
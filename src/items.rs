@@ -0,0 +1,259 @@
+use rusqlite::{Connection, Result};
+use std::error::Error as StdError;
+use std::fmt;
+
+// 装備スロット（items.type に入る値）
+pub const SLOTS: [&str; 3] = ["weapon", "armor", "accessory"];
+
+// アイテムの情報を保持する構造体
+#[derive(Clone)]
+pub struct Item {
+    pub item_id: i32,
+    pub name: String,
+    pub item_type: String,
+    pub attack_bonus: i32,
+    pub defense_bonus: i32,
+    pub agility_bonus: i32,
+}
+
+// 所持品1種類分
+pub struct InventoryEntry {
+    pub item: Item,
+    pub quantity: i32,
+    pub equipped: bool,
+}
+
+// 装備による能力値の上乗せ分
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EquipmentBonus {
+    pub attack: i32,
+    pub defense: i32,
+    pub agility: i32,
+}
+
+#[derive(Debug)]
+pub(crate) enum EquipError {
+    ItemNotOwned(String),
+    NotEquippable(String),
+    SlotEmpty(String),
+}
+
+impl fmt::Display for EquipError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EquipError::ItemNotOwned(name) => write!(f, "Item '{}' is not owned", name),
+            EquipError::NotEquippable(name) => write!(f, "Item '{}' is not equippable", name),
+            EquipError::SlotEmpty(slot) => write!(f, "Slot '{}' is empty", slot),
+        }
+    }
+}
+
+impl StdError for EquipError {}
+
+pub fn slot_name(slot: &str) -> &str {
+    match slot {
+        "weapon" => "ぶき",
+        "armor" => "ぼうぐ",
+        "accessory" => "そうしょくひん",
+        _ => slot,
+    }
+}
+
+// "ぶき" のような表示名でも "weapon" のようなスロット名でも受け付ける
+pub fn parse_slot(value: &str) -> Option<&'static str> {
+    SLOTS
+        .iter()
+        .find(|slot| **slot == value || slot_name(slot) == value)
+        .copied()
+}
+
+fn item_from_row(row: &rusqlite::Row) -> Result<Item> {
+    Ok(Item {
+        item_id: row.get(0)?,
+        name: row.get(1)?,
+        item_type: row.get(2)?,
+        attack_bonus: row.get(3)?,
+        defense_bonus: row.get(4)?,
+        agility_bonus: row.get(5)?,
+    })
+}
+
+pub fn add_item(
+    conn: &Connection,
+    name: &str,
+    item_type: &str,
+    attack_bonus: i32,
+    defense_bonus: i32,
+    agility_bonus: i32,
+) -> Result<Item, Box<dyn StdError>> {
+    conn.execute(
+        "INSERT INTO items (name, type, attack_bonus, defense_bonus, agility_bonus)
+           VALUES (?1, ?2, ?3, ?4, ?5)",
+        rusqlite::params![name, item_type, attack_bonus, defense_bonus, agility_bonus],
+    )?;
+
+    Ok(Item {
+        item_id: conn.last_insert_rowid() as i32,
+        name: name.to_string(),
+        item_type: item_type.to_string(),
+        attack_bonus,
+        defense_bonus,
+        agility_bonus,
+    })
+}
+
+pub fn get_item_by_name(conn: &Connection, name: &str) -> Result<Option<Item>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(
+        "SELECT item_id, name, type, attack_bonus, defense_bonus, agility_bonus
+        FROM items WHERE name = ?1",
+    )?;
+    let mut item_iter = statement.query_map(rusqlite::params![name], item_from_row)?;
+
+    Ok(item_iter.next().transpose()?)
+}
+
+// 所持品を追加する
+pub fn give_item(
+    conn: &Connection,
+    user_id: i32,
+    item_id: i32,
+    quantity: i32,
+) -> Result<(), Box<dyn StdError>> {
+    conn.execute(
+        "INSERT INTO inventory (user_id, item_id, quantity) VALUES (?1, ?2, ?3)
+        ON CONFLICT (user_id, item_id) DO UPDATE SET quantity = quantity + excluded.quantity",
+        rusqlite::params![user_id, item_id, quantity],
+    )?;
+
+    Ok(())
+}
+
+// 所持品を減らす。足りなければ ItemNotOwned
+pub fn take_item(
+    conn: &Connection,
+    user_id: i32,
+    item_id: i32,
+    quantity: i32,
+) -> Result<(), Box<dyn StdError>> {
+    let updated = conn.execute(
+        "UPDATE inventory SET quantity = quantity - ?3
+        WHERE user_id = ?1 AND item_id = ?2 AND quantity >= ?3",
+        rusqlite::params![user_id, item_id, quantity],
+    )?;
+    if updated == 0 {
+        return Err(Box::new(EquipError::ItemNotOwned(item_id.to_string())));
+    }
+
+    Ok(())
+}
+
+pub fn get_inventory(
+    conn: &Connection,
+    user_id: i32,
+) -> Result<Vec<InventoryEntry>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(
+        "SELECT
+            items.item_id,
+            items.name,
+            items.type,
+            items.attack_bonus,
+            items.defense_bonus,
+            items.agility_bonus,
+            inventory.quantity,
+            EXISTS (
+                SELECT 1 FROM equipment
+                WHERE equipment.user_id = inventory.user_id AND equipment.item_id = items.item_id
+            )
+        FROM inventory
+        JOIN items ON items.item_id = inventory.item_id
+        WHERE inventory.user_id = ?1 AND inventory.quantity > 0
+        ORDER BY items.item_id",
+    )?;
+    let entries = statement
+        .query_map(rusqlite::params![user_id], |row| {
+            Ok(InventoryEntry {
+                item: item_from_row(row)?,
+                quantity: row.get(6)?,
+                equipped: row.get(7)?,
+            })
+        })?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(entries)
+}
+
+// スロットごとの装備品
+pub fn get_equipment(conn: &Connection, user_id: i32) -> Result<Vec<Item>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(
+        "SELECT
+            items.item_id,
+            items.name,
+            items.type,
+            items.attack_bonus,
+            items.defense_bonus,
+            items.agility_bonus
+        FROM equipment
+        JOIN items ON items.item_id = equipment.item_id
+        WHERE equipment.user_id = ?1",
+    )?;
+    let items = statement
+        .query_map(rusqlite::params![user_id], item_from_row)?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(items)
+}
+
+pub fn equipment_bonus(equipment: &[Item]) -> EquipmentBonus {
+    equipment
+        .iter()
+        .fold(EquipmentBonus::default(), |bonus, item| EquipmentBonus {
+            attack: bonus.attack + item.attack_bonus,
+            defense: bonus.defense + item.defense_bonus,
+            agility: bonus.agility + item.agility_bonus,
+        })
+}
+
+// 装備できるのは SLOTS のどれかに入るアイテムだけ
+pub(crate) fn check_equippable(item: &Item) -> Result<(), EquipError> {
+    if !SLOTS.contains(&item.item_type.as_str()) {
+        return Err(EquipError::NotEquippable(item.name.clone()));
+    }
+
+    Ok(())
+}
+
+// 所持しているアイテムを装備する。同じスロットの装備は入れ替わる
+pub fn equip_item(conn: &Connection, user_id: i32, name: &str) -> Result<Item, Box<dyn StdError>> {
+    let entry = get_inventory(conn, user_id)?
+        .into_iter()
+        .find(|entry| entry.item.name == name)
+        .ok_or_else(|| EquipError::ItemNotOwned(name.to_string()))?;
+    check_equippable(&entry.item)?;
+
+    conn.execute(
+        "INSERT INTO equipment (user_id, slot, item_id) VALUES (?1, ?2, ?3)
+        ON CONFLICT (user_id, slot) DO UPDATE SET item_id = excluded.item_id",
+        rusqlite::params![user_id, entry.item.item_type, entry.item.item_id],
+    )?;
+
+    Ok(entry.item)
+}
+
+// スロットの装備を外して、外したアイテムを返す
+pub fn unequip_slot(
+    conn: &Connection,
+    user_id: i32,
+    slot: &str,
+) -> Result<Item, Box<dyn StdError>> {
+    let item = get_equipment(conn, user_id)?
+        .into_iter()
+        .find(|item| item.item_type == slot)
+        .ok_or_else(|| EquipError::SlotEmpty(slot.to_string()))?;
+
+    conn.execute(
+        "DELETE FROM equipment WHERE user_id = ?1 AND slot = ?2",
+        rusqlite::params![user_id, slot],
+    )?;
+
+    Ok(item)
+}
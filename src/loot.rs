@@ -0,0 +1,199 @@
+use rand::Rng;
+use rusqlite::{Connection, Result};
+use std::error::Error as StdError;
+
+use crate::monsters::Monster;
+
+// モンスターを倒したときに落とすアイテム1種類分
+pub struct LootEntry {
+    pub monster_id: i32, // 現れたモンスター（monsters の id）
+    pub item_id: i32,
+    pub item_name: String,
+    pub probability: f64, // 0.0〜1.0
+    pub min_quantity: i32,
+    pub max_quantity: i32,
+}
+
+// 実際に落としたアイテム
+#[derive(Debug, Clone)]
+pub struct LootDrop {
+    pub item_id: i32,
+    pub item_name: String,
+    pub quantity: i32,
+}
+
+// 現れたモンスターの種類（monster_master）ごとのドロップ表をまとめて読み込む
+pub fn get_loot_for_monsters(
+    conn: &Connection,
+    monsters: &[Monster],
+) -> Result<Vec<LootEntry>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(
+        "SELECT monsters.id, items.item_id, items.name,
+            loot_tables.probability, loot_tables.min_quantity, loot_tables.max_quantity
+        FROM monsters
+        JOIN loot_tables ON loot_tables.monster_id = monsters.monster_id
+        JOIN items ON items.item_id = loot_tables.item_id
+        WHERE monsters.id = ?1
+        ORDER BY loot_tables.item_id",
+    )?;
+    let mut entries = Vec::new();
+    for monster in monsters {
+        let rows = statement.query_map(rusqlite::params![monster.id], |row| {
+            Ok(LootEntry {
+                monster_id: row.get(0)?,
+                item_id: row.get(1)?,
+                item_name: row.get(2)?,
+                probability: row.get(3)?,
+                min_quantity: row.get(4)?,
+                max_quantity: row.get(5)?,
+            })
+        })?;
+        for entry in rows {
+            entries.push(entry?);
+        }
+    }
+
+    Ok(entries)
+}
+
+// monster_master のモンスターのドロップ表に追加する。同じアイテムがあれば上書き
+pub fn set_loot_entry(
+    conn: &Connection,
+    monster_master_id: i32,
+    item_id: i32,
+    probability: f64,
+    min_quantity: i32,
+    max_quantity: i32,
+) -> Result<(), Box<dyn StdError>> {
+    conn.execute(
+        "INSERT INTO loot_tables (monster_id, item_id, probability, min_quantity, max_quantity)
+        VALUES (?1, ?2, ?3, ?4, ?5)
+        ON CONFLICT (monster_id, item_id) DO UPDATE SET
+            probability = excluded.probability,
+            min_quantity = excluded.min_quantity,
+            max_quantity = excluded.max_quantity",
+        rusqlite::params![
+            monster_master_id,
+            item_id,
+            probability,
+            min_quantity,
+            max_quantity
+        ],
+    )?;
+
+    Ok(())
+}
+
+// 倒したモンスターごとにドロップ表を順に判定する
+// ドロップ表がなければ乱数を使わない（これまでの戦闘の再現に影響させないため）
+pub fn roll_loot<R: Rng>(rng: &mut R, loot: &[LootEntry], defeated: &[&Monster]) -> Vec<LootDrop> {
+    let mut drops = Vec::new();
+    for monster in defeated {
+        for entry in loot.iter().filter(|entry| entry.monster_id == monster.id) {
+            if !rng.gen_bool(entry.probability.clamp(0.0, 1.0)) {
+                continue;
+            }
+            let min_quantity = entry.min_quantity.max(1);
+            drops.push(LootDrop {
+                item_id: entry.item_id,
+                item_name: entry.item_name.clone(),
+                quantity: rng.gen_range(min_quantity..=entry.max_quantity.max(min_quantity)),
+            });
+        }
+    }
+
+    drops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::battle;
+
+    fn monster(id: i32) -> Monster {
+        Monster {
+            id,
+            level: 1,
+            status: 2,
+            name: "スライム".to_string(),
+            picture: String::new(),
+            attack: 1,
+            defense: 1,
+            agility: 1,
+            experience_reward: 1,
+            gold_reward: 1,
+            hp: 0,
+            mp: 0,
+            defeat_user_id: 1,
+            luck: 0,
+        }
+    }
+
+    fn entry(monster_id: i32, item_id: i32, probability: f64, min: i32, max: i32) -> LootEntry {
+        LootEntry {
+            monster_id,
+            item_id,
+            item_name: format!("アイテム{}", item_id),
+            probability,
+            min_quantity: min,
+            max_quantity: max,
+        }
+    }
+
+    #[test]
+    fn drops_follow_the_probability() {
+        let loot = [entry(1, 1, 1.0, 2, 4), entry(1, 2, 0.0, 1, 1)];
+        let slime = monster(1);
+        let mut rng = battle::rng_from_seed(19);
+
+        for _ in 0..50 {
+            let drops = roll_loot(&mut rng, &loot, &[&slime]);
+
+            assert_eq!(drops.len(), 1);
+            assert_eq!(drops[0].item_id, 1);
+            assert!((2..=4).contains(&drops[0].quantity));
+        }
+    }
+
+    #[test]
+    fn out_of_range_entries_are_clamped() {
+        // 確率は 0.0〜1.0 に、数は1個以上・max が min より小さければ min に
+        let loot = [entry(1, 1, 2.0, 0, 0), entry(1, 2, 1.0, 3, 1)];
+        let slime = monster(1);
+        let mut rng = battle::rng_from_seed(19);
+
+        let drops = roll_loot(&mut rng, &loot, &[&slime]);
+
+        let quantities: Vec<(i32, i32)> = drops
+            .iter()
+            .map(|drop| (drop.item_id, drop.quantity))
+            .collect();
+        assert_eq!(quantities, vec![(1, 1), (2, 3)]);
+    }
+
+    #[test]
+    fn each_defeated_monster_rolls_its_own_table() {
+        let loot = [
+            entry(1, 1, 1.0, 1, 1),
+            entry(2, 2, 1.0, 1, 1),
+            entry(3, 3, 1.0, 1, 1),
+        ];
+        let (first, second) = (monster(1), monster(2));
+        let mut rng = battle::rng_from_seed(19);
+
+        let drops = roll_loot(&mut rng, &loot, &[&first, &second]);
+
+        let items: Vec<i32> = drops.iter().map(|drop| drop.item_id).collect();
+        assert_eq!(items, vec![1, 2]);
+    }
+
+    #[test]
+    fn no_loot_table_leaves_the_rng_alone() {
+        let slime = monster(1);
+        let mut rng = battle::rng_from_seed(19);
+
+        assert!(roll_loot(&mut rng, &[entry(2, 1, 1.0, 1, 1)], &[&slime]).is_empty());
+
+        assert_eq!(rng.gen::<u64>(), battle::rng_from_seed(19).gen::<u64>());
+    }
+}
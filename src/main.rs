@@ -1,11 +1,30 @@
 mod battle;
+mod battle_log;
+mod battle_results;
 mod commands;
 mod config;
 mod db;
+mod duel;
+mod dungeon;
 mod gpt;
+mod growth;
+mod items;
+mod loot;
 mod users;
+#[cfg(test)]
+mod memory_repository;
 mod monsters;
+mod party;
+#[cfg(feature = "postgres")]
+mod postgres_repository;
+mod raid;
+mod repository;
+mod respawn;
+mod shop;
+mod skills;
+mod stats;
 mod util;
+mod zones;
 use dotenv::dotenv;
 use nostr_sdk::prelude::*;
 use std::env;
@@ -17,7 +36,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("start");
     let file = File::open("config.yml")?;
     let config: config::AppConfig = serde_yaml::from_reader(file)?;
-    let conn = db::connect()?;
+    let repo = repository::open(&config.storage)?;
+    tokio::spawn(respawn::run(
+        config.storage.clone(),
+        config.respawn.clone(),
+        config.variants.clone(),
+    ));
 
     let bot_secret_key = env::var("BOT_SECRETKEY").expect("BOT_SECRETKEY is not set");
     let bot_public_key = env::var("BOT_PUBLICKEY").expect("BOT_PUBLICKEY is not set");
@@ -54,7 +78,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     let follower =
                         util::is_follower(&event.pubkey.to_string(), &bot_public_key).await?;
                     if follower {
-                        commands::command_handler(&config, &conn, my_keys.clone(), &event).await?;
+                        // コマンドが失敗してもイベントの受信は続ける
+                        if let Err(e) = commands::command_handler(
+                            &config,
+                            repo.as_ref(),
+                            my_keys.clone(),
+                            &event,
+                        )
+                        .await
+                        {
+                            eprintln!("Error command_handler: {:?}", e);
+                        }
                     }
                 } else {
                     println!("{:?}", event);
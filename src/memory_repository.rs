@@ -0,0 +1,2075 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+
+use crate::battle::{self, BattleOutcome, BattleResult, BattleSnapshot};
+use crate::battle_results::{BattleRecord, BattleStats};
+use crate::config::{EncounterConfig, GrowthConfig, VariantConfig};
+use crate::duel::{self, Duel, DuelError, DuelStatus};
+use crate::dungeon::{Dungeon, DungeonError, DungeonRun, FloorResult, RunStatus};
+use crate::items::{self, EquipError, InventoryEntry, Item};
+use crate::loot::LootEntry;
+use crate::monsters::{self, EncounterRow, Monster, MonsterError, NewMonsterMaster};
+use crate::party::{self, Party, PartyError, PartyMember};
+use crate::raid::{self, Contribution, Raid, RaidAttack, RaidError, RaidReward};
+use crate::repository::Repository;
+use crate::shop::{ShopEntry, ShopError, UNLIMITED_STOCK};
+use crate::skills::{Skill, SkillKind};
+use crate::stats::{self, Stat};
+use crate::users::{self, User, UserAlreadyExistsError, UserNotFoundError};
+use crate::zones::Zone;
+
+// users の1行。User に入らない列もここに持つ
+#[derive(Clone)]
+struct MemoryUser {
+    user: User,
+    regenerated_at: Option<i64>, // 最後に自然回復を計算した時刻
+    log_style: Option<String>,
+    selected_skill: Option<String>,
+    zone_id: Option<i64>,
+}
+
+// monster_master の1行
+#[derive(Clone)]
+struct MemoryMaster {
+    monster: Monster,
+    rarity_weight: i32,
+    zone_id: Option<i64>,
+}
+
+// monsters の1行
+#[derive(Clone)]
+struct MemoryMonster {
+    monster: Monster,
+    species: i32, // monster_master の id
+    reserved_by: Option<i32>,
+    reserved_until: Option<i64>,
+    dungeon_run_id: Option<i64>,
+}
+
+#[derive(Clone)]
+struct MemoryPartyMember {
+    party_id: i64,
+    user_id: i32,
+    joined: bool,
+}
+
+#[derive(Clone)]
+struct MemoryDuel {
+    duel_id: i64,
+    challenger_id: i32,
+    opponent_id: i32,
+    wager: i32,
+    created_at: i64,
+    status: DuelStatus,
+    resolved_at: Option<i64>,
+}
+
+#[derive(Clone)]
+struct MemoryRun {
+    run_id: i64,
+    user_id: i32,
+    dungeon_id: i64,
+    status: RunStatus,
+    floor: i32,
+    current_hp: i32,
+    current_mp: i32,
+    experience: i32,
+    gold: i32,
+    battle_log: String,
+}
+
+#[derive(Clone)]
+struct MemoryLoot {
+    monster_id: i32, // monster_master の id
+    item_id: i32,
+    probability: f64,
+    min_quantity: i32,
+    max_quantity: i32,
+}
+
+// raids の1行。monster.status が 0 なら出現中、1 なら討伐済み
+#[derive(Clone)]
+struct MemoryRaid {
+    raid_id: i64,
+    monster: Monster,
+    max_hp: i32,
+}
+
+#[derive(Clone)]
+struct MemoryContribution {
+    raid_id: i64,
+    user_id: i32,
+    damage: i32,
+    attacks: i32,
+    last_attack_at: i64,
+}
+
+#[derive(Clone, Default)]
+struct MemoryState {
+    users: Vec<MemoryUser>,
+    skills: Vec<Skill>,
+    masters: Vec<MemoryMaster>,
+    monsters: Vec<MemoryMonster>,
+    battles: Vec<BattleRecord>,
+    items: Vec<Item>,
+    inventory: BTreeMap<(i32, i32), i32>, // (user_id, item_id) ごとの個数
+    equipment: BTreeMap<(i32, String), i32>, // (user_id, slot) ごとの item_id
+    shop: BTreeMap<i32, (i32, i32)>,      // item_id ごとの (price, stock)
+    stat_allocations: BTreeMap<(i32, String), i32>, // (user_id, stat) ごとのポイント
+    zones: Vec<Zone>,
+    parties: BTreeMap<i64, i32>,           // party_id ごとの leader_id
+    party_members: Vec<MemoryPartyMember>, // 招待した順
+    duels: Vec<MemoryDuel>,
+    dungeons: Vec<Dungeon>,
+    runs: Vec<MemoryRun>,
+    loot: Vec<MemoryLoot>,
+    raids: Vec<MemoryRaid>,
+    contributions: Vec<MemoryContribution>,
+    next_party_id: i64,
+}
+
+impl MemoryState {
+    fn user(&self, user_id: i32) -> Option<&MemoryUser> {
+        self.users
+            .iter()
+            .find(|stored| stored.user.user_id == user_id)
+    }
+
+    fn user_mut(&mut self, user_id: i32) -> Option<&mut MemoryUser> {
+        self.users
+            .iter_mut()
+            .find(|stored| stored.user.user_id == user_id)
+    }
+
+    fn update_user(&mut self, user: &User) {
+        if let Some(stored) = self.user_mut(user.user_id) {
+            stored.user = user.clone();
+        }
+    }
+
+    fn gold(&self, user_id: i32) -> i32 {
+        self.user(user_id).map_or(0, |stored| stored.user.gold)
+    }
+
+    // GOLDが足りていれば払う（足りなければfalse）
+    fn pay_gold(&mut self, user_id: i32, amount: i32) -> bool {
+        match self.user_mut(user_id) {
+            Some(stored) if stored.user.gold >= amount => {
+                stored.user.gold -= amount;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn add_gold(&mut self, user_id: i32, amount: i32) {
+        if let Some(stored) = self.user_mut(user_id) {
+            stored.user.gold += amount;
+        }
+    }
+
+    fn give_item(&mut self, user_id: i32, item_id: i32, quantity: i32) {
+        *self.inventory.entry((user_id, item_id)).or_insert(0) += quantity;
+    }
+
+    fn get_inventory(&self, user_id: i32) -> Vec<InventoryEntry> {
+        self.inventory
+            .iter()
+            .filter(|((owner, _), quantity)| *owner == user_id && **quantity > 0)
+            .filter_map(|(&(_, item_id), &quantity)| {
+                let item = self.items.iter().find(|item| item.item_id == item_id)?;
+                Some(InventoryEntry {
+                    item: item.clone(),
+                    quantity,
+                    equipped: self
+                        .equipment
+                        .iter()
+                        .any(|((owner, _), equipped)| *owner == user_id && *equipped == item_id),
+                })
+            })
+            .collect()
+    }
+
+    fn get_equipment(&self, user_id: i32) -> Vec<Item> {
+        self.equipment
+            .iter()
+            .filter(|((owner, _), _)| *owner == user_id)
+            .filter_map(|(_, item_id)| self.items.iter().find(|item| item.item_id == *item_id))
+            .cloned()
+            .collect()
+    }
+
+    fn get_shop_entry_by_name(&self, name: &str) -> Option<ShopEntry> {
+        let item = self.items.iter().find(|item| item.name == name)?;
+        let &(price, stock) = self.shop.get(&item.item_id)?;
+
+        Some(ShopEntry {
+            item: item.clone(),
+            price,
+            stock,
+        })
+    }
+
+    fn get_monster_master(&self, id: i32) -> Option<Monster> {
+        self.masters
+            .iter()
+            .find(|master| master.monster.id == id && master.monster.status == 1)
+            .map(|master| master.monster.clone())
+    }
+
+    // dungeon_run_id をつけたモンスターはその探索専用で、.leveling の出現候補にも再出現の数にも入らない
+    fn spawn_monsters(
+        &mut self,
+        monster_id: i32,
+        amount: i32,
+        dungeon_run_id: Option<i64>,
+        variants: &[VariantConfig],
+    ) -> Result<(Monster, Vec<i32>), MonsterError> {
+        let master = self
+            .get_monster_master(monster_id)
+            .ok_or(MonsterError::NotFound(monster_id))?;
+        let mut rng = rand::thread_rng();
+        let mut ids = Vec::new();
+        for _ in 0..amount {
+            let (mut spawned, _) = monsters::roll_spawn(&mut rng, &master, variants);
+            spawned.id = self.monsters.len() as i32 + 1;
+            ids.push(spawned.id);
+            self.monsters.push(MemoryMonster {
+                monster: spawned,
+                species: monster_id,
+                reserved_by: None,
+                reserved_until: None,
+                dungeon_run_id,
+            });
+        }
+
+        Ok((master, ids))
+    }
+
+    // 出現中のモンスターを戦闘中にする。すでにほかのユーザーが予約していたら false
+    fn reserve_monster(&mut self, id: i32, user_id: i32, reserved_until: i64) -> bool {
+        match self
+            .monsters
+            .iter_mut()
+            .find(|spawned| spawned.monster.id == id && spawned.monster.status == 1)
+        {
+            Some(spawned) => {
+                spawned.monster.status = 3;
+                spawned.reserved_by = Some(user_id);
+                spawned.reserved_until = Some(reserved_until);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // apply_battle_result の中身。呼び出し側の transaction の中で使う
+    fn write_battle_result(
+        &mut self,
+        monsters: &[Monster],
+        result: &BattleResult,
+        battle_log: &str,
+        event_id: &str,
+    ) -> Result<i64, Box<dyn StdError>> {
+        // 倒したモンスターはにげられても倒れたまま。倒せなかったモンスターは予約を外す
+        for monster in monsters {
+            let spawned = self.monsters.iter_mut().find(|spawned| {
+                spawned.monster.id == monster.id
+                    && spawned.monster.status == 3
+                    && spawned.reserved_by == Some(result.user_id)
+            });
+            let defeated = result.defeated_monster_ids.contains(&monster.id);
+            match spawned {
+                Some(spawned) if defeated => {
+                    spawned.monster.status = 2;
+                    spawned.monster.hp = monster.hp;
+                    spawned.monster.mp = monster.mp;
+                    spawned.monster.defeat_user_id = result.user_id;
+                    spawned.reserved_by = None;
+                    spawned.reserved_until = None;
+                }
+                None if defeated => {
+                    return Err(Box::new(MonsterError::NotReserved(monster.id)));
+                }
+                Some(spawned) => {
+                    spawned.monster.status = 1;
+                    spawned.reserved_by = None;
+                    spawned.reserved_until = None;
+                }
+                None => {}
+            }
+        }
+        for user in std::iter::once(&result.updated_user).chain(&result.updated_allies) {
+            self.update_user(user);
+        }
+        for drop in &result.drops {
+            self.give_item(result.user_id, drop.item_id, drop.quantity);
+        }
+
+        Ok(self.add_battle_result(result, battle_log, event_id))
+    }
+
+    // パーティで戦ったときは参加者全員の戦歴に同じ戦闘を記録し、.leveling したユーザーの battle_id を返す
+    fn add_battle_result(
+        &mut self,
+        result: &BattleResult,
+        battle_log: &str,
+        event_id: &str,
+    ) -> i64 {
+        let battle_id = self.battles.len() as i64 + 1;
+        let created_at = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
+        let user_ids = std::iter::once(result.user_id)
+            .chain(result.updated_allies.iter().map(|ally| ally.user_id));
+        for user_id in user_ids {
+            self.battles.push(BattleRecord {
+                battle_id: self.battles.len() as i64 + 1,
+                user_id,
+                monster_id: result.monster_id,
+                outcome: result.outcome,
+                experience_gain: result.experience_gain,
+                gold_gain: result.gold_gain,
+                battle_log: battle_log.to_string(),
+                event_id: event_id.to_string(),
+                created_at: created_at.clone(),
+                seed: result.seed,
+                snapshot: Some(result.snapshot.clone()),
+                engine_version: battle::ENGINE_VERSION,
+                events: Some(result.events.clone()),
+            });
+        }
+
+        battle_id
+    }
+
+    fn get_party(&self, party_id: i64) -> Option<Party> {
+        let leader_id = *self.parties.get(&party_id)?;
+        let mut members: Vec<PartyMember> = self
+            .party_members
+            .iter()
+            .filter(|member| member.party_id == party_id)
+            .filter_map(|member| {
+                Some(PartyMember {
+                    user_id: member.user_id,
+                    npub: self.user(member.user_id)?.user.npub.clone(),
+                    joined: member.joined,
+                })
+            })
+            .collect();
+        members.sort_by_key(|member| member.user_id != leader_id);
+
+        Some(Party {
+            party_id,
+            leader_id,
+            members,
+        })
+    }
+
+    fn get_party_by_user(&self, user_id: i32) -> Option<Party> {
+        self.party_members
+            .iter()
+            .find(|member| member.user_id == user_id && member.joined)
+            .and_then(|member| self.get_party(member.party_id))
+    }
+
+    fn get_active_run(&self, user_id: i32) -> Option<DungeonRun> {
+        let run = self
+            .runs
+            .iter()
+            .rev()
+            .find(|run| run.user_id == user_id && run.status == RunStatus::Active)?;
+        let dungeon = self
+            .dungeons
+            .iter()
+            .find(|dungeon| dungeon.dungeon_id == run.dungeon_id)?;
+
+        Some(DungeonRun {
+            run_id: run.run_id,
+            dungeon: dungeon.clone(),
+            floor: run.floor,
+            current_hp: run.current_hp,
+            current_mp: run.current_mp,
+            experience: run.experience,
+            gold: run.gold,
+            battle_log: run.battle_log.clone(),
+        })
+    }
+
+    // 1フロア分の結果を記録する。探索が終わったら、その探索のために出したモンスターを片付ける
+    fn record_floor(&mut self, run: &DungeonRun, result: &FloorResult) -> Result<(), DungeonError> {
+        let stored = self
+            .runs
+            .iter_mut()
+            .find(|stored| stored.run_id == run.run_id && stored.status == RunStatus::Active)
+            .ok_or(DungeonError::NoRun)?;
+        stored.floor += result.cleared as i32;
+        stored.status = result.status;
+        stored.current_hp = result.current_hp;
+        stored.current_mp = result.current_mp;
+        stored.experience += result.experience;
+        stored.gold += result.gold;
+        stored.battle_log.push_str(result.battle_log);
+        if result.status != RunStatus::Active {
+            self.remove_run_monsters(run.run_id);
+        }
+
+        Ok(())
+    }
+
+    fn remove_run_monsters(&mut self, run_id: i64) {
+        self.monsters
+            .retain(|spawned| spawned.dungeon_run_id != Some(run_id));
+    }
+
+    fn raid_from(raid: &MemoryRaid) -> Raid {
+        Raid {
+            raid_id: raid.raid_id,
+            monster: raid.monster.clone(),
+            max_hp: raid.max_hp,
+        }
+    }
+
+    fn get_active_raid(&self) -> Option<Raid> {
+        self.raids
+            .iter()
+            .rev()
+            .find(|raid| raid.monster.status == 0)
+            .map(Self::raid_from)
+    }
+
+    // 与えたダメージの多い順
+    fn get_contributions(&self, raid_id: i64) -> Vec<Contribution> {
+        let mut rows: Vec<&MemoryContribution> = self
+            .contributions
+            .iter()
+            .filter(|contribution| contribution.raid_id == raid_id && contribution.damage > 0)
+            .collect();
+        rows.sort_by_key(|contribution| (-contribution.damage, contribution.last_attack_at));
+
+        rows.into_iter()
+            .filter_map(|contribution| {
+                Some(Contribution {
+                    npub: self.user(contribution.user_id)?.user.npub.clone(),
+                    damage: contribution.damage,
+                    attacks: contribution.attacks,
+                })
+            })
+            .collect()
+    }
+}
+
+// ファイルを使わずにメモリ上に保存する。ゲームの処理をテストで試すときに使う
+// 読み書きの意味は SqliteRepository と同じにする。あとから読み返さない記録用の列（finished_at など）は持たない
+#[derive(Default)]
+pub struct MemoryRepository {
+    state: RefCell<MemoryState>,
+}
+
+impl MemoryRepository {
+    // db::migrate と同じ初期スキルを入れておく
+    pub fn new() -> Self {
+        let skills = [
+            ("ガード", SkillKind::Guard, 2, 0, 1),
+            ("ヒール", SkillKind::Heal, 3, 10, 2),
+            ("ファイア", SkillKind::Attack, 4, 8, 3),
+            ("ハイヒール", SkillKind::Heal, 8, 30, 8),
+            ("フレイム", SkillKind::Attack, 9, 20, 10),
+        ];
+        let repo = Self::default();
+        repo.state.borrow_mut().skills = skills
+            .iter()
+            .enumerate()
+            .map(
+                |(index, &(name, kind, mp_cost, power, learn_level))| Skill {
+                    skill_id: index as i32 + 1,
+                    name: name.to_string(),
+                    kind,
+                    mp_cost,
+                    power,
+                    learn_level,
+                },
+            )
+            .collect();
+
+        repo
+    }
+
+    // トレイトにない能力値やGOLDの書き換え。テストの準備に使う
+    pub fn update_user(&self, user: &User) {
+        self.state.borrow_mut().update_user(user);
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&MemoryState) -> T) -> T {
+        f(&self.state.borrow())
+    }
+
+    // 写しの上で書き換えて、成功したときだけ置き換える。失敗したら何も変わらない
+    fn transaction<T>(
+        &self,
+        f: impl FnOnce(&mut MemoryState) -> Result<T, Box<dyn StdError>>,
+    ) -> Result<T, Box<dyn StdError>> {
+        let mut state = self.state.borrow().clone();
+        let value = f(&mut state)?;
+        *self.state.borrow_mut() = state;
+
+        Ok(value)
+    }
+}
+
+impl Repository for MemoryRepository {
+    fn add_user(&self, npub: &str) -> Result<User, Box<dyn StdError>> {
+        self.transaction(|state| {
+            if state.users.iter().any(|stored| stored.user.npub == npub) {
+                return Err(Box::new(UserAlreadyExistsError {
+                    npub: npub.to_string(),
+                }));
+            }
+            let user = users::new_user(state.users.len() as i32 + 1, npub);
+            state.users.push(MemoryUser {
+                user: user.clone(),
+                regenerated_at: None,
+                log_style: None,
+                selected_skill: None,
+                zone_id: None,
+            });
+
+            Ok(user)
+        })
+    }
+
+    fn get_user_by_npub(&self, npub: &str) -> Result<User, Box<dyn StdError>> {
+        self.read(
+            |state| match state.users.iter().find(|stored| stored.user.npub == npub) {
+                Some(stored) => Ok(stored.user.clone()),
+                None => Err(Box::new(UserNotFoundError {
+                    npub: npub.to_string(),
+                }) as Box<dyn StdError>),
+            },
+        )
+    }
+
+    fn get_user_by_id(&self, user_id: i32) -> Result<Option<User>, Box<dyn StdError>> {
+        Ok(self.read(|state| state.user(user_id).map(|stored| stored.user.clone())))
+    }
+
+    fn apply_regeneration(
+        &self,
+        user: User,
+        hp_per_hour: i32,
+        mp_per_hour: i32,
+        now: i64,
+    ) -> Result<User, Box<dyn StdError>> {
+        self.transaction(|state| {
+            let regenerated_at = state
+                .user(user.user_id)
+                .and_then(|stored| stored.regenerated_at);
+            let user = match users::regenerate(&user, regenerated_at, hp_per_hour, mp_per_hour, now)
+            {
+                Some(user) => user,
+                None => return Ok(user),
+            };
+            if let Some(stored) = state.user_mut(user.user_id) {
+                stored.user.current_hp = user.current_hp;
+                stored.user.current_mp = user.current_mp;
+                stored.regenerated_at = Some(now);
+            }
+
+            Ok(user)
+        })
+    }
+
+    fn rest_at_inn(&self, user: &User, cost: i32, now: i64) -> Result<bool, Box<dyn StdError>> {
+        self.transaction(|state| {
+            if !state.pay_gold(user.user_id, cost) {
+                return Ok(false);
+            }
+            if let Some(stored) = state.user_mut(user.user_id) {
+                stored.user.current_hp = stored.user.max_hp;
+                stored.user.current_mp = stored.user.max_mp;
+                stored.regenerated_at = Some(now);
+            }
+
+            Ok(true)
+        })
+    }
+
+    fn allocate(&self, user: &User, stat: Stat, points: i32) -> Result<User, Box<dyn StdError>> {
+        let updated_user = stats::allocated_user(user, stat, points)?;
+        self.transaction(|state| {
+            state.update_user(&updated_user);
+            *state
+                .stat_allocations
+                .entry((user.user_id, stat.as_str().to_string()))
+                .or_insert(0) += points;
+
+            Ok(updated_user)
+        })
+    }
+
+    fn respec(&self, user: &User, cost: i32) -> Result<User, Box<dyn StdError>> {
+        self.transaction(|state| {
+            let allocations: Vec<(String, i32)> = state
+                .stat_allocations
+                .iter()
+                .filter(|((owner, _), points)| *owner == user.user_id && **points > 0)
+                .map(|((_, stat), points)| (stat.clone(), *points))
+                .collect();
+            let updated_user = stats::respecced_user(user, cost, &allocations)?;
+
+            state.update_user(&updated_user);
+            state
+                .stat_allocations
+                .retain(|(owner, _), _| *owner != user.user_id);
+
+            Ok(updated_user)
+        })
+    }
+
+    fn get_log_style(&self, user_id: i32) -> Result<Option<String>, Box<dyn StdError>> {
+        self.read(|state| match state.user(user_id) {
+            Some(stored) => Ok(stored.log_style.clone()),
+            None => Err(Box::new(rusqlite::Error::QueryReturnedNoRows) as Box<dyn StdError>),
+        })
+    }
+
+    fn set_log_style(&self, user_id: i32, log_style: &str) -> Result<(), Box<dyn StdError>> {
+        self.transaction(|state| {
+            if let Some(stored) = state.user_mut(user_id) {
+                stored.log_style = Some(log_style.to_string());
+            }
+
+            Ok(())
+        })
+    }
+
+    fn get_selected_skill(&self, user_id: i32) -> Result<Option<String>, Box<dyn StdError>> {
+        self.read(|state| match state.user(user_id) {
+            Some(stored) => Ok(stored.selected_skill.clone()),
+            None => Err(Box::new(rusqlite::Error::QueryReturnedNoRows) as Box<dyn StdError>),
+        })
+    }
+
+    fn set_selected_skill(
+        &self,
+        user_id: i32,
+        selected_skill: Option<&str>,
+    ) -> Result<(), Box<dyn StdError>> {
+        self.transaction(|state| {
+            if let Some(stored) = state.user_mut(user_id) {
+                stored.selected_skill = selected_skill.map(str::to_string);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn get_skills_for_level(&self, level: i32) -> Result<Vec<Skill>, Box<dyn StdError>> {
+        self.read(|state| {
+            let mut skills: Vec<Skill> = state
+                .skills
+                .iter()
+                .filter(|skill| skill.learn_level <= level)
+                .cloned()
+                .collect();
+            skills.sort_by_key(|skill| (skill.learn_level, skill.skill_id));
+
+            Ok(skills)
+        })
+    }
+
+    fn get_next_skill(&self, level: i32) -> Result<Option<Skill>, Box<dyn StdError>> {
+        self.read(|state| {
+            Ok(state
+                .skills
+                .iter()
+                .filter(|skill| skill.learn_level > level)
+                .min_by_key(|skill| (skill.learn_level, skill.skill_id))
+                .cloned())
+        })
+    }
+
+    fn spawn_monster(
+        &self,
+        monster_id: i32,
+        amount: i32,
+        variants: &[VariantConfig],
+    ) -> Result<Monster, Box<dyn StdError>> {
+        self.transaction(|state| {
+            let (master, _) = state.spawn_monsters(monster_id, amount, None, variants)?;
+
+            Ok(master)
+        })
+    }
+
+    fn spawn_for_run(
+        &self,
+        run_id: i64,
+        monster_ids: &[i32],
+        user_id: i32,
+        reserved_until: i64,
+        variants: &[VariantConfig],
+    ) -> Result<Vec<Monster>, Box<dyn StdError>> {
+        self.transaction(|state| {
+            let mut spawned_monsters = Vec::new();
+            for &monster_id in monster_ids {
+                let (_, ids) = state.spawn_monsters(monster_id, 1, Some(run_id), variants)?;
+                for id in ids {
+                    if !state.reserve_monster(id, user_id, reserved_until) {
+                        return Err(Box::new(MonsterError::NotReserved(id)));
+                    }
+                    if let Some(spawned) = state
+                        .monsters
+                        .iter()
+                        .find(|spawned| spawned.monster.id == id)
+                    {
+                        spawned_monsters.push(spawned.monster.clone());
+                    }
+                }
+            }
+
+            Ok(spawned_monsters)
+        })
+    }
+
+    fn choose_floor_monsters(
+        &self,
+        level: i32,
+        zone_id: i64,
+        config: &EncounterConfig,
+    ) -> Result<Vec<i32>, Box<dyn StdError>> {
+        let rows = self.read(|state| {
+            state
+                .masters
+                .iter()
+                .filter(|master| master.zone_id == Some(zone_id) && master.monster.status == 1)
+                .map(|master| EncounterRow {
+                    monster: master.monster.clone(),
+                    species: master.monster.id,
+                    rarity_weight: master.rarity_weight,
+                })
+                .collect()
+        });
+
+        Ok(monsters::choose_masters(rows, level, config))
+    }
+
+    fn get_random_monsters(
+        &self,
+        level: i32,
+        zone_id: Option<i64>,
+        user_id: i32,
+        now: i64,
+        config: &EncounterConfig,
+    ) -> Result<Vec<Monster>, Box<dyn StdError>> {
+        self.transaction(|state| {
+            // 予約が切れたモンスターを出現中に戻す
+            for spawned in state.monsters.iter_mut() {
+                if spawned.monster.status == 3
+                    && spawned.reserved_until.is_some_and(|until| until <= now)
+                {
+                    spawned.monster.status = 1;
+                    spawned.reserved_by = None;
+                    spawned.reserved_until = None;
+                }
+            }
+
+            let rows = state
+                .monsters
+                .iter()
+                .filter(|spawned| spawned.monster.status == 1 && spawned.dungeon_run_id.is_none())
+                .filter_map(|spawned| {
+                    let master = state
+                        .masters
+                        .iter()
+                        .find(|master| master.monster.id == spawned.species);
+                    let in_zone =
+                        zone_id.is_none() || master.and_then(|master| master.zone_id) == zone_id;
+                    in_zone.then(|| EncounterRow {
+                        monster: spawned.monster.clone(),
+                        species: spawned.species,
+                        rarity_weight: master.map_or(100, |master| master.rarity_weight),
+                    })
+                })
+                .collect();
+            let reserved_until = now + config.reservation_minutes.max(1) * 60;
+            monsters::choose_monsters(rows, level, config, |id| {
+                Ok(state.reserve_monster(id, user_id, reserved_until))
+            })
+        })
+    }
+
+    fn count_alive(&self, monster_id: i32) -> Result<i32, Box<dyn StdError>> {
+        self.read(|state| {
+            Ok(state
+                .monsters
+                .iter()
+                .filter(|spawned| {
+                    spawned.species == monster_id
+                        && matches!(spawned.monster.status, 1 | 3)
+                        && spawned.dungeon_run_id.is_none()
+                })
+                .count() as i32)
+        })
+    }
+
+    fn get_monster_master(&self, id: i32) -> Result<Option<Monster>, Box<dyn StdError>> {
+        Ok(self.read(|state| state.get_monster_master(id)))
+    }
+
+    // HP/MPはテーブルの初期値と同じ 10/5 にする
+    fn add_monster_master(&self, master: &NewMonsterMaster) -> Result<i32, Box<dyn StdError>> {
+        self.transaction(|state| {
+            let id = state.masters.len() as i32 + 1;
+            state.masters.push(MemoryMaster {
+                monster: Monster {
+                    id,
+                    level: master.level,
+                    status: 1,
+                    name: master.name.clone(),
+                    picture: master.picture.clone(),
+                    attack: master.attack,
+                    defense: master.defense,
+                    agility: master.agility,
+                    experience_reward: master.experience_reward,
+                    gold_reward: master.gold_reward,
+                    hp: 10,
+                    mp: 5,
+                    defeat_user_id: -1,
+                    luck: master.luck,
+                },
+                rarity_weight: master.rarity_weight,
+                zone_id: master.zone_id,
+            });
+
+            Ok(id)
+        })
+    }
+
+    fn apply_battle_result(
+        &self,
+        monsters: &[Monster],
+        result: &BattleResult,
+        battle_log: &str,
+        event_id: &str,
+    ) -> Result<i64, Box<dyn StdError>> {
+        self.transaction(|state| state.write_battle_result(monsters, result, battle_log, event_id))
+    }
+
+    fn get_battle_result_by_id(
+        &self,
+        battle_id: i64,
+    ) -> Result<Option<BattleRecord>, Box<dyn StdError>> {
+        Ok(self.read(|state| {
+            state
+                .battles
+                .iter()
+                .find(|record| record.battle_id == battle_id)
+                .cloned()
+        }))
+    }
+
+    fn get_battle_results_by_user(
+        &self,
+        user_id: i32,
+        limit: i32,
+        offset: i32,
+    ) -> Result<Vec<BattleRecord>, Box<dyn StdError>> {
+        Ok(self.read(|state| {
+            state
+                .battles
+                .iter()
+                .rev()
+                .filter(|record| record.user_id == user_id)
+                .skip(offset.max(0) as usize)
+                .take(limit.max(0) as usize)
+                .cloned()
+                .collect()
+        }))
+    }
+
+    fn get_battle_stats_by_user(&self, user_id: i32) -> Result<BattleStats, Box<dyn StdError>> {
+        Ok(self.read(|state| {
+            let count = |outcome: BattleOutcome| {
+                state
+                    .battles
+                    .iter()
+                    .filter(|record| record.user_id == user_id && record.outcome == outcome)
+                    .count() as i32
+            };
+
+            BattleStats {
+                victories: count(BattleOutcome::Victory),
+                defeats: count(BattleOutcome::Defeat),
+                escapes: count(BattleOutcome::Escape),
+            }
+        }))
+    }
+
+    fn get_item_by_name(&self, name: &str) -> Result<Option<Item>, Box<dyn StdError>> {
+        Ok(self.read(|state| state.items.iter().find(|item| item.name == name).cloned()))
+    }
+
+    fn give_item(
+        &self,
+        user_id: i32,
+        item_id: i32,
+        quantity: i32,
+    ) -> Result<(), Box<dyn StdError>> {
+        self.transaction(|state| {
+            state.give_item(user_id, item_id, quantity);
+
+            Ok(())
+        })
+    }
+
+    fn get_inventory(&self, user_id: i32) -> Result<Vec<InventoryEntry>, Box<dyn StdError>> {
+        Ok(self.read(|state| state.get_inventory(user_id)))
+    }
+
+    fn add_item(
+        &self,
+        name: &str,
+        item_type: &str,
+        attack_bonus: i32,
+        defense_bonus: i32,
+        agility_bonus: i32,
+    ) -> Result<Item, Box<dyn StdError>> {
+        self.transaction(|state| {
+            let item = Item {
+                item_id: state.items.len() as i32 + 1,
+                name: name.to_string(),
+                item_type: item_type.to_string(),
+                attack_bonus,
+                defense_bonus,
+                agility_bonus,
+            };
+            state.items.push(item.clone());
+
+            Ok(item)
+        })
+    }
+
+    fn get_equipment(&self, user_id: i32) -> Result<Vec<Item>, Box<dyn StdError>> {
+        Ok(self.read(|state| state.get_equipment(user_id)))
+    }
+
+    fn equip_item(&self, user_id: i32, name: &str) -> Result<Item, Box<dyn StdError>> {
+        self.transaction(|state| {
+            let entry = state
+                .get_inventory(user_id)
+                .into_iter()
+                .find(|entry| entry.item.name == name)
+                .ok_or_else(|| EquipError::ItemNotOwned(name.to_string()))?;
+            items::check_equippable(&entry.item)?;
+
+            state
+                .equipment
+                .insert((user_id, entry.item.item_type.clone()), entry.item.item_id);
+
+            Ok(entry.item)
+        })
+    }
+
+    fn unequip_slot(&self, user_id: i32, slot: &str) -> Result<Item, Box<dyn StdError>> {
+        self.transaction(|state| {
+            let item = state
+                .get_equipment(user_id)
+                .into_iter()
+                .find(|item| item.item_type == slot)
+                .ok_or_else(|| EquipError::SlotEmpty(slot.to_string()))?;
+
+            state.equipment.remove(&(user_id, slot.to_string()));
+
+            Ok(item)
+        })
+    }
+
+    fn get_shop_entries(&self) -> Result<Vec<ShopEntry>, Box<dyn StdError>> {
+        Ok(self.read(|state| {
+            let mut entries: Vec<ShopEntry> = state
+                .items
+                .iter()
+                .filter_map(|item| state.get_shop_entry_by_name(&item.name))
+                .collect();
+            entries.sort_by_key(|entry| entry.price);
+
+            entries
+        }))
+    }
+
+    fn set_shop_entry(
+        &self,
+        item_id: i32,
+        price: i32,
+        stock: i32,
+    ) -> Result<(), Box<dyn StdError>> {
+        self.transaction(|state| {
+            state.shop.insert(item_id, (price, stock));
+
+            Ok(())
+        })
+    }
+
+    fn remove_shop_entry(&self, item_id: i32) -> Result<bool, Box<dyn StdError>> {
+        self.transaction(|state| Ok(state.shop.remove(&item_id).is_some()))
+    }
+
+    fn buy_item(&self, user_id: i32, name: &str) -> Result<ShopEntry, Box<dyn StdError>> {
+        self.transaction(|state| {
+            let entry = state
+                .get_shop_entry_by_name(name)
+                .ok_or_else(|| ShopError::NotForSale(name.to_string()))?;
+            if entry.stock == 0 {
+                return Err(Box::new(ShopError::OutOfStock(name.to_string())));
+            }
+            if !state.pay_gold(user_id, entry.price) {
+                return Err(Box::new(ShopError::NotEnoughGold {
+                    price: entry.price,
+                    gold: state.gold(user_id),
+                }));
+            }
+
+            if entry.stock != UNLIMITED_STOCK {
+                state
+                    .shop
+                    .insert(entry.item.item_id, (entry.price, entry.stock - 1));
+            }
+            state.give_item(user_id, entry.item.item_id, 1);
+
+            Ok(entry)
+        })
+    }
+
+    fn sell_item(&self, user_id: i32, name: &str) -> Result<ShopEntry, Box<dyn StdError>> {
+        self.transaction(|state| {
+            let entry = state
+                .get_shop_entry_by_name(name)
+                .ok_or_else(|| ShopError::NotForSale(name.to_string()))?;
+            let owned = state
+                .get_inventory(user_id)
+                .into_iter()
+                .find(|owned| owned.item.item_id == entry.item.item_id)
+                .ok_or_else(|| ShopError::NotOwned(name.to_string()))?;
+            if owned.equipped && owned.quantity <= 1 {
+                return Err(Box::new(ShopError::Equipped(name.to_string())));
+            }
+
+            state.give_item(user_id, entry.item.item_id, -1);
+            state.add_gold(user_id, entry.sell_price());
+            if entry.stock != UNLIMITED_STOCK {
+                state
+                    .shop
+                    .insert(entry.item.item_id, (entry.price, entry.stock + 1));
+            }
+
+            Ok(entry)
+        })
+    }
+
+    fn get_zones(&self) -> Result<Vec<Zone>, Box<dyn StdError>> {
+        Ok(self.read(|state| {
+            let mut zones = state.zones.clone();
+            zones.sort_by_key(|zone| (zone.recommended_level, zone.zone_id));
+
+            zones
+        }))
+    }
+
+    fn get_zone_by_name(&self, name: &str) -> Result<Option<Zone>, Box<dyn StdError>> {
+        Ok(self.read(|state| state.zones.iter().find(|zone| zone.name == name).cloned()))
+    }
+
+    fn add_zone(
+        &self,
+        name: &str,
+        recommended_level: i32,
+        description: &str,
+    ) -> Result<Zone, Box<dyn StdError>> {
+        self.transaction(|state| {
+            let zone_id = match state.zones.iter_mut().find(|zone| zone.name == name) {
+                Some(zone) => {
+                    zone.recommended_level = recommended_level;
+                    zone.description = description.to_string();
+                    zone.zone_id
+                }
+                None => {
+                    let zone_id = state.zones.len() as i64 + 1;
+                    state.zones.push(Zone {
+                        zone_id,
+                        name: name.to_string(),
+                        recommended_level,
+                        description: description.to_string(),
+                    });
+                    zone_id
+                }
+            };
+
+            Ok(Zone {
+                zone_id,
+                name: name.to_string(),
+                recommended_level,
+                description: description.to_string(),
+            })
+        })
+    }
+
+    fn assign_monster(
+        &self,
+        monster_master_id: i32,
+        zone_id: i64,
+    ) -> Result<bool, Box<dyn StdError>> {
+        self.transaction(|state| {
+            match state
+                .masters
+                .iter_mut()
+                .find(|master| master.monster.id == monster_master_id)
+            {
+                Some(master) => {
+                    master.zone_id = Some(zone_id);
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        })
+    }
+
+    fn get_current_zone(&self, user_id: i32) -> Result<Option<Zone>, Box<dyn StdError>> {
+        Ok(self.read(|state| {
+            let zone_id = state.user(user_id)?.zone_id?;
+            state
+                .zones
+                .iter()
+                .find(|zone| zone.zone_id == zone_id)
+                .cloned()
+        }))
+    }
+
+    fn travel(&self, user_id: i32, zone: &Zone) -> Result<(), Box<dyn StdError>> {
+        self.transaction(|state| {
+            if let Some(stored) = state.user_mut(user_id) {
+                stored.zone_id = Some(zone.zone_id);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn get_party_by_user(&self, user_id: i32) -> Result<Option<Party>, Box<dyn StdError>> {
+        Ok(self.read(|state| state.get_party_by_user(user_id)))
+    }
+
+    fn create_party(&self, user_id: i32) -> Result<Party, Box<dyn StdError>> {
+        self.transaction(|state| {
+            if state.get_party_by_user(user_id).is_some() {
+                return Err(Box::new(PartyError::AlreadyInParty));
+            }
+
+            state.next_party_id += 1;
+            let party_id = state.next_party_id;
+            state.parties.insert(party_id, user_id);
+            state.party_members.push(MemoryPartyMember {
+                party_id,
+                user_id,
+                joined: true,
+            });
+
+            Ok(state.get_party(party_id).ok_or(PartyError::NotInParty)?)
+        })
+    }
+
+    fn invite(&self, leader_id: i32, user_id: i32) -> Result<Party, Box<dyn StdError>> {
+        self.transaction(|state| {
+            let party = state
+                .get_party_by_user(leader_id)
+                .ok_or(PartyError::NotInParty)?;
+            party::check_invite(&party, leader_id, user_id)?;
+
+            state.party_members.push(MemoryPartyMember {
+                party_id: party.party_id,
+                user_id,
+                joined: false,
+            });
+
+            Ok(state
+                .get_party(party.party_id)
+                .ok_or(PartyError::NotInParty)?)
+        })
+    }
+
+    fn join_party(&self, user_id: i32) -> Result<Party, Box<dyn StdError>> {
+        self.transaction(|state| {
+            if state.get_party_by_user(user_id).is_some() {
+                return Err(Box::new(PartyError::AlreadyInParty));
+            }
+
+            let member = state
+                .party_members
+                .iter_mut()
+                .filter(|member| member.user_id == user_id && !member.joined)
+                .max_by_key(|member| member.party_id)
+                .ok_or(PartyError::NoInvitation)?;
+            member.joined = true;
+            let party_id = member.party_id;
+
+            Ok(state.get_party(party_id).ok_or(PartyError::NotInParty)?)
+        })
+    }
+
+    fn leave_party(&self, user_id: i32) -> Result<bool, Box<dyn StdError>> {
+        self.transaction(|state| {
+            let party = state
+                .get_party_by_user(user_id)
+                .ok_or(PartyError::NotInParty)?;
+
+            let disbanded = party.leader_id == user_id;
+            if disbanded {
+                state
+                    .party_members
+                    .retain(|member| member.party_id != party.party_id);
+                state.parties.remove(&party.party_id);
+            } else {
+                state.party_members.retain(|member| {
+                    member.party_id != party.party_id || member.user_id != user_id
+                });
+            }
+
+            Ok(disbanded)
+        })
+    }
+
+    fn get_pending_duel_for(&self, opponent_id: i32) -> Result<Option<Duel>, Box<dyn StdError>> {
+        Ok(self.read(|state| {
+            state
+                .duels
+                .iter()
+                .rev()
+                .find(|duel| duel.opponent_id == opponent_id && duel.status == DuelStatus::Pending)
+                .map(duel_from)
+        }))
+    }
+
+    fn get_pending_duel_by(&self, challenger_id: i32) -> Result<Option<Duel>, Box<dyn StdError>> {
+        Ok(self.read(|state| {
+            state
+                .duels
+                .iter()
+                .rev()
+                .find(|duel| {
+                    duel.challenger_id == challenger_id && duel.status == DuelStatus::Pending
+                })
+                .map(duel_from)
+        }))
+    }
+
+    fn challenge(
+        &self,
+        challenger_id: i32,
+        opponent_id: i32,
+        wager: i32,
+        cooldown_minutes: i64,
+        now: i64,
+    ) -> Result<Duel, Box<dyn StdError>> {
+        duel::check_challenge(challenger_id, opponent_id, wager)?;
+        self.transaction(|state| {
+            let involved = |user_id: i32| user_id == challenger_id || user_id == opponent_id;
+            if state.duels.iter().any(|duel| {
+                duel.status == DuelStatus::Pending
+                    && (involved(duel.challenger_id) || involved(duel.opponent_id))
+            }) {
+                return Err(Box::new(DuelError::AlreadyPending));
+            }
+
+            let last_resolved = state
+                .duels
+                .iter()
+                .filter(|duel| {
+                    duel.status == DuelStatus::Finished
+                        && involved(duel.challenger_id)
+                        && involved(duel.opponent_id)
+                })
+                .filter_map(|duel| duel.resolved_at)
+                .max();
+            duel::check_cooldown(last_resolved, cooldown_minutes, now)?;
+
+            if wager > 0 && !state.pay_gold(challenger_id, wager) {
+                return Err(Box::new(DuelError::NotEnoughGold {
+                    wager,
+                    gold: state.gold(challenger_id),
+                }));
+            }
+            let stored = MemoryDuel {
+                duel_id: state.duels.len() as i64 + 1,
+                challenger_id,
+                opponent_id,
+                wager,
+                created_at: now,
+                status: DuelStatus::Pending,
+                resolved_at: None,
+            };
+            let duel = duel_from(&stored);
+            state.duels.push(stored);
+
+            Ok(duel)
+        })
+    }
+
+    fn close_duel(
+        &self,
+        duel: &Duel,
+        status: DuelStatus,
+        now: i64,
+    ) -> Result<(), Box<dyn StdError>> {
+        self.transaction(|state| {
+            let stored = state
+                .duels
+                .iter_mut()
+                .find(|stored| {
+                    stored.duel_id == duel.duel_id && stored.status == DuelStatus::Pending
+                })
+                .ok_or(DuelError::NoChallenge)?;
+            stored.status = status;
+            stored.resolved_at = Some(now);
+            state.add_gold(duel.challenger_id, duel.wager);
+
+            Ok(())
+        })
+    }
+
+    // 勝敗の記録（seed・snapshot・ログ）は読み返さないので、勝者への支払いと決着の時刻だけを残す
+    fn resolve_duel(
+        &self,
+        duel: &Duel,
+        winner_id: Option<i32>,
+        _seed: u64,
+        _snapshot: &BattleSnapshot,
+        _battle_log: &str,
+        now: i64,
+    ) -> Result<(), Box<dyn StdError>> {
+        self.transaction(|state| {
+            if duel.wager > 0 && !state.pay_gold(duel.opponent_id, duel.wager) {
+                return Err(Box::new(DuelError::NotEnoughGold {
+                    wager: duel.wager,
+                    gold: state.gold(duel.opponent_id),
+                }));
+            }
+            let stored = state
+                .duels
+                .iter_mut()
+                .find(|stored| {
+                    stored.duel_id == duel.duel_id && stored.status == DuelStatus::Pending
+                })
+                .ok_or(DuelError::NoChallenge)?;
+            stored.status = DuelStatus::Finished;
+            stored.resolved_at = Some(now);
+            match winner_id {
+                Some(winner_id) => state.add_gold(winner_id, duel.wager * 2),
+                None => {
+                    state.add_gold(duel.challenger_id, duel.wager);
+                    state.add_gold(duel.opponent_id, duel.wager);
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    fn get_dungeons(&self) -> Result<Vec<Dungeon>, Box<dyn StdError>> {
+        Ok(self.read(|state| state.dungeons.clone()))
+    }
+
+    fn get_dungeon_by_name(&self, name: &str) -> Result<Option<Dungeon>, Box<dyn StdError>> {
+        Ok(self.read(|state| {
+            state
+                .dungeons
+                .iter()
+                .find(|dungeon| dungeon.name == name)
+                .cloned()
+        }))
+    }
+
+    fn add_dungeon(
+        &self,
+        name: &str,
+        zone_id: i64,
+        floors: i32,
+        boss_monster_id: i32,
+    ) -> Result<Dungeon, Box<dyn StdError>> {
+        self.transaction(|state| {
+            let dungeon_id = match state.dungeons.iter().find(|dungeon| dungeon.name == name) {
+                Some(dungeon) => dungeon.dungeon_id,
+                None => state.dungeons.len() as i64 + 1,
+            };
+            let dungeon = Dungeon {
+                dungeon_id,
+                name: name.to_string(),
+                zone_id,
+                floors,
+                boss_monster_id,
+            };
+            match state
+                .dungeons
+                .iter_mut()
+                .find(|stored| stored.dungeon_id == dungeon_id)
+            {
+                Some(stored) => *stored = dungeon.clone(),
+                None => state.dungeons.push(dungeon.clone()),
+            }
+
+            Ok(dungeon)
+        })
+    }
+
+    fn get_active_run(&self, user_id: i32) -> Result<Option<DungeonRun>, Box<dyn StdError>> {
+        Ok(self.read(|state| state.get_active_run(user_id)))
+    }
+
+    fn start_run(
+        &self,
+        user_id: i32,
+        dungeon: &Dungeon,
+        current_hp: i32,
+        current_mp: i32,
+        _now: i64,
+    ) -> Result<DungeonRun, Box<dyn StdError>> {
+        self.transaction(|state| {
+            if let Some(run) = state.get_active_run(user_id) {
+                return Err(Box::new(DungeonError::AlreadyInRun(run.dungeon.name)));
+            }
+
+            state.runs.push(MemoryRun {
+                run_id: state.runs.len() as i64 + 1,
+                user_id,
+                dungeon_id: dungeon.dungeon_id,
+                status: RunStatus::Active,
+                floor: 0,
+                current_hp,
+                current_mp,
+                experience: 0,
+                gold: 0,
+                battle_log: String::new(),
+            });
+
+            Ok(state.get_active_run(user_id).ok_or(DungeonError::NoRun)?)
+        })
+    }
+
+    fn record_floor(
+        &self,
+        run: &DungeonRun,
+        result: &FloorResult,
+    ) -> Result<(), Box<dyn StdError>> {
+        self.transaction(|state| Ok(state.record_floor(run, result)?))
+    }
+
+    fn apply_floor_battle(
+        &self,
+        run: &DungeonRun,
+        floor: &FloorResult,
+        monsters: &[Monster],
+        result: &BattleResult,
+        battle_log: &str,
+        event_id: &str,
+    ) -> Result<i64, Box<dyn StdError>> {
+        self.transaction(|state| {
+            let battle_id = state.write_battle_result(monsters, result, battle_log, event_id)?;
+            state.record_floor(run, floor)?;
+
+            Ok(battle_id)
+        })
+    }
+
+    fn retreat(&self, run: &DungeonRun, _now: i64) -> Result<(), Box<dyn StdError>> {
+        self.transaction(|state| {
+            let stored = state
+                .runs
+                .iter_mut()
+                .find(|stored| stored.run_id == run.run_id && stored.status == RunStatus::Active)
+                .ok_or(DungeonError::NoRun)?;
+            stored.status = RunStatus::Retreated;
+            state.remove_run_monsters(run.run_id);
+
+            Ok(())
+        })
+    }
+
+    fn get_loot_for_monsters(
+        &self,
+        monsters: &[Monster],
+    ) -> Result<Vec<LootEntry>, Box<dyn StdError>> {
+        Ok(self.read(|state| {
+            let mut entries = Vec::new();
+            for monster in monsters {
+                let species = match state
+                    .monsters
+                    .iter()
+                    .find(|spawned| spawned.monster.id == monster.id)
+                {
+                    Some(spawned) => spawned.species,
+                    None => continue,
+                };
+                let mut loot: Vec<&MemoryLoot> = state
+                    .loot
+                    .iter()
+                    .filter(|entry| entry.monster_id == species)
+                    .collect();
+                loot.sort_by_key(|entry| entry.item_id);
+                for entry in loot {
+                    if let Some(item) = state
+                        .items
+                        .iter()
+                        .find(|item| item.item_id == entry.item_id)
+                    {
+                        entries.push(LootEntry {
+                            monster_id: monster.id,
+                            item_id: entry.item_id,
+                            item_name: item.name.clone(),
+                            probability: entry.probability,
+                            min_quantity: entry.min_quantity,
+                            max_quantity: entry.max_quantity,
+                        });
+                    }
+                }
+            }
+
+            entries
+        }))
+    }
+
+    fn set_loot_entry(
+        &self,
+        monster_master_id: i32,
+        item_id: i32,
+        probability: f64,
+        min_quantity: i32,
+        max_quantity: i32,
+    ) -> Result<(), Box<dyn StdError>> {
+        self.transaction(|state| {
+            let entry = MemoryLoot {
+                monster_id: monster_master_id,
+                item_id,
+                probability,
+                min_quantity,
+                max_quantity,
+            };
+            match state
+                .loot
+                .iter_mut()
+                .find(|stored| stored.monster_id == monster_master_id && stored.item_id == item_id)
+            {
+                Some(stored) => *stored = entry,
+                None => state.loot.push(entry),
+            }
+
+            Ok(())
+        })
+    }
+
+    fn spawn_raid(
+        &self,
+        monster: &Monster,
+        hp: i32,
+        experience_reward: i32,
+        gold_reward: i32,
+        _now: i64,
+    ) -> Result<Raid, Box<dyn StdError>> {
+        self.transaction(|state| {
+            if let Some(raid) = state.get_active_raid() {
+                return Err(Box::new(RaidError::AlreadyActive(raid.monster.name)));
+            }
+            let raid = MemoryRaid {
+                raid_id: state.raids.len() as i64 + 1,
+                monster: Monster {
+                    experience_reward,
+                    gold_reward,
+                    hp,
+                    status: 0,
+                    defeat_user_id: -1,
+                    ..monster.clone()
+                },
+                max_hp: hp,
+            };
+            state.raids.push(raid.clone());
+
+            Ok(MemoryState::raid_from(&raid))
+        })
+    }
+
+    fn get_active_raid(&self) -> Result<Option<Raid>, Box<dyn StdError>> {
+        Ok(self.read(|state| state.get_active_raid()))
+    }
+
+    fn get_latest_raid(&self) -> Result<Option<Raid>, Box<dyn StdError>> {
+        Ok(self.read(|state| state.raids.last().map(MemoryState::raid_from)))
+    }
+
+    fn record_attack(
+        &self,
+        raid: &Raid,
+        attack: &RaidAttack,
+        cooldown_minutes: i64,
+        now: i64,
+    ) -> Result<i32, Box<dyn StdError>> {
+        let user = &attack.result.updated_user;
+        self.transaction(|state| {
+            let last_attack_at = state
+                .contributions
+                .iter()
+                .find(|contribution| {
+                    contribution.raid_id == raid.raid_id && contribution.user_id == user.user_id
+                })
+                .map(|contribution| contribution.last_attack_at);
+            raid::check_cooldown(last_attack_at, cooldown_minutes, now)?;
+
+            let stored = state
+                .raids
+                .iter_mut()
+                .find(|stored| stored.raid_id == raid.raid_id && stored.monster.status == 0)
+                .ok_or(RaidError::NoRaid)?;
+            stored.monster.hp = (stored.monster.hp - attack.damage).max(0);
+            let hp = stored.monster.hp;
+            match state.contributions.iter_mut().find(|contribution| {
+                contribution.raid_id == raid.raid_id && contribution.user_id == user.user_id
+            }) {
+                Some(contribution) => {
+                    contribution.damage += attack.damage;
+                    contribution.attacks += 1;
+                    contribution.last_attack_at = now;
+                }
+                None => state.contributions.push(MemoryContribution {
+                    raid_id: raid.raid_id,
+                    user_id: user.user_id,
+                    damage: attack.damage,
+                    attacks: 1,
+                    last_attack_at: now,
+                }),
+            }
+            state.update_user(user);
+            state.add_battle_result(attack.result, attack.battle_log, attack.event_id);
+
+            Ok(hp)
+        })
+    }
+
+    fn get_contributions(&self, raid_id: i64) -> Result<Vec<Contribution>, Box<dyn StdError>> {
+        Ok(self.read(|state| state.get_contributions(raid_id)))
+    }
+
+    fn finish_raid(
+        &self,
+        raid: &Raid,
+        growth_config: &GrowthConfig,
+        _now: i64,
+    ) -> Result<Vec<RaidReward>, Box<dyn StdError>> {
+        self.transaction(|state| {
+            match state.raids.iter_mut().find(|stored| {
+                stored.raid_id == raid.raid_id
+                    && stored.monster.status == 0
+                    && stored.monster.hp <= 0
+            }) {
+                Some(stored) => stored.monster.status = 1,
+                None => return Ok(Vec::new()),
+            }
+
+            let contributions = state.get_contributions(raid.raid_id);
+            let mut rng = rand::thread_rng();
+            let shares = raid::split_rewards(raid, &contributions);
+            let mut rewards = Vec::new();
+            for (contribution, (experience, gold)) in contributions.iter().zip(shares) {
+                let user = match state
+                    .users
+                    .iter()
+                    .find(|stored| stored.user.npub == contribution.npub)
+                {
+                    Some(stored) => stored.user.clone(),
+                    None => {
+                        return Err(Box::new(UserNotFoundError {
+                            npub: contribution.npub.clone(),
+                        }))
+                    }
+                };
+                let updated_user =
+                    battle::grant_rewards(&mut rng, &user, experience, gold, growth_config);
+                state.update_user(&updated_user);
+                rewards.push(raid::reward_for(
+                    contribution,
+                    &user,
+                    &updated_user,
+                    experience,
+                    gold,
+                ));
+            }
+
+            Ok(rewards)
+        })
+    }
+}
+
+fn duel_from(duel: &MemoryDuel) -> Duel {
+    Duel {
+        duel_id: duel.duel_id,
+        challenger_id: duel.challenger_id,
+        opponent_id: duel.opponent_id,
+        wager: duel.wager,
+        created_at: duel.created_at,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::battle::BattleOutcome;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn weak_master(name: &str) -> NewMonsterMaster {
+        NewMonsterMaster {
+            level: 1,
+            name: name.to_string(),
+            picture: String::new(),
+            attack: 1,
+            defense: 1,
+            agility: 1,
+            experience_reward: 10,
+            gold_reward: 5,
+            luck: 0,
+            rarity_weight: 100,
+            zone_id: None,
+        }
+    }
+
+    fn one_monster() -> EncounterConfig {
+        EncounterConfig {
+            max_monsters: 1,
+            ..EncounterConfig::default()
+        }
+    }
+
+    fn add_user(repo: &MemoryRepository, npub: &str, gold: i32, strength: i32) -> User {
+        let mut user = repo.add_user(npub).unwrap();
+        user.gold = gold;
+        user.attack += strength;
+        user.agility += strength;
+        repo.update_user(&user);
+
+        user
+    }
+
+    fn gold(repo: &dyn Repository, user_id: i32) -> i32 {
+        repo.get_user_by_id(user_id).unwrap().unwrap().gold
+    }
+
+    // 出現中のモンスター1体と戦って、結果を保存する前まで
+    fn fight_one(repo: &dyn Repository, user: &User) -> (Vec<Monster>, BattleResult) {
+        let monsters = repo
+            .get_random_monsters(user.level, None, user.user_id, NOW, &one_monster())
+            .unwrap();
+        let snapshot = battle::load_snapshot(repo, user, &[], &monsters).unwrap();
+        let result = battle::simulate_battle(
+            7,
+            user,
+            &[],
+            &monsters,
+            &[],
+            snapshot,
+            &GrowthConfig::default(),
+        );
+
+        (monsters, result)
+    }
+
+    #[test]
+    fn leveling_battle_is_saved_through_the_repository() {
+        let memory = MemoryRepository::new();
+        let user = add_user(&memory, "npub_hero", 0, 100);
+        let repo: &dyn Repository = &memory;
+        let master_id = repo.add_monster_master(&weak_master("スライム")).unwrap();
+        repo.spawn_monster(master_id, 1, &[]).unwrap();
+
+        let (monsters, result) = fight_one(repo, &user);
+        assert_eq!(result.outcome, BattleOutcome::Victory);
+        let battle_id = repo
+            .apply_battle_result(&monsters, &result, "log", "event")
+            .unwrap();
+
+        assert_eq!(repo.count_alive(master_id).unwrap(), 0);
+        let saved = repo.get_user_by_id(user.user_id).unwrap().unwrap();
+        assert_eq!(saved.experience, result.updated_user.experience);
+        assert_eq!(saved.gold, result.gold_gain);
+        let record = repo.get_battle_result_by_id(battle_id).unwrap().unwrap();
+        assert_eq!(record.seed, 7);
+        assert_eq!(record.engine_version, battle::ENGINE_VERSION);
+        assert_eq!(record.events.as_deref(), Some(result.events.as_slice()));
+        assert_eq!(
+            repo.get_battle_stats_by_user(user.user_id)
+                .unwrap()
+                .victories,
+            1
+        );
+    }
+
+    #[test]
+    fn battle_history_is_paged_newest_first() {
+        let memory = MemoryRepository::new();
+        let user = add_user(&memory, "npub_hero", 0, 100);
+        let repo: &dyn Repository = &memory;
+        let master_id = repo.add_monster_master(&weak_master("スライム")).unwrap();
+        let mut battle_ids = Vec::new();
+        for _ in 0..3 {
+            repo.spawn_monster(master_id, 1, &[]).unwrap();
+            let (monsters, result) = fight_one(repo, &user);
+            battle_ids.push(
+                repo.apply_battle_result(&monsters, &result, "log", "event")
+                    .unwrap(),
+            );
+        }
+
+        let page: Vec<i64> = repo
+            .get_battle_results_by_user(user.user_id, 2, 1)
+            .unwrap()
+            .iter()
+            .map(|record| record.battle_id)
+            .collect();
+
+        assert_eq!(page, vec![battle_ids[1], battle_ids[0]]);
+    }
+
+    #[test]
+    fn lost_reservation_saves_nothing() {
+        let memory = MemoryRepository::new();
+        let user = add_user(&memory, "npub_hero", 0, 100);
+        let rival = add_user(&memory, "npub_rival", 0, 0);
+        let repo: &dyn Repository = &memory;
+        let master_id = repo.add_monster_master(&weak_master("スライム")).unwrap();
+        repo.spawn_monster(master_id, 1, &[]).unwrap();
+        let (monsters, result) = fight_one(repo, &user);
+        // 予約が切れて、ほかのユーザーが同じモンスターと戦い始めた
+        let later = NOW + one_monster().reservation_minutes * 60;
+        let taken = repo
+            .get_random_monsters(rival.level, None, rival.user_id, later, &one_monster())
+            .unwrap();
+        assert_eq!(taken.len(), 1);
+
+        let e = repo
+            .apply_battle_result(&monsters, &result, "log", "event")
+            .unwrap_err();
+
+        assert!(matches!(
+            e.downcast_ref::<MonsterError>(),
+            Some(MonsterError::NotReserved(_))
+        ));
+        let saved = repo.get_user_by_id(user.user_id).unwrap().unwrap();
+        assert_eq!(saved.experience, user.experience);
+        assert!(repo
+            .get_battle_results_by_user(user.user_id, 10, 0)
+            .unwrap()
+            .is_empty());
+        assert_eq!(repo.count_alive(master_id).unwrap(), 1);
+    }
+
+    #[test]
+    fn duel_wager_goes_to_the_winner() {
+        let memory = MemoryRepository::new();
+        let challenger = add_user(&memory, "npub_challenger", 100, 0);
+        let opponent = add_user(&memory, "npub_opponent", 100, 0);
+        let repo: &dyn Repository = &memory;
+
+        let duel = repo
+            .challenge(challenger.user_id, opponent.user_id, 50, 60, NOW)
+            .unwrap();
+        assert_eq!(gold(repo, challenger.user_id), 50);
+        let snapshot = battle::load_duel_snapshot(repo, &challenger, &opponent).unwrap();
+        repo.resolve_duel(&duel, Some(opponent.user_id), 1, &snapshot, "log", NOW)
+            .unwrap();
+
+        assert_eq!(gold(repo, challenger.user_id), 50);
+        assert_eq!(gold(repo, opponent.user_id), 150);
+        assert!(repo
+            .get_pending_duel_for(opponent.user_id)
+            .unwrap()
+            .is_none());
+        let e = repo
+            .challenge(challenger.user_id, opponent.user_id, 0, 60, NOW + 60)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            e.downcast_ref::<DuelError>(),
+            Some(DuelError::Cooldown {
+                remaining_minutes: 59
+            })
+        ));
+    }
+
+    #[test]
+    fn leader_leaving_disbands_the_party() {
+        let memory = MemoryRepository::new();
+        let leader = add_user(&memory, "npub_leader", 0, 0);
+        let member = add_user(&memory, "npub_member", 0, 0);
+        let repo: &dyn Repository = &memory;
+
+        repo.create_party(leader.user_id).unwrap();
+        repo.invite(leader.user_id, member.user_id).unwrap();
+        let party = repo.join_party(member.user_id).unwrap();
+        let members: Vec<i32> = party.joined_members().map(|m| m.user_id).collect();
+        assert_eq!(members, vec![leader.user_id, member.user_id]);
+
+        assert!(repo.leave_party(leader.user_id).unwrap());
+        assert!(repo.get_party_by_user(member.user_id).unwrap().is_none());
+    }
+
+    #[test]
+    fn dungeon_monsters_stay_out_of_the_world() {
+        let memory = MemoryRepository::new();
+        let user = add_user(&memory, "npub_hero", 0, 0);
+        let repo: &dyn Repository = &memory;
+        let zone = repo.add_zone("はじまりの森", 1, "").unwrap();
+        let master_id = repo
+            .add_monster_master(&NewMonsterMaster {
+                zone_id: Some(zone.zone_id),
+                ..weak_master("スライム")
+            })
+            .unwrap();
+        let dungeon = repo
+            .add_dungeon("森の洞窟", zone.zone_id, 3, master_id)
+            .unwrap();
+        let run = repo.start_run(user.user_id, &dungeon, 10, 5, NOW).unwrap();
+
+        let ids = repo
+            .choose_floor_monsters(user.level, zone.zone_id, &one_monster())
+            .unwrap();
+        let spawned = repo
+            .spawn_for_run(run.run_id, &ids, user.user_id, NOW + 60, &[])
+            .unwrap();
+
+        assert_eq!(ids, vec![master_id]);
+        assert_eq!(spawned.len(), 1);
+        assert_eq!(spawned[0].status, 3);
+        assert_eq!(repo.count_alive(master_id).unwrap(), 0);
+        assert!(repo
+            .get_random_monsters(user.level, None, user.user_id, NOW, &one_monster())
+            .unwrap()
+            .is_empty());
+        let e = repo
+            .start_run(user.user_id, &dungeon, 10, 5, NOW)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            e.downcast_ref::<DungeonError>(),
+            Some(DungeonError::AlreadyInRun(_))
+        ));
+    }
+
+    #[test]
+    fn retreat_clears_the_run_monsters() {
+        let memory = MemoryRepository::new();
+        let user = add_user(&memory, "npub_hero", 0, 0);
+        let repo: &dyn Repository = &memory;
+        let zone = repo.add_zone("はじまりの森", 1, "").unwrap();
+        let master_id = repo
+            .add_monster_master(&NewMonsterMaster {
+                zone_id: Some(zone.zone_id),
+                ..weak_master("スライム")
+            })
+            .unwrap();
+        repo.spawn_monster(master_id, 1, &[]).unwrap();
+        let dungeon = repo
+            .add_dungeon("森の洞窟", zone.zone_id, 3, master_id)
+            .unwrap();
+        let run = repo.start_run(user.user_id, &dungeon, 10, 5, NOW).unwrap();
+        repo.spawn_for_run(run.run_id, &[master_id], user.user_id, NOW + 60, &[])
+            .unwrap();
+
+        repo.retreat(&run, NOW).unwrap();
+
+        let run_ids: Vec<Option<i64>> = memory.read(|state| {
+            state
+                .monsters
+                .iter()
+                .map(|spawned| spawned.dungeon_run_id)
+                .collect()
+        });
+        assert_eq!(run_ids, vec![None]);
+        assert_eq!(repo.count_alive(master_id).unwrap(), 1);
+    }
+
+    // レイドボスと戦い、damage を与えたことにして記録する
+    fn attack_raid(
+        repo: &dyn Repository,
+        raid: &Raid,
+        user: &User,
+        damage: i32,
+    ) -> Result<i32, Box<dyn StdError>> {
+        let snapshot =
+            battle::load_snapshot(repo, user, &[], std::slice::from_ref(&raid.monster)).unwrap();
+        let fight = battle::replay(7, &snapshot);
+        let result = raid::battle_result(raid, user, 7, snapshot, fight);
+        repo.record_attack(
+            raid,
+            &RaidAttack {
+                result: &result,
+                damage,
+                battle_log: "log",
+                event_id: "event",
+            },
+            10,
+            NOW,
+        )
+    }
+
+    #[test]
+    fn raid_rewards_are_shared_by_damage() {
+        let memory = MemoryRepository::new();
+        let first = add_user(&memory, "npub_first", 0, 0);
+        let second = add_user(&memory, "npub_second", 0, 0);
+        let repo: &dyn Repository = &memory;
+        let master_id = repo.add_monster_master(&weak_master("ドラゴン")).unwrap();
+        let master = repo.get_monster_master(master_id).unwrap().unwrap();
+        let raid = repo.spawn_raid(&master, 30, 90, 30, NOW).unwrap();
+
+        assert_eq!(attack_raid(repo, &raid, &first, 20).unwrap(), 10);
+        let e = attack_raid(repo, &raid, &first, 20).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<RaidError>(),
+            Some(RaidError::Cooldown { .. })
+        ));
+        assert_eq!(attack_raid(repo, &raid, &second, 10).unwrap(), 0);
+        // クールダウン中の攻撃は戦歴に残らない
+        let history = repo
+            .get_battle_results_by_user(first.user_id, 10, 0)
+            .unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].monster_id, master_id);
+        let rewards = repo
+            .finish_raid(&raid, &GrowthConfig::default(), NOW)
+            .unwrap();
+
+        let golds: Vec<i32> = rewards.iter().map(|reward| reward.gold).collect();
+        assert_eq!(golds, vec![20, 10]);
+        assert_eq!(gold(repo, first.user_id), 20);
+        assert!(repo.get_active_raid().unwrap().is_none());
+        assert!(repo
+            .finish_raid(&raid, &GrowthConfig::default(), NOW)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn equipped_item_cannot_be_sold_out() {
+        let memory = MemoryRepository::new();
+        let user = add_user(&memory, "npub_shopper", 100, 0);
+        let repo: &dyn Repository = &memory;
+        let item = repo.add_item("どうのつるぎ", "weapon", 3, 0, 0).unwrap();
+        repo.set_shop_entry(item.item_id, 100, 1).unwrap();
+
+        repo.buy_item(user.user_id, "どうのつるぎ").unwrap();
+        repo.equip_item(user.user_id, "どうのつるぎ").unwrap();
+        let e = repo.sell_item(user.user_id, "どうのつるぎ").err().unwrap();
+
+        assert!(matches!(
+            e.downcast_ref::<ShopError>(),
+            Some(ShopError::Equipped(_))
+        ));
+        assert_eq!(gold(repo, user.user_id), 0);
+        assert_eq!(repo.get_equipment_bonus(user.user_id).unwrap().attack, 3);
+        assert!(repo.get_inventory(user.user_id).unwrap()[0].equipped);
+    }
+}
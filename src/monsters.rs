@@ -3,11 +3,14 @@ use rusqlite::{Connection, Error, Result};
 use std::error::Error as StdError;
 use std::fmt;
 
+use crate::config::{EncounterConfig, VariantConfig};
+
 // monsterの情報を保持する構造体
+#[derive(Clone)]
 pub struct Monster {
     pub id: i32,
     pub level: i32,
-    pub status: i32,
+    pub status: i32, // 1: 出現中 2: 倒された 3: 戦闘中（ほかのユーザーは戦えない）
     pub name: String,
     pub picture: String,
     pub attack: i32,
@@ -18,6 +21,7 @@ pub struct Monster {
     pub hp: i32,
     pub mp: i32,
     pub defeat_user_id: i32,
+    pub luck: i32, // 会心の一撃の出やすさ
 }
 
 fn distribute_bonus_points(bonus_min: i32, bonus_max: i32) -> (i32, i32, i32, i32, i32) {
@@ -59,19 +63,82 @@ fn distribute_bonus_points(bonus_min: i32, bonus_max: i32) -> (i32, i32, i32, i3
     ) // 各属性のボーナスポイントを返す
 }
 
+// 召喚したモンスターが変わり種になるかを決める（どれにも当たらなければNone）
+fn roll_variant<'a, R: Rng>(
+    rng: &mut R,
+    variants: &'a [VariantConfig],
+) -> Option<&'a VariantConfig> {
+    let mut point: f64 = rng.gen();
+    variants.iter().find(|variant| {
+        point -= variant.chance.max(0.0);
+        point < 0.0
+    })
+}
+
+fn multiply(value: i32, multiplier: f64) -> i32 {
+    (value as f64 * multiplier).round() as i32
+}
+
+// マスターのモンスターにボーナスポイントを振り分け、variants の確率で変わり種にしたモンスター
+// （id と status はマスターのまま）と、なった変わり種を返す
+pub(crate) fn roll_spawn<'a, R: Rng>(
+    rng: &mut R,
+    master: &Monster,
+    variants: &'a [VariantConfig],
+) -> (Monster, Option<&'a VariantConfig>) {
+    let (hp_bonus, mp_bonus, attack_bonus, defense_bonus, agility_bonus) =
+        distribute_bonus_points(rng.gen_range(1..3), rng.gen_range(4..10));
+    let variant = roll_variant(rng, variants);
+    let (name, stat_multiplier, reward_multiplier) = match variant {
+        Some(variant) => (
+            format!("{}{}", variant.prefix, master.name),
+            variant.stat_multiplier,
+            variant.reward_multiplier,
+        ),
+        None => (master.name.clone(), 1.0, 1.0),
+    };
+    let monster = Monster {
+        name,
+        attack: multiply(master.attack + attack_bonus, stat_multiplier),
+        defense: multiply(master.defense + defense_bonus, stat_multiplier),
+        agility: multiply(master.agility + agility_bonus, stat_multiplier),
+        experience_reward: multiply(master.experience_reward, reward_multiplier),
+        gold_reward: multiply(master.gold_reward, reward_multiplier),
+        hp: multiply(master.hp + hp_bonus, stat_multiplier),
+        mp: master.mp + mp_bonus,
+        ..master.clone()
+    };
+
+    (monster, variant)
+}
+
+// variants の確率で、名前に prefix がつき能力値と報酬が増えた変わり種になる
 pub fn spawn_monster(
     conn: &Connection,
     monster_id: i32,
     amount: i32,
+    variants: &[VariantConfig],
 ) -> Result<Monster, Box<dyn StdError>> {
-    let monster = get_monster_by_id(conn, monster_id).unwrap();
-    let mut rng = rand::thread_rng();
+    let monster = get_monster_by_id(conn, monster_id)?.ok_or(MonsterError::NotFound(monster_id))?;
+    insert_spawned(conn, &monster, amount, None, variants)?;
 
-    for n in 0..amount {
-        println!("index:{}", n);
-        let (hp_bonus, mp_bonus, attack_bonus, defense_bonus, agility_bonus) =
-            distribute_bonus_points(rng.gen_range(1..3), rng.gen_range(4..10));
-        let result = conn.execute(
+    Ok(monster)
+}
+
+// マスターから amount 体を召喚して、召喚したモンスターの id を返す
+// dungeon_run_id をつけたモンスターはその探索専用で、.leveling の出現候補にも再出現の数にも入らない
+fn insert_spawned(
+    conn: &Connection,
+    master: &Monster,
+    amount: i32,
+    dungeon_run_id: Option<i64>,
+    variants: &[VariantConfig],
+) -> Result<Vec<i32>, Box<dyn StdError>> {
+    let mut rng = rand::thread_rng();
+    let mut ids = Vec::new();
+    for _ in 0..amount {
+        let (spawned, variant) = roll_spawn(&mut rng, master, variants);
+        conn.execute(
             "INSERT INTO monsters (
                     monster_id,
                     level,
@@ -84,32 +151,102 @@ pub fn spawn_monster(
                     experience_reward,
                     gold_reward,
                     hp,
-                    mp)
-               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                    mp,
+                    luck,
+                    variant,
+                    dungeon_run_id)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
             rusqlite::params![
-                monster_id,
-                monster.level,
-                monster.status,
-                monster.name,
-                monster.picture,
-                monster.attack + attack_bonus,
-                monster.defense + defense_bonus,
-                monster.agility + agility_bonus,
-                monster.experience_reward,
-                monster.gold_reward,
-                monster.hp + hp_bonus,
-                monster.mp + mp_bonus
+                master.id,
+                spawned.level,
+                spawned.status,
+                spawned.name,
+                spawned.picture,
+                spawned.attack,
+                spawned.defense,
+                spawned.agility,
+                spawned.experience_reward,
+                spawned.gold_reward,
+                spawned.hp,
+                spawned.mp,
+                spawned.luck,
+                variant.map(|variant| variant.name.as_str()),
+                dungeon_run_id
             ],
-        );
-        if result.is_err() {
-            println!("Error inserting monster: {:?}", result.err().unwrap());
+        )?;
+        ids.push(conn.last_insert_rowid() as i32);
+    }
+
+    Ok(ids)
+}
+
+// ダンジョンの探索専用に monster_ids の種類を1体ずつ召喚して、user_id が予約したモンスターを返す
+pub fn spawn_for_run(
+    conn: &Connection,
+    run_id: i64,
+    monster_ids: &[i32],
+    user_id: i32,
+    reserved_until: i64,
+    variants: &[VariantConfig],
+) -> Result<Vec<Monster>, Box<dyn StdError>> {
+    let tx = conn.unchecked_transaction()?;
+    let mut monsters = Vec::new();
+    for &monster_id in monster_ids {
+        let master =
+            get_monster_by_id(&tx, monster_id)?.ok_or(MonsterError::NotFound(monster_id))?;
+        for id in insert_spawned(&tx, &master, 1, Some(run_id), variants)? {
+            if !reserve_monster(&tx, id, user_id, reserved_until)? {
+                return Err(Box::new(MonsterError::NotReserved(id)));
+            }
+            monsters.push(tx.query_row(
+                "SELECT * FROM monsters WHERE id = ?1",
+                rusqlite::params![id],
+                monster_from_row,
+            )?);
         }
     }
-    Ok(monster)
+    tx.commit()?;
+
+    Ok(monsters)
+}
+
+// まだ倒されていないモンスターの数（戦闘中のモンスターも含む）
+// ダンジョンの探索専用のモンスターは数えない
+pub fn count_alive(conn: &Connection, monster_id: i32) -> Result<i32, Box<dyn StdError>> {
+    let count = conn.query_row(
+        "SELECT COUNT(*) FROM monsters
+        WHERE monster_id = ?1 AND status IN (1, 3) AND dungeon_run_id IS NULL",
+        rusqlite::params![monster_id],
+        |row| row.get(0),
+    )?;
+
+    Ok(count)
 }
 
-pub fn get_monster_by_id(conn: &Connection, id: i32) -> Result<Monster, Box<dyn StdError>> {
-    let result = conn.query_row(
+// monster_master の列を id, level, status, name, picture, attack, defense, agility,
+// experience_reward, gold_reward, hp, mp, luck の順に読んだ行から作る
+fn master_from_row(row: &rusqlite::Row) -> Result<Monster> {
+    Ok(Monster {
+        id: row.get(0)?,
+        level: row.get(1)?,
+        status: row.get(2)?,
+        name: row.get(3)?,
+        picture: row.get(4)?,
+        attack: row.get(5)?,
+        defense: row.get(6)?,
+        agility: row.get(7)?,
+        experience_reward: row.get(8)?,
+        gold_reward: row.get(9)?,
+        hp: row.get(10)?,
+        mp: row.get(11)?,
+        defeat_user_id: -1,
+        luck: row.get(12)?,
+    })
+}
+
+// monster_master のモンスター（なければNone）
+pub fn get_monster_by_id(conn: &Connection, id: i32) -> Result<Option<Monster>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(
         "SELECT
           id,
           level,
@@ -122,74 +259,687 @@ pub fn get_monster_by_id(conn: &Connection, id: i32) -> Result<Monster, Box<dyn
           experience_reward,
           gold_reward,
           hp,
-          mp
+          mp,
+          luck
         FROM monster_master WHERE id = ?1 and status = 1",
-        rusqlite::params![id],
-        |row| {
-            Ok(Monster {
-                id: row.get(0)?,
-                level: row.get(1)?,
-                status: row.get(2)?,
-                name: row.get(3)?,
-                picture: row.get(4)?,
-                attack: row.get(5)?,
-                defense: row.get(6)?,
-                agility: row.get(7)?,
-                experience_reward: row.get(8)?,
-                gold_reward: row.get(9)?,
-                hp: row.get(10)?,
-                mp: row.get(11)?,
-                defeat_user_id: -1,
+    )?;
+    let mut monster_iter = statement.query_map(rusqlite::params![id], master_from_row)?;
+
+    Ok(monster_iter.next().transpose()?)
+}
+
+// .add monster で monster_master に追加する内容。HP/MPはテーブルの初期値のまま
+pub struct NewMonsterMaster {
+    pub level: i32,
+    pub name: String,
+    pub picture: String,
+    pub attack: i32,
+    pub defense: i32,
+    pub agility: i32,
+    pub experience_reward: i32,
+    pub gold_reward: i32,
+    pub luck: i32,
+    pub rarity_weight: i32,
+    pub zone_id: Option<i64>,
+}
+
+// monster_master に追加して、割り当てた id を返す
+pub fn add_monster_master(
+    conn: &Connection,
+    master: &NewMonsterMaster,
+) -> Result<i32, Box<dyn StdError>> {
+    conn.execute(
+        "INSERT INTO monster_master (
+            level, name, picture, attack, defense, agility,
+            experience_reward, gold_reward, luck, rarity_weight, zone_id)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        rusqlite::params![
+            master.level,
+            master.name,
+            master.picture,
+            master.attack,
+            master.defense,
+            master.agility,
+            master.experience_reward,
+            master.gold_reward,
+            master.luck,
+            master.rarity_weight,
+            master.zone_id
+        ],
+    )?;
+
+    Ok(conn.last_insert_rowid() as i32)
+}
+
+fn monster_from_row(row: &rusqlite::Row) -> Result<Monster> {
+    Ok(Monster {
+        id: row.get(0)?,
+        level: row.get(2)?,
+        status: row.get(3)?,
+        name: row.get(4)?,
+        picture: row.get(5)?,
+        attack: row.get(6)?,
+        defense: row.get(7)?,
+        agility: row.get(8)?,
+        experience_reward: row.get(9)?,
+        gold_reward: row.get(10)?,
+        hp: row.get(11)?,
+        mp: row.get(12)?,
+        defeat_user_id: row.get(13)?,
+        luck: row.get(14)?,
+    })
+}
+
+#[derive(Debug)]
+pub(crate) enum MonsterError {
+    NotFound(i32),
+    NotReserved(i32),
+}
+
+impl fmt::Display for MonsterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MonsterError::NotFound(id) => write!(f, "Monster master {} not found", id),
+            MonsterError::NotReserved(id) => {
+                write!(f, "Monster {} is not reserved by this user", id)
+            }
+        }
+    }
+}
+
+impl StdError for MonsterError {}
+
+// 出現中のモンスターを戦闘中にして、reserved_until までほかのユーザーが選べないようにする
+// すでにほかのユーザーが予約していたら false
+pub fn reserve_monster(
+    conn: &Connection,
+    id: i32,
+    user_id: i32,
+    reserved_until: i64,
+) -> Result<bool, Box<dyn StdError>> {
+    let updated = conn.execute(
+        "UPDATE monsters SET status = 3, reserved_by = ?1, reserved_until = ?2
+        WHERE id = ?3 AND status = 1",
+        rusqlite::params![user_id, reserved_until, id],
+    )?;
+
+    Ok(updated > 0)
+}
+
+// 倒せなかったモンスターを出現中に戻す
+pub fn release_monster(
+    conn: &Connection,
+    monster: &Monster,
+    user_id: i32,
+) -> Result<(), Box<dyn StdError>> {
+    conn.execute(
+        "UPDATE monsters SET status = 1, reserved_by = NULL, reserved_until = NULL
+        WHERE id = ?1 AND status = 3 AND reserved_by = ?2",
+        rusqlite::params![monster.id, user_id],
+    )?;
+
+    Ok(())
+}
+
+// 戦闘の途中で失敗したなどで予約が切れたモンスターを出現中に戻し、戻した数を返す
+pub fn release_expired_reservations(
+    conn: &Connection,
+    now: i64,
+) -> Result<usize, Box<dyn StdError>> {
+    let released = conn.execute(
+        "UPDATE monsters SET status = 1, reserved_by = NULL, reserved_until = NULL
+        WHERE status = 3 AND reserved_until <= ?1",
+        rusqlite::params![now],
+    )?;
+
+    Ok(released)
+}
+
+// 出現候補のモンスターと、その出やすさ
+struct Candidate {
+    monster: Monster,
+    species: i32, // monster_master の id
+    weight: f64,
+}
+
+// 出現中のモンスター1体と、その種類と出やすさ
+pub(crate) struct EncounterRow {
+    pub(crate) monster: Monster,
+    pub(crate) species: i32, // monster_master の id
+    pub(crate) rarity_weight: i32,
+}
+
+// zone_id を指定したときは、その地域に割り当てられたモンスターだけを返す
+// ダンジョンの探索専用のモンスターは返さない
+fn encounter_rows(
+    conn: &Connection,
+    zone_id: Option<i64>,
+) -> Result<Vec<EncounterRow>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(
+        "SELECT monsters.*, COALESCE(monster_master.rarity_weight, 100) AS rarity_weight
+        FROM monsters
+        LEFT JOIN monster_master ON monster_master.id = monsters.monster_id
+        WHERE monsters.status=1 AND monsters.dungeon_run_id IS NULL
+            AND (?1 IS NULL OR monster_master.zone_id = ?1)",
+    )?;
+    let rows = statement
+        .query_map(rusqlite::params![zone_id], |row| {
+            Ok(EncounterRow {
+                monster: monster_from_row(row)?,
+                species: row.get(1)?,
+                rarity_weight: row.get("rarity_weight")?,
             })
-        },
-    );
-
-    result.map_err(|e| Box::new(e) as Box<dyn StdError>)
-}
-
-// モンスターテーブルからランダムにモンスターを取得する関数
-pub fn get_random_monster(conn: &Connection) -> Result<Option<Monster>, Box<dyn StdError>> {
-    // モンスターテーブルの行数を取得
-    let mut statement =
-        conn.prepare("SELECT * FROM monsters WHERE status=1 ORDER BY RANDOM() LIMIT 1")?;
-    let mut monster_iter = statement.query_map([], |row| {
-        Ok(Monster {
-            id: row.get(0)?,
-            level: row.get(2)?,
-            status: row.get(3)?,
-            name: row.get(4)?,
-            picture: row.get(5)?,
-            attack: row.get(6)?,
-            defense: row.get(7)?,
-            agility: row.get(8)?,
-            experience_reward: row.get(9)?,
-            gold_reward: row.get(10)?,
-            hp: row.get(11)?,
-            mp: row.get(12)?,
-            defeat_user_id: row.get(13)?,
+        })?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(rows)
+}
+
+// 出やすさ = rarity_weight * (level_window + 1 - レベル差)
+// レベル差が level_window を超えるモンスターは候補にならない（候補がいなければ遭遇しない）
+fn encounter_candidates(rows: Vec<EncounterRow>, level: i32, level_window: i32) -> Vec<Candidate> {
+    let in_window = |monster: &Monster| (monster.level - level).abs() <= level_window.max(0);
+    rows.into_iter()
+        .filter(|row| in_window(&row.monster))
+        .map(|row| {
+            let closeness = (level_window.max(0) + 1 - (row.monster.level - level).abs()).max(1);
+            Candidate {
+                monster: row.monster,
+                species: row.species,
+                weight: (row.rarity_weight.max(0) * closeness) as f64,
+            }
         })
-    })?;
+        .collect()
+}
+
+// 重みにしたがって1体選んで、その位置を返す
+fn weighted_index<R: Rng>(rng: &mut R, candidates: &[Candidate]) -> Option<usize> {
+    let total: f64 = candidates.iter().map(|candidate| candidate.weight).sum();
+    if candidates.is_empty() || total <= 0.0 {
+        return None;
+    }
 
-    let monster = monster_iter.next().transpose()?; // 最初のモンスターを取得
+    let mut point = rng.gen_range(0.0..total);
+    let index = candidates
+        .iter()
+        .position(|candidate| {
+            point -= candidate.weight;
+            point < 0.0
+        })
+        .unwrap_or(candidates.len() - 1);
 
-    Ok(monster)
+    Some(index)
+}
+
+// 重みにしたがって1体選び、候補から取り除く
+fn take_weighted<R: Rng>(rng: &mut R, candidates: &mut Vec<Candidate>) -> Option<Candidate> {
+    weighted_index(rng, candidates).map(|index| candidates.remove(index))
+}
+
+// 重みにしたがって1体選んで予約する。ほかのユーザーに先に予約されたモンスターは候補から外して選び直す
+fn take_reserved<R, F>(
+    rng: &mut R,
+    candidates: &mut Vec<Candidate>,
+    reserve: &mut F,
+) -> Result<Option<Candidate>, Box<dyn StdError>>
+where
+    R: Rng,
+    F: FnMut(i32) -> Result<bool, Box<dyn StdError>>,
+{
+    while let Some(mut candidate) = take_weighted(rng, candidates) {
+        if reserve(candidate.monster.id)? {
+            candidate.monster.status = 3;
+            return Ok(Some(candidate));
+        }
+    }
+
+    Ok(None)
+}
+
+// 出現中のモンスターから1〜max_monsters体の群れを選ぶ
+// mixed_species が false なら、最初に選ばれたモンスターと同じ種類だけで群れを作る
+// 選んだモンスターは reserve で予約し、予約できなかったモンスターは選び直す
+pub(crate) fn choose_monsters<F>(
+    rows: Vec<EncounterRow>,
+    level: i32,
+    config: &EncounterConfig,
+    mut reserve: F,
+) -> Result<Vec<Monster>, Box<dyn StdError>>
+where
+    F: FnMut(i32) -> Result<bool, Box<dyn StdError>>,
+{
+    let mut rng = rand::thread_rng();
+    let count = rng.gen_range(1..=config.max_monsters.max(1));
+    let mut candidates = encounter_candidates(rows, level, config.level_window);
+    let first = match take_reserved(&mut rng, &mut candidates, &mut reserve)? {
+        Some(first) => first,
+        None => return Ok(Vec::new()),
+    };
+
+    // 残りの仲間を選ぶ
+    if !config.mixed_species {
+        candidates.retain(|candidate| candidate.species == first.species);
+    }
+    let mut monsters = vec![first.monster];
+    while (monsters.len() as i32) < count {
+        match take_reserved(&mut rng, &mut candidates, &mut reserve)? {
+            Some(candidate) => monsters.push(candidate.monster),
+            None => break,
+        }
+    }
+
+    Ok(monsters)
+}
+
+// ダンジョンのフロアに出すモンスターの種類（monster_master の id）を1〜max_monsters体ぶん選ぶ
+// 召喚してから戦うので予約の取り合いはなく、同じ種類を何体選んでもよい
+pub(crate) fn choose_masters(
+    rows: Vec<EncounterRow>,
+    level: i32,
+    config: &EncounterConfig,
+) -> Vec<i32> {
+    let mut rng = rand::thread_rng();
+    let count = rng.gen_range(1..=config.max_monsters.max(1));
+    let mut candidates = encounter_candidates(rows, level, config.level_window);
+    let first = match weighted_index(&mut rng, &candidates) {
+        Some(index) => candidates[index].species,
+        None => return Vec::new(),
+    };
+
+    if !config.mixed_species {
+        candidates.retain(|candidate| candidate.species == first);
+    }
+    let mut ids = vec![first];
+    while (ids.len() as i32) < count {
+        match weighted_index(&mut rng, &candidates) {
+            Some(index) => ids.push(candidates[index].species),
+            None => break,
+        }
+    }
+
+    ids
+}
+
+// zone_id の地域に割り当てられた monster_master を出現候補として返す
+fn master_rows(conn: &Connection, zone_id: i64) -> Result<Vec<EncounterRow>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(
+        "SELECT
+          id,
+          level,
+          status,
+          name,
+          picture,
+          attack,
+          defense,
+          agility,
+          experience_reward,
+          gold_reward,
+          hp,
+          mp,
+          luck,
+          COALESCE(rarity_weight, 100)
+        FROM monster_master WHERE zone_id = ?1 and status = 1",
+    )?;
+    let rows = statement
+        .query_map(rusqlite::params![zone_id], |row| {
+            Ok(EncounterRow {
+                monster: master_from_row(row)?,
+                species: row.get(0)?,
+                rarity_weight: row.get(13)?,
+            })
+        })?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(rows)
 }
 
+// ダンジョンのフロアに出すモンスターの種類を、ワールドに出現中のモンスターではなく地域のマスターから選ぶ
+pub fn choose_floor_monsters(
+    conn: &Connection,
+    level: i32,
+    zone_id: i64,
+    config: &EncounterConfig,
+) -> Result<Vec<i32>, Box<dyn StdError>> {
+    Ok(choose_masters(master_rows(conn, zone_id)?, level, config))
+}
+
+// ユーザーのレベルに合ったモンスターを1〜max_monsters体の群れで取得する関数
+// zone_id が None なら地域に関係なく全体から選ぶ
+// 選んだモンスターは user_id が予約するので、同時に .leveling したユーザーと同じモンスターにはならない
+pub fn get_random_monsters(
+    conn: &Connection,
+    level: i32,
+    zone_id: Option<i64>,
+    user_id: i32,
+    now: i64,
+    config: &EncounterConfig,
+) -> Result<Vec<Monster>, Box<dyn StdError>> {
+    release_expired_reservations(conn, now)?;
+    let reserved_until = now + config.reservation_minutes.max(1) * 60;
+    choose_monsters(encounter_rows(conn, zone_id)?, level, config, |id| {
+        reserve_monster(conn, id, user_id, reserved_until)
+    })
+}
+
+// user_id が予約したモンスターだけ倒せる（予約が切れてほかのユーザーに取られていたら NotReserved）
 pub fn defeat_monster(
     conn: &Connection,
     monster: &Monster,
     user_id: i32,
 ) -> Result<(), Box<dyn StdError>> {
-    conn.execute(
+    let updated = conn.execute(
         "UPDATE monsters 
         SET 
             status = ?1,
             hp = ?2,
             mp = ?3,
-            defeat_user_id = ?4
-        WHERE id = ?5",
+            defeat_user_id = ?4,
+            reserved_by = NULL,
+            reserved_until = NULL
+        WHERE id = ?5 AND status = 3 AND reserved_by = ?4",
         rusqlite::params![2, monster.hp, monster.mp, user_id, monster.id],
     )?;
+    if updated == 0 {
+        return Err(Box::new(MonsterError::NotReserved(monster.id)));
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    // 出現中のスライムを1体だけ召喚して、その id を返す
+    fn setup() -> (Connection, i32) {
+        let conn = Connection::open_in_memory().unwrap();
+        db::migrate(&conn).unwrap();
+        let master_id = add_monster_master(
+            &conn,
+            &NewMonsterMaster {
+                level: 1,
+                name: "スライム".to_string(),
+                picture: String::new(),
+                attack: 1,
+                defense: 1,
+                agility: 1,
+                experience_reward: 1,
+                gold_reward: 1,
+                luck: 0,
+                rarity_weight: 100,
+                zone_id: None,
+            },
+        )
+        .unwrap();
+        spawn_monster(&conn, master_id, 1, &[]).unwrap();
+        let id = conn
+            .query_row("SELECT id FROM monsters", [], |row| row.get(0))
+            .unwrap();
+
+        (conn, id)
+    }
+
+    // (status, reserved_by, hp)
+    fn reservation(conn: &Connection, id: i32) -> (i32, Option<i32>, i32) {
+        conn.query_row(
+            "SELECT status, reserved_by, hp FROM monsters WHERE id = ?1",
+            rusqlite::params![id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .unwrap()
+    }
+
+    fn monster(conn: &Connection, id: i32) -> Monster {
+        conn.query_row(
+            "SELECT * FROM monsters WHERE id = ?1",
+            rusqlite::params![id],
+            monster_from_row,
+        )
+        .unwrap()
+    }
+
+    // レベル level の出現候補（species はモンスターの id と同じにする）
+    fn row(id: i32, level: i32, rarity_weight: i32) -> EncounterRow {
+        EncounterRow {
+            monster: Monster {
+                id,
+                level,
+                status: 1,
+                name: format!("モンスター{}", id),
+                picture: String::new(),
+                attack: 1,
+                defense: 1,
+                agility: 1,
+                experience_reward: 1,
+                gold_reward: 1,
+                hp: 10,
+                mp: 0,
+                defeat_user_id: 0,
+                luck: 0,
+            },
+            species: id,
+            rarity_weight,
+        }
+    }
+
+    fn encounter(max_monsters: i32, mixed_species: bool) -> EncounterConfig {
+        EncounterConfig {
+            max_monsters,
+            mixed_species,
+            level_window: 3,
+            reservation_minutes: 5,
+        }
+    }
+
+    #[test]
+    fn closer_levels_weigh_more() {
+        let rows = vec![
+            row(1, 5, 100),
+            row(2, 7, 100),
+            row(3, 8, 50),
+            row(4, 9, 100),
+        ];
+
+        let weights: Vec<(i32, f64)> = encounter_candidates(rows, 5, 3)
+            .iter()
+            .map(|candidate| (candidate.monster.id, candidate.weight))
+            .collect();
+
+        // レベル差4のモンスターは候補にならない
+        assert_eq!(weights, vec![(1, 400.0), (2, 200.0), (3, 50.0)]);
+    }
+
+    #[test]
+    fn weighted_picks_follow_the_weights() {
+        let mut candidates = encounter_candidates(vec![row(1, 5, 300), row(2, 5, 100)], 5, 0);
+        let mut rng = crate::battle::rng_from_seed(14);
+        let mut picks = [0; 2];
+
+        for _ in 0..1000 {
+            picks[weighted_index(&mut rng, &candidates).unwrap()] += 1;
+        }
+
+        // 3:1 の重みなので、だいたい750回と250回になる
+        assert!((650..850).contains(&picks[0]), "{:?}", picks);
+        assert!((150..350).contains(&picks[1]), "{:?}", picks);
+
+        candidates
+            .iter_mut()
+            .for_each(|candidate| candidate.weight = 0.0);
+        assert_eq!(weighted_index(&mut rng, &candidates), None);
+    }
+
+    #[test]
+    fn nobody_in_the_level_window_means_no_encounter() {
+        let rows = vec![row(1, 20, 100)];
+
+        let monsters = choose_monsters(rows, 1, &encounter(3, true), |_| Ok(true)).unwrap();
+
+        assert!(monsters.is_empty());
+    }
+
+    #[test]
+    fn unmixed_groups_share_one_species() {
+        for _ in 0..20 {
+            let mut rows = vec![row(1, 5, 100), row(2, 5, 100)];
+            // 同じ種類のモンスターをもう2体出しておく
+            for id in [3, 4] {
+                rows.push(EncounterRow {
+                    species: 1,
+                    ..row(id, 5, 100)
+                });
+            }
+
+            let monsters = choose_monsters(rows, 5, &encounter(3, false), |_| Ok(true)).unwrap();
+
+            let species: Vec<i32> = monsters
+                .iter()
+                .map(|monster| if monster.id == 2 { 2 } else { 1 })
+                .collect();
+            assert!(!monsters.is_empty() && monsters.len() <= 3);
+            assert!(species.windows(2).all(|pair| pair[0] == pair[1]));
+            assert!(monsters.iter().all(|monster| monster.status == 3));
+        }
+    }
+
+    #[test]
+    fn reserved_elsewhere_monsters_are_skipped() {
+        let rows = vec![row(1, 5, 100), row(2, 5, 100), row(3, 5, 100)];
+        let mut tried = Vec::new();
+
+        let monsters = choose_monsters(rows, 5, &encounter(1, true), |id| {
+            tried.push(id);
+            Ok(id == 3)
+        })
+        .unwrap();
+
+        assert_eq!(monsters.len(), 1);
+        assert_eq!(monsters[0].id, 3);
+        assert_eq!(tried.last(), Some(&3));
+    }
+
+    fn variant(name: &str, chance: f64) -> VariantConfig {
+        VariantConfig {
+            name: name.to_string(),
+            prefix: "エリート".to_string(),
+            chance,
+            stat_multiplier: 2.0,
+            reward_multiplier: 3.0,
+        }
+    }
+
+    #[test]
+    fn variants_roll_by_their_chance() {
+        let variants = [variant("elite", 0.25), variant("shiny", 0.25)];
+        let mut rng = crate::battle::rng_from_seed(20);
+        let mut counts = [0; 3];
+
+        for _ in 0..1000 {
+            match roll_variant(&mut rng, &variants).map(|variant| variant.name.as_str()) {
+                Some("elite") => counts[0] += 1,
+                Some("shiny") => counts[1] += 1,
+                _ => counts[2] += 1,
+            }
+        }
+
+        assert!((150..350).contains(&counts[0]), "{:?}", counts);
+        assert!((150..350).contains(&counts[1]), "{:?}", counts);
+        assert!((400..600).contains(&counts[2]), "{:?}", counts);
+    }
+
+    #[test]
+    fn zero_or_negative_chance_never_rolls() {
+        let variants = [variant("elite", 0.0), variant("shiny", -1.0)];
+        let mut rng = crate::battle::rng_from_seed(20);
+
+        for _ in 0..100 {
+            assert!(roll_variant(&mut rng, &variants).is_none());
+        }
+    }
+
+    #[test]
+    fn variants_multiply_stats_and_rewards() {
+        let (conn, id) = setup();
+        let master = Monster {
+            experience_reward: 5,
+            gold_reward: 7,
+            ..monster(&conn, id)
+        };
+        let variants = [variant("elite", 1.0)];
+        let mut rng = crate::battle::rng_from_seed(20);
+
+        for _ in 0..20 {
+            let (spawned, rolled) = roll_spawn(&mut rng, &master, &variants);
+
+            assert_eq!(rolled.map(|variant| variant.name.as_str()), Some("elite"));
+            assert_eq!(spawned.name, "エリートスライム");
+            assert_eq!((spawned.id, spawned.status), (master.id, master.status));
+            assert_eq!((spawned.experience_reward, spawned.gold_reward), (15, 21));
+            // ボーナスポイントを足してから倍にするので、必ず偶数でマスターの2倍以上
+            for (stat, base) in [
+                (spawned.hp, master.hp),
+                (spawned.attack, master.attack),
+                (spawned.defense, master.defense),
+                (spawned.agility, master.agility),
+            ] {
+                assert!(stat >= base * 2 && stat % 2 == 0, "{} {}", stat, base);
+            }
+        }
+
+        let (plain, rolled) = roll_spawn(&mut rng, &master, &[]);
+        assert!(rolled.is_none());
+        assert_eq!(plain.name, "スライム");
+        assert_eq!((plain.experience_reward, plain.gold_reward), (5, 7));
+    }
+
+    #[test]
+    fn second_user_cannot_reserve_a_reserved_monster() {
+        let (conn, id) = setup();
+
+        assert!(reserve_monster(&conn, id, 1, 100).unwrap());
+        assert!(!reserve_monster(&conn, id, 2, 100).unwrap());
+
+        assert_eq!(reservation(&conn, id).0, 3);
+        assert_eq!(reservation(&conn, id).1, Some(1));
+    }
+
+    #[test]
+    fn only_the_reserving_user_can_defeat() {
+        let (conn, id) = setup();
+        reserve_monster(&conn, id, 1, 100).unwrap();
+        let mut defeated = monster(&conn, id);
+        let hp = defeated.hp;
+        defeated.hp = 0;
+
+        let e = defeat_monster(&conn, &defeated, 2).unwrap_err();
+
+        assert!(matches!(
+            e.downcast_ref::<MonsterError>(),
+            Some(MonsterError::NotReserved(monster_id)) if *monster_id == id
+        ));
+        assert_eq!(reservation(&conn, id), (3, Some(1), hp));
+
+        defeat_monster(&conn, &defeated, 1).unwrap();
+        assert_eq!(reservation(&conn, id), (2, None, 0));
+    }
+
+    #[test]
+    fn expired_reservations_are_released() {
+        let (conn, expired) = setup();
+        let master_id = conn
+            .query_row("SELECT monster_id FROM monsters", [], |row| row.get(0))
+            .unwrap();
+        spawn_monster(&conn, master_id, 1, &[]).unwrap();
+        let live = expired + 1;
+        reserve_monster(&conn, expired, 1, 100).unwrap();
+        reserve_monster(&conn, live, 2, 200).unwrap();
+
+        assert_eq!(release_expired_reservations(&conn, 100).unwrap(), 1);
+
+        assert_eq!(reservation(&conn, expired).0, 1);
+        assert_eq!(reservation(&conn, expired).1, None);
+        assert_eq!(reservation(&conn, live).0, 3);
+        assert_eq!(reservation(&conn, live).1, Some(2));
+    }
+}
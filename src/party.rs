@@ -0,0 +1,194 @@
+use rusqlite::{Connection, Result};
+use std::error::Error as StdError;
+use std::fmt;
+
+pub const MAX_PARTY_SIZE: usize = 4; // リーダーを含めた人数
+
+pub struct PartyMember {
+    pub user_id: i32,
+    pub npub: String,
+    pub joined: bool, // falseなら招待中
+}
+
+// パーティの情報を保持する構造体
+pub struct Party {
+    pub party_id: i64,
+    pub leader_id: i32,
+    pub members: Vec<PartyMember>, // リーダーが先頭
+}
+
+impl Party {
+    // 参加済みのメンバー（招待中は含まない）
+    pub fn joined_members(&self) -> impl Iterator<Item = &PartyMember> {
+        self.members.iter().filter(|member| member.joined)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum PartyError {
+    AlreadyInParty,
+    NotInParty,
+    NotLeader,
+    NoInvitation,
+    AlreadyInvited,
+    PartyFull,
+}
+
+impl fmt::Display for PartyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartyError::AlreadyInParty => write!(f, "Already in a party"),
+            PartyError::NotInParty => write!(f, "Not in a party"),
+            PartyError::NotLeader => write!(f, "Only the leader can do this"),
+            PartyError::NoInvitation => write!(f, "No invitation"),
+            PartyError::AlreadyInvited => write!(f, "Already invited"),
+            PartyError::PartyFull => write!(f, "Party is full"),
+        }
+    }
+}
+
+impl StdError for PartyError {}
+
+fn get_party(conn: &Connection, party_id: i64) -> Result<Party, Box<dyn StdError>> {
+    let leader_id: i32 = conn.query_row(
+        "SELECT leader_id FROM parties WHERE party_id = ?1",
+        rusqlite::params![party_id],
+        |row| row.get(0),
+    )?;
+    let mut statement = conn.prepare(
+        "SELECT party_members.user_id, users.npub, party_members.joined
+        FROM party_members
+        JOIN users ON users.user_id = party_members.user_id
+        WHERE party_members.party_id = ?1
+        ORDER BY party_members.user_id != ?2, party_members.rowid",
+    )?;
+    let members = statement
+        .query_map(rusqlite::params![party_id, leader_id], |row| {
+            Ok(PartyMember {
+                user_id: row.get(0)?,
+                npub: row.get(1)?,
+                joined: row.get(2)?,
+            })
+        })?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Party {
+        party_id,
+        leader_id,
+        members,
+    })
+}
+
+// 参加しているパーティ（招待されているだけのものは含まない）
+pub fn get_party_by_user(
+    conn: &Connection,
+    user_id: i32,
+) -> Result<Option<Party>, Box<dyn StdError>> {
+    let mut statement =
+        conn.prepare("SELECT party_id FROM party_members WHERE user_id = ?1 AND joined = 1")?;
+    let mut party_iter = statement.query_map(rusqlite::params![user_id], |row| row.get(0))?;
+
+    match party_iter.next().transpose()? {
+        Some(party_id) => Ok(Some(get_party(conn, party_id)?)),
+        None => Ok(None),
+    }
+}
+
+// 自分がリーダーのパーティを作る
+pub fn create_party(conn: &Connection, user_id: i32) -> Result<Party, Box<dyn StdError>> {
+    if get_party_by_user(conn, user_id)?.is_some() {
+        return Err(Box::new(PartyError::AlreadyInParty));
+    }
+
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "INSERT INTO parties (leader_id, created_at) VALUES (?1, datetime('now'))",
+        rusqlite::params![user_id],
+    )?;
+    let party_id = tx.last_insert_rowid();
+    tx.execute(
+        "INSERT INTO party_members (party_id, user_id, joined) VALUES (?1, ?2, 1)",
+        rusqlite::params![party_id, user_id],
+    )?;
+    tx.commit()?;
+
+    get_party(conn, party_id)
+}
+
+pub(crate) fn check_invite(party: &Party, leader_id: i32, user_id: i32) -> Result<(), PartyError> {
+    if party.leader_id != leader_id {
+        return Err(PartyError::NotLeader);
+    }
+    if party.members.iter().any(|member| member.user_id == user_id) {
+        return Err(PartyError::AlreadyInvited);
+    }
+    if party.members.len() >= MAX_PARTY_SIZE {
+        return Err(PartyError::PartyFull);
+    }
+
+    Ok(())
+}
+
+// リーダーだけが招待できる。招待された側は .party join で参加する
+pub fn invite(conn: &Connection, leader_id: i32, user_id: i32) -> Result<Party, Box<dyn StdError>> {
+    let party = get_party_by_user(conn, leader_id)?.ok_or(PartyError::NotInParty)?;
+    check_invite(&party, leader_id, user_id)?;
+
+    conn.execute(
+        "INSERT INTO party_members (party_id, user_id, joined) VALUES (?1, ?2, 0)",
+        rusqlite::params![party.party_id, user_id],
+    )?;
+
+    get_party(conn, party.party_id)
+}
+
+// いちばん新しい招待を受けてパーティに参加する
+pub fn join_party(conn: &Connection, user_id: i32) -> Result<Party, Box<dyn StdError>> {
+    if get_party_by_user(conn, user_id)?.is_some() {
+        return Err(Box::new(PartyError::AlreadyInParty));
+    }
+
+    let mut statement = conn.prepare(
+        "SELECT party_id FROM party_members
+        WHERE user_id = ?1 AND joined = 0
+        ORDER BY party_id DESC LIMIT 1",
+    )?;
+    let party_id: i64 = statement
+        .query_map(rusqlite::params![user_id], |row| row.get(0))?
+        .next()
+        .transpose()?
+        .ok_or(PartyError::NoInvitation)?;
+
+    conn.execute(
+        "UPDATE party_members SET joined = 1 WHERE party_id = ?1 AND user_id = ?2",
+        rusqlite::params![party_id, user_id],
+    )?;
+
+    get_party(conn, party_id)
+}
+
+// パーティから抜ける。リーダーが抜けたらパーティは解散する（解散したらtrue）
+pub fn leave_party(conn: &Connection, user_id: i32) -> Result<bool, Box<dyn StdError>> {
+    let party = get_party_by_user(conn, user_id)?.ok_or(PartyError::NotInParty)?;
+
+    if party.leader_id == user_id {
+        let tx = conn.unchecked_transaction()?;
+        tx.execute(
+            "DELETE FROM party_members WHERE party_id = ?1",
+            rusqlite::params![party.party_id],
+        )?;
+        tx.execute(
+            "DELETE FROM parties WHERE party_id = ?1",
+            rusqlite::params![party.party_id],
+        )?;
+        tx.commit()?;
+        return Ok(true);
+    }
+
+    conn.execute(
+        "DELETE FROM party_members WHERE party_id = ?1 AND user_id = ?2",
+        rusqlite::params![party.party_id, user_id],
+    )?;
+
+    Ok(false)
+}
@@ -0,0 +1,2347 @@
+use postgres::{Client, GenericClient, NoTls, Row};
+use std::cell::RefCell;
+use std::error::Error as StdError;
+
+use crate::battle::{self, BattleOutcome, BattleResult, BattleSnapshot};
+use crate::battle_results::{BattleRecord, BattleStats};
+use crate::config::{EncounterConfig, GrowthConfig, VariantConfig};
+use crate::db::{SchemaError, MIGRATIONS};
+use crate::duel::{self, Duel, DuelError, DuelStatus};
+use crate::dungeon::{Dungeon, DungeonError, DungeonRun, FloorResult, RunStatus};
+use crate::items::{self, EquipError, InventoryEntry, Item};
+use crate::loot::LootEntry;
+use crate::monsters::{self, EncounterRow, Monster, MonsterError, NewMonsterMaster};
+use crate::party::{self, Party, PartyError, PartyMember};
+use crate::raid::{self, Contribution, Raid, RaidAttack, RaidError, RaidReward};
+use crate::repository::Repository;
+use crate::shop::{ShopEntry, ShopError, UNLIMITED_STOCK};
+use crate::skills::{Skill, SkillKind};
+use crate::stats::{self, Stat};
+use crate::users::{self, User, UserAlreadyExistsError, UserNotFoundError};
+use crate::zones::Zone;
+
+// まだ適用していないマイグレーションを1つのトランザクションで適用し、適用後のバージョンを返す
+// このビルドが知らない新しいバージョンのDBなら SchemaError::TooNew で止める
+fn migrate(client: &mut Client) -> Result<i32, Box<dyn StdError>> {
+    client.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT,
+            applied_at BIGINT
+        )",
+    )?;
+    let mut tx = client.transaction()?;
+    // 複数のボットが同時に起動しても、マイグレーションは1つずつ適用する
+    tx.batch_execute("LOCK TABLE schema_version IN EXCLUSIVE MODE")?;
+    let current: i32 = tx
+        .query_one("SELECT COALESCE(MAX(version), 0) FROM schema_version", &[])?
+        .try_get(0)?;
+    let latest = MIGRATIONS.last().map_or(0, |migration| migration.version);
+    if current > latest {
+        return Err(Box::new(SchemaError::TooNew {
+            found: current,
+            known: latest,
+        }));
+    }
+
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > current)
+    {
+        tx.batch_execute(migration.postgres)?;
+        tx.execute(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES ($1, $2, $3)",
+            &[
+                &migration.version,
+                &migration.description,
+                &chrono::Utc::now().timestamp(),
+            ],
+        )?;
+    }
+    tx.commit()?;
+
+    Ok(latest)
+}
+
+const SELECT_USER: &str = "SELECT
+        user_id,
+        npub,
+        level,
+        experience,
+        gold,
+        current_hp,
+        max_hp,
+        current_mp,
+        max_mp,
+        attack,
+        defense,
+        agility,
+        luck,
+        stat_points
+    FROM users";
+
+fn user_from_row(row: &Row) -> Result<User, postgres::Error> {
+    Ok(User {
+        user_id: row.try_get(0)?,
+        npub: row.try_get(1)?,
+        level: row.try_get(2)?,
+        experience: row.try_get(3)?,
+        gold: row.try_get(4)?,
+        current_hp: row.try_get(5)?,
+        max_hp: row.try_get(6)?,
+        current_mp: row.try_get(7)?,
+        max_mp: row.try_get(8)?,
+        attack: row.try_get(9)?,
+        defense: row.try_get(10)?,
+        agility: row.try_get(11)?,
+        luck: row.try_get(12)?,
+        stat_points: row.try_get(13)?,
+    })
+}
+
+const SELECT_MONSTER: &str = "SELECT
+        monsters.id,
+        monsters.level,
+        monsters.status,
+        monsters.name,
+        monsters.picture,
+        monsters.attack,
+        monsters.defense,
+        monsters.agility,
+        monsters.experience_reward,
+        monsters.gold_reward,
+        monsters.hp,
+        monsters.mp,
+        monsters.defeat_user_id,
+        monsters.luck
+    FROM monsters";
+
+fn monster_from_row(row: &Row) -> Result<Monster, postgres::Error> {
+    Ok(Monster {
+        id: row.try_get(0)?,
+        level: row.try_get(1)?,
+        status: row.try_get(2)?,
+        name: row.try_get(3)?,
+        picture: row.try_get(4)?,
+        attack: row.try_get(5)?,
+        defense: row.try_get(6)?,
+        agility: row.try_get(7)?,
+        experience_reward: row.try_get(8)?,
+        gold_reward: row.try_get(9)?,
+        hp: row.try_get(10)?,
+        mp: row.try_get(11)?,
+        defeat_user_id: row.try_get(12)?,
+        luck: row.try_get(13)?,
+    })
+}
+
+const SELECT_BATTLE_RECORD: &str = "SELECT
+        battle_id,
+        user_id,
+        monster_id,
+        COALESCE(outcome, CASE WHEN victory THEN 1 ELSE 2 END),
+        experience_gain,
+        gold_gain,
+        battle_log,
+        COALESCE(event_id, ''),
+        COALESCE(created_at, ''),
+        COALESCE(seed, 0),
+        snapshot,
+        COALESCE(engine_version, 0),
+        events
+    FROM battle_results";
+
+fn battle_record_from_row(row: &Row) -> Result<BattleRecord, postgres::Error> {
+    let seed: i64 = row.try_get(9)?;
+    let snapshot: Option<String> = row.try_get(10)?;
+    let events: Option<String> = row.try_get(12)?;
+    Ok(BattleRecord {
+        battle_id: row.try_get(0)?,
+        user_id: row.try_get(1)?,
+        monster_id: row.try_get(2)?,
+        outcome: BattleOutcome::from_i32(row.try_get(3)?).unwrap_or(BattleOutcome::Defeat),
+        experience_gain: row.try_get(4)?,
+        gold_gain: row.try_get(5)?,
+        battle_log: row.try_get(6)?,
+        event_id: row.try_get(7)?,
+        created_at: row.try_get(8)?,
+        seed: seed as u64,
+        snapshot: snapshot.and_then(|json| serde_json::from_str(&json).ok()),
+        engine_version: row.try_get(11)?,
+        events: events.and_then(|json| serde_json::from_str(&json).ok()),
+    })
+}
+
+fn item_from_row(row: &Row) -> Result<Item, postgres::Error> {
+    Ok(Item {
+        item_id: row.try_get(0)?,
+        name: row.try_get(1)?,
+        item_type: row.try_get(2)?,
+        attack_bonus: row.try_get(3)?,
+        defense_bonus: row.try_get(4)?,
+        agility_bonus: row.try_get(5)?,
+    })
+}
+
+const SELECT_SHOP_ENTRY: &str = "SELECT
+        items.item_id,
+        items.name,
+        items.type,
+        items.attack_bonus,
+        items.defense_bonus,
+        items.agility_bonus,
+        shop.price,
+        shop.stock
+    FROM shop
+    JOIN items ON items.item_id = shop.item_id";
+
+fn shop_entry_from_row(row: &Row) -> Result<ShopEntry, postgres::Error> {
+    Ok(ShopEntry {
+        item: item_from_row(row)?,
+        price: row.try_get(6)?,
+        stock: row.try_get(7)?,
+    })
+}
+
+fn skill_from_row(row: &Row) -> Result<Skill, postgres::Error> {
+    let kind: String = row.try_get(2)?;
+    Ok(Skill {
+        skill_id: row.try_get(0)?,
+        name: row.try_get(1)?,
+        kind: SkillKind::parse(&kind).unwrap_or(SkillKind::Attack),
+        mp_cost: row.try_get(3)?,
+        power: row.try_get(4)?,
+        learn_level: row.try_get(5)?,
+    })
+}
+
+const SELECT_ZONE: &str = "SELECT zone_id, name, recommended_level, description FROM zones";
+
+fn zone_from_row(row: &Row) -> Result<Zone, postgres::Error> {
+    Ok(Zone {
+        zone_id: row.try_get(0)?,
+        name: row.try_get(1)?,
+        recommended_level: row.try_get(2)?,
+        description: row.try_get::<_, Option<String>>(3)?.unwrap_or_default(),
+    })
+}
+
+const SELECT_DUNGEON: &str =
+    "SELECT dungeon_id, name, zone_id, floors, boss_monster_id FROM dungeons";
+
+fn dungeon_from_row(row: &Row) -> Result<Dungeon, postgres::Error> {
+    dungeon_from_row_at(row, 0)
+}
+
+// start 番目のカラムから dungeon_id, name, zone_id, floors, boss_monster_id の順に読む
+fn dungeon_from_row_at(row: &Row, start: usize) -> Result<Dungeon, postgres::Error> {
+    Ok(Dungeon {
+        dungeon_id: row.try_get(start)?,
+        name: row.try_get(start + 1)?,
+        zone_id: row.try_get(start + 2)?,
+        floors: row.try_get(start + 3)?,
+        boss_monster_id: row.try_get(start + 4)?,
+    })
+}
+
+fn dungeon_run_from_row(row: &Row) -> Result<DungeonRun, postgres::Error> {
+    Ok(DungeonRun {
+        run_id: row.try_get(0)?,
+        floor: row.try_get(1)?,
+        current_hp: row.try_get(2)?,
+        current_mp: row.try_get(3)?,
+        experience: row.try_get(4)?,
+        gold: row.try_get(5)?,
+        battle_log: row.try_get(6)?,
+        dungeon: dungeon_from_row_at(row, 7)?,
+    })
+}
+
+const SELECT_DUEL: &str =
+    "SELECT duel_id, challenger_id, opponent_id, wager, created_at FROM duels";
+
+fn duel_from_row(row: &Row) -> Result<Duel, postgres::Error> {
+    Ok(Duel {
+        duel_id: row.try_get(0)?,
+        challenger_id: row.try_get(1)?,
+        opponent_id: row.try_get(2)?,
+        wager: row.try_get(3)?,
+        created_at: row.try_get(4)?,
+    })
+}
+
+const SELECT_RAID: &str = "SELECT
+        raid_id,
+        monster_id,
+        level,
+        name,
+        picture,
+        attack,
+        defense,
+        agility,
+        experience_reward,
+        gold_reward,
+        hp,
+        max_hp,
+        mp,
+        luck,
+        status
+    FROM raids";
+
+fn raid_from_row(row: &Row) -> Result<Raid, postgres::Error> {
+    Ok(Raid {
+        raid_id: row.try_get(0)?,
+        monster: Monster {
+            id: row.try_get(1)?,
+            level: row.try_get(2)?,
+            name: row.try_get(3)?,
+            picture: row.try_get(4)?,
+            attack: row.try_get(5)?,
+            defense: row.try_get(6)?,
+            agility: row.try_get(7)?,
+            experience_reward: row.try_get(8)?,
+            gold_reward: row.try_get(9)?,
+            hp: row.try_get(10)?,
+            mp: row.try_get(12)?,
+            luck: row.try_get(13)?,
+            status: row.try_get(14)?,
+            defeat_user_id: -1,
+        },
+        max_hp: row.try_get(11)?,
+    })
+}
+
+fn get_user_by_npub(
+    client: &mut impl GenericClient,
+    npub: &str,
+) -> Result<User, Box<dyn StdError>> {
+    match client.query_opt(&format!("{} WHERE npub = $1", SELECT_USER), &[&npub])? {
+        Some(row) => Ok(user_from_row(&row)?),
+        None => Err(Box::new(UserNotFoundError {
+            npub: npub.to_string(),
+        })),
+    }
+}
+
+fn update_user(client: &mut impl GenericClient, user: &User) -> Result<(), Box<dyn StdError>> {
+    client.execute(
+        "UPDATE users
+        SET
+            npub = $1,
+            level = $2,
+            experience = $3,
+            gold = $4,
+            current_hp = $5,
+            max_hp = $6,
+            current_mp = $7,
+            max_mp = $8,
+            attack = $9,
+            defense = $10,
+            agility = $11,
+            luck = $12,
+            stat_points = $13
+        WHERE user_id = $14",
+        &[
+            &user.npub,
+            &user.level,
+            &user.experience,
+            &user.gold,
+            &user.current_hp,
+            &user.max_hp,
+            &user.current_mp,
+            &user.max_mp,
+            &user.attack,
+            &user.defense,
+            &user.agility,
+            &user.luck,
+            &user.stat_points,
+            &user.user_id,
+        ],
+    )?;
+
+    Ok(())
+}
+
+fn give_item(
+    client: &mut impl GenericClient,
+    user_id: i32,
+    item_id: i32,
+    quantity: i32,
+) -> Result<(), Box<dyn StdError>> {
+    client.execute(
+        "INSERT INTO inventory (user_id, item_id, quantity) VALUES ($1, $2, $3)
+        ON CONFLICT (user_id, item_id) DO UPDATE SET quantity = inventory.quantity + excluded.quantity",
+        &[&user_id, &item_id, &quantity],
+    )?;
+
+    Ok(())
+}
+
+fn get_inventory(
+    client: &mut impl GenericClient,
+    user_id: i32,
+) -> Result<Vec<InventoryEntry>, Box<dyn StdError>> {
+    let rows = client.query(
+        "SELECT
+            items.item_id,
+            items.name,
+            items.type,
+            items.attack_bonus,
+            items.defense_bonus,
+            items.agility_bonus,
+            inventory.quantity,
+            EXISTS (
+                SELECT 1 FROM equipment
+                WHERE equipment.user_id = inventory.user_id AND equipment.item_id = items.item_id
+            )
+        FROM inventory
+        JOIN items ON items.item_id = inventory.item_id
+        WHERE inventory.user_id = $1 AND inventory.quantity > 0
+        ORDER BY items.item_id",
+        &[&user_id],
+    )?;
+
+    Ok(rows
+        .iter()
+        .map(|row| {
+            Ok(InventoryEntry {
+                item: item_from_row(row)?,
+                quantity: row.try_get(6)?,
+                equipped: row.try_get(7)?,
+            })
+        })
+        .collect::<Result<Vec<_>, postgres::Error>>()?)
+}
+
+fn get_equipment(
+    client: &mut impl GenericClient,
+    user_id: i32,
+) -> Result<Vec<Item>, Box<dyn StdError>> {
+    let rows = client.query(
+        "SELECT
+            items.item_id,
+            items.name,
+            items.type,
+            items.attack_bonus,
+            items.defense_bonus,
+            items.agility_bonus
+        FROM equipment
+        JOIN items ON items.item_id = equipment.item_id
+        WHERE equipment.user_id = $1",
+        &[&user_id],
+    )?;
+
+    Ok(rows
+        .iter()
+        .map(item_from_row)
+        .collect::<Result<Vec<_>, _>>()?)
+}
+
+fn get_shop_entry_by_name(
+    client: &mut impl GenericClient,
+    name: &str,
+) -> Result<Option<ShopEntry>, Box<dyn StdError>> {
+    let row = client.query_opt(
+        &format!("{} WHERE items.name = $1", SELECT_SHOP_ENTRY),
+        &[&name],
+    )?;
+
+    Ok(row.as_ref().map(shop_entry_from_row).transpose()?)
+}
+
+fn get_party(client: &mut impl GenericClient, party_id: i64) -> Result<Party, Box<dyn StdError>> {
+    let leader_id: i32 = client
+        .query_one(
+            "SELECT leader_id FROM parties WHERE party_id = $1",
+            &[&party_id],
+        )?
+        .try_get(0)?;
+    let members = client
+        .query(
+            "SELECT party_members.user_id, users.npub, party_members.joined
+            FROM party_members
+            JOIN users ON users.user_id = party_members.user_id
+            WHERE party_members.party_id = $1
+            ORDER BY party_members.user_id != $2, party_members.invited_order",
+            &[&party_id, &leader_id],
+        )?
+        .iter()
+        .map(|row| {
+            Ok(PartyMember {
+                user_id: row.try_get(0)?,
+                npub: row.try_get(1)?,
+                joined: row.try_get(2)?,
+            })
+        })
+        .collect::<Result<Vec<_>, postgres::Error>>()?;
+
+    Ok(Party {
+        party_id,
+        leader_id,
+        members,
+    })
+}
+
+fn get_party_by_user(
+    client: &mut impl GenericClient,
+    user_id: i32,
+) -> Result<Option<Party>, Box<dyn StdError>> {
+    let row = client.query_opt(
+        "SELECT party_id FROM party_members WHERE user_id = $1 AND joined",
+        &[&user_id],
+    )?;
+
+    match row {
+        Some(row) => Ok(Some(get_party(client, row.try_get(0)?)?)),
+        None => Ok(None),
+    }
+}
+
+// GOLDが足りていれば預かる
+fn hold_wager(
+    client: &mut impl GenericClient,
+    user_id: i32,
+    wager: i32,
+) -> Result<(), Box<dyn StdError>> {
+    let paid = client.execute(
+        "UPDATE users SET gold = gold - $1 WHERE user_id = $2 AND gold >= $1",
+        &[&wager, &user_id],
+    )?;
+    if paid == 0 {
+        let gold: i32 = client
+            .query_one("SELECT gold FROM users WHERE user_id = $1", &[&user_id])?
+            .try_get(0)?;
+        return Err(Box::new(DuelError::NotEnoughGold { wager, gold }));
+    }
+
+    Ok(())
+}
+
+fn pay(
+    client: &mut impl GenericClient,
+    user_id: i32,
+    amount: i32,
+) -> Result<(), Box<dyn StdError>> {
+    client.execute(
+        "UPDATE users SET gold = gold + $1 WHERE user_id = $2",
+        &[&amount, &user_id],
+    )?;
+
+    Ok(())
+}
+
+fn get_active_run(
+    client: &mut impl GenericClient,
+    user_id: i32,
+) -> Result<Option<DungeonRun>, Box<dyn StdError>> {
+    let row = client.query_opt(
+        "SELECT
+            dungeon_runs.run_id,
+            dungeon_runs.floor,
+            dungeon_runs.current_hp,
+            dungeon_runs.current_mp,
+            dungeon_runs.experience,
+            dungeon_runs.gold,
+            dungeon_runs.battle_log,
+            dungeons.dungeon_id,
+            dungeons.name,
+            dungeons.zone_id,
+            dungeons.floors,
+            dungeons.boss_monster_id
+        FROM dungeon_runs
+        JOIN dungeons ON dungeons.dungeon_id = dungeon_runs.dungeon_id
+        WHERE dungeon_runs.user_id = $1 AND dungeon_runs.status = 0
+        ORDER BY dungeon_runs.run_id DESC LIMIT 1",
+        &[&user_id],
+    )?;
+
+    Ok(row.as_ref().map(dungeon_run_from_row).transpose()?)
+}
+
+fn get_contributions(
+    client: &mut impl GenericClient,
+    raid_id: i64,
+) -> Result<Vec<Contribution>, Box<dyn StdError>> {
+    let rows = client.query(
+        "SELECT users.npub, raid_contributions.damage, raid_contributions.attacks
+        FROM raid_contributions
+        JOIN users ON users.user_id = raid_contributions.user_id
+        WHERE raid_contributions.raid_id = $1 AND raid_contributions.damage > 0
+        ORDER BY raid_contributions.damage DESC, raid_contributions.last_attack_at",
+        &[&raid_id],
+    )?;
+
+    Ok(rows
+        .iter()
+        .map(|row| {
+            Ok(Contribution {
+                npub: row.try_get(0)?,
+                damage: row.try_get(1)?,
+                attacks: row.try_get(2)?,
+            })
+        })
+        .collect::<Result<Vec<_>, postgres::Error>>()?)
+}
+
+// 出現中のレイドボス（いなければNone）
+fn get_active_raid(client: &mut impl GenericClient) -> Result<Option<Raid>, Box<dyn StdError>> {
+    let row = client.query_opt(
+        &format!(
+            "{} WHERE status = 0 ORDER BY raid_id DESC LIMIT 1",
+            SELECT_RAID
+        ),
+        &[],
+    )?;
+
+    Ok(row.as_ref().map(raid_from_row).transpose()?)
+}
+
+// monster_master のモンスター（なければNone）
+fn get_monster_master(
+    client: &mut impl GenericClient,
+    id: i32,
+) -> Result<Option<Monster>, Box<dyn StdError>> {
+    let row = client.query_opt(
+        "SELECT id, level, status, name, picture, attack, defense, agility,
+            experience_reward, gold_reward, hp, mp, -1, luck
+        FROM monster_master WHERE id = $1 AND status = 1",
+        &[&id],
+    )?;
+
+    Ok(row.as_ref().map(monster_from_row).transpose()?)
+}
+
+// 出現中のモンスターを戦闘中にする。すでにほかのユーザーが予約していたら false
+fn reserve_monster(
+    client: &mut impl GenericClient,
+    id: i32,
+    user_id: i32,
+    reserved_until: i64,
+) -> Result<bool, Box<dyn StdError>> {
+    let updated = client.execute(
+        "UPDATE monsters SET status = 3, reserved_by = $1, reserved_until = $2
+        WHERE id = $3 AND status = 1",
+        &[&user_id, &reserved_until, &id],
+    )?;
+
+    Ok(updated > 0)
+}
+
+// dungeon_run_id をつけたモンスターはその探索専用で、.leveling の出現候補にも再出現の数にも入らない
+fn spawn_monsters(
+    client: &mut impl GenericClient,
+    monster_id: i32,
+    amount: i32,
+    dungeon_run_id: Option<i64>,
+    variants: &[VariantConfig],
+) -> Result<(Monster, Vec<i32>), Box<dyn StdError>> {
+    let master =
+        get_monster_master(client, monster_id)?.ok_or(MonsterError::NotFound(monster_id))?;
+    let mut rng = rand::thread_rng();
+    let mut ids = Vec::new();
+    for _ in 0..amount {
+        let (spawned, variant) = monsters::roll_spawn(&mut rng, &master, variants);
+        let row = client.query_one(
+            "INSERT INTO monsters (
+                monster_id, level, status, name, picture, attack, defense, agility,
+                experience_reward, gold_reward, hp, mp, luck, variant, dungeon_run_id)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)
+            RETURNING id",
+            &[
+                &monster_id,
+                &spawned.level,
+                &spawned.status,
+                &spawned.name,
+                &spawned.picture,
+                &spawned.attack,
+                &spawned.defense,
+                &spawned.agility,
+                &spawned.experience_reward,
+                &spawned.gold_reward,
+                &spawned.hp,
+                &spawned.mp,
+                &spawned.luck,
+                &variant.map(|variant| variant.name.as_str()),
+                &dungeon_run_id,
+            ],
+        )?;
+        ids.push(row.try_get(0)?);
+    }
+
+    Ok((master, ids))
+}
+
+// apply_battle_result の中身。呼び出し側のトランザクションの中で使う
+fn write_battle_result(
+    client: &mut impl GenericClient,
+    monsters: &[Monster],
+    result: &BattleResult,
+    battle_log: &str,
+    event_id: &str,
+) -> Result<i64, Box<dyn StdError>> {
+    // 倒したモンスターはにげられても倒れたまま。倒せなかったモンスターは予約を外す
+    for monster in monsters {
+        if result.defeated_monster_ids.contains(&monster.id) {
+            let updated = client.execute(
+                "UPDATE monsters
+                SET status = 2, hp = $1, mp = $2, defeat_user_id = $3,
+                    reserved_by = NULL, reserved_until = NULL
+                WHERE id = $4 AND status = 3 AND reserved_by = $3",
+                &[&monster.hp, &monster.mp, &result.user_id, &monster.id],
+            )?;
+            if updated == 0 {
+                return Err(Box::new(MonsterError::NotReserved(monster.id)));
+            }
+        } else {
+            client.execute(
+                "UPDATE monsters SET status = 1, reserved_by = NULL, reserved_until = NULL
+                WHERE id = $1 AND status = 3 AND reserved_by = $2",
+                &[&monster.id, &result.user_id],
+            )?;
+        }
+    }
+    for user in std::iter::once(&result.updated_user).chain(&result.updated_allies) {
+        update_user(client, user)?;
+    }
+    for drop in &result.drops {
+        give_item(client, result.user_id, drop.item_id, drop.quantity)?;
+    }
+
+    add_battle_result(client, result, battle_log, event_id)
+}
+
+// パーティで戦ったときは参加者全員の戦歴に同じ戦闘を記録し、.leveling したユーザーの battle_id を返す
+fn add_battle_result(
+    client: &mut impl GenericClient,
+    result: &BattleResult,
+    battle_log: &str,
+    event_id: &str,
+) -> Result<i64, Box<dyn StdError>> {
+    let snapshot = serde_json::to_string(&result.snapshot)?;
+    let events = serde_json::to_string(&result.events)?;
+    let user_ids = std::iter::once(result.user_id)
+        .chain(result.updated_allies.iter().map(|ally| ally.user_id));
+    let mut battle_id = None;
+    for user_id in user_ids {
+        let row = client.query_one(
+            "INSERT INTO battle_results (
+                user_id, monster_id, victory, experience_gain, gold_gain, battle_log,
+                outcome, event_id, created_at, seed, snapshot, engine_version, events)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8,
+                to_char(now() AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS'), $9, $10, $11, $12)
+            RETURNING battle_id",
+            &[
+                &user_id,
+                &result.monster_id,
+                &result.victory,
+                &result.experience_gain,
+                &result.gold_gain,
+                &battle_log,
+                &result.outcome.to_i32(),
+                &event_id,
+                &(result.seed as i64),
+                &snapshot,
+                &battle::ENGINE_VERSION,
+                &events,
+            ],
+        )?;
+        battle_id.get_or_insert(row.try_get::<_, i64>(0)?);
+    }
+
+    Ok(battle_id.unwrap_or_default())
+}
+
+// 1フロア分の結果を記録する。探索が終わったら finished_at も記録し、その探索のために出したモンスターを片付ける
+fn record_floor(
+    client: &mut impl GenericClient,
+    run: &DungeonRun,
+    result: &FloorResult,
+) -> Result<(), Box<dyn StdError>> {
+    let finished_at = if result.status == RunStatus::Active {
+        None
+    } else {
+        Some(result.recorded_at)
+    };
+    let updated = client.execute(
+        "UPDATE dungeon_runs
+        SET floor = floor + $1,
+            status = $2,
+            current_hp = $3,
+            current_mp = $4,
+            experience = experience + $5,
+            gold = gold + $6,
+            battle_log = battle_log || $7,
+            finished_at = $8
+        WHERE run_id = $9 AND status = 0",
+        &[
+            &(result.cleared as i32),
+            &result.status.to_i32(),
+            &result.current_hp,
+            &result.current_mp,
+            &result.experience,
+            &result.gold,
+            &result.battle_log,
+            &finished_at,
+            &run.run_id,
+        ],
+    )?;
+    if updated == 0 {
+        return Err(Box::new(DungeonError::NoRun));
+    }
+    if finished_at.is_some() {
+        remove_run_monsters(client, run.run_id)?;
+    }
+
+    Ok(())
+}
+
+// 探索のために出したモンスターを消す。倒したかどうかは battle_results に残っている
+fn remove_run_monsters(
+    client: &mut impl GenericClient,
+    run_id: i64,
+) -> Result<(), Box<dyn StdError>> {
+    client.execute("DELETE FROM monsters WHERE dungeon_run_id = $1", &[&run_id])?;
+
+    Ok(())
+}
+
+// PostgreSQL に保存する。複数のボットで同じ状態を共有するときに使う
+// postgres::Client は内部で別のランタイムを動かすので、呼び出しはすべて block_in_place の中で行う
+pub struct PostgresRepository {
+    client: Option<RefCell<Client>>, // drop でランタイムの外に出してから閉じるために Option にしている
+}
+
+impl PostgresRepository {
+    // 接続して、まだ適用していないマイグレーションを適用する
+    pub fn connect(url: &str) -> Result<Self, Box<dyn StdError>> {
+        tokio::task::block_in_place(|| {
+            let mut client = Client::connect(url, NoTls)?;
+            migrate(&mut client)?;
+
+            Ok(PostgresRepository {
+                client: Some(RefCell::new(client)),
+            })
+        })
+    }
+
+    fn with_client<T>(
+        &self,
+        f: impl FnOnce(&mut Client) -> Result<T, Box<dyn StdError>>,
+    ) -> Result<T, Box<dyn StdError>> {
+        let client = self.client.as_ref().expect("postgres client is closed");
+        tokio::task::block_in_place(|| f(&mut client.borrow_mut()))
+    }
+}
+
+impl Drop for PostgresRepository {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            tokio::task::block_in_place(|| drop(client));
+        }
+    }
+}
+
+impl Repository for PostgresRepository {
+    fn add_user(&self, npub: &str) -> Result<User, Box<dyn StdError>> {
+        let user = users::new_user(0, npub);
+        self.with_client(|client| {
+            let row = client.query_opt(
+                "INSERT INTO users (npub, current_hp, max_hp, current_mp, max_mp, attack, defense, agility, luck)
+                VALUES ($1, $2, $2, $3, $3, $4, $5, $6, $7)
+                ON CONFLICT (npub) DO NOTHING
+                RETURNING user_id",
+                &[
+                    &npub,
+                    &user.max_hp,
+                    &user.max_mp,
+                    &user.attack,
+                    &user.defense,
+                    &user.agility,
+                    &user.luck,
+                ],
+            )?;
+            let user_id: i32 = match row {
+                Some(row) => row.try_get(0)?,
+                None => {
+                    return Err(Box::new(UserAlreadyExistsError {
+                        npub: npub.to_string(),
+                    }))
+                }
+            };
+            let row = client.query_one(&format!("{} WHERE user_id = $1", SELECT_USER), &[&user_id])?;
+
+            Ok(user_from_row(&row)?)
+        })
+    }
+
+    fn get_user_by_npub(&self, npub: &str) -> Result<User, Box<dyn StdError>> {
+        self.with_client(|client| get_user_by_npub(client, npub))
+    }
+
+    fn get_user_by_id(&self, user_id: i32) -> Result<Option<User>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let row =
+                client.query_opt(&format!("{} WHERE user_id = $1", SELECT_USER), &[&user_id])?;
+
+            Ok(row.as_ref().map(user_from_row).transpose()?)
+        })
+    }
+
+    fn apply_regeneration(
+        &self,
+        user: User,
+        hp_per_hour: i32,
+        mp_per_hour: i32,
+        now: i64,
+    ) -> Result<User, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let regenerated_at: Option<i64> = client
+                .query_one(
+                    "SELECT regenerated_at FROM users WHERE user_id = $1",
+                    &[&user.user_id],
+                )?
+                .try_get(0)?;
+            let user = match users::regenerate(&user, regenerated_at, hp_per_hour, mp_per_hour, now)
+            {
+                Some(user) => user,
+                None => return Ok(user),
+            };
+            client.execute(
+                "UPDATE users SET current_hp = $1, current_mp = $2, regenerated_at = $3 WHERE user_id = $4",
+                &[&user.current_hp, &user.current_mp, &now, &user.user_id],
+            )?;
+
+            Ok(user)
+        })
+    }
+
+    fn rest_at_inn(&self, user: &User, cost: i32, now: i64) -> Result<bool, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let updated = client.execute(
+                "UPDATE users
+                SET
+                    gold = gold - $1,
+                    current_hp = max_hp,
+                    current_mp = max_mp,
+                    regenerated_at = $2
+                WHERE user_id = $3 AND gold >= $1",
+                &[&cost, &now, &user.user_id],
+            )?;
+
+            Ok(updated > 0)
+        })
+    }
+
+    fn allocate(&self, user: &User, stat: Stat, points: i32) -> Result<User, Box<dyn StdError>> {
+        let updated_user = stats::allocated_user(user, stat, points)?;
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            update_user(&mut tx, &updated_user)?;
+            tx.execute(
+                "INSERT INTO stat_allocations (user_id, stat, points) VALUES ($1, $2, $3)
+                ON CONFLICT (user_id, stat) DO UPDATE SET points = stat_allocations.points + excluded.points",
+                &[&user.user_id, &stat.as_str(), &points],
+            )?;
+            tx.commit()?;
+
+            Ok(updated_user)
+        })
+    }
+
+    fn respec(&self, user: &User, cost: i32) -> Result<User, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            let allocations = tx
+                .query(
+                    "SELECT stat, points FROM stat_allocations WHERE user_id = $1 AND points > 0",
+                    &[&user.user_id],
+                )?
+                .iter()
+                .map(|row| Ok((row.try_get::<_, String>(0)?, row.try_get::<_, i32>(1)?)))
+                .collect::<Result<Vec<_>, postgres::Error>>()?;
+            let updated_user = stats::respecced_user(user, cost, &allocations)?;
+
+            update_user(&mut tx, &updated_user)?;
+            tx.execute(
+                "DELETE FROM stat_allocations WHERE user_id = $1",
+                &[&user.user_id],
+            )?;
+            tx.commit()?;
+
+            Ok(updated_user)
+        })
+    }
+
+    fn get_log_style(&self, user_id: i32) -> Result<Option<String>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            Ok(client
+                .query_one(
+                    "SELECT log_style FROM users WHERE user_id = $1",
+                    &[&user_id],
+                )?
+                .try_get(0)?)
+        })
+    }
+
+    fn set_log_style(&self, user_id: i32, log_style: &str) -> Result<(), Box<dyn StdError>> {
+        self.with_client(|client| {
+            client.execute(
+                "UPDATE users SET log_style = $1 WHERE user_id = $2",
+                &[&log_style, &user_id],
+            )?;
+
+            Ok(())
+        })
+    }
+
+    fn get_selected_skill(&self, user_id: i32) -> Result<Option<String>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            Ok(client
+                .query_one(
+                    "SELECT selected_skill FROM users WHERE user_id = $1",
+                    &[&user_id],
+                )?
+                .try_get(0)?)
+        })
+    }
+
+    fn set_selected_skill(
+        &self,
+        user_id: i32,
+        selected_skill: Option<&str>,
+    ) -> Result<(), Box<dyn StdError>> {
+        self.with_client(|client| {
+            client.execute(
+                "UPDATE users SET selected_skill = $1 WHERE user_id = $2",
+                &[&selected_skill, &user_id],
+            )?;
+
+            Ok(())
+        })
+    }
+
+    fn get_skills_for_level(&self, level: i32) -> Result<Vec<Skill>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let rows = client.query(
+                "SELECT skill_id, name, kind, mp_cost, power, learn_level
+                FROM skills WHERE learn_level <= $1 ORDER BY learn_level, skill_id",
+                &[&level],
+            )?;
+
+            Ok(rows
+                .iter()
+                .map(skill_from_row)
+                .collect::<Result<Vec<_>, _>>()?)
+        })
+    }
+
+    fn get_next_skill(&self, level: i32) -> Result<Option<Skill>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let row = client.query_opt(
+                "SELECT skill_id, name, kind, mp_cost, power, learn_level
+                FROM skills WHERE learn_level > $1 ORDER BY learn_level, skill_id LIMIT 1",
+                &[&level],
+            )?;
+
+            Ok(row.as_ref().map(skill_from_row).transpose()?)
+        })
+    }
+
+    fn spawn_monster(
+        &self,
+        monster_id: i32,
+        amount: i32,
+        variants: &[VariantConfig],
+    ) -> Result<Monster, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let (master, _) = spawn_monsters(client, monster_id, amount, None, variants)?;
+
+            Ok(master)
+        })
+    }
+
+    fn spawn_for_run(
+        &self,
+        run_id: i64,
+        monster_ids: &[i32],
+        user_id: i32,
+        reserved_until: i64,
+        variants: &[VariantConfig],
+    ) -> Result<Vec<Monster>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            let mut monsters = Vec::new();
+            for &monster_id in monster_ids {
+                let (_, ids) = spawn_monsters(&mut tx, monster_id, 1, Some(run_id), variants)?;
+                for id in ids {
+                    if !reserve_monster(&mut tx, id, user_id, reserved_until)? {
+                        return Err(Box::new(MonsterError::NotReserved(id)));
+                    }
+                    let row = tx.query_one(&format!("{} WHERE id = $1", SELECT_MONSTER), &[&id])?;
+                    monsters.push(monster_from_row(&row)?);
+                }
+            }
+            tx.commit()?;
+
+            Ok(monsters)
+        })
+    }
+
+    fn choose_floor_monsters(
+        &self,
+        level: i32,
+        zone_id: i64,
+        config: &EncounterConfig,
+    ) -> Result<Vec<i32>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let rows = client
+                .query(
+                    "SELECT id, level, status, name, picture, attack, defense, agility,
+                        experience_reward, gold_reward, hp, mp, -1, luck,
+                        id, COALESCE(rarity_weight, 100)
+                    FROM monster_master WHERE zone_id = $1 AND status = 1",
+                    &[&zone_id],
+                )?
+                .iter()
+                .map(|row| {
+                    Ok(EncounterRow {
+                        monster: monster_from_row(row)?,
+                        species: row.try_get(14)?,
+                        rarity_weight: row.try_get(15)?,
+                    })
+                })
+                .collect::<Result<Vec<_>, postgres::Error>>()?;
+
+            Ok(monsters::choose_masters(rows, level, config))
+        })
+    }
+
+    fn get_random_monsters(
+        &self,
+        level: i32,
+        zone_id: Option<i64>,
+        user_id: i32,
+        now: i64,
+        config: &EncounterConfig,
+    ) -> Result<Vec<Monster>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            client.execute(
+                "UPDATE monsters SET status = 1, reserved_by = NULL, reserved_until = NULL
+                WHERE status = 3 AND reserved_until <= $1",
+                &[&now],
+            )?;
+            let rows = client
+                .query(
+                    "SELECT
+                        monsters.id,
+                        monsters.level,
+                        monsters.status,
+                        monsters.name,
+                        monsters.picture,
+                        monsters.attack,
+                        monsters.defense,
+                        monsters.agility,
+                        monsters.experience_reward,
+                        monsters.gold_reward,
+                        monsters.hp,
+                        monsters.mp,
+                        monsters.defeat_user_id,
+                        monsters.luck,
+                        monsters.monster_id,
+                        COALESCE(monster_master.rarity_weight, 100)
+                    FROM monsters
+                    LEFT JOIN monster_master ON monster_master.id = monsters.monster_id
+                    WHERE monsters.status = 1 AND monsters.dungeon_run_id IS NULL
+                        AND ($1::BIGINT IS NULL OR monster_master.zone_id = $1)",
+                    &[&zone_id],
+                )?
+                .iter()
+                .map(|row| {
+                    Ok(EncounterRow {
+                        monster: monster_from_row(row)?,
+                        species: row.try_get(14)?,
+                        rarity_weight: row.try_get(15)?,
+                    })
+                })
+                .collect::<Result<Vec<_>, postgres::Error>>()?;
+            let reserved_until = now + config.reservation_minutes.max(1) * 60;
+            monsters::choose_monsters(rows, level, config, |id| {
+                reserve_monster(client, id, user_id, reserved_until)
+            })
+        })
+    }
+
+    fn apply_battle_result(
+        &self,
+        monsters: &[Monster],
+        result: &BattleResult,
+        battle_log: &str,
+        event_id: &str,
+    ) -> Result<i64, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            let battle_id = write_battle_result(&mut tx, monsters, result, battle_log, event_id)?;
+            tx.commit()?;
+
+            Ok(battle_id)
+        })
+    }
+
+    fn get_battle_result_by_id(
+        &self,
+        battle_id: i64,
+    ) -> Result<Option<BattleRecord>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let row = client.query_opt(
+                &format!("{} WHERE battle_id = $1", SELECT_BATTLE_RECORD),
+                &[&battle_id],
+            )?;
+
+            Ok(row.as_ref().map(battle_record_from_row).transpose()?)
+        })
+    }
+
+    fn get_battle_results_by_user(
+        &self,
+        user_id: i32,
+        limit: i32,
+        offset: i32,
+    ) -> Result<Vec<BattleRecord>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let rows = client.query(
+                &format!(
+                    "{} WHERE user_id = $1 ORDER BY battle_id DESC LIMIT $2 OFFSET $3",
+                    SELECT_BATTLE_RECORD
+                ),
+                &[&user_id, &(limit as i64), &(offset as i64)],
+            )?;
+
+            Ok(rows
+                .iter()
+                .map(battle_record_from_row)
+                .collect::<Result<Vec<_>, _>>()?)
+        })
+    }
+
+    fn get_battle_stats_by_user(&self, user_id: i32) -> Result<BattleStats, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let row = client.query_one(
+                "SELECT
+                    COUNT(*) FILTER (WHERE o = 1)::INTEGER,
+                    COUNT(*) FILTER (WHERE o = 2)::INTEGER,
+                    COUNT(*) FILTER (WHERE o = 3)::INTEGER
+                FROM (
+                    SELECT COALESCE(outcome, CASE WHEN victory THEN 1 ELSE 2 END) AS o
+                    FROM battle_results WHERE user_id = $1
+                ) AS outcomes",
+                &[&user_id],
+            )?;
+
+            Ok(BattleStats {
+                victories: row.try_get(0)?,
+                defeats: row.try_get(1)?,
+                escapes: row.try_get(2)?,
+            })
+        })
+    }
+
+    fn get_item_by_name(&self, name: &str) -> Result<Option<Item>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let row = client.query_opt(
+                "SELECT item_id, name, type, attack_bonus, defense_bonus, agility_bonus
+                FROM items WHERE name = $1",
+                &[&name],
+            )?;
+
+            Ok(row.as_ref().map(item_from_row).transpose()?)
+        })
+    }
+
+    fn give_item(
+        &self,
+        user_id: i32,
+        item_id: i32,
+        quantity: i32,
+    ) -> Result<(), Box<dyn StdError>> {
+        self.with_client(|client| give_item(client, user_id, item_id, quantity))
+    }
+
+    fn get_inventory(&self, user_id: i32) -> Result<Vec<InventoryEntry>, Box<dyn StdError>> {
+        self.with_client(|client| get_inventory(client, user_id))
+    }
+
+    fn count_alive(&self, monster_id: i32) -> Result<i32, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let count: i64 = client
+                .query_one(
+                    "SELECT COUNT(*) FROM monsters
+                    WHERE monster_id = $1 AND status IN (1, 3) AND dungeon_run_id IS NULL",
+                    &[&monster_id],
+                )?
+                .try_get(0)?;
+
+            Ok(count as i32)
+        })
+    }
+
+    fn get_monster_master(&self, id: i32) -> Result<Option<Monster>, Box<dyn StdError>> {
+        self.with_client(|client| get_monster_master(client, id))
+    }
+
+    fn add_monster_master(&self, master: &NewMonsterMaster) -> Result<i32, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let row = client.query_one(
+                "INSERT INTO monster_master (
+                    level, name, picture, attack, defense, agility,
+                    experience_reward, gold_reward, luck, rarity_weight, zone_id)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+                RETURNING id",
+                &[
+                    &master.level,
+                    &master.name,
+                    &master.picture,
+                    &master.attack,
+                    &master.defense,
+                    &master.agility,
+                    &master.experience_reward,
+                    &master.gold_reward,
+                    &master.luck,
+                    &master.rarity_weight,
+                    &master.zone_id,
+                ],
+            )?;
+
+            Ok(row.try_get(0)?)
+        })
+    }
+
+    fn add_item(
+        &self,
+        name: &str,
+        item_type: &str,
+        attack_bonus: i32,
+        defense_bonus: i32,
+        agility_bonus: i32,
+    ) -> Result<Item, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let row = client.query_one(
+                "INSERT INTO items (name, type, attack_bonus, defense_bonus, agility_bonus)
+                VALUES ($1, $2, $3, $4, $5)
+                RETURNING item_id, name, type, attack_bonus, defense_bonus, agility_bonus",
+                &[
+                    &name,
+                    &item_type,
+                    &attack_bonus,
+                    &defense_bonus,
+                    &agility_bonus,
+                ],
+            )?;
+
+            Ok(item_from_row(&row)?)
+        })
+    }
+
+    fn get_equipment(&self, user_id: i32) -> Result<Vec<Item>, Box<dyn StdError>> {
+        self.with_client(|client| get_equipment(client, user_id))
+    }
+
+    fn equip_item(&self, user_id: i32, name: &str) -> Result<Item, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let entry = get_inventory(client, user_id)?
+                .into_iter()
+                .find(|entry| entry.item.name == name)
+                .ok_or_else(|| EquipError::ItemNotOwned(name.to_string()))?;
+            items::check_equippable(&entry.item)?;
+
+            client.execute(
+                "INSERT INTO equipment (user_id, slot, item_id) VALUES ($1, $2, $3)
+                ON CONFLICT (user_id, slot) DO UPDATE SET item_id = excluded.item_id",
+                &[&user_id, &entry.item.item_type, &entry.item.item_id],
+            )?;
+
+            Ok(entry.item)
+        })
+    }
+
+    fn unequip_slot(&self, user_id: i32, slot: &str) -> Result<Item, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let item = get_equipment(client, user_id)?
+                .into_iter()
+                .find(|item| item.item_type == slot)
+                .ok_or_else(|| EquipError::SlotEmpty(slot.to_string()))?;
+
+            client.execute(
+                "DELETE FROM equipment WHERE user_id = $1 AND slot = $2",
+                &[&user_id, &slot],
+            )?;
+
+            Ok(item)
+        })
+    }
+
+    fn get_shop_entries(&self) -> Result<Vec<ShopEntry>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let rows = client.query(&format!("{} ORDER BY shop.price", SELECT_SHOP_ENTRY), &[])?;
+
+            Ok(rows
+                .iter()
+                .map(shop_entry_from_row)
+                .collect::<Result<Vec<_>, _>>()?)
+        })
+    }
+
+    fn set_shop_entry(
+        &self,
+        item_id: i32,
+        price: i32,
+        stock: i32,
+    ) -> Result<(), Box<dyn StdError>> {
+        self.with_client(|client| {
+            client.execute(
+                "INSERT INTO shop (item_id, price, stock) VALUES ($1, $2, $3)
+                ON CONFLICT (item_id) DO UPDATE SET price = excluded.price, stock = excluded.stock",
+                &[&item_id, &price, &stock],
+            )?;
+
+            Ok(())
+        })
+    }
+
+    fn remove_shop_entry(&self, item_id: i32) -> Result<bool, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let removed = client.execute("DELETE FROM shop WHERE item_id = $1", &[&item_id])?;
+
+            Ok(removed > 0)
+        })
+    }
+
+    // GOLDの支払い・在庫・持ち物の更新をひとつのトランザクションで行う
+    fn buy_item(&self, user_id: i32, name: &str) -> Result<ShopEntry, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            // 在庫の確認から減らすまでのあいだに、ほかのボットが同じ品を売らないようにする
+            tx.batch_execute("LOCK TABLE shop IN EXCLUSIVE MODE")?;
+
+            let entry = get_shop_entry_by_name(&mut tx, name)?
+                .ok_or_else(|| ShopError::NotForSale(name.to_string()))?;
+            if entry.stock == 0 {
+                return Err(Box::new(ShopError::OutOfStock(name.to_string())));
+            }
+
+            let paid = tx.execute(
+                "UPDATE users SET gold = gold - $1 WHERE user_id = $2 AND gold >= $1",
+                &[&entry.price, &user_id],
+            )?;
+            if paid == 0 {
+                let gold: i32 = tx
+                    .query_one("SELECT gold FROM users WHERE user_id = $1", &[&user_id])?
+                    .try_get(0)?;
+                return Err(Box::new(ShopError::NotEnoughGold {
+                    price: entry.price,
+                    gold,
+                }));
+            }
+
+            if entry.stock != UNLIMITED_STOCK {
+                tx.execute(
+                    "UPDATE shop SET stock = stock - 1 WHERE item_id = $1",
+                    &[&entry.item.item_id],
+                )?;
+            }
+            give_item(&mut tx, user_id, entry.item.item_id, 1)?;
+
+            tx.commit()?;
+
+            Ok(entry)
+        })
+    }
+
+    // お店に1個売る。装備中の最後の1個は売れない
+    fn sell_item(&self, user_id: i32, name: &str) -> Result<ShopEntry, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+
+            let entry = get_shop_entry_by_name(&mut tx, name)?
+                .ok_or_else(|| ShopError::NotForSale(name.to_string()))?;
+            let owned = get_inventory(&mut tx, user_id)?
+                .into_iter()
+                .find(|owned| owned.item.item_id == entry.item.item_id)
+                .ok_or_else(|| ShopError::NotOwned(name.to_string()))?;
+            if owned.equipped && owned.quantity <= 1 {
+                return Err(Box::new(ShopError::Equipped(name.to_string())));
+            }
+
+            let taken = tx.execute(
+                "UPDATE inventory SET quantity = quantity - 1
+                WHERE user_id = $1 AND item_id = $2 AND quantity >= 1",
+                &[&user_id, &entry.item.item_id],
+            )?;
+            if taken == 0 {
+                return Err(Box::new(ShopError::NotOwned(name.to_string())));
+            }
+            tx.execute(
+                "UPDATE users SET gold = gold + $1 WHERE user_id = $2",
+                &[&entry.sell_price(), &user_id],
+            )?;
+            if entry.stock != UNLIMITED_STOCK {
+                tx.execute(
+                    "UPDATE shop SET stock = stock + 1 WHERE item_id = $1",
+                    &[&entry.item.item_id],
+                )?;
+            }
+
+            tx.commit()?;
+
+            Ok(entry)
+        })
+    }
+
+    fn get_zones(&self) -> Result<Vec<Zone>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let rows = client.query(
+                &format!("{} ORDER BY recommended_level, zone_id", SELECT_ZONE),
+                &[],
+            )?;
+
+            Ok(rows
+                .iter()
+                .map(zone_from_row)
+                .collect::<Result<Vec<_>, _>>()?)
+        })
+    }
+
+    fn get_zone_by_name(&self, name: &str) -> Result<Option<Zone>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let row = client.query_opt(&format!("{} WHERE name = $1", SELECT_ZONE), &[&name])?;
+
+            Ok(row.as_ref().map(zone_from_row).transpose()?)
+        })
+    }
+
+    fn add_zone(
+        &self,
+        name: &str,
+        recommended_level: i32,
+        description: &str,
+    ) -> Result<Zone, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let row = client.query_one(
+                "INSERT INTO zones (name, recommended_level, description) VALUES ($1, $2, $3)
+                ON CONFLICT (name) DO UPDATE SET
+                    recommended_level = excluded.recommended_level,
+                    description = excluded.description
+                RETURNING zone_id, name, recommended_level, description",
+                &[&name, &recommended_level, &description],
+            )?;
+
+            Ok(zone_from_row(&row)?)
+        })
+    }
+
+    fn assign_monster(
+        &self,
+        monster_master_id: i32,
+        zone_id: i64,
+    ) -> Result<bool, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let updated = client.execute(
+                "UPDATE monster_master SET zone_id = $1 WHERE id = $2",
+                &[&zone_id, &monster_master_id],
+            )?;
+
+            Ok(updated > 0)
+        })
+    }
+
+    fn get_current_zone(&self, user_id: i32) -> Result<Option<Zone>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let row = client.query_opt(
+                &format!(
+                    "{} WHERE zone_id = (SELECT zone_id FROM users WHERE user_id = $1)",
+                    SELECT_ZONE
+                ),
+                &[&user_id],
+            )?;
+
+            Ok(row.as_ref().map(zone_from_row).transpose()?)
+        })
+    }
+
+    fn travel(&self, user_id: i32, zone: &Zone) -> Result<(), Box<dyn StdError>> {
+        self.with_client(|client| {
+            client.execute(
+                "UPDATE users SET zone_id = $1 WHERE user_id = $2",
+                &[&zone.zone_id, &user_id],
+            )?;
+
+            Ok(())
+        })
+    }
+
+    fn get_party_by_user(&self, user_id: i32) -> Result<Option<Party>, Box<dyn StdError>> {
+        self.with_client(|client| get_party_by_user(client, user_id))
+    }
+
+    // パーティの作成・招待・参加は、確認から書き込みまでほかのボットに割り込まれないように1つずつ行う
+    fn create_party(&self, user_id: i32) -> Result<Party, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            tx.batch_execute("LOCK TABLE party_members IN EXCLUSIVE MODE")?;
+            if get_party_by_user(&mut tx, user_id)?.is_some() {
+                return Err(Box::new(PartyError::AlreadyInParty));
+            }
+
+            let party_id: i64 = tx
+                .query_one(
+                    "INSERT INTO parties (leader_id, created_at)
+                    VALUES ($1, to_char(now() AT TIME ZONE 'UTC', 'YYYY-MM-DD HH24:MI:SS'))
+                    RETURNING party_id",
+                    &[&user_id],
+                )?
+                .try_get(0)?;
+            tx.execute(
+                "INSERT INTO party_members (party_id, user_id, joined) VALUES ($1, $2, TRUE)",
+                &[&party_id, &user_id],
+            )?;
+            let party = get_party(&mut tx, party_id)?;
+            tx.commit()?;
+
+            Ok(party)
+        })
+    }
+
+    fn invite(&self, leader_id: i32, user_id: i32) -> Result<Party, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            tx.batch_execute("LOCK TABLE party_members IN EXCLUSIVE MODE")?;
+            let party = get_party_by_user(&mut tx, leader_id)?.ok_or(PartyError::NotInParty)?;
+            party::check_invite(&party, leader_id, user_id)?;
+
+            tx.execute(
+                "INSERT INTO party_members (party_id, user_id, joined) VALUES ($1, $2, FALSE)",
+                &[&party.party_id, &user_id],
+            )?;
+            let party = get_party(&mut tx, party.party_id)?;
+            tx.commit()?;
+
+            Ok(party)
+        })
+    }
+
+    fn join_party(&self, user_id: i32) -> Result<Party, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            tx.batch_execute("LOCK TABLE party_members IN EXCLUSIVE MODE")?;
+            if get_party_by_user(&mut tx, user_id)?.is_some() {
+                return Err(Box::new(PartyError::AlreadyInParty));
+            }
+
+            let party_id: i64 = tx
+                .query_opt(
+                    "SELECT party_id FROM party_members
+                    WHERE user_id = $1 AND NOT joined
+                    ORDER BY party_id DESC LIMIT 1",
+                    &[&user_id],
+                )?
+                .ok_or(PartyError::NoInvitation)?
+                .try_get(0)?;
+            tx.execute(
+                "UPDATE party_members SET joined = TRUE WHERE party_id = $1 AND user_id = $2",
+                &[&party_id, &user_id],
+            )?;
+            let party = get_party(&mut tx, party_id)?;
+            tx.commit()?;
+
+            Ok(party)
+        })
+    }
+
+    fn leave_party(&self, user_id: i32) -> Result<bool, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            let party = get_party_by_user(&mut tx, user_id)?.ok_or(PartyError::NotInParty)?;
+
+            let disbanded = party.leader_id == user_id;
+            if disbanded {
+                tx.execute(
+                    "DELETE FROM party_members WHERE party_id = $1",
+                    &[&party.party_id],
+                )?;
+                tx.execute(
+                    "DELETE FROM parties WHERE party_id = $1",
+                    &[&party.party_id],
+                )?;
+            } else {
+                tx.execute(
+                    "DELETE FROM party_members WHERE party_id = $1 AND user_id = $2",
+                    &[&party.party_id, &user_id],
+                )?;
+            }
+            tx.commit()?;
+
+            Ok(disbanded)
+        })
+    }
+
+    fn get_pending_duel_for(&self, opponent_id: i32) -> Result<Option<Duel>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let row = client.query_opt(
+                &format!(
+                    "{} WHERE opponent_id = $1 AND status = 0 ORDER BY duel_id DESC LIMIT 1",
+                    SELECT_DUEL
+                ),
+                &[&opponent_id],
+            )?;
+
+            Ok(row.as_ref().map(duel_from_row).transpose()?)
+        })
+    }
+
+    fn get_pending_duel_by(&self, challenger_id: i32) -> Result<Option<Duel>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let row = client.query_opt(
+                &format!(
+                    "{} WHERE challenger_id = $1 AND status = 0 ORDER BY duel_id DESC LIMIT 1",
+                    SELECT_DUEL
+                ),
+                &[&challenger_id],
+            )?;
+
+            Ok(row.as_ref().map(duel_from_row).transpose()?)
+        })
+    }
+
+    fn challenge(
+        &self,
+        challenger_id: i32,
+        opponent_id: i32,
+        wager: i32,
+        cooldown_minutes: i64,
+        now: i64,
+    ) -> Result<Duel, Box<dyn StdError>> {
+        duel::check_challenge(challenger_id, opponent_id, wager)?;
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            // 同じ二人への申し込みが同時に2つできないように、申し込みは1つずつ行う
+            tx.batch_execute("LOCK TABLE duels IN EXCLUSIVE MODE")?;
+            let pending: i64 = tx
+                .query_one(
+                    "SELECT COUNT(*) FROM duels
+                    WHERE status = 0 AND (challenger_id IN ($1, $2) OR opponent_id IN ($1, $2))",
+                    &[&challenger_id, &opponent_id],
+                )?
+                .try_get(0)?;
+            if pending > 0 {
+                return Err(Box::new(DuelError::AlreadyPending));
+            }
+
+            let last_resolved: Option<i64> = tx
+                .query_one(
+                    "SELECT MAX(resolved_at) FROM duels
+                    WHERE status = 1
+                      AND ((challenger_id = $1 AND opponent_id = $2) OR (challenger_id = $2 AND opponent_id = $1))",
+                    &[&challenger_id, &opponent_id],
+                )?
+                .try_get(0)?;
+            duel::check_cooldown(last_resolved, cooldown_minutes, now)?;
+
+            if wager > 0 {
+                hold_wager(&mut tx, challenger_id, wager)?;
+            }
+            let duel_id: i64 = tx
+                .query_one(
+                    "INSERT INTO duels (challenger_id, opponent_id, wager, status, created_at)
+                    VALUES ($1, $2, $3, $4, $5)
+                    RETURNING duel_id",
+                    &[
+                        &challenger_id,
+                        &opponent_id,
+                        &wager,
+                        &DuelStatus::Pending.to_i32(),
+                        &now,
+                    ],
+                )?
+                .try_get(0)?;
+            tx.commit()?;
+
+            Ok(Duel {
+                duel_id,
+                challenger_id,
+                opponent_id,
+                wager,
+                created_at: now,
+            })
+        })
+    }
+
+    fn close_duel(
+        &self,
+        duel: &Duel,
+        status: DuelStatus,
+        now: i64,
+    ) -> Result<(), Box<dyn StdError>> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            let closed = tx.execute(
+                "UPDATE duels SET status = $1, resolved_at = $2 WHERE duel_id = $3 AND status = 0",
+                &[&status.to_i32(), &now, &duel.duel_id],
+            )?;
+            if closed == 0 {
+                return Err(Box::new(DuelError::NoChallenge));
+            }
+            pay(&mut tx, duel.challenger_id, duel.wager)?;
+            tx.commit()?;
+
+            Ok(())
+        })
+    }
+
+    fn resolve_duel(
+        &self,
+        duel: &Duel,
+        winner_id: Option<i32>,
+        seed: u64,
+        snapshot: &BattleSnapshot,
+        battle_log: &str,
+        now: i64,
+    ) -> Result<(), Box<dyn StdError>> {
+        let snapshot = serde_json::to_string(snapshot)?;
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            if duel.wager > 0 {
+                hold_wager(&mut tx, duel.opponent_id, duel.wager)?;
+            }
+            let resolved = tx.execute(
+                "UPDATE duels
+                SET status = $1, resolved_at = $2, winner_id = $3, seed = $4, snapshot = $5, battle_log = $6
+                WHERE duel_id = $7 AND status = 0",
+                &[
+                    &DuelStatus::Finished.to_i32(),
+                    &now,
+                    &winner_id,
+                    &(seed as i64),
+                    &snapshot,
+                    &battle_log,
+                    &duel.duel_id,
+                ],
+            )?;
+            if resolved == 0 {
+                return Err(Box::new(DuelError::NoChallenge));
+            }
+            match winner_id {
+                Some(winner_id) => pay(&mut tx, winner_id, duel.wager * 2)?,
+                None => {
+                    pay(&mut tx, duel.challenger_id, duel.wager)?;
+                    pay(&mut tx, duel.opponent_id, duel.wager)?;
+                }
+            }
+            tx.commit()?;
+
+            Ok(())
+        })
+    }
+
+    fn get_dungeons(&self) -> Result<Vec<Dungeon>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let rows = client.query(&format!("{} ORDER BY dungeon_id", SELECT_DUNGEON), &[])?;
+
+            Ok(rows
+                .iter()
+                .map(dungeon_from_row)
+                .collect::<Result<Vec<_>, _>>()?)
+        })
+    }
+
+    fn get_dungeon_by_name(&self, name: &str) -> Result<Option<Dungeon>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let row = client.query_opt(&format!("{} WHERE name = $1", SELECT_DUNGEON), &[&name])?;
+
+            Ok(row.as_ref().map(dungeon_from_row).transpose()?)
+        })
+    }
+
+    fn add_dungeon(
+        &self,
+        name: &str,
+        zone_id: i64,
+        floors: i32,
+        boss_monster_id: i32,
+    ) -> Result<Dungeon, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let row = client.query_one(
+                "INSERT INTO dungeons (name, zone_id, floors, boss_monster_id) VALUES ($1, $2, $3, $4)
+                ON CONFLICT (name) DO UPDATE SET
+                    zone_id = excluded.zone_id,
+                    floors = excluded.floors,
+                    boss_monster_id = excluded.boss_monster_id
+                RETURNING dungeon_id, name, zone_id, floors, boss_monster_id",
+                &[&name, &zone_id, &floors, &boss_monster_id],
+            )?;
+
+            Ok(dungeon_from_row(&row)?)
+        })
+    }
+
+    fn get_active_run(&self, user_id: i32) -> Result<Option<DungeonRun>, Box<dyn StdError>> {
+        self.with_client(|client| get_active_run(client, user_id))
+    }
+
+    fn start_run(
+        &self,
+        user_id: i32,
+        dungeon: &Dungeon,
+        current_hp: i32,
+        current_mp: i32,
+        now: i64,
+    ) -> Result<DungeonRun, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            // 同じユーザーが2つのダンジョンを同時に探索しないようにする
+            tx.batch_execute("LOCK TABLE dungeon_runs IN EXCLUSIVE MODE")?;
+            if let Some(run) = get_active_run(&mut tx, user_id)? {
+                return Err(Box::new(DungeonError::AlreadyInRun(run.dungeon.name)));
+            }
+
+            tx.execute(
+                "INSERT INTO dungeon_runs (user_id, dungeon_id, status, current_hp, current_mp, started_at)
+                VALUES ($1, $2, $3, $4, $5, $6)",
+                &[
+                    &user_id,
+                    &dungeon.dungeon_id,
+                    &RunStatus::Active.to_i32(),
+                    &current_hp,
+                    &current_mp,
+                    &now,
+                ],
+            )?;
+            let run = get_active_run(&mut tx, user_id)?.ok_or(DungeonError::NoRun)?;
+            tx.commit()?;
+
+            Ok(run)
+        })
+    }
+
+    fn record_floor(
+        &self,
+        run: &DungeonRun,
+        result: &FloorResult,
+    ) -> Result<(), Box<dyn StdError>> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            record_floor(&mut tx, run, result)?;
+            tx.commit()?;
+
+            Ok(())
+        })
+    }
+
+    fn apply_floor_battle(
+        &self,
+        run: &DungeonRun,
+        floor: &FloorResult,
+        monsters: &[Monster],
+        result: &BattleResult,
+        battle_log: &str,
+        event_id: &str,
+    ) -> Result<i64, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            let battle_id = write_battle_result(&mut tx, monsters, result, battle_log, event_id)?;
+            record_floor(&mut tx, run, floor)?;
+            tx.commit()?;
+
+            Ok(battle_id)
+        })
+    }
+
+    fn retreat(&self, run: &DungeonRun, now: i64) -> Result<(), Box<dyn StdError>> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            let updated = tx.execute(
+                "UPDATE dungeon_runs SET status = $1, finished_at = $2 WHERE run_id = $3 AND status = 0",
+                &[&RunStatus::Retreated.to_i32(), &now, &run.run_id],
+            )?;
+            if updated == 0 {
+                return Err(Box::new(DungeonError::NoRun));
+            }
+            remove_run_monsters(&mut tx, run.run_id)?;
+            tx.commit()?;
+
+            Ok(())
+        })
+    }
+
+    fn get_loot_for_monsters(
+        &self,
+        monsters: &[Monster],
+    ) -> Result<Vec<LootEntry>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let statement = client.prepare(
+                "SELECT monsters.id, items.item_id, items.name,
+                    loot_tables.probability, loot_tables.min_quantity, loot_tables.max_quantity
+                FROM monsters
+                JOIN loot_tables ON loot_tables.monster_id = monsters.monster_id
+                JOIN items ON items.item_id = loot_tables.item_id
+                WHERE monsters.id = $1
+                ORDER BY loot_tables.item_id",
+            )?;
+            let mut entries = Vec::new();
+            for monster in monsters {
+                for row in client.query(&statement, &[&monster.id])? {
+                    entries.push(LootEntry {
+                        monster_id: row.try_get(0)?,
+                        item_id: row.try_get(1)?,
+                        item_name: row.try_get(2)?,
+                        probability: row.try_get(3)?,
+                        min_quantity: row.try_get(4)?,
+                        max_quantity: row.try_get(5)?,
+                    });
+                }
+            }
+
+            Ok(entries)
+        })
+    }
+
+    fn set_loot_entry(
+        &self,
+        monster_master_id: i32,
+        item_id: i32,
+        probability: f64,
+        min_quantity: i32,
+        max_quantity: i32,
+    ) -> Result<(), Box<dyn StdError>> {
+        self.with_client(|client| {
+            client.execute(
+                "INSERT INTO loot_tables (monster_id, item_id, probability, min_quantity, max_quantity)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (monster_id, item_id) DO UPDATE SET
+                    probability = excluded.probability,
+                    min_quantity = excluded.min_quantity,
+                    max_quantity = excluded.max_quantity",
+                &[
+                    &monster_master_id,
+                    &item_id,
+                    &probability,
+                    &min_quantity,
+                    &max_quantity,
+                ],
+            )?;
+
+            Ok(())
+        })
+    }
+
+    fn spawn_raid(
+        &self,
+        monster: &Monster,
+        hp: i32,
+        experience_reward: i32,
+        gold_reward: i32,
+        now: i64,
+    ) -> Result<Raid, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            // 同時に2体出現しないように、レイドボスの出現は1つずつ行う
+            tx.batch_execute("LOCK TABLE raids IN EXCLUSIVE MODE")?;
+            if let Some(raid) = get_active_raid(&mut tx)? {
+                return Err(Box::new(RaidError::AlreadyActive(raid.monster.name)));
+            }
+            tx.execute(
+                "INSERT INTO raids (
+                    monster_id, level, name, picture, attack, defense, agility,
+                    experience_reward, gold_reward, hp, max_hp, mp, luck, status, created_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $10, $11, $12, 0, $13)",
+                &[
+                    &monster.id,
+                    &monster.level,
+                    &monster.name,
+                    &monster.picture,
+                    &monster.attack,
+                    &monster.defense,
+                    &monster.agility,
+                    &experience_reward,
+                    &gold_reward,
+                    &hp,
+                    &monster.mp,
+                    &monster.luck,
+                    &now,
+                ],
+            )?;
+            let raid = get_active_raid(&mut tx)?.ok_or(RaidError::NoRaid)?;
+            tx.commit()?;
+
+            Ok(raid)
+        })
+    }
+
+    fn get_active_raid(&self) -> Result<Option<Raid>, Box<dyn StdError>> {
+        self.with_client(get_active_raid)
+    }
+
+    fn get_latest_raid(&self) -> Result<Option<Raid>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let row = client.query_opt(
+                &format!("{} ORDER BY raid_id DESC LIMIT 1", SELECT_RAID),
+                &[],
+            )?;
+
+            Ok(row.as_ref().map(raid_from_row).transpose()?)
+        })
+    }
+
+    fn record_attack(
+        &self,
+        raid: &Raid,
+        attack: &RaidAttack,
+        cooldown_minutes: i64,
+        now: i64,
+    ) -> Result<i32, Box<dyn StdError>> {
+        let user = &attack.result.updated_user;
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            // 先にレイドボスの行を更新してロックし、同じユーザーの攻撃が同時にクールダウンをすり抜けないようにする
+            // クールダウン中ならコミットせずに戻るので、HPは減らない
+            let row = tx.query_opt(
+                "UPDATE raids SET hp = GREATEST(hp - $1, 0) WHERE raid_id = $2 AND status = 0
+                RETURNING hp",
+                &[&attack.damage, &raid.raid_id],
+            )?;
+            let hp: i32 = row.ok_or(RaidError::NoRaid)?.try_get(0)?;
+            let last_attack_at: Option<i64> = tx
+                .query_opt(
+                    "SELECT last_attack_at FROM raid_contributions WHERE raid_id = $1 AND user_id = $2",
+                    &[&raid.raid_id, &user.user_id],
+                )?
+                .map(|row| row.try_get(0))
+                .transpose()?
+                .flatten();
+            raid::check_cooldown(last_attack_at, cooldown_minutes, now)?;
+
+            tx.execute(
+                "INSERT INTO raid_contributions (raid_id, user_id, damage, attacks, last_attack_at)
+                VALUES ($1, $2, $3, 1, $4)
+                ON CONFLICT (raid_id, user_id) DO UPDATE SET
+                    damage = raid_contributions.damage + excluded.damage,
+                    attacks = raid_contributions.attacks + 1,
+                    last_attack_at = excluded.last_attack_at",
+                &[&raid.raid_id, &user.user_id, &attack.damage, &now],
+            )?;
+            update_user(&mut tx, user)?;
+            add_battle_result(&mut tx, attack.result, attack.battle_log, attack.event_id)?;
+            tx.commit()?;
+
+            Ok(hp)
+        })
+    }
+
+    fn get_contributions(&self, raid_id: i64) -> Result<Vec<Contribution>, Box<dyn StdError>> {
+        self.with_client(|client| get_contributions(client, raid_id))
+    }
+
+    fn finish_raid(
+        &self,
+        raid: &Raid,
+        growth_config: &GrowthConfig,
+        now: i64,
+    ) -> Result<Vec<RaidReward>, Box<dyn StdError>> {
+        self.with_client(|client| {
+            let mut tx = client.transaction()?;
+            let finished = tx.execute(
+                "UPDATE raids SET status = 1, defeated_at = $1 WHERE raid_id = $2 AND status = 0 AND hp <= 0",
+                &[&now, &raid.raid_id],
+            )?;
+            if finished == 0 {
+                return Ok(Vec::new());
+            }
+
+            let contributions = get_contributions(&mut tx, raid.raid_id)?;
+            let mut rng = rand::thread_rng();
+            let shares = raid::split_rewards(raid, &contributions);
+            let mut rewards = Vec::new();
+            for (contribution, (experience, gold)) in contributions.iter().zip(shares) {
+                let user = get_user_by_npub(&mut tx, &contribution.npub)?;
+                let updated_user =
+                    battle::grant_rewards(&mut rng, &user, experience, gold, growth_config);
+                update_user(&mut tx, &updated_user)?;
+                rewards.push(raid::reward_for(
+                    contribution,
+                    &user,
+                    &updated_user,
+                    experience,
+                    gold,
+                ));
+            }
+            tx.commit()?;
+
+            Ok(rewards)
+        })
+    }
+}
+
+// QUEST_TEST_POSTGRES_URL に接続先があるときだけ、実際の PostgreSQL に書き込んで確かめる
+// 同じDBで何度流してもぶつからないように、ユーザーとゾーンは毎回新しく作る
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GrowthConfig;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn connect() -> Option<PostgresRepository> {
+        let url = std::env::var("QUEST_TEST_POSTGRES_URL").ok()?;
+        Some(PostgresRepository::connect(&url).unwrap())
+    }
+
+    // 強いユーザーと、新しいゾーンに弱いモンスターの monster_master を用意する
+    fn new_zone(repo: &PostgresRepository) -> (User, Zone, i32) {
+        let suffix: u64 = rand::random();
+        let mut user = repo.add_user(&format!("npub_test_{}", suffix)).unwrap();
+        user.attack += 100;
+        user.agility += 100;
+        repo.with_client(|client| update_user(client, &user))
+            .unwrap();
+        let zone = repo.add_zone(&format!("テスト{}", suffix), 1, "").unwrap();
+        let master_id = repo
+            .add_monster_master(&NewMonsterMaster {
+                level: 1,
+                name: "スライム".to_string(),
+                picture: String::new(),
+                attack: 1,
+                defense: 1,
+                agility: 1,
+                experience_reward: 10,
+                gold_reward: 5,
+                luck: 0,
+                rarity_weight: 100,
+                zone_id: Some(zone.zone_id),
+            })
+            .unwrap();
+
+        (user, zone, master_id)
+    }
+
+    // 新しいゾーンに弱いモンスターを1体出して、強いユーザーと戦わせる
+    fn fight_in_new_zone(repo: &PostgresRepository) -> (User, Vec<Monster>, BattleResult) {
+        let (user, zone, master_id) = new_zone(repo);
+        repo.spawn_monster(master_id, 1, &[]).unwrap();
+
+        let config = EncounterConfig {
+            max_monsters: 1,
+            ..EncounterConfig::default()
+        };
+        let monsters = repo
+            .get_random_monsters(user.level, Some(zone.zone_id), user.user_id, NOW, &config)
+            .unwrap();
+        let snapshot = battle::load_snapshot(repo, &user, &[], &monsters).unwrap();
+        let result = battle::simulate_battle(
+            7,
+            &user,
+            &[],
+            &monsters,
+            &[],
+            snapshot,
+            &GrowthConfig::default(),
+        );
+
+        (user, monsters, result)
+    }
+
+    fn run_monster_count(repo: &PostgresRepository, run_id: i64) -> i64 {
+        repo.with_client(|client| {
+            let row = client.query_one(
+                "SELECT COUNT(*) FROM monsters WHERE dungeon_run_id = $1",
+                &[&run_id],
+            )?;
+            Ok(row.try_get(0)?)
+        })
+        .unwrap()
+    }
+
+    fn monster_row(repo: &PostgresRepository, id: i32) -> (i32, i32, Option<i32>) {
+        repo.with_client(|client| {
+            let row = client.query_one(
+                "SELECT status, hp, reserved_by FROM monsters WHERE id = $1",
+                &[&id],
+            )?;
+            Ok((row.try_get(0)?, row.try_get(1)?, row.try_get(2)?))
+        })
+        .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn defeated_monster_keeps_its_final_hp() {
+        let Some(repo) = connect() else {
+            return;
+        };
+        let (user, monsters, result) = fight_in_new_zone(&repo);
+        assert_eq!(result.outcome, BattleOutcome::Victory);
+        let defeated = Monster {
+            hp: 0,
+            ..monsters[0].clone()
+        };
+
+        let battle_id = repo
+            .apply_battle_result(&[defeated], &result, "log", "event")
+            .unwrap();
+
+        assert_eq!(monster_row(&repo, monsters[0].id), (2, 0, None));
+        let record = repo.get_battle_result_by_id(battle_id).unwrap().unwrap();
+        assert_eq!(record.user_id, user.user_id);
+        assert_eq!(record.seed, 7);
+        let saved = repo.get_user_by_id(user.user_id).unwrap().unwrap();
+        assert_eq!(saved.experience, result.updated_user.experience);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn lost_reservation_saves_nothing() {
+        let Some(repo) = connect() else {
+            return;
+        };
+        let (user, monsters, result) = fight_in_new_zone(&repo);
+        // 予約が切れて、ほかのユーザーが先に取ってしまった
+        repo.with_client(|client| {
+            client.execute(
+                "UPDATE monsters SET reserved_by = $1 WHERE id = $2",
+                &[&(user.user_id + 1), &monsters[0].id],
+            )?;
+            Ok(())
+        })
+        .unwrap();
+
+        let e = repo
+            .apply_battle_result(&monsters, &result, "log", "event")
+            .unwrap_err();
+
+        assert!(matches!(
+            e.downcast_ref::<MonsterError>(),
+            Some(MonsterError::NotReserved(id)) if *id == monsters[0].id
+        ));
+        let saved = repo.get_user_by_id(user.user_id).unwrap().unwrap();
+        assert_eq!(saved.experience, user.experience);
+        assert_eq!(saved.gold, user.gold);
+        assert!(repo
+            .get_battle_results_by_user(user.user_id, 10, 0)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn retreat_clears_the_run_monsters() {
+        let Some(repo) = connect() else {
+            return;
+        };
+        let (user, zone, master_id) = new_zone(&repo);
+        let dungeon = repo
+            .add_dungeon(&format!("{}の洞窟", zone.name), zone.zone_id, 3, master_id)
+            .unwrap();
+        let run = repo.start_run(user.user_id, &dungeon, 10, 5, NOW).unwrap();
+        repo.spawn_for_run(run.run_id, &[master_id], user.user_id, NOW + 60, &[])
+            .unwrap();
+        assert_eq!(run_monster_count(&repo, run.run_id), 1);
+
+        repo.retreat(&run, NOW).unwrap();
+
+        assert_eq!(run_monster_count(&repo, run.run_id), 0);
+    }
+}
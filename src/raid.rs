@@ -0,0 +1,574 @@
+use rusqlite::{Connection, Result};
+use std::error::Error as StdError;
+use std::fmt;
+
+use crate::battle::{self, BattleOutcome, BattleResult, BattleSnapshot, Fight};
+use crate::battle_results;
+use crate::config::GrowthConfig;
+use crate::monsters::Monster;
+use crate::users::{self, User};
+
+// みんなで戦うレイドボス
+pub struct Raid {
+    pub raid_id: i64,
+    pub monster: Monster, // id は monster_master の id、hp は残りのHP、報酬は全員で分ける総額
+    pub max_hp: i32,
+}
+
+// レイドボスに与えたダメージの合計
+pub struct Contribution {
+    pub npub: String,
+    pub damage: i32,
+    pub attacks: i32,
+}
+
+// 1回分のレイドの戦闘。ダメージと一緒に battle_results にも記録する
+pub struct RaidAttack<'a> {
+    pub result: &'a BattleResult,
+    pub damage: i32,
+    pub battle_log: &'a str,
+    pub event_id: &'a str,
+}
+
+// 討伐したときに受け取った報酬
+pub struct RaidReward {
+    pub npub: String,
+    pub damage: i32,
+    pub experience: i32,
+    pub gold: i32,
+    pub level: Option<i32>, // レベルが上がったときは新しいレベル
+}
+
+#[derive(Debug)]
+pub(crate) enum RaidError {
+    NoRaid,
+    AlreadyActive(String),
+    Cooldown { remaining_minutes: i64 },
+}
+
+impl fmt::Display for RaidError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RaidError::NoRaid => write!(f, "No raid boss"),
+            RaidError::AlreadyActive(name) => write!(f, "Raid boss '{}' is still alive", name),
+            RaidError::Cooldown { remaining_minutes } => {
+                write!(f, "Raid cooldown: {} minutes left", remaining_minutes)
+            }
+        }
+    }
+}
+
+impl StdError for RaidError {}
+
+const SELECT_RAID: &str = "SELECT
+        raid_id,
+        monster_id,
+        level,
+        name,
+        picture,
+        attack,
+        defense,
+        agility,
+        experience_reward,
+        gold_reward,
+        hp,
+        max_hp,
+        mp,
+        luck,
+        status
+    FROM raids";
+
+fn raid_from_row(row: &rusqlite::Row) -> Result<Raid> {
+    Ok(Raid {
+        raid_id: row.get(0)?,
+        monster: Monster {
+            id: row.get(1)?,
+            level: row.get(2)?,
+            name: row.get(3)?,
+            picture: row.get(4)?,
+            attack: row.get(5)?,
+            defense: row.get(6)?,
+            agility: row.get(7)?,
+            experience_reward: row.get(8)?,
+            gold_reward: row.get(9)?,
+            hp: row.get(10)?,
+            mp: row.get(12)?,
+            luck: row.get(13)?,
+            status: row.get(14)?,
+            defeat_user_id: -1,
+        },
+        max_hp: row.get(11)?,
+    })
+}
+
+// 出現中のレイドボス（いなければNone）
+pub fn get_active_raid(conn: &Connection) -> Result<Option<Raid>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(&format!(
+        "{} WHERE status = 0 ORDER BY raid_id DESC LIMIT 1",
+        SELECT_RAID
+    ))?;
+    let mut raid_iter = statement.query_map([], raid_from_row)?;
+
+    Ok(raid_iter.next().transpose()?)
+}
+
+// いちばん新しいレイドボス（討伐済みも含む）
+pub fn get_latest_raid(conn: &Connection) -> Result<Option<Raid>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(&format!("{} ORDER BY raid_id DESC LIMIT 1", SELECT_RAID))?;
+    let mut raid_iter = statement.query_map([], raid_from_row)?;
+
+    Ok(raid_iter.next().transpose()?)
+}
+
+// monster_master のモンスターをもとにレイドボスを出現させる。同時に出現できるのは1体だけ
+pub fn spawn_raid(
+    conn: &Connection,
+    monster: &Monster,
+    hp: i32,
+    experience_reward: i32,
+    gold_reward: i32,
+    now: i64,
+) -> Result<Raid, Box<dyn StdError>> {
+    let tx = conn.unchecked_transaction()?;
+    if let Some(raid) = get_active_raid(&tx)? {
+        return Err(Box::new(RaidError::AlreadyActive(raid.monster.name)));
+    }
+    tx.execute(
+        "INSERT INTO raids (
+            monster_id, level, name, picture, attack, defense, agility,
+            experience_reward, gold_reward, hp, max_hp, mp, luck, status, created_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?10, ?11, ?12, 0, ?13)",
+        rusqlite::params![
+            monster.id,
+            monster.level,
+            monster.name,
+            monster.picture,
+            monster.attack,
+            monster.defense,
+            monster.agility,
+            experience_reward,
+            gold_reward,
+            hp,
+            monster.mp,
+            monster.luck,
+            now
+        ],
+    )?;
+    let raid = get_active_raid(&tx)?.ok_or(RaidError::NoRaid)?;
+    tx.commit()?;
+
+    Ok(raid)
+}
+
+// 最後に攻撃してから cooldown_minutes たっていなければ RaidError::Cooldown
+pub(crate) fn check_cooldown(
+    last_attack_at: Option<i64>,
+    cooldown_minutes: i64,
+    now: i64,
+) -> Result<(), RaidError> {
+    if let Some(last_attack_at) = last_attack_at {
+        let remaining = last_attack_at + cooldown_minutes * 60 - now;
+        if remaining > 0 {
+            return Err(RaidError::Cooldown {
+                remaining_minutes: (remaining + 59) / 60,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// レイドの戦闘を battle_results に記録できる形にする。倒れてもHP1で戻る
+// monster_id は monster_master の id。報酬は討伐したときに分けるので、けいけんちとGOLDは0
+pub fn battle_result(
+    raid: &Raid,
+    user: &User,
+    seed: u64,
+    snapshot: BattleSnapshot,
+    fight: Fight,
+) -> BattleResult {
+    let mut updated_user = user.clone();
+    updated_user.current_hp = fight.player_hp.max(1);
+    updated_user.current_mp = fight.player_mp;
+
+    BattleResult {
+        user_id: user.user_id,
+        monster_id: raid.monster.id,
+        defeated_monster_ids: Vec::new(),
+        outcome: fight.outcome,
+        victory: fight.outcome == BattleOutcome::Victory,
+        experience_gain: 0,
+        gold_gain: 0,
+        seed,
+        snapshot,
+        events: fight.events,
+        updated_user,
+        updated_allies: Vec::new(),
+        drops: Vec::new(),
+    }
+}
+
+// 与えたダメージを共有のHPから引き、戦闘後のユーザーの状態と戦闘の記録を保存する。残りのHPを返す
+// 最後に攻撃してから cooldown_minutes たっていなければ RaidError::Cooldown
+pub fn record_attack(
+    conn: &Connection,
+    raid: &Raid,
+    attack: &RaidAttack,
+    cooldown_minutes: i64,
+    now: i64,
+) -> Result<i32, Box<dyn StdError>> {
+    let user = &attack.result.updated_user;
+    let tx = conn.unchecked_transaction()?;
+    let last_attack_at: Option<i64> = tx
+        .query_row(
+            "SELECT last_attack_at FROM raid_contributions WHERE raid_id = ?1 AND user_id = ?2",
+            rusqlite::params![raid.raid_id, user.user_id],
+            |row| row.get(0),
+        )
+        .or_else(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => Ok(None),
+            e => Err(e),
+        })?;
+    check_cooldown(last_attack_at, cooldown_minutes, now)?;
+
+    let updated = tx.execute(
+        "UPDATE raids SET hp = MAX(hp - ?1, 0) WHERE raid_id = ?2 AND status = 0",
+        rusqlite::params![attack.damage, raid.raid_id],
+    )?;
+    if updated == 0 {
+        return Err(Box::new(RaidError::NoRaid));
+    }
+    tx.execute(
+        "INSERT INTO raid_contributions (raid_id, user_id, damage, attacks, last_attack_at)
+        VALUES (?1, ?2, ?3, 1, ?4)
+        ON CONFLICT (raid_id, user_id) DO UPDATE SET
+            damage = damage + excluded.damage,
+            attacks = attacks + 1,
+            last_attack_at = excluded.last_attack_at",
+        rusqlite::params![raid.raid_id, user.user_id, attack.damage, now],
+    )?;
+    users::update_user(&tx, user)?;
+    battle_results::add_battle_result(&tx, attack.result, attack.battle_log, attack.event_id)?;
+    let hp: i32 = tx.query_row(
+        "SELECT hp FROM raids WHERE raid_id = ?1",
+        rusqlite::params![raid.raid_id],
+        |row| row.get(0),
+    )?;
+    tx.commit()?;
+
+    Ok(hp)
+}
+
+// 与えたダメージの多い順
+pub fn get_contributions(
+    conn: &Connection,
+    raid_id: i64,
+) -> Result<Vec<Contribution>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(
+        "SELECT users.npub, raid_contributions.damage, raid_contributions.attacks
+        FROM raid_contributions
+        JOIN users ON users.user_id = raid_contributions.user_id
+        WHERE raid_contributions.raid_id = ?1 AND raid_contributions.damage > 0
+        ORDER BY raid_contributions.damage DESC, raid_contributions.last_attack_at",
+    )?;
+    let contributions = statement
+        .query_map(rusqlite::params![raid_id], |row| {
+            Ok(Contribution {
+                npub: row.get(0)?,
+                damage: row.get(1)?,
+                attacks: row.get(2)?,
+            })
+        })?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(contributions)
+}
+
+// HPが0になったレイドボスを討伐済みにして、与えたダメージに応じて報酬を分ける（端数はダメージの多い順に1ずつ）
+// ほかのユーザーがすでに討伐済みにしていたら何もしない
+pub fn finish_raid(
+    conn: &Connection,
+    raid: &Raid,
+    growth_config: &GrowthConfig,
+    now: i64,
+) -> Result<Vec<RaidReward>, Box<dyn StdError>> {
+    let tx = conn.unchecked_transaction()?;
+    let finished = tx.execute(
+        "UPDATE raids SET status = 1, defeated_at = ?1 WHERE raid_id = ?2 AND status = 0 AND hp <= 0",
+        rusqlite::params![now, raid.raid_id],
+    )?;
+    if finished == 0 {
+        return Ok(Vec::new());
+    }
+
+    let contributions = get_contributions(&tx, raid.raid_id)?;
+    let mut rng = rand::thread_rng();
+    let shares = split_rewards(raid, &contributions);
+    let mut rewards = Vec::new();
+    for (contribution, (experience, gold)) in contributions.iter().zip(shares) {
+        let user = users::get_user_by_npub(&tx, &contribution.npub)?;
+        let updated_user = battle::grant_rewards(&mut rng, &user, experience, gold, growth_config);
+        users::update_user(&tx, &updated_user)?;
+        rewards.push(reward_for(
+            contribution,
+            &user,
+            &updated_user,
+            experience,
+            gold,
+        ));
+    }
+    tx.commit()?;
+
+    Ok(rewards)
+}
+
+// 与えたダメージに応じて、報酬の総額を (けいけんち, GOLD) に分ける
+// 切り捨てで分けたあと、余りをダメージの多い順に1ずつ配るので、合計は総額をこえない
+pub(crate) fn split_rewards(raid: &Raid, contributions: &[Contribution]) -> Vec<(i32, i32)> {
+    let experience = split_pool(raid.monster.experience_reward, contributions);
+    let gold = split_pool(raid.monster.gold_reward, contributions);
+
+    experience.into_iter().zip(gold).collect()
+}
+
+fn split_pool(pool: i32, contributions: &[Contribution]) -> Vec<i32> {
+    let total_damage: i64 = contributions
+        .iter()
+        .map(|contribution| contribution.damage as i64)
+        .sum();
+    if total_damage <= 0 {
+        return vec![0; contributions.len()];
+    }
+    let mut shares: Vec<i32> = contributions
+        .iter()
+        .map(|contribution| (pool as i64 * contribution.damage as i64 / total_damage) as i32)
+        .collect();
+
+    // ダメージが同じなら先に並んでいる方（先に攻撃した方）から
+    let mut order: Vec<usize> = (0..contributions.len()).collect();
+    order.sort_by_key(|&index| std::cmp::Reverse(contributions[index].damage));
+    let remainder = pool - shares.iter().sum::<i32>();
+    for &index in order.iter().take(remainder.max(0) as usize) {
+        shares[index] += 1;
+    }
+
+    shares
+}
+
+pub(crate) fn reward_for(
+    contribution: &Contribution,
+    user: &User,
+    updated_user: &User,
+    experience: i32,
+    gold: i32,
+) -> RaidReward {
+    RaidReward {
+        npub: contribution.npub.clone(),
+        damage: contribution.damage,
+        experience,
+        gold,
+        level: (updated_user.level > user.level).then_some(updated_user.level),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::battle::Combatant;
+    use crate::db;
+    use crate::items::EquipmentBonus;
+
+    fn boss(experience_reward: i32, gold_reward: i32) -> Monster {
+        Monster {
+            id: 1,
+            level: 10,
+            status: 0,
+            name: "ドラゴン".to_string(),
+            picture: String::new(),
+            attack: 20,
+            defense: 10,
+            agility: 5,
+            experience_reward,
+            gold_reward,
+            hp: 100,
+            mp: 0,
+            defeat_user_id: -1,
+            luck: 0,
+        }
+    }
+
+    fn contribution(npub: &str, damage: i32) -> Contribution {
+        Contribution {
+            npub: npub.to_string(),
+            damage,
+            attacks: 1,
+        }
+    }
+
+    fn raid(experience_reward: i32, gold_reward: i32) -> Raid {
+        Raid {
+            raid_id: 1,
+            monster: boss(experience_reward, gold_reward),
+            max_hp: 100,
+        }
+    }
+
+    // 装備もスキルもない状態でレイドボスと戦い、damage を与えたことにして記録する
+    fn attack(
+        conn: &Connection,
+        raid: &Raid,
+        user: &User,
+        damage: i32,
+        now: i64,
+    ) -> Result<i32, Box<dyn StdError>> {
+        let snapshot = BattleSnapshot {
+            player: Combatant::from_user(user, &EquipmentBonus::default()),
+            monster: Combatant::from_monster(&raid.monster),
+            allies: Vec::new(),
+            enemies: Vec::new(),
+        };
+        let fight = battle::replay(1, &snapshot);
+        let result = battle_result(raid, user, 1, snapshot, fight);
+        record_attack(
+            conn,
+            raid,
+            &RaidAttack {
+                result: &result,
+                damage,
+                battle_log: "log",
+                event_id: "event",
+            },
+            10,
+            now,
+        )
+    }
+
+    #[test]
+    fn remainder_goes_to_the_top_damage_dealers() {
+        let contributions = [
+            contribution("a", 50),
+            contribution("b", 25),
+            contribution("c", 25),
+        ];
+
+        let shares = split_rewards(&raid(101, 7), &contributions);
+
+        assert_eq!(shares, vec![(51, 4), (25, 2), (25, 1)]);
+    }
+
+    #[test]
+    fn shares_never_exceed_the_pool() {
+        let damages = [
+            vec![1, 1, 1],
+            vec![7, 3],
+            vec![33, 33, 33, 1],
+            vec![5, 9, 2, 9, 1],
+            vec![1, 1_000_000],
+        ];
+        for damages in damages {
+            let contributions: Vec<Contribution> = damages
+                .iter()
+                .map(|&damage| contribution("npub", damage))
+                .collect();
+            for (experience, gold) in [(0, 0), (1, 2), (100, 7), (101, 999), (12_345, 67)] {
+                let shares = split_rewards(&raid(experience, gold), &contributions);
+
+                let total_experience: i32 = shares.iter().map(|share| share.0).sum();
+                let total_gold: i32 = shares.iter().map(|share| share.1).sum();
+                assert_eq!((total_experience, total_gold), (experience, gold));
+            }
+        }
+    }
+
+    #[test]
+    fn single_attacker_takes_the_whole_pool() {
+        let shares = split_rewards(&raid(300, 50), &[contribution("a", 7)]);
+
+        assert_eq!(shares, vec![(300, 50)]);
+    }
+
+    #[test]
+    fn no_contributions_no_rewards() {
+        assert!(split_rewards(&raid(300, 50), &[]).is_empty());
+    }
+
+    #[test]
+    fn finished_raid_pays_once() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::migrate(&conn).unwrap();
+        let raid = spawn_raid(&conn, &boss(0, 0), 30, 0, 90, 0).unwrap();
+        let first = users::add_user(&conn, "npub_first").unwrap();
+        let second = users::add_user(&conn, "npub_second").unwrap();
+        attack(&conn, &raid, &first, 20, 0).unwrap();
+        let hp = attack(&conn, &raid, &second, 40, 0).unwrap();
+        assert_eq!(hp, 0);
+
+        let rewards = finish_raid(&conn, &raid, &GrowthConfig::default(), 1).unwrap();
+        let again = finish_raid(&conn, &raid, &GrowthConfig::default(), 2).unwrap();
+
+        let paid: Vec<(&str, i32)> = rewards
+            .iter()
+            .map(|reward| (reward.npub.as_str(), reward.gold))
+            .collect();
+        assert_eq!(paid, vec![("npub_second", 60), ("npub_first", 30)]);
+        assert!(again.is_empty());
+        assert_eq!(
+            users::get_user_by_npub(&conn, "npub_second").unwrap().gold,
+            60
+        );
+        assert_eq!(
+            users::get_user_by_npub(&conn, "npub_first").unwrap().gold,
+            30
+        );
+        assert!(get_active_raid(&conn).unwrap().is_none());
+    }
+
+    #[test]
+    fn attacks_wait_for_cooldown() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::migrate(&conn).unwrap();
+        let raid = spawn_raid(&conn, &boss(0, 0), 100, 0, 0, 0).unwrap();
+        let user = users::add_user(&conn, "npub_attacker").unwrap();
+        attack(&conn, &raid, &user, 10, 0).unwrap();
+
+        let e = attack(&conn, &raid, &user, 10, 9 * 60 + 1).unwrap_err();
+
+        assert!(matches!(
+            e.downcast_ref::<RaidError>(),
+            Some(RaidError::Cooldown {
+                remaining_minutes: 1
+            })
+        ));
+        assert_eq!(attack(&conn, &raid, &user, 10, 10 * 60).unwrap(), 80);
+        // クールダウン中の攻撃は記録しない
+        let records =
+            battle_results::get_battle_results_by_user(&conn, user.user_id, 10, 0).unwrap();
+        assert_eq!(records.len(), 2);
+    }
+
+    #[test]
+    fn each_attack_is_saved_as_a_battle() {
+        let conn = Connection::open_in_memory().unwrap();
+        db::migrate(&conn).unwrap();
+        let raid = spawn_raid(&conn, &boss(0, 0), 100, 0, 0, 0).unwrap();
+        let user = users::add_user(&conn, "npub_attacker").unwrap();
+
+        attack(&conn, &raid, &user, 10, 0).unwrap();
+
+        let records =
+            battle_results::get_battle_results_by_user(&conn, user.user_id, 10, 0).unwrap();
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert_eq!(record.monster_id, raid.monster.id);
+        assert_eq!(record.battle_log, "log");
+        assert_eq!(record.event_id, "event");
+        assert_eq!(record.seed, 1);
+        assert_eq!((record.experience_gain, record.gold_gain), (0, 0));
+        assert!(battle::replay_matches(
+            record.seed,
+            record.snapshot.as_ref().unwrap(),
+            record.outcome,
+            record.events.as_deref().unwrap(),
+        ));
+    }
+}
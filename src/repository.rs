@@ -0,0 +1,756 @@
+use rusqlite::Connection;
+use std::error::Error as StdError;
+#[cfg(not(feature = "postgres"))]
+use std::fmt;
+
+use crate::battle::{self, BattleResult, BattleSnapshot};
+use crate::battle_results::{self, BattleRecord, BattleStats};
+use crate::config::{EncounterConfig, GrowthConfig, StorageBackend, StorageConfig, VariantConfig};
+use crate::db;
+use crate::duel::{self, Duel, DuelStatus};
+use crate::dungeon::{self, Dungeon, DungeonRun, FloorResult};
+use crate::items::{self, EquipmentBonus, InventoryEntry, Item};
+use crate::loot::{self, LootEntry};
+use crate::monsters::{self, Monster, NewMonsterMaster};
+use crate::party::{self, Party};
+use crate::raid::{self, Contribution, Raid, RaidAttack, RaidReward};
+use crate::shop::{self, ShopEntry};
+use crate::skills::{self, Skill};
+use crate::stats::{self, Stat};
+use crate::users::{self, User};
+use crate::zones::{self, Zone};
+
+// ユーザー・モンスター・戦闘結果・アイテムなど、ゲームの状態の保存先
+// ゲームの処理はこのトレイトを通して読み書きし、どこに保存するかは実装に任せる
+pub trait Repository {
+    // ユーザー
+    fn add_user(&self, npub: &str) -> Result<User, Box<dyn StdError>>;
+    fn get_user_by_npub(&self, npub: &str) -> Result<User, Box<dyn StdError>>;
+    fn get_user_by_id(&self, user_id: i32) -> Result<Option<User>, Box<dyn StdError>>;
+    fn apply_regeneration(
+        &self,
+        user: User,
+        hp_per_hour: i32,
+        mp_per_hour: i32,
+        now: i64,
+    ) -> Result<User, Box<dyn StdError>>;
+    fn rest_at_inn(&self, user: &User, cost: i32, now: i64) -> Result<bool, Box<dyn StdError>>;
+    fn allocate(&self, user: &User, stat: Stat, points: i32) -> Result<User, Box<dyn StdError>>;
+    fn respec(&self, user: &User, cost: i32) -> Result<User, Box<dyn StdError>>;
+    fn get_log_style(&self, user_id: i32) -> Result<Option<String>, Box<dyn StdError>>;
+    fn set_log_style(&self, user_id: i32, log_style: &str) -> Result<(), Box<dyn StdError>>;
+    fn get_selected_skill(&self, user_id: i32) -> Result<Option<String>, Box<dyn StdError>>;
+    fn set_selected_skill(
+        &self,
+        user_id: i32,
+        selected_skill: Option<&str>,
+    ) -> Result<(), Box<dyn StdError>>;
+
+    // スキル
+    fn get_skills_for_level(&self, level: i32) -> Result<Vec<Skill>, Box<dyn StdError>>;
+    fn get_next_skill(&self, level: i32) -> Result<Option<Skill>, Box<dyn StdError>>;
+
+    // モンスター
+    fn spawn_monster(
+        &self,
+        monster_id: i32,
+        amount: i32,
+        variants: &[VariantConfig],
+    ) -> Result<Monster, Box<dyn StdError>>;
+    fn spawn_for_run(
+        &self,
+        run_id: i64,
+        monster_ids: &[i32],
+        user_id: i32,
+        reserved_until: i64,
+        variants: &[VariantConfig],
+    ) -> Result<Vec<Monster>, Box<dyn StdError>>;
+    fn choose_floor_monsters(
+        &self,
+        level: i32,
+        zone_id: i64,
+        config: &EncounterConfig,
+    ) -> Result<Vec<i32>, Box<dyn StdError>>;
+    fn get_random_monsters(
+        &self,
+        level: i32,
+        zone_id: Option<i64>,
+        user_id: i32,
+        now: i64,
+        config: &EncounterConfig,
+    ) -> Result<Vec<Monster>, Box<dyn StdError>>;
+    fn count_alive(&self, monster_id: i32) -> Result<i32, Box<dyn StdError>>;
+    fn get_monster_master(&self, id: i32) -> Result<Option<Monster>, Box<dyn StdError>>;
+    fn add_monster_master(&self, master: &NewMonsterMaster) -> Result<i32, Box<dyn StdError>>;
+
+    // 戦闘結果
+    fn apply_battle_result(
+        &self,
+        monsters: &[Monster],
+        result: &BattleResult,
+        battle_log: &str,
+        event_id: &str,
+    ) -> Result<i64, Box<dyn StdError>>;
+    fn get_battle_result_by_id(
+        &self,
+        battle_id: i64,
+    ) -> Result<Option<BattleRecord>, Box<dyn StdError>>;
+    fn get_battle_results_by_user(
+        &self,
+        user_id: i32,
+        limit: i32,
+        offset: i32,
+    ) -> Result<Vec<BattleRecord>, Box<dyn StdError>>;
+    fn get_battle_stats_by_user(&self, user_id: i32) -> Result<BattleStats, Box<dyn StdError>>;
+
+    // アイテム
+    fn get_item_by_name(&self, name: &str) -> Result<Option<Item>, Box<dyn StdError>>;
+    fn give_item(&self, user_id: i32, item_id: i32, quantity: i32)
+        -> Result<(), Box<dyn StdError>>;
+    fn get_inventory(&self, user_id: i32) -> Result<Vec<InventoryEntry>, Box<dyn StdError>>;
+    fn add_item(
+        &self,
+        name: &str,
+        item_type: &str,
+        attack_bonus: i32,
+        defense_bonus: i32,
+        agility_bonus: i32,
+    ) -> Result<Item, Box<dyn StdError>>;
+    fn get_equipment(&self, user_id: i32) -> Result<Vec<Item>, Box<dyn StdError>>;
+    fn get_equipment_bonus(&self, user_id: i32) -> Result<EquipmentBonus, Box<dyn StdError>> {
+        Ok(items::equipment_bonus(&self.get_equipment(user_id)?))
+    }
+    fn equip_item(&self, user_id: i32, name: &str) -> Result<Item, Box<dyn StdError>>;
+    fn unequip_slot(&self, user_id: i32, slot: &str) -> Result<Item, Box<dyn StdError>>;
+
+    // お店
+    fn get_shop_entries(&self) -> Result<Vec<ShopEntry>, Box<dyn StdError>>;
+    fn set_shop_entry(&self, item_id: i32, price: i32, stock: i32)
+        -> Result<(), Box<dyn StdError>>;
+    fn remove_shop_entry(&self, item_id: i32) -> Result<bool, Box<dyn StdError>>;
+    fn buy_item(&self, user_id: i32, name: &str) -> Result<ShopEntry, Box<dyn StdError>>;
+    fn sell_item(&self, user_id: i32, name: &str) -> Result<ShopEntry, Box<dyn StdError>>;
+
+    // 地域
+    fn get_zones(&self) -> Result<Vec<Zone>, Box<dyn StdError>>;
+    fn get_zone_by_name(&self, name: &str) -> Result<Option<Zone>, Box<dyn StdError>>;
+    fn add_zone(
+        &self,
+        name: &str,
+        recommended_level: i32,
+        description: &str,
+    ) -> Result<Zone, Box<dyn StdError>>;
+    fn assign_monster(
+        &self,
+        monster_master_id: i32,
+        zone_id: i64,
+    ) -> Result<bool, Box<dyn StdError>>;
+    fn get_current_zone(&self, user_id: i32) -> Result<Option<Zone>, Box<dyn StdError>>;
+    fn travel(&self, user_id: i32, zone: &Zone) -> Result<(), Box<dyn StdError>>;
+
+    // パーティー
+    fn get_party_by_user(&self, user_id: i32) -> Result<Option<Party>, Box<dyn StdError>>;
+    fn create_party(&self, user_id: i32) -> Result<Party, Box<dyn StdError>>;
+    fn invite(&self, leader_id: i32, user_id: i32) -> Result<Party, Box<dyn StdError>>;
+    fn join_party(&self, user_id: i32) -> Result<Party, Box<dyn StdError>>;
+    fn leave_party(&self, user_id: i32) -> Result<bool, Box<dyn StdError>>;
+
+    // 決闘
+    fn get_pending_duel_for(&self, opponent_id: i32) -> Result<Option<Duel>, Box<dyn StdError>>;
+    fn get_pending_duel_by(&self, challenger_id: i32) -> Result<Option<Duel>, Box<dyn StdError>>;
+    fn challenge(
+        &self,
+        challenger_id: i32,
+        opponent_id: i32,
+        wager: i32,
+        cooldown_minutes: i64,
+        now: i64,
+    ) -> Result<Duel, Box<dyn StdError>>;
+    fn close_duel(
+        &self,
+        duel: &Duel,
+        status: DuelStatus,
+        now: i64,
+    ) -> Result<(), Box<dyn StdError>>;
+    fn resolve_duel(
+        &self,
+        duel: &Duel,
+        winner_id: Option<i32>,
+        seed: u64,
+        snapshot: &BattleSnapshot,
+        battle_log: &str,
+        now: i64,
+    ) -> Result<(), Box<dyn StdError>>;
+
+    // ダンジョン
+    fn get_dungeons(&self) -> Result<Vec<Dungeon>, Box<dyn StdError>>;
+    fn get_dungeon_by_name(&self, name: &str) -> Result<Option<Dungeon>, Box<dyn StdError>>;
+    fn add_dungeon(
+        &self,
+        name: &str,
+        zone_id: i64,
+        floors: i32,
+        boss_monster_id: i32,
+    ) -> Result<Dungeon, Box<dyn StdError>>;
+    fn get_active_run(&self, user_id: i32) -> Result<Option<DungeonRun>, Box<dyn StdError>>;
+    fn start_run(
+        &self,
+        user_id: i32,
+        dungeon: &Dungeon,
+        current_hp: i32,
+        current_mp: i32,
+        now: i64,
+    ) -> Result<DungeonRun, Box<dyn StdError>>;
+    fn record_floor(&self, run: &DungeonRun, result: &FloorResult)
+        -> Result<(), Box<dyn StdError>>;
+    fn apply_floor_battle(
+        &self,
+        run: &DungeonRun,
+        floor: &FloorResult,
+        monsters: &[Monster],
+        result: &BattleResult,
+        battle_log: &str,
+        event_id: &str,
+    ) -> Result<i64, Box<dyn StdError>>;
+    fn retreat(&self, run: &DungeonRun, now: i64) -> Result<(), Box<dyn StdError>>;
+
+    // ドロップ表
+    fn get_loot_for_monsters(
+        &self,
+        monsters: &[Monster],
+    ) -> Result<Vec<LootEntry>, Box<dyn StdError>>;
+    fn set_loot_entry(
+        &self,
+        monster_master_id: i32,
+        item_id: i32,
+        probability: f64,
+        min_quantity: i32,
+        max_quantity: i32,
+    ) -> Result<(), Box<dyn StdError>>;
+
+    // レイド
+    fn spawn_raid(
+        &self,
+        monster: &Monster,
+        hp: i32,
+        experience_reward: i32,
+        gold_reward: i32,
+        now: i64,
+    ) -> Result<Raid, Box<dyn StdError>>;
+    fn get_active_raid(&self) -> Result<Option<Raid>, Box<dyn StdError>>;
+    fn get_latest_raid(&self) -> Result<Option<Raid>, Box<dyn StdError>>;
+    fn record_attack(
+        &self,
+        raid: &Raid,
+        attack: &RaidAttack,
+        cooldown_minutes: i64,
+        now: i64,
+    ) -> Result<i32, Box<dyn StdError>>;
+    fn get_contributions(&self, raid_id: i64) -> Result<Vec<Contribution>, Box<dyn StdError>>;
+    fn finish_raid(
+        &self,
+        raid: &Raid,
+        growth_config: &GrowthConfig,
+        now: i64,
+    ) -> Result<Vec<RaidReward>, Box<dyn StdError>>;
+}
+
+// postgres を有効にせずにビルドしたときだけ使う
+#[cfg(not(feature = "postgres"))]
+#[derive(Debug)]
+pub(crate) enum StorageError {
+    FeatureDisabled(StorageBackend),
+}
+
+#[cfg(not(feature = "postgres"))]
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageError::FeatureDisabled(backend) => write!(
+                f,
+                "Storage backend {:?} is not enabled in this build (build with --features postgres)",
+                backend
+            ),
+        }
+    }
+}
+
+#[cfg(not(feature = "postgres"))]
+impl StdError for StorageError {}
+
+// config.yml の storage で選んだ保存先を開く
+pub fn open(config: &StorageConfig) -> Result<Box<dyn Repository + Send>, Box<dyn StdError>> {
+    match config.backend {
+        StorageBackend::Sqlite => Ok(Box::new(SqliteRepository::new(db::connect()?))),
+        #[cfg(feature = "postgres")]
+        StorageBackend::Postgres => Ok(Box::new(
+            crate::postgres_repository::PostgresRepository::connect(&config.url)?,
+        )),
+        #[cfg(not(feature = "postgres"))]
+        StorageBackend::Postgres => Err(Box::new(StorageError::FeatureDisabled(
+            StorageBackend::Postgres,
+        ))),
+    }
+}
+
+// quest.db に保存する
+pub struct SqliteRepository {
+    conn: Connection,
+}
+
+impl SqliteRepository {
+    pub fn new(conn: Connection) -> Self {
+        SqliteRepository { conn }
+    }
+}
+
+impl Repository for SqliteRepository {
+    fn add_user(&self, npub: &str) -> Result<User, Box<dyn StdError>> {
+        users::add_user(&self.conn, npub)
+    }
+
+    fn get_user_by_npub(&self, npub: &str) -> Result<User, Box<dyn StdError>> {
+        users::get_user_by_npub(&self.conn, npub)
+    }
+
+    fn get_user_by_id(&self, user_id: i32) -> Result<Option<User>, Box<dyn StdError>> {
+        users::get_user_by_id(&self.conn, user_id)
+    }
+
+    fn apply_regeneration(
+        &self,
+        user: User,
+        hp_per_hour: i32,
+        mp_per_hour: i32,
+        now: i64,
+    ) -> Result<User, Box<dyn StdError>> {
+        users::apply_regeneration(&self.conn, user, hp_per_hour, mp_per_hour, now)
+    }
+
+    fn rest_at_inn(&self, user: &User, cost: i32, now: i64) -> Result<bool, Box<dyn StdError>> {
+        users::rest_at_inn(&self.conn, user, cost, now)
+    }
+
+    fn allocate(&self, user: &User, stat: Stat, points: i32) -> Result<User, Box<dyn StdError>> {
+        stats::allocate(&self.conn, user, stat, points)
+    }
+
+    fn respec(&self, user: &User, cost: i32) -> Result<User, Box<dyn StdError>> {
+        stats::respec(&self.conn, user, cost)
+    }
+
+    fn get_log_style(&self, user_id: i32) -> Result<Option<String>, Box<dyn StdError>> {
+        users::get_log_style(&self.conn, user_id)
+    }
+
+    fn set_log_style(&self, user_id: i32, log_style: &str) -> Result<(), Box<dyn StdError>> {
+        users::set_log_style(&self.conn, user_id, log_style)
+    }
+
+    fn get_selected_skill(&self, user_id: i32) -> Result<Option<String>, Box<dyn StdError>> {
+        users::get_selected_skill(&self.conn, user_id)
+    }
+
+    fn set_selected_skill(
+        &self,
+        user_id: i32,
+        selected_skill: Option<&str>,
+    ) -> Result<(), Box<dyn StdError>> {
+        users::set_selected_skill(&self.conn, user_id, selected_skill)
+    }
+
+    fn get_skills_for_level(&self, level: i32) -> Result<Vec<Skill>, Box<dyn StdError>> {
+        skills::get_skills_for_level(&self.conn, level)
+    }
+
+    fn get_next_skill(&self, level: i32) -> Result<Option<Skill>, Box<dyn StdError>> {
+        skills::get_next_skill(&self.conn, level)
+    }
+
+    fn spawn_monster(
+        &self,
+        monster_id: i32,
+        amount: i32,
+        variants: &[VariantConfig],
+    ) -> Result<Monster, Box<dyn StdError>> {
+        monsters::spawn_monster(&self.conn, monster_id, amount, variants)
+    }
+
+    fn spawn_for_run(
+        &self,
+        run_id: i64,
+        monster_ids: &[i32],
+        user_id: i32,
+        reserved_until: i64,
+        variants: &[VariantConfig],
+    ) -> Result<Vec<Monster>, Box<dyn StdError>> {
+        monsters::spawn_for_run(
+            &self.conn,
+            run_id,
+            monster_ids,
+            user_id,
+            reserved_until,
+            variants,
+        )
+    }
+
+    fn choose_floor_monsters(
+        &self,
+        level: i32,
+        zone_id: i64,
+        config: &EncounterConfig,
+    ) -> Result<Vec<i32>, Box<dyn StdError>> {
+        monsters::choose_floor_monsters(&self.conn, level, zone_id, config)
+    }
+
+    fn get_random_monsters(
+        &self,
+        level: i32,
+        zone_id: Option<i64>,
+        user_id: i32,
+        now: i64,
+        config: &EncounterConfig,
+    ) -> Result<Vec<Monster>, Box<dyn StdError>> {
+        monsters::get_random_monsters(&self.conn, level, zone_id, user_id, now, config)
+    }
+
+    fn apply_battle_result(
+        &self,
+        monsters: &[Monster],
+        result: &BattleResult,
+        battle_log: &str,
+        event_id: &str,
+    ) -> Result<i64, Box<dyn StdError>> {
+        battle::apply_battle_result(&self.conn, monsters, result, battle_log, event_id)
+    }
+
+    fn get_battle_result_by_id(
+        &self,
+        battle_id: i64,
+    ) -> Result<Option<BattleRecord>, Box<dyn StdError>> {
+        battle_results::get_battle_result_by_id(&self.conn, battle_id)
+    }
+
+    fn get_battle_results_by_user(
+        &self,
+        user_id: i32,
+        limit: i32,
+        offset: i32,
+    ) -> Result<Vec<BattleRecord>, Box<dyn StdError>> {
+        battle_results::get_battle_results_by_user(&self.conn, user_id, limit, offset)
+    }
+
+    fn get_battle_stats_by_user(&self, user_id: i32) -> Result<BattleStats, Box<dyn StdError>> {
+        battle_results::get_battle_stats_by_user(&self.conn, user_id)
+    }
+
+    fn get_item_by_name(&self, name: &str) -> Result<Option<Item>, Box<dyn StdError>> {
+        items::get_item_by_name(&self.conn, name)
+    }
+
+    fn give_item(
+        &self,
+        user_id: i32,
+        item_id: i32,
+        quantity: i32,
+    ) -> Result<(), Box<dyn StdError>> {
+        items::give_item(&self.conn, user_id, item_id, quantity)
+    }
+
+    fn get_inventory(&self, user_id: i32) -> Result<Vec<InventoryEntry>, Box<dyn StdError>> {
+        items::get_inventory(&self.conn, user_id)
+    }
+
+    fn count_alive(&self, monster_id: i32) -> Result<i32, Box<dyn StdError>> {
+        monsters::count_alive(&self.conn, monster_id)
+    }
+
+    fn get_monster_master(&self, id: i32) -> Result<Option<Monster>, Box<dyn StdError>> {
+        monsters::get_monster_by_id(&self.conn, id)
+    }
+
+    fn add_monster_master(&self, master: &NewMonsterMaster) -> Result<i32, Box<dyn StdError>> {
+        monsters::add_monster_master(&self.conn, master)
+    }
+
+    fn add_item(
+        &self,
+        name: &str,
+        item_type: &str,
+        attack_bonus: i32,
+        defense_bonus: i32,
+        agility_bonus: i32,
+    ) -> Result<Item, Box<dyn StdError>> {
+        items::add_item(
+            &self.conn,
+            name,
+            item_type,
+            attack_bonus,
+            defense_bonus,
+            agility_bonus,
+        )
+    }
+
+    fn get_equipment(&self, user_id: i32) -> Result<Vec<Item>, Box<dyn StdError>> {
+        items::get_equipment(&self.conn, user_id)
+    }
+
+    fn equip_item(&self, user_id: i32, name: &str) -> Result<Item, Box<dyn StdError>> {
+        items::equip_item(&self.conn, user_id, name)
+    }
+
+    fn unequip_slot(&self, user_id: i32, slot: &str) -> Result<Item, Box<dyn StdError>> {
+        items::unequip_slot(&self.conn, user_id, slot)
+    }
+
+    fn get_shop_entries(&self) -> Result<Vec<ShopEntry>, Box<dyn StdError>> {
+        shop::get_shop_entries(&self.conn)
+    }
+
+    fn set_shop_entry(
+        &self,
+        item_id: i32,
+        price: i32,
+        stock: i32,
+    ) -> Result<(), Box<dyn StdError>> {
+        shop::set_shop_entry(&self.conn, item_id, price, stock)
+    }
+
+    fn remove_shop_entry(&self, item_id: i32) -> Result<bool, Box<dyn StdError>> {
+        shop::remove_shop_entry(&self.conn, item_id)
+    }
+
+    fn buy_item(&self, user_id: i32, name: &str) -> Result<ShopEntry, Box<dyn StdError>> {
+        shop::buy_item(&self.conn, user_id, name)
+    }
+
+    fn sell_item(&self, user_id: i32, name: &str) -> Result<ShopEntry, Box<dyn StdError>> {
+        shop::sell_item(&self.conn, user_id, name)
+    }
+
+    fn get_zones(&self) -> Result<Vec<Zone>, Box<dyn StdError>> {
+        zones::get_zones(&self.conn)
+    }
+
+    fn get_zone_by_name(&self, name: &str) -> Result<Option<Zone>, Box<dyn StdError>> {
+        zones::get_zone_by_name(&self.conn, name)
+    }
+
+    fn add_zone(
+        &self,
+        name: &str,
+        recommended_level: i32,
+        description: &str,
+    ) -> Result<Zone, Box<dyn StdError>> {
+        zones::add_zone(&self.conn, name, recommended_level, description)
+    }
+
+    fn assign_monster(
+        &self,
+        monster_master_id: i32,
+        zone_id: i64,
+    ) -> Result<bool, Box<dyn StdError>> {
+        zones::assign_monster(&self.conn, monster_master_id, zone_id)
+    }
+
+    fn get_current_zone(&self, user_id: i32) -> Result<Option<Zone>, Box<dyn StdError>> {
+        zones::get_current_zone(&self.conn, user_id)
+    }
+
+    fn travel(&self, user_id: i32, zone: &Zone) -> Result<(), Box<dyn StdError>> {
+        zones::travel(&self.conn, user_id, zone)
+    }
+
+    fn get_party_by_user(&self, user_id: i32) -> Result<Option<Party>, Box<dyn StdError>> {
+        party::get_party_by_user(&self.conn, user_id)
+    }
+
+    fn create_party(&self, user_id: i32) -> Result<Party, Box<dyn StdError>> {
+        party::create_party(&self.conn, user_id)
+    }
+
+    fn invite(&self, leader_id: i32, user_id: i32) -> Result<Party, Box<dyn StdError>> {
+        party::invite(&self.conn, leader_id, user_id)
+    }
+
+    fn join_party(&self, user_id: i32) -> Result<Party, Box<dyn StdError>> {
+        party::join_party(&self.conn, user_id)
+    }
+
+    fn leave_party(&self, user_id: i32) -> Result<bool, Box<dyn StdError>> {
+        party::leave_party(&self.conn, user_id)
+    }
+
+    fn get_pending_duel_for(&self, opponent_id: i32) -> Result<Option<Duel>, Box<dyn StdError>> {
+        duel::get_pending_duel_for(&self.conn, opponent_id)
+    }
+
+    fn get_pending_duel_by(&self, challenger_id: i32) -> Result<Option<Duel>, Box<dyn StdError>> {
+        duel::get_pending_duel_by(&self.conn, challenger_id)
+    }
+
+    fn challenge(
+        &self,
+        challenger_id: i32,
+        opponent_id: i32,
+        wager: i32,
+        cooldown_minutes: i64,
+        now: i64,
+    ) -> Result<Duel, Box<dyn StdError>> {
+        duel::challenge(
+            &self.conn,
+            challenger_id,
+            opponent_id,
+            wager,
+            cooldown_minutes,
+            now,
+        )
+    }
+
+    fn close_duel(
+        &self,
+        duel: &Duel,
+        status: DuelStatus,
+        now: i64,
+    ) -> Result<(), Box<dyn StdError>> {
+        duel::close_duel(&self.conn, duel, status, now)
+    }
+
+    fn resolve_duel(
+        &self,
+        duel: &Duel,
+        winner_id: Option<i32>,
+        seed: u64,
+        snapshot: &BattleSnapshot,
+        battle_log: &str,
+        now: i64,
+    ) -> Result<(), Box<dyn StdError>> {
+        duel::resolve_duel(&self.conn, duel, winner_id, seed, snapshot, battle_log, now)
+    }
+
+    fn get_dungeons(&self) -> Result<Vec<Dungeon>, Box<dyn StdError>> {
+        dungeon::get_dungeons(&self.conn)
+    }
+
+    fn get_dungeon_by_name(&self, name: &str) -> Result<Option<Dungeon>, Box<dyn StdError>> {
+        dungeon::get_dungeon_by_name(&self.conn, name)
+    }
+
+    fn add_dungeon(
+        &self,
+        name: &str,
+        zone_id: i64,
+        floors: i32,
+        boss_monster_id: i32,
+    ) -> Result<Dungeon, Box<dyn StdError>> {
+        dungeon::add_dungeon(&self.conn, name, zone_id, floors, boss_monster_id)
+    }
+
+    fn get_active_run(&self, user_id: i32) -> Result<Option<DungeonRun>, Box<dyn StdError>> {
+        dungeon::get_active_run(&self.conn, user_id)
+    }
+
+    fn start_run(
+        &self,
+        user_id: i32,
+        dungeon: &Dungeon,
+        current_hp: i32,
+        current_mp: i32,
+        now: i64,
+    ) -> Result<DungeonRun, Box<dyn StdError>> {
+        dungeon::start_run(&self.conn, user_id, dungeon, current_hp, current_mp, now)
+    }
+
+    fn record_floor(
+        &self,
+        run: &DungeonRun,
+        result: &FloorResult,
+    ) -> Result<(), Box<dyn StdError>> {
+        dungeon::record_floor(&self.conn, run, result)
+    }
+
+    fn apply_floor_battle(
+        &self,
+        run: &DungeonRun,
+        floor: &FloorResult,
+        monsters: &[Monster],
+        result: &BattleResult,
+        battle_log: &str,
+        event_id: &str,
+    ) -> Result<i64, Box<dyn StdError>> {
+        dungeon::apply_floor_battle(
+            &self.conn, run, floor, monsters, result, battle_log, event_id,
+        )
+    }
+
+    fn retreat(&self, run: &DungeonRun, now: i64) -> Result<(), Box<dyn StdError>> {
+        dungeon::retreat(&self.conn, run, now)
+    }
+
+    fn get_loot_for_monsters(
+        &self,
+        monsters: &[Monster],
+    ) -> Result<Vec<LootEntry>, Box<dyn StdError>> {
+        loot::get_loot_for_monsters(&self.conn, monsters)
+    }
+
+    fn set_loot_entry(
+        &self,
+        monster_master_id: i32,
+        item_id: i32,
+        probability: f64,
+        min_quantity: i32,
+        max_quantity: i32,
+    ) -> Result<(), Box<dyn StdError>> {
+        loot::set_loot_entry(
+            &self.conn,
+            monster_master_id,
+            item_id,
+            probability,
+            min_quantity,
+            max_quantity,
+        )
+    }
+
+    fn spawn_raid(
+        &self,
+        monster: &Monster,
+        hp: i32,
+        experience_reward: i32,
+        gold_reward: i32,
+        now: i64,
+    ) -> Result<Raid, Box<dyn StdError>> {
+        raid::spawn_raid(&self.conn, monster, hp, experience_reward, gold_reward, now)
+    }
+
+    fn get_active_raid(&self) -> Result<Option<Raid>, Box<dyn StdError>> {
+        raid::get_active_raid(&self.conn)
+    }
+
+    fn get_latest_raid(&self) -> Result<Option<Raid>, Box<dyn StdError>> {
+        raid::get_latest_raid(&self.conn)
+    }
+
+    fn record_attack(
+        &self,
+        raid: &Raid,
+        attack: &RaidAttack,
+        cooldown_minutes: i64,
+        now: i64,
+    ) -> Result<i32, Box<dyn StdError>> {
+        raid::record_attack(&self.conn, raid, attack, cooldown_minutes, now)
+    }
+
+    fn get_contributions(&self, raid_id: i64) -> Result<Vec<Contribution>, Box<dyn StdError>> {
+        raid::get_contributions(&self.conn, raid_id)
+    }
+
+    fn finish_raid(
+        &self,
+        raid: &Raid,
+        growth_config: &GrowthConfig,
+        now: i64,
+    ) -> Result<Vec<RaidReward>, Box<dyn StdError>> {
+        raid::finish_raid(&self.conn, raid, growth_config, now)
+    }
+}
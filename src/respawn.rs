@@ -0,0 +1,60 @@
+use std::error::Error as StdError;
+use std::time::Duration;
+
+use crate::config::{RespawnConfig, RespawnTarget, StorageConfig, VariantConfig};
+use crate::repository::{self, Repository};
+
+// 目標の数に足りない分を per_tick 体まで召喚して、召喚した数を返す
+fn respawn_target(
+    repo: &dyn Repository,
+    target: &RespawnTarget,
+    variants: &[VariantConfig],
+) -> Result<i32, Box<dyn StdError>> {
+    let missing = target.population - repo.count_alive(target.monster_id)?;
+    let amount = missing.min(target.per_tick.max(1));
+    if amount > 0 {
+        repo.spawn_monster(target.monster_id, amount, variants)?;
+        return Ok(amount);
+    }
+
+    Ok(0)
+}
+
+// すべての目標について1回ずつ召喚する。失敗した目標はログに出して次に進む
+pub fn respawn_once(
+    repo: &dyn Repository,
+    config: &RespawnConfig,
+    variants: &[VariantConfig],
+) -> i32 {
+    let mut spawned = 0;
+    for target in config.targets.iter() {
+        match respawn_target(repo, target, variants) {
+            Ok(amount) => spawned += amount,
+            Err(e) => eprintln!("Error respawn monster_id {}: {:?}", target.monster_id, e),
+        }
+    }
+
+    spawned
+}
+
+// interval_minutes ごとにモンスターを召喚し続ける。main から tokio::spawn で起動する
+pub async fn run(storage: StorageConfig, config: RespawnConfig, variants: Vec<VariantConfig>) {
+    if config.targets.is_empty() {
+        return;
+    }
+
+    // 接続はスレッドをまたいで共有できないので、このタスク専用に開く
+    let repo = match repository::open(&storage) {
+        Ok(repo) => repo,
+        Err(e) => {
+            eprintln!("Error respawn connect: {:?}", e);
+            return;
+        }
+    };
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(config.interval_minutes.max(1) * 60));
+    loop {
+        interval.tick().await;
+        respawn_once(repo.as_ref(), &config, &variants);
+    }
+}
@@ -0,0 +1,335 @@
+use rusqlite::{Connection, Result};
+use std::error::Error as StdError;
+use std::fmt;
+
+use crate::items::{self, Item};
+
+pub const UNLIMITED_STOCK: i32 = -1;
+
+// お店の品ぞろえ1件分
+pub struct ShopEntry {
+    pub item: Item,
+    pub price: i32,
+    pub stock: i32, // UNLIMITED_STOCK なら売り切れなし
+}
+
+impl ShopEntry {
+    // 買い取り価格は売値の半分
+    pub fn sell_price(&self) -> i32 {
+        self.price / 2
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum ShopError {
+    NotForSale(String),
+    OutOfStock(String),
+    NotEnoughGold { price: i32, gold: i32 },
+    NotOwned(String),
+    Equipped(String),
+}
+
+impl fmt::Display for ShopError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShopError::NotForSale(name) => write!(f, "Item '{}' is not for sale", name),
+            ShopError::OutOfStock(name) => write!(f, "Item '{}' is out of stock", name),
+            ShopError::NotEnoughGold { price, gold } => {
+                write!(f, "Not enough gold: price {} but has {}", price, gold)
+            }
+            ShopError::NotOwned(name) => write!(f, "Item '{}' is not owned", name),
+            ShopError::Equipped(name) => write!(f, "Item '{}' is equipped", name),
+        }
+    }
+}
+
+impl StdError for ShopError {}
+
+const SELECT_SHOP_ENTRY: &str = "SELECT
+        items.item_id,
+        items.name,
+        items.type,
+        items.attack_bonus,
+        items.defense_bonus,
+        items.agility_bonus,
+        shop.price,
+        shop.stock
+    FROM shop
+    JOIN items ON items.item_id = shop.item_id";
+
+fn shop_entry_from_row(row: &rusqlite::Row) -> Result<ShopEntry> {
+    Ok(ShopEntry {
+        item: Item {
+            item_id: row.get(0)?,
+            name: row.get(1)?,
+            item_type: row.get(2)?,
+            attack_bonus: row.get(3)?,
+            defense_bonus: row.get(4)?,
+            agility_bonus: row.get(5)?,
+        },
+        price: row.get(6)?,
+        stock: row.get(7)?,
+    })
+}
+
+pub fn get_shop_entries(conn: &Connection) -> Result<Vec<ShopEntry>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(&format!("{} ORDER BY shop.price", SELECT_SHOP_ENTRY))?;
+    let entries = statement
+        .query_map([], shop_entry_from_row)?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(entries)
+}
+
+pub fn get_shop_entry_by_name(
+    conn: &Connection,
+    name: &str,
+) -> Result<Option<ShopEntry>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(&format!("{} WHERE items.name = ?1", SELECT_SHOP_ENTRY))?;
+    let mut entry_iter = statement.query_map(rusqlite::params![name], shop_entry_from_row)?;
+
+    Ok(entry_iter.next().transpose()?)
+}
+
+// 品ぞろえに追加する。すでにあれば価格と在庫を上書きする
+pub fn set_shop_entry(
+    conn: &Connection,
+    item_id: i32,
+    price: i32,
+    stock: i32,
+) -> Result<(), Box<dyn StdError>> {
+    conn.execute(
+        "INSERT INTO shop (item_id, price, stock) VALUES (?1, ?2, ?3)
+        ON CONFLICT (item_id) DO UPDATE SET price = excluded.price, stock = excluded.stock",
+        rusqlite::params![item_id, price, stock],
+    )?;
+
+    Ok(())
+}
+
+pub fn remove_shop_entry(conn: &Connection, item_id: i32) -> Result<bool, Box<dyn StdError>> {
+    let removed = conn.execute(
+        "DELETE FROM shop WHERE item_id = ?1",
+        rusqlite::params![item_id],
+    )?;
+
+    Ok(removed > 0)
+}
+
+// GOLDの支払い・在庫・持ち物の更新をひとつのトランザクションで行う
+pub fn buy_item(
+    conn: &Connection,
+    user_id: i32,
+    name: &str,
+) -> Result<ShopEntry, Box<dyn StdError>> {
+    let tx = conn.unchecked_transaction()?;
+
+    let entry = get_shop_entry_by_name(&tx, name)?
+        .ok_or_else(|| ShopError::NotForSale(name.to_string()))?;
+    if entry.stock == 0 {
+        return Err(Box::new(ShopError::OutOfStock(name.to_string())));
+    }
+
+    let paid = tx.execute(
+        "UPDATE users SET gold = gold - ?1 WHERE user_id = ?2 AND gold >= ?1",
+        rusqlite::params![entry.price, user_id],
+    )?;
+    if paid == 0 {
+        let gold: i32 = tx.query_row(
+            "SELECT gold FROM users WHERE user_id = ?1",
+            rusqlite::params![user_id],
+            |row| row.get(0),
+        )?;
+        return Err(Box::new(ShopError::NotEnoughGold {
+            price: entry.price,
+            gold,
+        }));
+    }
+
+    if entry.stock != UNLIMITED_STOCK {
+        tx.execute(
+            "UPDATE shop SET stock = stock - 1 WHERE item_id = ?1",
+            rusqlite::params![entry.item.item_id],
+        )?;
+    }
+    items::give_item(&tx, user_id, entry.item.item_id, 1)?;
+
+    tx.commit()?;
+
+    Ok(entry)
+}
+
+// お店に1個売る。装備中の最後の1個は売れない
+pub fn sell_item(
+    conn: &Connection,
+    user_id: i32,
+    name: &str,
+) -> Result<ShopEntry, Box<dyn StdError>> {
+    let tx = conn.unchecked_transaction()?;
+
+    let entry = get_shop_entry_by_name(&tx, name)?
+        .ok_or_else(|| ShopError::NotForSale(name.to_string()))?;
+    let owned = items::get_inventory(&tx, user_id)?
+        .into_iter()
+        .find(|owned| owned.item.item_id == entry.item.item_id)
+        .ok_or_else(|| ShopError::NotOwned(name.to_string()))?;
+    if owned.equipped && owned.quantity <= 1 {
+        return Err(Box::new(ShopError::Equipped(name.to_string())));
+    }
+
+    items::take_item(&tx, user_id, entry.item.item_id, 1)?;
+    tx.execute(
+        "UPDATE users SET gold = gold + ?1 WHERE user_id = ?2",
+        rusqlite::params![entry.sell_price(), user_id],
+    )?;
+    if entry.stock != UNLIMITED_STOCK {
+        tx.execute(
+            "UPDATE shop SET stock = stock + 1 WHERE item_id = ?1",
+            rusqlite::params![entry.item.item_id],
+        )?;
+    }
+
+    tx.commit()?;
+
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db, users};
+
+    // ゴールドを持ったユーザーと、在庫つきの「どうのつるぎ」を売っているお店
+    fn setup(gold: i32, stock: i32) -> (Connection, i32, Item) {
+        let conn = Connection::open_in_memory().unwrap();
+        db::migrate(&conn).unwrap();
+        let mut user = users::add_user(&conn, "npub_shopper").unwrap();
+        user.gold = gold;
+        users::update_user(&conn, &user).unwrap();
+        let item = items::add_item(&conn, "どうのつるぎ", "weapon", 3, 0, 0).unwrap();
+        set_shop_entry(&conn, item.item_id, 100, stock).unwrap();
+
+        (conn, user.user_id, item)
+    }
+
+    fn gold(conn: &Connection, user_id: i32) -> i32 {
+        users::get_user_by_id(conn, user_id).unwrap().unwrap().gold
+    }
+
+    fn quantity(conn: &Connection, user_id: i32, item_id: i32) -> i32 {
+        items::get_inventory(conn, user_id)
+            .unwrap()
+            .iter()
+            .find(|owned| owned.item.item_id == item_id)
+            .map_or(0, |owned| owned.quantity)
+    }
+
+    // 失敗するはずの売り買いのエラー
+    fn rejected(result: Result<ShopEntry, Box<dyn StdError>>) -> Box<dyn StdError> {
+        match result {
+            Ok(entry) => panic!("{} should be rejected", entry.item.name),
+            Err(e) => e,
+        }
+    }
+
+    fn stock(conn: &Connection, name: &str) -> i32 {
+        get_shop_entry_by_name(conn, name).unwrap().unwrap().stock
+    }
+
+    #[test]
+    fn buy_pays_gold_and_takes_stock() {
+        let (conn, user_id, item) = setup(150, 2);
+
+        buy_item(&conn, user_id, "どうのつるぎ").unwrap();
+
+        assert_eq!(gold(&conn, user_id), 50);
+        assert_eq!(stock(&conn, "どうのつるぎ"), 1);
+        assert_eq!(quantity(&conn, user_id, item.item_id), 1);
+    }
+
+    #[test]
+    fn buy_without_enough_gold_changes_nothing() {
+        let (conn, user_id, item) = setup(99, 2);
+
+        let e = rejected(buy_item(&conn, user_id, "どうのつるぎ"));
+
+        assert!(matches!(
+            e.downcast_ref::<ShopError>(),
+            Some(ShopError::NotEnoughGold {
+                price: 100,
+                gold: 99
+            })
+        ));
+        assert_eq!(gold(&conn, user_id), 99);
+        assert_eq!(stock(&conn, "どうのつるぎ"), 2);
+        assert_eq!(quantity(&conn, user_id, item.item_id), 0);
+    }
+
+    #[test]
+    fn buy_stops_when_out_of_stock() {
+        let (conn, user_id, _) = setup(300, 1);
+        buy_item(&conn, user_id, "どうのつるぎ").unwrap();
+
+        let e = rejected(buy_item(&conn, user_id, "どうのつるぎ"));
+
+        assert!(matches!(
+            e.downcast_ref::<ShopError>(),
+            Some(ShopError::OutOfStock(_))
+        ));
+        assert_eq!(gold(&conn, user_id), 200);
+    }
+
+    #[test]
+    fn unlimited_stock_never_runs_out() {
+        let (conn, user_id, item) = setup(300, UNLIMITED_STOCK);
+
+        for _ in 0..3 {
+            buy_item(&conn, user_id, "どうのつるぎ").unwrap();
+        }
+
+        assert_eq!(stock(&conn, "どうのつるぎ"), UNLIMITED_STOCK);
+        assert_eq!(quantity(&conn, user_id, item.item_id), 3);
+    }
+
+    #[test]
+    fn sell_pays_half_and_restocks() {
+        let (conn, user_id, item) = setup(100, 1);
+        buy_item(&conn, user_id, "どうのつるぎ").unwrap();
+
+        sell_item(&conn, user_id, "どうのつるぎ").unwrap();
+
+        assert_eq!(gold(&conn, user_id), 50);
+        assert_eq!(stock(&conn, "どうのつるぎ"), 1);
+        assert_eq!(quantity(&conn, user_id, item.item_id), 0);
+    }
+
+    #[test]
+    fn cannot_sell_last_equipped_item() {
+        let (conn, user_id, item) = setup(200, 2);
+        buy_item(&conn, user_id, "どうのつるぎ").unwrap();
+        buy_item(&conn, user_id, "どうのつるぎ").unwrap();
+        items::equip_item(&conn, user_id, "どうのつるぎ").unwrap();
+
+        sell_item(&conn, user_id, "どうのつるぎ").unwrap();
+        let e = rejected(sell_item(&conn, user_id, "どうのつるぎ"));
+
+        assert!(matches!(
+            e.downcast_ref::<ShopError>(),
+            Some(ShopError::Equipped(_))
+        ));
+        assert_eq!(quantity(&conn, user_id, item.item_id), 1);
+        assert_eq!(gold(&conn, user_id), 50);
+    }
+
+    #[test]
+    fn cannot_sell_what_is_not_owned() {
+        let (conn, user_id, _) = setup(0, 1);
+
+        let e = rejected(sell_item(&conn, user_id, "どうのつるぎ"));
+
+        assert!(matches!(
+            e.downcast_ref::<ShopError>(),
+            Some(ShopError::NotOwned(_))
+        ));
+    }
+}
@@ -0,0 +1,72 @@
+use rusqlite::{Connection, Result};
+use serde::{Deserialize, Serialize};
+use std::error::Error as StdError;
+
+// じゅもん・とくぎの効果の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SkillKind {
+    Heal,   // 自分のHPを回復する
+    Attack, // 防御を半分無視する攻撃。回避されない
+    Guard,  // 次の自分の番まで受けるダメージを半分にする
+}
+
+impl SkillKind {
+    pub fn parse(value: &str) -> Option<SkillKind> {
+        match value {
+            "heal" => Some(SkillKind::Heal),
+            "attack" => Some(SkillKind::Attack),
+            "guard" => Some(SkillKind::Guard),
+            _ => None,
+        }
+    }
+}
+
+// スキルの情報を保持する構造体
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Skill {
+    pub skill_id: i32,
+    pub name: String,
+    pub kind: SkillKind,
+    pub mp_cost: i32,
+    pub power: i32,
+    pub learn_level: i32,
+}
+
+fn skill_from_row(row: &rusqlite::Row) -> Result<Skill> {
+    let kind: String = row.get(2)?;
+    Ok(Skill {
+        skill_id: row.get(0)?,
+        name: row.get(1)?,
+        kind: SkillKind::parse(&kind).unwrap_or(SkillKind::Attack),
+        mp_cost: row.get(3)?,
+        power: row.get(4)?,
+        learn_level: row.get(5)?,
+    })
+}
+
+// そのレベルまでに覚えるスキル
+pub fn get_skills_for_level(
+    conn: &Connection,
+    level: i32,
+) -> Result<Vec<Skill>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(
+        "SELECT skill_id, name, kind, mp_cost, power, learn_level
+        FROM skills WHERE learn_level <= ?1 ORDER BY learn_level, skill_id",
+    )?;
+    let skills = statement
+        .query_map(rusqlite::params![level], skill_from_row)?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(skills)
+}
+
+// 次のレベル以降で覚えるスキルのうち一番近いもの
+pub fn get_next_skill(conn: &Connection, level: i32) -> Result<Option<Skill>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(
+        "SELECT skill_id, name, kind, mp_cost, power, learn_level
+        FROM skills WHERE learn_level > ?1 ORDER BY learn_level, skill_id LIMIT 1",
+    )?;
+    let mut skill_iter = statement.query_map(rusqlite::params![level], skill_from_row)?;
+
+    Ok(skill_iter.next().transpose()?)
+}
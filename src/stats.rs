@@ -0,0 +1,331 @@
+use rusqlite::{Connection, Result};
+use std::error::Error as StdError;
+use std::fmt;
+
+use crate::users::{self, User};
+
+// ステータスポイントを振り分けられる能力値
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stat {
+    Hp,
+    Mp,
+    Attack,
+    Defense,
+    Agility,
+    Luck,
+}
+
+pub const STATS: [Stat; 6] = [
+    Stat::Hp,
+    Stat::Mp,
+    Stat::Attack,
+    Stat::Defense,
+    Stat::Agility,
+    Stat::Luck,
+];
+
+impl Stat {
+    // "attack" でも "ちから" でも受け付ける
+    pub fn parse(value: &str) -> Option<Stat> {
+        STATS
+            .iter()
+            .find(|stat| stat.as_str() == value || stat.label() == value)
+            .copied()
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Stat::Hp => "hp",
+            Stat::Mp => "mp",
+            Stat::Attack => "attack",
+            Stat::Defense => "defense",
+            Stat::Agility => "agility",
+            Stat::Luck => "luck",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Stat::Hp => "たいりょく",
+            Stat::Mp => "まりょく",
+            Stat::Attack => "ちから",
+            Stat::Defense => "しゅびりょく",
+            Stat::Agility => "すばやさ",
+            Stat::Luck => "うん",
+        }
+    }
+
+    // 1ポイントあたりの上昇量
+    pub fn per_point(self) -> i32 {
+        match self {
+            Stat::Hp => 3,
+            Stat::Mp => 2,
+            _ => 1,
+        }
+    }
+
+    // amount だけ能力値を増減する（マイナスならリセット）
+    fn add_to(self, user: &mut User, amount: i32) {
+        match self {
+            Stat::Hp => {
+                user.max_hp += amount;
+                user.current_hp = (user.current_hp + amount.max(0)).min(user.max_hp);
+            }
+            Stat::Mp => {
+                user.max_mp += amount;
+                user.current_mp = (user.current_mp + amount.max(0)).min(user.max_mp);
+            }
+            Stat::Attack => user.attack += amount,
+            Stat::Defense => user.defense += amount,
+            Stat::Agility => user.agility += amount,
+            Stat::Luck => user.luck += amount,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum AllocateError {
+    InvalidAmount(i32),
+    NotEnoughPoints { requested: i32, available: i32 },
+    NotEnoughGold { cost: i32, gold: i32 },
+    NothingToReset,
+}
+
+impl fmt::Display for AllocateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AllocateError::InvalidAmount(amount) => write!(f, "Invalid amount: {}", amount),
+            AllocateError::NotEnoughPoints {
+                requested,
+                available,
+            } => write!(
+                f,
+                "Not enough stat points: requested {} but has {}",
+                requested, available
+            ),
+            AllocateError::NotEnoughGold { cost, gold } => {
+                write!(f, "Not enough gold: cost {} but has {}", cost, gold)
+            }
+            AllocateError::NothingToReset => write!(f, "No allocated stat points"),
+        }
+    }
+}
+
+impl StdError for AllocateError {}
+
+// ステータスポイントを振り分けたあとのユーザー（保存はしない）
+pub(crate) fn allocated_user(user: &User, stat: Stat, points: i32) -> Result<User, AllocateError> {
+    if points <= 0 {
+        return Err(AllocateError::InvalidAmount(points));
+    }
+    if points > user.stat_points {
+        return Err(AllocateError::NotEnoughPoints {
+            requested: points,
+            available: user.stat_points,
+        });
+    }
+
+    let mut updated_user = user.clone();
+    updated_user.stat_points -= points;
+    stat.add_to(&mut updated_user, points * stat.per_point());
+
+    Ok(updated_user)
+}
+
+// 振り分けたポイント（stat_allocations の stat と points）を全部戻したあとのユーザー（保存はしない）
+pub(crate) fn respecced_user(
+    user: &User,
+    cost: i32,
+    allocations: &[(String, i32)],
+) -> Result<User, AllocateError> {
+    if user.gold < cost {
+        return Err(AllocateError::NotEnoughGold {
+            cost,
+            gold: user.gold,
+        });
+    }
+    if allocations.is_empty() {
+        return Err(AllocateError::NothingToReset);
+    }
+
+    let mut updated_user = user.clone();
+    updated_user.gold -= cost;
+    for (name, points) in allocations.iter() {
+        if let Some(stat) = Stat::parse(name) {
+            stat.add_to(&mut updated_user, -points * stat.per_point());
+            updated_user.stat_points += points;
+        }
+    }
+    updated_user.current_hp = updated_user.current_hp.min(updated_user.max_hp);
+    updated_user.current_mp = updated_user.current_mp.min(updated_user.max_mp);
+
+    Ok(updated_user)
+}
+
+// ステータスポイントを振り分けて、振り分け後のユーザーを返す
+pub fn allocate(
+    conn: &Connection,
+    user: &User,
+    stat: Stat,
+    points: i32,
+) -> Result<User, Box<dyn StdError>> {
+    let updated_user = allocated_user(user, stat, points)?;
+
+    let tx = conn.unchecked_transaction()?;
+    users::update_user(&tx, &updated_user)?;
+    tx.execute(
+        "INSERT INTO stat_allocations (user_id, stat, points) VALUES (?1, ?2, ?3)
+        ON CONFLICT (user_id, stat) DO UPDATE SET points = points + excluded.points",
+        rusqlite::params![user.user_id, stat.as_str(), points],
+    )?;
+    tx.commit()?;
+
+    Ok(updated_user)
+}
+
+// 振り分けたポイントを全部戻す。GOLDが足りなければ AllocateError::NotEnoughGold
+pub fn respec(conn: &Connection, user: &User, cost: i32) -> Result<User, Box<dyn StdError>> {
+    let tx = conn.unchecked_transaction()?;
+    let mut statement =
+        tx.prepare("SELECT stat, points FROM stat_allocations WHERE user_id = ?1 AND points > 0")?;
+    let allocations = statement
+        .query_map(rusqlite::params![user.user_id], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i32>(1)?))
+        })?
+        .collect::<Result<Vec<_>>>()?;
+    drop(statement);
+    let updated_user = respecced_user(user, cost, &allocations)?;
+
+    users::update_user(&tx, &updated_user)?;
+    tx.execute(
+        "DELETE FROM stat_allocations WHERE user_id = ?1",
+        rusqlite::params![user.user_id],
+    )?;
+    tx.commit()?;
+
+    Ok(updated_user)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    // ステータスポイント5とGOLD100を持ったユーザー
+    fn setup() -> (Connection, User) {
+        let conn = Connection::open_in_memory().unwrap();
+        db::migrate(&conn).unwrap();
+        let mut user = users::add_user(&conn, "npub_hero").unwrap();
+        user.stat_points = 5;
+        user.gold = 100;
+        users::update_user(&conn, &user).unwrap();
+
+        (conn, user)
+    }
+
+    fn saved(conn: &Connection, user: &User) -> User {
+        users::get_user_by_id(conn, user.user_id).unwrap().unwrap()
+    }
+
+    fn allocate_error(result: Result<User, Box<dyn StdError>>) -> AllocateError {
+        *result.err().unwrap().downcast::<AllocateError>().unwrap()
+    }
+
+    #[test]
+    fn stats_are_parsed_by_name_or_label() {
+        assert_eq!(Stat::parse("attack"), Some(Stat::Attack));
+        assert_eq!(Stat::parse("ちから"), Some(Stat::Attack));
+        assert_eq!(Stat::parse("うん"), Some(Stat::Luck));
+        assert_eq!(Stat::parse("str"), None);
+    }
+
+    #[test]
+    fn allocation_spends_points_per_stat_rate() {
+        let (conn, user) = setup();
+
+        let updated = allocate(&conn, &user, Stat::Hp, 2).unwrap();
+        let updated = allocate(&conn, &updated, Stat::Attack, 1).unwrap();
+
+        assert_eq!(updated.stat_points, 2);
+        assert_eq!(updated.max_hp, user.max_hp + 6);
+        assert_eq!(updated.current_hp, user.current_hp + 6);
+        assert_eq!(updated.attack, user.attack + 1);
+        let saved = saved(&conn, &user);
+        assert_eq!((saved.stat_points, saved.max_hp), (2, user.max_hp + 6));
+    }
+
+    #[test]
+    fn allocation_rejects_bad_amounts() {
+        let (conn, user) = setup();
+
+        for points in [0, -3] {
+            assert!(matches!(
+                allocate_error(allocate(&conn, &user, Stat::Luck, points)),
+                AllocateError::InvalidAmount(amount) if amount == points
+            ));
+        }
+        assert!(matches!(
+            allocate_error(allocate(&conn, &user, Stat::Luck, 6)),
+            AllocateError::NotEnoughPoints {
+                requested: 6,
+                available: 5
+            }
+        ));
+        let saved = saved(&conn, &user);
+        assert_eq!((saved.stat_points, saved.luck), (5, user.luck));
+    }
+
+    #[test]
+    fn respec_returns_every_point_for_gold() {
+        let (conn, user) = setup();
+        let updated = allocate(&conn, &user, Stat::Attack, 2).unwrap();
+        let updated = allocate(&conn, &updated, Stat::Attack, 1).unwrap();
+        let updated = allocate(&conn, &updated, Stat::Mp, 2).unwrap();
+
+        let reset = respec(&conn, &updated, 30).unwrap();
+
+        assert_eq!(reset.stat_points, 5);
+        assert_eq!(reset.gold, 70);
+        assert_eq!(reset.attack, user.attack);
+        assert_eq!(
+            (reset.current_mp, reset.max_mp),
+            (user.current_mp, user.max_mp)
+        );
+        assert_eq!(saved(&conn, &user).gold, 70);
+        // 振り分けの記録も消えるので、もう一度はできない
+        assert!(matches!(
+            allocate_error(respec(&conn, &reset, 30)),
+            AllocateError::NothingToReset
+        ));
+    }
+
+    #[test]
+    fn respec_without_enough_gold_changes_nothing() {
+        let (conn, user) = setup();
+        let updated = allocate(&conn, &user, Stat::Defense, 4).unwrap();
+
+        assert!(matches!(
+            allocate_error(respec(&conn, &updated, 101)),
+            AllocateError::NotEnoughGold {
+                cost: 101,
+                gold: 100
+            }
+        ));
+        let saved = saved(&conn, &user);
+        assert_eq!((saved.stat_points, saved.gold), (1, 100));
+        assert_eq!(saved.defense, user.defense + 4);
+    }
+
+    // 振り分けで増えた最大HPを戻すとき、現在のHPも最大HPまで下げる
+    #[test]
+    fn respec_caps_current_hp_at_the_new_maximum() {
+        let (_, user) = setup();
+        let mut allocated = allocated_user(&user, Stat::Hp, 3).unwrap();
+        allocated.current_hp = allocated.max_hp;
+
+        let reset = respecced_user(&allocated, 0, &[("hp".to_string(), 3)]).unwrap();
+
+        assert_eq!(reset.max_hp, user.max_hp);
+        assert_eq!(reset.current_hp, user.max_hp);
+    }
+}
@@ -4,6 +4,7 @@ use std::error::Error as StdError;
 use std::fmt;
 
 // ユーザーの情報を保持する構造体
+#[derive(Clone)]
 pub struct User {
     pub user_id: i32,
     pub npub: String,
@@ -18,11 +19,12 @@ pub struct User {
     pub defense: i32,
     pub agility: i32,
     pub luck: i32,
+    pub stat_points: i32, // まだ振り分けていないステータスポイント
 }
 
 #[derive(Debug)]
 pub(crate) struct UserAlreadyExistsError {
-    npub: String,
+    pub(crate) npub: String,
 }
 
 impl fmt::Display for UserAlreadyExistsError {
@@ -34,8 +36,8 @@ impl fmt::Display for UserAlreadyExistsError {
 impl StdError for UserAlreadyExistsError {}
 
 #[derive(Debug)]
-struct UserNotFoundError {
-    npub: String,
+pub(crate) struct UserNotFoundError {
+    pub(crate) npub: String,
 }
 
 impl fmt::Display for UserNotFoundError {
@@ -88,21 +90,43 @@ fn distribute_bonus_points() -> (i32, i32, i32, i32, i32, i32) {
     ) // 各属性のボーナスポイントを返す
 }
 
-pub fn add_user(conn: &Connection, npub: &str) -> Result<User, Box<dyn StdError>> {
+// ギルドに登録したばかりのユーザー（ボーナスポイントを振り分け済み）
+pub(crate) fn new_user(user_id: i32, npub: &str) -> User {
     let (hp_bonus, mp_bonus, attack_bonus, defense_bonus, agility_bonus, luck_bonus) =
         distribute_bonus_points();
 
+    User {
+        user_id,
+        npub: npub.to_string(),
+        level: 1,
+        experience: 0,
+        gold: 0,
+        current_hp: 10 + hp_bonus,
+        max_hp: 10 + hp_bonus,
+        current_mp: mp_bonus,
+        max_mp: mp_bonus,
+        attack: 3 + attack_bonus,
+        defense: 3 + defense_bonus,
+        agility: 3 + agility_bonus,
+        luck: luck_bonus,
+        stat_points: 0,
+    }
+}
+
+pub fn add_user(conn: &Connection, npub: &str) -> Result<User, Box<dyn StdError>> {
+    let user = new_user(0, npub);
+
     if let Err(e) = conn.execute(
         "INSERT INTO users (npub, current_hp, max_hp, current_mp, max_mp, attack, defense, agility, luck)
        VALUES (?1, ?2, ?2, ?3, ?3, ?4, ?5, ?6, ?7)",
         rusqlite::params![
             npub,
-            10 + hp_bonus,
-            0 + mp_bonus,
-            3 + attack_bonus,
-            3 + defense_bonus,
-            3 + agility_bonus,
-            luck_bonus,
+            user.max_hp,
+            user.max_mp,
+            user.attack,
+            user.defense,
+            user.agility,
+            user.luck,
         ],
     ) {
         if let Error::SqliteFailure(err, Some(msg)) = &e {
@@ -131,7 +155,8 @@ pub fn add_user(conn: &Connection, npub: &str) -> Result<User, Box<dyn StdError>
           attack,
           defense,
           agility,
-          luck
+          luck,
+          stat_points
       FROM users WHERE npub = ?1",
         rusqlite::params![npub],
         |row| {
@@ -149,6 +174,7 @@ pub fn add_user(conn: &Connection, npub: &str) -> Result<User, Box<dyn StdError>
                 defense: row.get(10)?,
                 agility: row.get(11)?,
                 luck: row.get(12)?,
+                stat_points: row.get(13)?,
             })
         },
     )?;
@@ -156,41 +182,47 @@ pub fn add_user(conn: &Connection, npub: &str) -> Result<User, Box<dyn StdError>
     Ok(user)
 }
 
+const SELECT_USER: &str = "SELECT
+    user_id,
+    npub,
+    level,
+    experience,
+    gold,
+    current_hp,
+    max_hp,
+    current_mp,
+    max_mp,
+    attack,
+    defense,
+    agility,
+    luck,
+    stat_points
+FROM users";
+
+fn user_from_row(row: &rusqlite::Row) -> Result<User> {
+    Ok(User {
+        user_id: row.get(0)?,
+        npub: row.get(1)?,
+        level: row.get(2)?,
+        experience: row.get(3)?,
+        gold: row.get(4)?,
+        current_hp: row.get(5)?,
+        max_hp: row.get(6)?,
+        current_mp: row.get(7)?,
+        max_mp: row.get(8)?,
+        attack: row.get(9)?,
+        defense: row.get(10)?,
+        agility: row.get(11)?,
+        luck: row.get(12)?,
+        stat_points: row.get(13)?,
+    })
+}
+
 pub fn get_user_by_npub(conn: &Connection, npub: &str) -> Result<User, Box<dyn StdError>> {
     let result = conn.query_row(
-        "SELECT 
-            user_id,
-            npub,
-            level,
-            experience,
-            gold,
-            current_hp,
-            max_hp,
-            current_mp,
-            max_mp,
-            attack,
-            defense,
-            agility,
-            luck
-        FROM users WHERE npub = ?1",
+        &format!("{} WHERE npub = ?1", SELECT_USER),
         rusqlite::params![npub],
-        |row| {
-            Ok(User {
-                user_id: row.get(0)?,
-                npub: row.get(1)?,
-                level: row.get(2)?,
-                experience: row.get(3)?,
-                gold: row.get(4)?,
-                current_hp: row.get(5)?,
-                max_hp: row.get(6)?,
-                current_mp: row.get(7)?,
-                max_mp: row.get(8)?,
-                attack: row.get(9)?,
-                defense: row.get(10)?,
-                agility: row.get(11)?,
-                luck: row.get(12)?,
-            })
-        },
+        user_from_row,
     );
 
     // ユーザーが見つからなかった場合
@@ -203,6 +235,16 @@ pub fn get_user_by_npub(conn: &Connection, npub: &str) -> Result<User, Box<dyn S
     result.map_err(|e| Box::new(e) as Box<dyn StdError>)
 }
 
+pub fn get_user_by_id(conn: &Connection, user_id: i32) -> Result<Option<User>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(&format!("{} WHERE user_id = ?1", SELECT_USER))?;
+    let user = statement
+        .query_map(rusqlite::params![user_id], user_from_row)?
+        .next()
+        .transpose()?;
+
+    Ok(user)
+}
+
 pub fn update_user(conn: &Connection, user: &User) -> Result<(), Box<dyn StdError>> {
     conn.execute(
         "UPDATE users 
@@ -218,8 +260,9 @@ pub fn update_user(conn: &Connection, user: &User) -> Result<(), Box<dyn StdErro
            attack = ?9,
            defense = ?10,
            agility = ?11,
-           luck = ?12 
-       WHERE user_id = ?13",
+           luck = ?12,
+           stat_points = ?13
+       WHERE user_id = ?14",
         rusqlite::params![
             user.npub,
             user.level,
@@ -233,6 +276,7 @@ pub fn update_user(conn: &Connection, user: &User) -> Result<(), Box<dyn StdErro
             user.defense,
             user.agility,
             user.luck,
+            user.stat_points,
             user.user_id
         ],
     )?;
@@ -240,6 +284,133 @@ pub fn update_user(conn: &Connection, user: &User) -> Result<(), Box<dyn StdErro
     Ok(())
 }
 
+// 戦闘ログの表示形式を取得（未設定ならNone）
+pub fn get_log_style(conn: &Connection, user_id: i32) -> Result<Option<String>, Box<dyn StdError>> {
+    let log_style = conn.query_row(
+        "SELECT log_style FROM users WHERE user_id = ?1",
+        rusqlite::params![user_id],
+        |row| row.get(0),
+    )?;
+
+    Ok(log_style)
+}
+
+pub fn set_log_style(
+    conn: &Connection,
+    user_id: i32,
+    log_style: &str,
+) -> Result<(), Box<dyn StdError>> {
+    conn.execute(
+        "UPDATE users SET log_style = ?1 WHERE user_id = ?2",
+        rusqlite::params![log_style, user_id],
+    )?;
+
+    Ok(())
+}
+
+// 戦闘で優先して使うスキル（未設定ならNone）
+pub fn get_selected_skill(
+    conn: &Connection,
+    user_id: i32,
+) -> Result<Option<String>, Box<dyn StdError>> {
+    let selected_skill = conn.query_row(
+        "SELECT selected_skill FROM users WHERE user_id = ?1",
+        rusqlite::params![user_id],
+        |row| row.get(0),
+    )?;
+
+    Ok(selected_skill)
+}
+
+pub fn set_selected_skill(
+    conn: &Connection,
+    user_id: i32,
+    selected_skill: Option<&str>,
+) -> Result<(), Box<dyn StdError>> {
+    conn.execute(
+        "UPDATE users SET selected_skill = ?1 WHERE user_id = ?2",
+        rusqlite::params![selected_skill, user_id],
+    )?;
+
+    Ok(())
+}
+
+// 最後に自然回復を計算した時刻（regenerated_at）から経過した分だけHP/MPを回復する
+// 保存しなくてよいとき（端数の時間しかたっていないとき）は None
+pub(crate) fn regenerate(
+    user: &User,
+    regenerated_at: Option<i64>,
+    hp_per_hour: i32,
+    mp_per_hour: i32,
+    now: i64,
+) -> Option<User> {
+    let elapsed = now - regenerated_at.unwrap_or(now);
+    let hp_gain = (elapsed * hp_per_hour as i64 / 3600) as i32;
+    let mp_gain = (elapsed * mp_per_hour as i64 / 3600) as i32;
+    let is_full = user.current_hp >= user.max_hp && user.current_mp >= user.max_mp;
+
+    // 端数の時間は次回に持ち越す。全快なら時刻だけ進める
+    if regenerated_at.is_some() && !is_full && hp_gain == 0 && mp_gain == 0 {
+        return None;
+    }
+
+    let mut user = user.clone();
+    user.current_hp = (user.current_hp + hp_gain)
+        .min(user.max_hp)
+        .max(user.current_hp);
+    user.current_mp = (user.current_mp + mp_gain)
+        .min(user.max_mp)
+        .max(user.current_mp);
+
+    Some(user)
+}
+
+// 自然回復を反映して保存する
+pub fn apply_regeneration(
+    conn: &Connection,
+    user: User,
+    hp_per_hour: i32,
+    mp_per_hour: i32,
+    now: i64,
+) -> Result<User, Box<dyn StdError>> {
+    let regenerated_at: Option<i64> = conn.query_row(
+        "SELECT regenerated_at FROM users WHERE user_id = ?1",
+        rusqlite::params![user.user_id],
+        |row| row.get(0),
+    )?;
+    let user = match regenerate(&user, regenerated_at, hp_per_hour, mp_per_hour, now) {
+        Some(user) => user,
+        None => return Ok(user),
+    };
+    conn.execute(
+        "UPDATE users SET current_hp = ?1, current_mp = ?2, regenerated_at = ?3 WHERE user_id = ?4",
+        rusqlite::params![user.current_hp, user.current_mp, now, user.user_id],
+    )?;
+
+    Ok(user)
+}
+
+// 宿屋に泊まる。GOLDが足りなければ false
+pub fn rest_at_inn(
+    conn: &Connection,
+    user: &User,
+    cost: i32,
+    now: i64,
+) -> Result<bool, Box<dyn StdError>> {
+    let updated = conn.execute(
+        "UPDATE users
+        SET
+            gold = gold - ?1,
+            current_hp = max_hp,
+            current_mp = max_mp,
+            regenerated_at = ?2
+        WHERE user_id = ?3 AND gold >= ?1",
+        rusqlite::params![cost, now, user.user_id],
+    )?;
+
+    Ok(updated > 0)
+}
+
 // ユーザーを削除
 pub fn delete_user(conn: &Connection, user_id: i32) -> Result<(), Box<dyn StdError>> {
     conn.execute(
@@ -249,3 +420,98 @@ pub fn delete_user(conn: &Connection, user_id: i32) -> Result<(), Box<dyn StdErr
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    // HP 2/20、MP 0/10、GOLD 50 のユーザー
+    fn setup() -> (Connection, User) {
+        let conn = Connection::open_in_memory().unwrap();
+        db::migrate(&conn).unwrap();
+        let mut user = add_user(&conn, "npub_hero").unwrap();
+        user.current_hp = 2;
+        user.max_hp = 20;
+        user.current_mp = 0;
+        user.max_mp = 10;
+        user.gold = 50;
+        update_user(&conn, &user).unwrap();
+
+        (conn, user)
+    }
+
+    fn regenerated_at(conn: &Connection, user_id: i32) -> Option<i64> {
+        conn.query_row(
+            "SELECT regenerated_at FROM users WHERE user_id = ?1",
+            rusqlite::params![user_id],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn regeneration_restores_per_hour_up_to_the_maximum() {
+        let (_, user) = setup();
+
+        let two_hours = regenerate(&user, Some(0), 6, 3, 2 * 3600).unwrap();
+        let all_day = regenerate(&user, Some(0), 6, 3, 24 * 3600).unwrap();
+
+        assert_eq!((two_hours.current_hp, two_hours.current_mp), (14, 6));
+        assert_eq!((all_day.current_hp, all_day.current_mp), (20, 10));
+    }
+
+    // 最大値をこえているHP（レベルアップ直後など）は減らさない
+    #[test]
+    fn regeneration_never_lowers_hp() {
+        let (_, mut user) = setup();
+        user.current_hp = 25;
+
+        let regenerated = regenerate(&user, Some(0), 6, 3, 3600).unwrap();
+
+        assert_eq!(regenerated.current_hp, 25);
+    }
+
+    #[test]
+    fn partial_hours_are_carried_over() {
+        let (conn, user) = setup();
+        // はじめて見たときは時刻だけ記録する
+        let user = apply_regeneration(&conn, user, 1, 1, 0).unwrap();
+        assert_eq!(user.current_hp, 2);
+        assert_eq!(regenerated_at(&conn, user.user_id), Some(0));
+
+        let user = apply_regeneration(&conn, user, 1, 1, 1800).unwrap();
+        assert_eq!(user.current_hp, 2);
+        assert_eq!(regenerated_at(&conn, user.user_id), Some(0));
+
+        let user = apply_regeneration(&conn, user, 1, 1, 3600).unwrap();
+        assert_eq!((user.current_hp, user.current_mp), (3, 1));
+        assert_eq!(regenerated_at(&conn, user.user_id), Some(3600));
+        let saved = get_user_by_id(&conn, user.user_id).unwrap().unwrap();
+        assert_eq!((saved.current_hp, saved.current_mp), (3, 1));
+    }
+
+    #[test]
+    fn inn_restores_everything_for_gold() {
+        let (conn, user) = setup();
+
+        assert!(rest_at_inn(&conn, &user, 30, 100).unwrap());
+
+        let saved = get_user_by_id(&conn, user.user_id).unwrap().unwrap();
+        assert_eq!((saved.current_hp, saved.current_mp), (20, 10));
+        assert_eq!(saved.gold, 20);
+        assert_eq!(regenerated_at(&conn, user.user_id), Some(100));
+    }
+
+    #[test]
+    fn inn_without_enough_gold_changes_nothing() {
+        let (conn, user) = setup();
+
+        assert!(!rest_at_inn(&conn, &user, 51, 100).unwrap());
+
+        let saved = get_user_by_id(&conn, user.user_id).unwrap().unwrap();
+        assert_eq!((saved.current_hp, saved.current_mp), (2, 0));
+        assert_eq!(saved.gold, 50);
+        assert_eq!(regenerated_at(&conn, user.user_id), None);
+    }
+}
@@ -145,6 +145,17 @@ pub fn get_npub1(npub :String) -> Result<String> {
   Ok(publickey.to_bech32().unwrap())
 }
 
+// "nostr:npub1..." / "@npub1..." / hex のどれかで書かれた公開鍵を探して hex で返す
+pub fn find_pubkey(text: &str) -> Option<String> {
+  text.split_whitespace().find_map(|word| {
+    let word = word.trim_start_matches("nostr:").trim_start_matches('@');
+    PublicKey::from_bech32(word)
+      .or_else(|_| PublicKey::from_hex(word))
+      .ok()
+      .map(|publickey| publickey.to_hex())
+  })
+}
+
 pub fn experience_for_level(level: u32) -> f64 {
   let k = 5.0; // 定数
   let a = 1.5; // 増加率
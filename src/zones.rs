@@ -0,0 +1,97 @@
+use rusqlite::{Connection, Result};
+use std::error::Error as StdError;
+
+// 冒険に出かける地域
+#[derive(Clone)]
+pub struct Zone {
+    pub zone_id: i64,
+    pub name: String,
+    pub recommended_level: i32,
+    pub description: String,
+}
+
+const SELECT_ZONE: &str = "SELECT zone_id, name, recommended_level, description FROM zones";
+
+fn zone_from_row(row: &rusqlite::Row) -> Result<Zone> {
+    Ok(Zone {
+        zone_id: row.get(0)?,
+        name: row.get(1)?,
+        recommended_level: row.get(2)?,
+        description: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+    })
+}
+
+// 推奨レベルの低い順
+pub fn get_zones(conn: &Connection) -> Result<Vec<Zone>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(&format!(
+        "{} ORDER BY recommended_level, zone_id",
+        SELECT_ZONE
+    ))?;
+    let zones = statement
+        .query_map([], zone_from_row)?
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(zones)
+}
+
+pub fn get_zone_by_name(conn: &Connection, name: &str) -> Result<Option<Zone>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(&format!("{} WHERE name = ?1", SELECT_ZONE))?;
+    let mut zone_iter = statement.query_map(rusqlite::params![name], zone_from_row)?;
+
+    Ok(zone_iter.next().transpose()?)
+}
+
+// 同じ名前の地域があれば上書きする
+pub fn add_zone(
+    conn: &Connection,
+    name: &str,
+    recommended_level: i32,
+    description: &str,
+) -> Result<Zone, Box<dyn StdError>> {
+    conn.execute(
+        "INSERT INTO zones (name, recommended_level, description) VALUES (?1, ?2, ?3)
+        ON CONFLICT (name) DO UPDATE SET
+            recommended_level = excluded.recommended_level,
+            description = excluded.description",
+        rusqlite::params![name, recommended_level, description],
+    )?;
+
+    Ok(get_zone_by_name(conn, name)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?)
+}
+
+// monster_master のモンスターを地域に割り当てる（見つからなければfalse）
+pub fn assign_monster(
+    conn: &Connection,
+    monster_master_id: i32,
+    zone_id: i64,
+) -> Result<bool, Box<dyn StdError>> {
+    let updated = conn.execute(
+        "UPDATE monster_master SET zone_id = ?1 WHERE id = ?2",
+        rusqlite::params![zone_id, monster_master_id],
+    )?;
+
+    Ok(updated > 0)
+}
+
+// ユーザーの今いる地域（どこにも出かけていなければNone）
+pub fn get_current_zone(
+    conn: &Connection,
+    user_id: i32,
+) -> Result<Option<Zone>, Box<dyn StdError>> {
+    let mut statement = conn.prepare(&format!(
+        "{} WHERE zone_id = (SELECT zone_id FROM users WHERE user_id = ?1)",
+        SELECT_ZONE
+    ))?;
+    let mut zone_iter = statement.query_map(rusqlite::params![user_id], zone_from_row)?;
+
+    Ok(zone_iter.next().transpose()?)
+}
+
+pub fn travel(conn: &Connection, user_id: i32, zone: &Zone) -> Result<(), Box<dyn StdError>> {
+    conn.execute(
+        "UPDATE users SET zone_id = ?1 WHERE user_id = ?2",
+        rusqlite::params![zone.zone_id, user_id],
+    )?;
+
+    Ok(())
+}